@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use shrinky_rs::{ImageFormat, imagedata::Image};
+
+fn bench_heif_encode(c: &mut Criterion) {
+    let image = Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load test image");
+
+    let mut group = c.benchmark_group("heif_encode");
+    group.throughput(Throughput::Elements(
+        image.image.width() as u64 * image.image.height() as u64,
+    ));
+    group.sample_size(10);
+    for format in [ImageFormat::Heic, ImageFormat::Avif] {
+        group.bench_function(format.extension(), |b| {
+            b.iter(|| {
+                image
+                    .output_as_format(format)
+                    .expect("failed to encode test image")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_heif_encode);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use shrinky_rs::imagedata::LazyImage;
+
+fn bench_lazy_image(c: &mut Criterion) {
+    let lazy = LazyImage::new("tests/test_images/bruny-oysters.jpg");
+
+    let mut group = c.benchmark_group("lazy_image");
+    group.sample_size(10);
+    group.bench_function("metadata", |b| {
+        b.iter(|| lazy.metadata().expect("failed to read metadata"))
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| lazy.clone().decode().expect("failed to decode test image"))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lazy_image);
+criterion_main!(benches);
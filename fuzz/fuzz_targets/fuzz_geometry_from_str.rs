@@ -0,0 +1,30 @@
+//! Fuzz `Geometry::from_str` with arbitrary input. It must never panic, anything it accepts must
+//! survive a round trip through `Display`, and anything it rejects must be
+//! `Error::InvalidGeometry`.
+//!
+//! Needs a nightly toolchain and `cargo install cargo-fuzz`, then from the repository root:
+//!
+//! ```shell
+//! cargo +nightly fuzz run fuzz_geometry_from_str
+//! ```
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use shrinky_rs::{Error, imagedata::Geometry};
+
+fuzz_target!(|data: &[u8]| {
+    let input = String::from_utf8_lossy(data);
+    match Geometry::from_str(&input) {
+        Ok(geometry) => {
+            let displayed = geometry.to_string();
+            match Geometry::from_str(&displayed) {
+                Ok(reparsed) => assert_eq!(reparsed, geometry, "{input:?} displayed as {displayed:?}"),
+                Err(err) => panic!("{input:?} displayed as {displayed:?} which doesn't parse: {err}"),
+            }
+        }
+        Err(Error::InvalidGeometry(_)) => {}
+        Err(err) => panic!("{input:?} failed with something other than InvalidGeometry: {err:?}"),
+    }
+});
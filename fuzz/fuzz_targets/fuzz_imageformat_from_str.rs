@@ -0,0 +1,16 @@
+//! Fuzz `ImageFormat::from_str`, which also takes file names, with arbitrary input. It must never
+//! panic.
+//!
+//! ```shell
+//! cargo +nightly fuzz run fuzz_imageformat_from_str
+//! ```
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use shrinky_rs::ImageFormat;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ImageFormat::from_str(&String::from_utf8_lossy(data));
+});
@@ -0,0 +1,52 @@
+//! Fuzz `Image::load_image` with arbitrary file contents, saved under each of the six main format
+//! extensions in turn, so the same bytes go through the JPEG, PNG and WebP decoders, the AVIF
+//! decoder and libheif. Loading must return `Ok` or a decoding error, never panic or overflow the
+//! stack. Crashes inside libheif show up as a crash rather than a panic, and are worth reporting
+//! upstream if they reproduce against the latest libheif.
+//!
+//! Start with one valid image per format in the corpus, so the fuzzer gets past the headers:
+//!
+//! ```shell
+//! mkdir -p fuzz/corpus/fuzz_load_image
+//! cp tests/test_images/bruny-oysters.{jpg,png,webp,avif,heic,heif} fuzz/corpus/fuzz_load_image/
+//! cargo +nightly fuzz run fuzz_load_image -- -max_len=262144
+//! ```
+//!
+//! The decoders are mature, so don't expect a crash in the first few minutes; a failure that
+//! quick is more likely an error variant missing from `is_decoding_error`. Leave it running for
+//! several hours, or overnight with `-max_total_time=28800`, before calling a run clean.
+#![no_main]
+
+use std::io::Write;
+
+use libfuzzer_sys::fuzz_target;
+use shrinky_rs::{Error, imagedata::Image};
+
+const EXTENSIONS: [&str; 6] = ["jpg", "png", "webp", "avif", "heic", "heif"];
+
+/// The errors a corrupt or unsupported file is expected to cause
+fn is_decoding_error(err: &Error) -> bool {
+    match err {
+        Error::Context(_, err) => is_decoding_error(err),
+        // the image crate reports truncated data as an I/O error
+        Error::Image(_) | Error::UnsupportedFormat(_) | Error::FileSystem(_) => true,
+        _ => false,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    for extension in EXTENSIONS {
+        let mut file = tempfile::Builder::new()
+            .suffix(&format!(".{extension}"))
+            .tempfile()
+            .expect("failed to create temp file");
+        file.write_all(data).expect("failed to write temp file");
+        if let Err(err) = Image::load_image(&file.path().to_path_buf()) {
+            assert!(
+                is_decoding_error(&err),
+                "unexpected error loading {} bytes as {extension}: {err:?}",
+                data.len()
+            );
+        }
+    }
+});
@@ -1,6 +1,72 @@
-use crate::ImageFormat;
+use crate::{
+    Error, HistogramFormat, ImageFormat, PngCompression, PngFilter, ResizeFilter, RotationAngle,
+    ToneMap, WatermarkPosition, imagedata::DEFAULT_PHASED_THRESHOLD,
+};
 use clap::Parser;
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
+
+/// Parse a `key=value` encoder parameter
+pub fn parse_encoder_param(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {input:?}")),
+    }
+}
+
+/// Parse a `RRGGBBAA` or `RRGGBB` hex color, with an optional leading `#`
+pub fn parse_rgba_color(input: &str) -> Result<image::Rgba<u8>, String> {
+    let hex = input.strip_prefix('#').unwrap_or(input);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return Err(format!("expected RRGGBBAA or RRGGBB, got {input:?}"));
+    }
+    let mut channels = [u8::MAX; 4];
+    for (channel, index) in channels.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&hex[index..index + 2], 16)
+            .map_err(|_| format!("invalid hex color {input:?}"))?;
+    }
+    Ok(image::Rgba(channels))
+}
+
+/// Parse a `RRGGBB` hex color, with an optional leading `#`
+pub fn parse_rgb_color(input: &str) -> Result<image::Rgb<u8>, String> {
+    if input.strip_prefix('#').unwrap_or(input).len() != 6 {
+        return Err(format!("expected RRGGBB, got {input:?}"));
+    }
+    let [r, g, b, _] = parse_rgba_color(input)?.0;
+    Ok(image::Rgb([r, g, b]))
+}
+
+/// Parse a contrast adjustment, which has to be between -100.0 and 100.0
+pub fn parse_contrast(input: &str) -> Result<f32, String> {
+    let contrast: f32 = input
+        .parse()
+        .map_err(|_| format!("invalid contrast {input:?}"))?;
+    if !(-100.0..=100.0).contains(&contrast) {
+        return Err(format!(
+            "contrast must be between -100.0 and 100.0, got {input}"
+        ));
+    }
+    Ok(contrast)
+}
+
+/// Parse a HEIF/HEIC/AVIF bit depth, which has to be 8, 10 or 12
+pub fn parse_bit_depth(input: &str) -> Result<u8, String> {
+    match input.parse() {
+        Ok(bit_depth @ (8 | 10 | 12)) => Ok(bit_depth),
+        _ => Err(format!("bit depth must be 8, 10 or 12, got {input:?}")),
+    }
+}
+
+/// Parse a positive number of seconds, which can have a fractional part
+pub fn parse_seconds(input: &str) -> Result<Duration, String> {
+    let seconds: f64 = input
+        .parse()
+        .map_err(|_| format!("invalid number of seconds {input:?}"))?;
+    if seconds <= 0.0 {
+        return Err(format!("seconds must be greater than 0, got {input}"));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("invalid duration {input:?}: {err}"))
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "Shrinky", version = env!("CARGO_PKG_VERSION"), author = "James Hodgkinson", about = "A simple image optimization tool")]
@@ -21,18 +87,39 @@ pub struct Cli {
     #[arg(long, env = "SHRINKY_OUTPUT_SUFFIX", allow_hyphen_values = true)]
     pub output_suffix: Option<String>,
 
-    /// Geometry options, eg. 800x, x800, 800x600
+    /// Geometry options, eg. 800x, x800, 800x600, 800,600 or 16:9@1920
     #[arg(short, long, env = "SHRINKY_GEOMETRY")]
     pub geometry: Option<String>,
 
+    /// Resampling filter for --geometry: nearest for pixel art, triangle for speed, gaussian for
+    /// smooth thumbnails
+    #[arg(long, value_enum, default_value_t, env = "SHRINKY_FILTER")]
+    pub filter: ResizeFilter,
+
     /// one or more input filenames
     #[arg(required = true, num_args = 1..)]
     pub filenames: Vec<PathBuf>,
 
+    /// Process the images in directories given as inputs, including subdirectories
+    #[arg(short, long, default_value = "false", env = "SHRINKY_RECURSIVE")]
+    pub recursive: bool,
+
+    /// How deep --recursive goes, 1 is only the files directly inside each directory
+    #[arg(long, env = "SHRINKY_MAX_DEPTH")]
+    pub max_depth: Option<usize>,
+
     /// Overwrite existing files without prompting
     #[arg(short, long, default_value = "false", env = "SHRINKY_FORCE")]
     pub force: bool,
 
+    /// Allow the output to replace the input file when re-encoding to the same format
+    #[arg(long, default_value = "false", env = "SHRINKY_IN_PLACE")]
+    pub in_place: bool,
+
+    /// Only write the output if it's smaller than the input file, otherwise skip it with a warning
+    #[arg(long, default_value = "false", env = "SHRINKY_OVERWRITE_IF_SMALLER")]
+    pub overwrite_if_smaller: bool,
+
     /// Compare source and compressed image quality
     #[arg(short = 'c', long, default_value = "false", env = "SHRINKY_COMPARE")]
     pub compare: bool,
@@ -45,9 +132,297 @@ pub struct Cli {
     #[arg(long, env = "SHRINKY_MIN_PSNR")]
     pub min_psnr: Option<f64>,
 
+    /// Encoder quality for lossy output formats (0-100)
+    #[arg(
+        long,
+        env = "SHRINKY_QUALITY",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub quality: Option<u8>,
+
+    /// Encoder quality for AVIF output (0-100), overrides --quality
+    #[arg(
+        long,
+        env = "SHRINKY_AVIF_QUALITY",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub avif_quality: Option<u8>,
+
+    /// Encode losslessly where the format supports it (JPG is skipped)
+    #[arg(long, default_value = "false", env = "SHRINKY_LOSSLESS")]
+    pub lossless: bool,
+
+    /// WebP near-lossless level (0-100, lower is smaller), for lossless-looking WebP at a smaller size
+    #[arg(
+        long,
+        env = "SHRINKY_NEAR_LOSSLESS",
+        conflicts_with = "lossless",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    pub near_lossless: Option<u8>,
+
+    /// Bits per sample for HEIF/HEIC/AVIF output (8, 10 or 12), defaults to matching the source
+    #[arg(long, env = "SHRINKY_BIT_DEPTH", value_parser = parse_bit_depth)]
+    pub bit_depth: Option<u8>,
+
+    /// HEIF/HEIC/AVIF encoder effort, 0 (fastest) to 9 (slowest, smallest)
+    #[arg(
+        long,
+        env = "SHRINKY_EFFORT",
+        value_parser = clap::value_parser!(u8).range(0..=9)
+    )]
+    pub effort: Option<u8>,
+
+    /// Set a libheif encoder parameter for HEIF/HEIC/AVIF output, eg. `chroma=444`. Can be repeated.
+    #[arg(long = "encoder-param", value_name = "KEY=VALUE", value_parser = parse_encoder_param)]
+    pub encoder_params: Vec<(String, String)>,
+
+    /// PNG compression level, auto-format uses `best` unless this is set
+    #[arg(long, env = "SHRINKY_PNG_COMPRESSION")]
+    pub png_compression: Option<PngCompression>,
+
+    /// PNG zlib compression level, from 0 (uncompressed) to 9 (smallest), overrides
+    /// `--png-compression`
+    #[arg(
+        long,
+        env = "SHRINKY_PNG_COMPRESSION_LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=9),
+        conflicts_with = "png_compression"
+    )]
+    pub png_compression_level: Option<u8>,
+
+    /// PNG row filter, defaults to `adaptive`
+    #[arg(long, env = "SHRINKY_PNG_FILTER")]
+    pub png_filter: Option<PngFilter>,
+
+    /// Color as RRGGBB hex that transparent areas are flattened onto for JPG output, defaults
+    /// to white
+    #[arg(long, value_parser = parse_rgb_color, env = "SHRINKY_BACKGROUND_COLOR")]
+    pub background_color: Option<image::Rgb<u8>>,
+
+    /// Losslessly optimize PNG output with oxipng, at LEVEL 0-6 (defaults to 2)
+    #[cfg(feature = "oxipng")]
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "2",
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=6),
+        env = "SHRINKY_OPTIMIZE_PNG"
+    )]
+    pub optimize_png: Option<u8>,
+
+    /// Quantize PNG output to an indexed palette of at most COLORS (2-256, defaults to 256)
+    ///
+    /// Only built with the GPL-3.0 `imagequant` feature, which makes the binary GPL-3.0.
+    #[cfg(feature = "imagequant")]
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "256",
+        value_name = "COLORS",
+        value_parser = clap::value_parser!(u16).range(2..=256),
+        env = "SHRINKY_QUANTIZE"
+    )]
+    pub quantize: Option<u16>,
+
+    /// Quantize even if the image looks like a photo or can't be quantized well
+    #[cfg(feature = "imagequant")]
+    #[arg(long, default_value = "false", env = "SHRINKY_FORCE_QUANTIZE")]
+    pub force_quantize: bool,
+
+    /// Convert the image to grayscale before encoding
+    #[arg(
+        long,
+        default_value = "false",
+        env = "SHRINKY_GRAYSCALE",
+        conflicts_with = "to_rgba"
+    )]
+    pub grayscale: bool,
+
+    /// Convert the image to 8-bit RGBA before encoding
+    #[arg(long, default_value = "false", env = "SHRINKY_TO_RGBA")]
+    pub to_rgba: bool,
+
+    /// Encode just the first frame of an animated GIF or WebP, so it can be saved in formats
+    /// that don't support animation
+    #[arg(long, default_value = "false", env = "SHRINKY_FIRST_FRAME")]
+    pub first_frame: bool,
+
+    /// Convert image N (counting from 0) of an ICO file, instead of the largest one
+    #[arg(long, value_name = "N", env = "SHRINKY_FRAME")]
+    pub frame: Option<usize>,
+
+    /// How to tone map OpenEXR and Radiance HDR input down to 8 bits
+    #[arg(long, value_enum, default_value_t, env = "SHRINKY_TONEMAP")]
+    pub tonemap: ToneMap,
+
+    /// Brighten (or darken, if negative) the image, from -100 to 100
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-100..=100),
+        env = "SHRINKY_BRIGHTNESS"
+    )]
+    pub brightness: Option<i32>,
+
+    /// Increase (or decrease, if negative) the image contrast, from -100.0 to 100.0
+    #[arg(long, allow_negative_numbers = true, value_parser = parse_contrast, env = "SHRINKY_CONTRAST")]
+    pub contrast: Option<f32>,
+
+    /// Rotate the (resized) image clockwise by 90, 180 or 270 degrees, or flip it with fliph/flipv
+    #[arg(long, value_enum, env = "SHRINKY_ROTATE")]
+    pub rotate: Option<RotationAngle>,
+
+    /// Add a border of this many pixels around the (resized) image
+    #[arg(long, env = "SHRINKY_BORDER")]
+    pub border: Option<u32>,
+
+    /// Border color as RRGGBBAA (or RRGGBB) hex
+    #[arg(long, default_value = "FFFFFFFF", value_parser = parse_rgba_color, env = "SHRINKY_BORDER_COLOR")]
+    pub border_color: image::Rgba<u8>,
+
+    /// Overlay this image as a watermark, after resizing and adding any border
+    #[arg(long, env = "SHRINKY_WATERMARK")]
+    pub watermark: Option<PathBuf>,
+
+    /// Where to put the watermark
+    #[arg(long, value_enum, default_value_t, env = "SHRINKY_WATERMARK_POSITION")]
+    pub watermark_position: WatermarkPosition,
+
+    /// Watermark opacity, from 0.0 (invisible) to 1.0
+    #[arg(long, default_value_t = 1.0, env = "SHRINKY_WATERMARK_OPACITY")]
+    pub watermark_opacity: f32,
+
+    /// Only try the slow HEIF/HEIC/AVIF encoders if the best JPG/PNG/WebP result is over the threshold
+    #[arg(long, default_value = "false", env = "SHRINKY_PHASED_AUTO_FORMAT")]
+    pub phased_auto_format: bool,
+
+    /// If auto-format can't encode any format, re-encode in the input's own format instead of failing
+    #[arg(
+        long,
+        default_value = "false",
+        env = "SHRINKY_FALLBACK_TO_ORIGINAL_FORMAT"
+    )]
+    pub fallback_to_original_format: bool,
+
+    /// Size in bytes above which --phased-auto-format tries the HEIF/HEIC/AVIF encoders
+    #[arg(long, default_value_t = DEFAULT_PHASED_THRESHOLD, env = "SHRINKY_PHASED_THRESHOLD")]
+    pub phased_threshold: usize,
+
+    /// Only try these formats when auto-selecting the output format, eg. `png,webp,qoi`.
+    /// QOI is only tried when it's listed here
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "FORMATS",
+        env = "SHRINKY_FORMATS"
+    )]
+    pub formats: Option<Vec<ImageFormat>>,
+
+    /// Stop auto-format after this many seconds and use the smallest format that finished,
+    /// or the input's own format if none did. Ignored with --explain and --phased-auto-format
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_seconds,
+        env = "SHRINKY_AUTO_FORMAT_TIMEOUT"
+    )]
+    pub auto_format_timeout: Option<Duration>,
+
+    /// Re-read and decode the written output, removing it if it doesn't match the source
+    #[arg(long, default_value = "false", env = "SHRINKY_VERIFY")]
+    pub verify: bool,
+
+    /// Minimum SSIM score the written output must reach when using --verify
+    #[arg(long, default_value_t = 0.90, env = "SHRINKY_VERIFY_MIN_SSIM")]
+    pub verify_min_ssim: f64,
+
+    /// Print the output as a base64 `data:` URL on stdout instead of writing a file
+    #[arg(long, default_value = "false", env = "SHRINKY_DATA_URL")]
+    pub data_url: bool,
+
+    /// Print a color histogram of the source image to stdout, as CSV (the default) or JSON
+    #[arg(long, num_args = 0..=1, default_missing_value = "csv", value_name = "FORMAT")]
+    pub histogram: Option<HistogramFormat>,
+
+    /// Print the N dominant colors of the source image to stdout as hex, most common first
+    #[arg(long, value_name = "N")]
+    pub palette: Option<usize>,
+
+    /// Include per-channel histogram stats (min, max, mean, std-dev) in --info output
+    #[arg(short, long, default_value = "false", env = "SHRINKY_VERBOSE")]
+    pub verbose: bool,
+
+    /// Print whether the source image looks like a "photo" or a "graphic" to stdout
+    #[arg(long, default_value = "false")]
+    pub classify: bool,
+
+    /// Print a table of the formats auto-format tried, their sizes and which was picked
+    #[arg(long, default_value = "false", env = "SHRINKY_EXPLAIN")]
+    pub explain: bool,
+
     /// Show image info and return
     #[arg(short, long, default_value = "false")]
     pub info: bool,
+
+    /// Only log errors, and don't print the original and new sizes after converting
+    #[arg(short, long, default_value = "false", env = "SHRINKY_QUIET")]
+    pub quiet: bool,
+}
+
+impl Cli {
+    /// Check for combinations of options that can't all be honoured, before touching any files
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.lossless && self.output_type == Some(ImageFormat::Jpg) {
+            return Err(Error::InvalidOptions(
+                "--lossless can't be used with --output-type jpg, JPG is always lossy".to_string(),
+            ));
+        }
+        if self.output_type.is_some() {
+            for (flag, set) in [
+                ("--explain", self.explain),
+                ("--phased-auto-format", self.phased_auto_format),
+                ("--auto-format-timeout", self.auto_format_timeout.is_some()),
+                ("--formats", self.formats.is_some()),
+                (
+                    "--fallback-to-original-format",
+                    self.fallback_to_original_format,
+                ),
+            ] {
+                if set {
+                    return Err(Error::InvalidOptions(format!(
+                        "{flag} only applies when auto-selecting the output format, so it can't be used with --output-type"
+                    )));
+                }
+            }
+        }
+        if self.data_url && self.delete {
+            return Err(Error::InvalidOptions(
+                "--data-url doesn't write an output file, so it can't be used with --delete"
+                    .to_string(),
+            ));
+        }
+        if self.max_depth.is_some() && !self.recursive {
+            return Err(Error::InvalidOptions(
+                "--max-depth only applies to --recursive".to_string(),
+            ));
+        }
+        if self.max_depth == Some(0) {
+            return Err(Error::InvalidOptions(
+                "--max-depth must be at least 1, the files directly inside the directory"
+                    .to_string(),
+            ));
+        }
+        if self.quiet && self.debug {
+            return Err(Error::InvalidOptions(
+                "--quiet only logs errors, so it can't be used with --debug".to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub fn test_setup_logging() {
@@ -1,14 +1,632 @@
 //! Image handling magic
 
-use std::{fmt::Display, io::Cursor, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Cursor,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
 
-use image::DynamicImage;
+use base64::Engine;
+use image::{
+    AnimationDecoder, DynamicImage,
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, PngEncoder},
+    },
+};
 use image_compare::{Algorithm, rgb_similarity_structure};
-use libheif_rs::{Channel, CompressionFormat, EncoderQuality, HeifContext, LibHeif};
-use log::{debug, error};
-use rayon::iter::IntoParallelIterator;
+use libheif_rs::{
+    Channel, ColorSpace, CompressionFormat, Encoder, EncoderParameterValue, EncoderQuality,
+    HeifContext, LibHeif, RgbChroma,
+};
+use log::{debug, error, warn};
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+    },
+    slice::{ParallelSlice, ParallelSliceMut},
+};
 
-use crate::{Error, ImageFormat};
+use strum::EnumIter;
+
+use crate::{
+    Error, ErrorContext, ImageFormat, PngCompression, PngFilter, ResizeFilter, RotationAngle,
+    ToneMap,
+};
+
+#[cfg(feature = "raw")]
+use crate::raw::decode_dng;
+
+/// Quality used by the libheif encoder when nothing else is specified
+pub const DEFAULT_HEIF_QUALITY: u8 = 85;
+
+/// Quality used for lossy WebP output when nothing else is specified. Graphics are encoded
+/// losslessly instead, unless a quality is given.
+pub const DEFAULT_WEBP_QUALITY: u8 = 80;
+
+/// Size in bytes above which phased auto-format also tries the HEIF-family formats
+pub const DEFAULT_PHASED_THRESHOLD: usize = 500 * 1024;
+
+/// Check that an encoder quality is in the 0-100 range, for library callers (the CLI range-checks
+/// `--quality` while parsing)
+pub fn validate_quality(quality: u8) -> Result<u8, Error> {
+    if quality > 100 {
+        return Err(Error::InvalidOptions(format!(
+            "Quality must be between 0 and 100, got {quality}"
+        )));
+    }
+    Ok(quality)
+}
+
+/// The highest (slowest, smallest output) encoder effort
+pub const MAX_EFFORT: u8 = 9;
+
+/// x265 presets, fastest first, indexed by effort
+const X265_PRESETS: [&str; 10] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+/// Check that an encoder effort is in the 0-9 range, for library callers (the CLI range-checks
+/// `--effort` while parsing)
+pub fn validate_effort(effort: u8) -> Result<u8, Error> {
+    if effort > MAX_EFFORT {
+        return Err(Error::InvalidOptions(format!(
+            "Effort must be between 0 and {MAX_EFFORT}, got {effort}"
+        )));
+    }
+    Ok(effort)
+}
+
+/// The highest (slowest, smallest output) PNG compression level
+pub const MAX_PNG_COMPRESSION_LEVEL: u8 = 9;
+
+/// Check that a PNG compression level is in the 0-9 range
+pub fn validate_png_compression_level(level: u8) -> Result<u8, Error> {
+    if level > MAX_PNG_COMPRESSION_LEVEL {
+        return Err(Error::InvalidOptions(format!(
+            "PNG compression level must be between 0 and {MAX_PNG_COMPRESSION_LEVEL}, got {level}"
+        )));
+    }
+    Ok(level)
+}
+
+/// What transparent areas are flattened onto for formats without an alpha channel
+pub const DEFAULT_BACKGROUND_COLOR: image::Rgb<u8> = image::Rgb([u8::MAX, u8::MAX, u8::MAX]);
+
+/// oxipng optimization level used by `--optimize-png` when no level is given
+#[cfg(feature = "oxipng")]
+pub const DEFAULT_OXIPNG_LEVEL: u8 = 2;
+
+/// Highest oxipng optimization level, higher levels are the same as this
+#[cfg(feature = "oxipng")]
+pub const MAX_OXIPNG_LEVEL: u8 = 6;
+
+/// Losslessly shrink PNG data with oxipng at the given level (0-6), dropping metadata chunks
+/// that don't affect how the image is displayed
+#[cfg(feature = "oxipng")]
+pub fn optimize_png(data: &[u8], level: u8) -> Result<Vec<u8>, Error> {
+    if level > MAX_OXIPNG_LEVEL {
+        return Err(Error::InvalidOptions(format!(
+            "PNG optimization level must be between 0 and {MAX_OXIPNG_LEVEL}, got {level}"
+        )));
+    }
+    let options = oxipng::Options {
+        strip: oxipng::StripChunks::Safe,
+        ..oxipng::Options::from_preset(level)
+    };
+    oxipng::optimize_from_memory(data, &options)
+        .map_err(|e| Error::ImageEncodingError(format!("oxipng failed to optimize PNG: {e}")))
+}
+
+/// Largest palette `--quantize` can produce
+#[cfg(feature = "imagequant")]
+pub const MAX_QUANTIZE_COLORS: u16 = 256;
+
+/// Minimum imagequant quality (0-100) before quantization is skipped, unless it's forced
+#[cfg(feature = "imagequant")]
+pub const QUANTIZE_MIN_QUALITY: u8 = 70;
+
+/// Check a WebP near-lossless level is in the 0-100 range
+pub fn validate_near_lossless(level: u8) -> Result<u8, Error> {
+    if level > 100 {
+        return Err(Error::InvalidOptions(format!(
+            "Near-lossless level must be between 0 and 100, got {level}"
+        )));
+    }
+    Ok(level)
+}
+
+/// Wrap encoded image data in a `data:` URL for embedding in HTML or CSS
+pub fn data_url(format: ImageFormat, data: &[u8]) -> String {
+    format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        base64::engine::general_purpose::STANDARD.encode(data)
+    )
+}
+
+/// Write `data` to a temporary file next to `path` and then rename it over `path`, so a failed
+/// write never leaves a partly written file behind. The temporary file is removed on failure.
+/// When `path` already exists the new file gets its permissions.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    if let Err(err) = std::fs::write(&temp_path, data)
+        .and_then(|()| match std::fs::metadata(path) {
+            Ok(existing) => std::fs::set_permissions(&temp_path, existing.permissions()),
+            Err(_) => Ok(()),
+        })
+        .and_then(|()| std::fs::rename(&temp_path, path))
+    {
+        if temp_path.exists()
+            && let Err(cleanup_err) = std::fs::remove_file(&temp_path)
+        {
+            warn!(
+                "Failed to remove temporary file {}: {cleanup_err}",
+                temp_path.display()
+            );
+        }
+        return Err(Error::from_io(&err, path));
+    }
+    Ok(())
+}
+
+/// The frames of an animated GIF or WebP, or `None` for formats (and WebP files) that aren't
+/// animated
+fn animation_frame_iter<'a, R: std::io::BufRead + std::io::Seek + 'a>(
+    reader: R,
+    format: ImageFormat,
+) -> image::ImageResult<Option<image::Frames<'a>>> {
+    match format {
+        ImageFormat::Gif => Ok(Some(
+            image::codecs::gif::GifDecoder::new(reader)?.into_frames(),
+        )),
+        ImageFormat::Webp => {
+            let decoder = image::codecs::webp::WebPDecoder::new(reader)?;
+            Ok(decoder.has_animation().then(|| decoder.into_frames()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Composite `image` onto a solid `background`, dropping the alpha channel. 16-bit images stay
+/// 16-bit.
+fn flatten_alpha_onto(image: &DynamicImage, background: image::Rgb<u8>) -> DynamicImage {
+    let background = background
+        .0
+        .map(|channel| f32::from(channel) / f32::from(u8::MAX));
+    let mut flattened = image::Rgb32FImage::new(image.width(), image.height());
+    for (output, pixel) in flattened.pixels_mut().zip(image.to_rgba32f().pixels()) {
+        let [r, g, b, alpha] = pixel.0;
+        let blend = |color: f32, background: f32| color * alpha + background * (1.0 - alpha);
+        *output = image::Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ]);
+    }
+    let flattened = DynamicImage::ImageRgb32F(flattened);
+    if image.color().bytes_per_pixel() / image.color().channel_count() > 1 {
+        flattened.to_rgb16().into()
+    } else {
+        flattened.to_rgb8().into()
+    }
+}
+
+/// The sRGB transfer function, for linear light from 0.0 to 1.0
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Tone map a linear HDR image to 8-bit sRGB with `operator`. Negative and NaN samples become
+/// black. Images that don't have float samples are returned unchanged.
+fn tone_map(image: DynamicImage, operator: ToneMap) -> DynamicImage {
+    let map_color = |value: f32| {
+        let value = if value.is_nan() { 0.0 } else { value.max(0.0) };
+        let mapped = match operator {
+            ToneMap::Reinhard => value / (1.0 + value),
+            ToneMap::Exposure => 1.0 - (-value).exp(),
+        };
+        (linear_to_srgb(mapped) * 255.0).round().clamp(0.0, 255.0) as u8
+    };
+    let map_alpha = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    match image {
+        DynamicImage::ImageRgb32F(hdr) => DynamicImage::ImageRgb8(image::RgbImage::from_fn(
+            hdr.width(),
+            hdr.height(),
+            |x, y| image::Rgb(hdr.get_pixel(x, y).0.map(map_color)),
+        )),
+        DynamicImage::ImageRgba32F(hdr) => DynamicImage::ImageRgba8(image::RgbaImage::from_fn(
+            hdr.width(),
+            hdr.height(),
+            |x, y| {
+                let [r, g, b, alpha] = hdr.get_pixel(x, y).0;
+                image::Rgba([map_color(r), map_color(g), map_color(b), map_alpha(alpha)])
+            },
+        )),
+        image => image,
+    }
+}
+
+/// One image in an ICO file's directory
+struct IcoEntry<'a> {
+    width: u32,
+    height: u32,
+    bits_per_pixel: u16,
+    /// The 16 byte directory entry
+    dir_entry: &'a [u8],
+    /// The entry's PNG or BMP data
+    data: &'a [u8],
+}
+
+impl IcoEntry<'_> {
+    /// Decode just this entry. PNG entries are decoded directly, since the image crate's ICO
+    /// decoder only takes RGBA ones. BMP entries are wrapped in a single image ICO for it.
+    fn decode(&self) -> image::ImageResult<DynamicImage> {
+        if self.data.starts_with(&[0x89, b'P', b'N', b'G']) {
+            return image::load_from_memory_with_format(self.data, image::ImageFormat::Png);
+        }
+        let mut single = vec![0, 0, 1, 0, 1, 0];
+        single.extend_from_slice(&self.dir_entry[..12]);
+        single.extend_from_slice(&22u32.to_le_bytes());
+        single.extend_from_slice(self.data);
+        image::load_from_memory_with_format(&single, image::ImageFormat::Ico)
+    }
+}
+
+fn ico_error(message: &str) -> image::ImageError {
+    image::ImageError::Decoding(image::error::DecodingError::new(
+        image::error::ImageFormatHint::Exact(image::ImageFormat::Ico),
+        message.to_string(),
+    ))
+}
+
+/// Read the directory of an ICO file, in file order
+fn ico_entries(data: &[u8]) -> image::ImageResult<Vec<IcoEntry<'_>>> {
+    let count = match data {
+        [0, 0, 1, 0, low, high, ..] => usize::from(u16::from_le_bytes([*low, *high])),
+        _ => return Err(ico_error("missing ICO header")),
+    };
+    (0..count)
+        .map(|index| {
+            let start = 6 + index * 16;
+            let dir_entry = data
+                .get(start..start + 16)
+                .ok_or_else(|| ico_error("truncated ICO directory"))?;
+            let u32_at = |offset: usize| {
+                u32::from_le_bytes([
+                    dir_entry[offset],
+                    dir_entry[offset + 1],
+                    dir_entry[offset + 2],
+                    dir_entry[offset + 3],
+                ]) as usize
+            };
+            let (length, offset) = (u32_at(8), u32_at(12));
+            let image_data = offset
+                .checked_add(length)
+                .and_then(|end| data.get(offset..end))
+                .ok_or_else(|| ico_error("ICO entry is outside the file"))?;
+            // a stored size of 0 means 256
+            let dimension = |stored: u8| match stored {
+                0 => 256,
+                stored => u32::from(stored),
+            };
+            Ok(IcoEntry {
+                width: dimension(dir_entry[0]),
+                height: dimension(dir_entry[1]),
+                bits_per_pixel: u16::from_le_bytes([dir_entry[6], dir_entry[7]]),
+                dir_entry,
+                data: image_data,
+            })
+        })
+        .collect()
+}
+
+/// Decode the largest image in an ICO file, or image `index` if given. The image crate's own
+/// ICO decoder prefers colour depth over size, which would turn a favicon into a 16x16 image.
+fn decode_ico(data: &[u8], index: Option<usize>) -> Result<DynamicImage, Error> {
+    let entries = ico_entries(data).context("reading ICO directory")?;
+    let entry = match index {
+        Some(index) => entries.get(index).ok_or_else(|| {
+            Error::InvalidOptions(format!(
+                "ICO image {index} doesn't exist, there are {} images (counting from 0)",
+                entries.len()
+            ))
+        })?,
+        None => entries
+            .iter()
+            .max_by_key(|entry| (entry.width * entry.height, entry.bits_per_pixel))
+            .ok_or_else(|| Error::Image(ico_error("ICO file has no images")))?,
+    };
+    debug!(
+        "Decoding the {}x{} {} bit ICO image",
+        entry.width, entry.height, entry.bits_per_pixel
+    );
+    entry.decode().context(format!(
+        "decoding {}x{} ICO image",
+        entry.width, entry.height
+    ))
+}
+
+/// Name the DXGI formats DDS textures commonly use, for error messages
+fn dxgi_format_name(dxgi_format: u32) -> Option<&'static str> {
+    Some(match dxgi_format {
+        2 => "R32G32B32A32_FLOAT",
+        10 => "R16G16B16A16_FLOAT",
+        24 => "R10G10B10A2_UNORM",
+        28 => "R8G8B8A8_UNORM",
+        29 => "R8G8B8A8_UNORM_SRGB",
+        61 => "R8_UNORM",
+        79 => "BC4_TYPELESS",
+        80 => "BC4_UNORM",
+        81 => "BC4_SNORM",
+        82 => "BC5_TYPELESS",
+        83 => "BC5_UNORM",
+        84 => "BC5_SNORM",
+        87 => "B8G8R8A8_UNORM",
+        88 => "B8G8R8X8_UNORM",
+        91 => "B8G8R8A8_UNORM_SRGB",
+        94 => "BC6H_TYPELESS",
+        95 => "BC6H_UF16",
+        96 => "BC6H_SF16",
+        97 => "BC7_TYPELESS",
+        98 => "BC7_UNORM",
+        99 => "BC7_UNORM_SRGB",
+        _ => return None,
+    })
+}
+
+/// Why the image crate can't decode a DDS texture, going by its header. `None` if it's BC1, BC2
+/// or BC3 compressed, or the header is too short to tell, which the decoder reports itself.
+fn dds_unsupported_reason(data: &[u8]) -> Option<String> {
+    const SUPPORTED: &str = "only BC1 (DXT1), BC2 (DXT3) and BC3 (DXT5) are supported";
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    // DDPF_FOURCC isn't set for uncompressed pixels
+    if u32_at(80)? & 0x4 == 0 {
+        return Some(format!(
+            "uncompressed DDS textures aren't supported, {SUPPORTED}"
+        ));
+    }
+    match data.get(84..88)? {
+        b"DXT1" | b"DXT3" | b"DXT5" => None,
+        b"DX10" => match u32_at(128)? {
+            // BC1, BC2 and BC3, each as TYPELESS, UNORM or UNORM_SRGB
+            70..=78 => None,
+            dxgi_format => Some(format!(
+                "DDS textures in DXGI format {} aren't supported, {SUPPORTED}",
+                dxgi_format_name(dxgi_format).map_or_else(
+                    || dxgi_format.to_string(),
+                    |name| format!("{name} ({dxgi_format})")
+                )
+            )),
+        },
+        fourcc if fourcc.iter().all(u8::is_ascii_graphic) => Some(format!(
+            "DDS textures compressed as {} aren't supported, {SUPPORTED}",
+            String::from_utf8_lossy(fourcc)
+        )),
+        _ => Some(format!(
+            "DDS textures in D3D format {} aren't supported, {SUPPORTED}",
+            u32_at(84)?
+        )),
+    }
+}
+
+/// Decode the top-level image of a DDS texture, with a readable error for compressions the image
+/// crate can't decode
+fn decode_dds(data: &[u8]) -> Result<DynamicImage, Error> {
+    if let Some(reason) = dds_unsupported_reason(data) {
+        return Err(Error::UnsupportedFormat(reason));
+    }
+    image::load_from_memory_with_format(data, image::ImageFormat::Dds)
+        .context("decoding DDS texture")
+}
+
+#[cfg(feature = "svg")]
+fn svg_error(message: &str) -> image::ImageError {
+    image::ImageError::Decoding(image::error::DecodingError::new(
+        image::error::ImageFormatHint::Name("svg".to_string()),
+        message.to_string(),
+    ))
+}
+
+/// Rasterize an SVG, or gzipped SVGZ, to RGBA. With both dimensions of `size` it's drawn at
+/// exactly that size, with one the other keeps the SVG's aspect ratio, and without `size` it's
+/// drawn at its own size. An SVG without a width, height or viewBox has no size of its own, so
+/// it needs `size`.
+#[cfg(feature = "svg")]
+fn render_svg(data: &[u8], size: Option<&Geometry>) -> Result<DynamicImage, Error> {
+    use resvg::{tiny_skia, usvg, usvg::roxmltree};
+
+    let data = if data.starts_with(&[0x1F, 0x8B]) {
+        usvg::decompress_svgz(data).map_err(|err| svg_error(&err.to_string()))?
+    } else {
+        data.to_vec()
+    };
+    let text = std::str::from_utf8(&data).map_err(|_| svg_error("SVG isn't UTF-8 text"))?;
+    let document = roxmltree::Document::parse_with_options(
+        text,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        },
+    )
+    .map_err(|err| svg_error(&err.to_string()))?;
+
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    if options.fontdb.is_empty() && document.descendants().any(|node| node.has_tag_name("text")) {
+        warn!("No fonts were found, so the text in the SVG won't be drawn");
+    }
+    let tree =
+        usvg::Tree::from_xmltree(&document, &options).map_err(|err| svg_error(&err.to_string()))?;
+
+    let root = document.root_element();
+    let has_size = root.has_attribute("viewBox")
+        || ["width", "height"].iter().all(|name| {
+            root.attribute(*name)
+                .is_some_and(|value| !value.ends_with('%'))
+        });
+    let (svg_width, svg_height) = (tree.size().width(), tree.size().height());
+    let scaled = |value: u32, multiplier: f32, divisor: f32| {
+        ((value as f32 * multiplier / divisor).round() as u32).max(1)
+    };
+    let (width, height) = match size.map(|size| (size.width, size.height)) {
+        Some((Some(width), Some(height))) => (width, height),
+        Some((Some(width), None)) if has_size => (width, scaled(width, svg_height, svg_width)),
+        Some((None, Some(height))) if has_size => (scaled(height, svg_width, svg_height), height),
+        None | Some((None, None)) if has_size => (
+            (svg_width.round() as u32).max(1),
+            (svg_height.round() as u32).max(1),
+        ),
+        _ => {
+            return Err(Error::InvalidGeometry(
+                "the SVG has no width, height or viewBox, so both dimensions of the geometry are needed to draw it"
+                    .to_string(),
+            ));
+        }
+    };
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| Error::InvalidGeometry(format!("can't draw an SVG at {width}x{height}")))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(width as f32 / svg_width, height as f32 / svg_height),
+        &mut pixmap.as_mut(),
+    );
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    image::RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| svg_error("the rendered SVG is the wrong size").into())
+}
+
+/// Without the `svg` feature [check_codec] refuses SVG before it gets here
+#[cfg(not(feature = "svg"))]
+fn render_svg(_data: &[u8], _size: Option<&Geometry>) -> Result<DynamicImage, Error> {
+    check_codec(ImageFormat::Svg).map(|()| DynamicImage::default())
+}
+
+/// Without the `raw` feature [check_codec] refuses camera RAW before it gets here
+#[cfg(not(feature = "raw"))]
+fn decode_dng(_data: &[u8]) -> Result<DynamicImage, Error> {
+    check_codec(ImageFormat::Raw).map(|()| DynamicImage::default())
+}
+
+/// Scale a `bit_depth` bit sample up to the full 16-bit range, repeating its top bits in the new
+/// low bits so the largest value maps to 65535
+pub fn scale_to_16_bits(sample: u16, bit_depth: u8) -> u16 {
+    let bit_depth = u32::from(bit_depth.clamp(1, 16));
+    let shift = 16 - bit_depth;
+    let low_bits = bit_depth
+        .checked_sub(shift)
+        .and_then(|fill| sample.checked_shr(fill))
+        .unwrap_or(0);
+    (sample << shift) | low_bits
+}
+
+fn heif_error(message: &str) -> image::ImageError {
+    image::ImageError::Decoding(image::error::DecodingError::new(
+        image::error::ImageFormatHint::Name("heif".to_string()),
+        message.to_string(),
+    ))
+}
+
+/// Decode a HEIF or HEIC image with more than 8 bits per channel to RGB16, or RGBA16 if it has
+/// alpha. The image crate's libheif hook leaves 10 and 12-bit samples in the low bits, which
+/// makes the image almost black, so they're scaled up to the full 16-bit range here. Returns
+/// `None` for 8-bit images, which the hook decodes fine.
+fn decode_heif_high_bit_depth(context: &HeifContext) -> Result<Option<DynamicImage>, Error> {
+    let handle = context
+        .primary_image_handle()
+        .map_err(image::ImageError::from)?;
+    if handle.luma_bits_per_pixel() <= 8 {
+        return Ok(None);
+    }
+    let has_alpha = handle.has_alpha_channel();
+    let (chroma, channels) = match has_alpha {
+        true => (RgbChroma::HdrRgbaLe, 4),
+        false => (RgbChroma::HdrRgbLe, 3),
+    };
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(image::ImageError::from)?;
+    let planes = decoded.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| heif_error("libheif didn't return interleaved RGB samples"))?;
+    debug!(
+        "Decoding {}x{} {} bit HEIF image to 16 bits",
+        plane.width, plane.height, plane.bits_per_pixel
+    );
+
+    let row_bytes = plane.width as usize * channels * 2;
+    let mut samples = Vec::with_capacity(row_bytes / 2 * plane.height as usize);
+    for y in 0..plane.height as usize {
+        let row = plane
+            .data
+            .get(y * plane.stride..y * plane.stride + row_bytes)
+            .ok_or_else(|| heif_error("HEIF plane is smaller than its dimensions"))?;
+        samples.extend(row.chunks_exact(2).map(|sample| {
+            scale_to_16_bits(
+                u16::from_le_bytes([sample[0], sample[1]]),
+                plane.bits_per_pixel,
+            )
+        }));
+    }
+    let image = match has_alpha {
+        true => image::ImageBuffer::from_raw(plane.width, plane.height, samples)
+            .map(DynamicImage::ImageRgba16),
+        false => image::ImageBuffer::from_raw(plane.width, plane.height, samples)
+            .map(DynamicImage::ImageRgb16),
+    };
+    image
+        .map(Some)
+        .ok_or_else(|| Error::Image(heif_error("HEIF plane is smaller than its dimensions")))
+}
+
+/// Check that a HEIF/AVIF bit depth is one libheif can encode, for library callers (the CLI
+/// checks `--bit-depth` while parsing)
+pub fn validate_bit_depth(bit_depth: u8) -> Result<u8, Error> {
+    match bit_depth {
+        8 | 10 | 12 => Ok(bit_depth),
+        _ => Err(Error::InvalidOptions(format!(
+            "Bit depth must be 8, 10 or 12, got {bit_depth}"
+        ))),
+    }
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Geometry {
@@ -22,6 +640,173 @@ pub struct QualityScore {
     pub psnr: Option<f64>,
 }
 
+/// Pixel-level differences between two images, see [Image::diff]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DifferenceMetrics {
+    /// Largest difference between any two corresponding RGB samples
+    pub max_delta: u8,
+    /// Mean absolute difference between corresponding RGB samples
+    pub mean_delta: f64,
+    pub ssim: f64,
+    pub psnr: f64,
+}
+
+/// Pixels handled by each rayon task when building a histogram
+const HISTOGRAM_CHUNK_PIXELS: usize = 16 * 1024;
+
+/// Per-channel counts of each 8-bit sample value
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ColorHistogram {
+    pub red: [u64; 256],
+    pub green: [u64; 256],
+    pub blue: [u64; 256],
+    pub alpha: [u64; 256],
+}
+
+impl Default for ColorHistogram {
+    fn default() -> Self {
+        ColorHistogram {
+            red: [0; 256],
+            green: [0; 256],
+            blue: [0; 256],
+            alpha: [0; 256],
+        }
+    }
+}
+
+impl ColorHistogram {
+    /// Count interleaved RGBA8 pixel data
+    fn add_pixels(mut self, rgba: &[u8]) -> Self {
+        for pixel in rgba.chunks_exact(4) {
+            self.red[pixel[0] as usize] += 1;
+            self.green[pixel[1] as usize] += 1;
+            self.blue[pixel[2] as usize] += 1;
+            self.alpha[pixel[3] as usize] += 1;
+        }
+        self
+    }
+
+    fn merge(mut self, other: &Self) -> Self {
+        for (ours, theirs) in [
+            (&mut self.red, &other.red),
+            (&mut self.green, &other.green),
+            (&mut self.blue, &other.blue),
+            (&mut self.alpha, &other.alpha),
+        ] {
+            ours.iter_mut().zip(theirs).for_each(|(a, b)| *a += b);
+        }
+        self
+    }
+
+    /// The counts for one of the RGBA channels, `None` for luminance which isn't counted here
+    pub fn channel(&self, channel: HistogramChannel) -> Option<&[u64; 256]> {
+        match channel {
+            HistogramChannel::R => Some(&self.red),
+            HistogramChannel::G => Some(&self.green),
+            HistogramChannel::B => Some(&self.blue),
+            HistogramChannel::A => Some(&self.alpha),
+            HistogramChannel::Luminance => None,
+        }
+    }
+
+    fn channels(&self) -> [(&'static str, &[u64; 256]); 4] {
+        [
+            ("red", &self.red),
+            ("green", &self.green),
+            ("blue", &self.blue),
+            ("alpha", &self.alpha),
+        ]
+    }
+
+    /// JSON object with an array of 256 counts per channel
+    pub fn to_json(&self) -> String {
+        let channels: Vec<String> = self
+            .channels()
+            .iter()
+            .map(|(name, counts)| {
+                let counts: Vec<String> = counts.iter().map(u64::to_string).collect();
+                format!("\"{}\":[{}]", name, counts.join(","))
+            })
+            .collect();
+        format!("{{{}}}", channels.join(","))
+    }
+
+    /// CSV with a `value` column and a column per channel, one row per sample value
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("value,red,green,blue,alpha\n");
+        for value in 0..256 {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                value, self.red[value], self.green[value], self.blue[value], self.alpha[value]
+            ));
+        }
+        csv
+    }
+}
+
+/// A single channel to build a histogram for
+#[derive(Copy, Clone, Debug, Eq, PartialEq, EnumIter)]
+pub enum HistogramChannel {
+    R,
+    G,
+    B,
+    A,
+    Luminance,
+}
+
+impl Display for HistogramChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HistogramChannel::R => "red",
+            HistogramChannel::G => "green",
+            HistogramChannel::B => "blue",
+            HistogramChannel::A => "alpha",
+            HistogramChannel::Luminance => "luminance",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Summary statistics of a single channel histogram
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramStats {
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+    pub std_dev: f64,
+}
+
+impl HistogramStats {
+    /// Stats for a histogram, or None if it's empty
+    pub fn from_histogram(histogram: &[u64; 256]) -> Option<Self> {
+        let total: u64 = histogram.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let min = histogram.iter().position(|count| *count > 0)? as u8;
+        let max = histogram.iter().rposition(|count| *count > 0)? as u8;
+        let total = total as f64;
+        let mean = histogram
+            .iter()
+            .enumerate()
+            .map(|(value, count)| value as f64 * *count as f64)
+            .sum::<f64>()
+            / total;
+        let variance = histogram
+            .iter()
+            .enumerate()
+            .map(|(value, count)| (value as f64 - mean).powi(2) * *count as f64)
+            .sum::<f64>()
+            / total;
+        Some(HistogramStats {
+            min,
+            max,
+            mean,
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
 impl Geometry {
     pub fn empty() -> Self {
         Geometry {
@@ -40,6 +825,233 @@ impl Geometry {
             height: Some(height),
         }
     }
+
+    /// Returns width / height, or None if either dimension is missing or the height is zero
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        match (self.width, self.height) {
+            (Some(w), Some(h)) if h > 0 => Some(w as f64 / h as f64),
+            _ => None,
+        }
+    }
+
+    /// The aspect ratio as a fraction in lowest terms, eg. 1920x1080 is `(16, 9)`. Returns None
+    /// if either dimension is missing or zero, like [Geometry::aspect_ratio].
+    pub fn reduced_aspect_ratio(&self) -> Option<(u32, u32)> {
+        match (self.width, self.height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => {
+                let divisor = gcd(w, h);
+                Some((w / divisor, h / divisor))
+            }
+            _ => None,
+        }
+    }
+
+    /// Geometry with the given width and the height that gives a `numerator:denominator` aspect
+    /// ratio, rounded to the nearest pixel, eg. `(16, 9, 1920)` is 1920x1080
+    pub fn from_aspect_ratio_and_width(
+        numerator: u32,
+        denominator: u32,
+        width: u32,
+    ) -> Result<Self, Error> {
+        let height = scale_by_ratio(width, denominator, numerator)?;
+        Ok(Geometry::new(width, height))
+    }
+
+    /// Geometry with the given height and the width that gives a `numerator:denominator` aspect
+    /// ratio, rounded to the nearest pixel, eg. `(16, 9, 1080)` is 1920x1080
+    pub fn from_aspect_ratio_and_height(
+        numerator: u32,
+        denominator: u32,
+        height: u32,
+    ) -> Result<Self, Error> {
+        let width = scale_by_ratio(height, numerator, denominator)?;
+        Ok(Geometry::new(width, height))
+    }
+
+    /// True if every dimension of `self` that's set is no bigger than the same dimension of
+    /// `bounds`, where a dimension that isn't set in `bounds` is unlimited
+    pub fn fits_within(&self, bounds: &Geometry) -> bool {
+        let fits = |dimension: Option<u32>, bound: Option<u32>| match (dimension, bound) {
+            (Some(dimension), Some(bound)) => dimension <= bound,
+            _ => true,
+        };
+        fits(self.width, bounds.width) && fits(self.height, bounds.height)
+    }
+
+    /// The smaller of `self` and `bounds` in each dimension, where a dimension that isn't set is
+    /// unlimited
+    pub fn clamp_to(&self, bounds: &Geometry) -> Geometry {
+        let clamp = |dimension: Option<u32>, bound: Option<u32>| match (dimension, bound) {
+            (Some(dimension), Some(bound)) => Some(dimension.min(bound)),
+            (dimension, bound) => dimension.or(bound),
+        };
+        Geometry {
+            width: clamp(self.width, bounds.width),
+            height: clamp(self.height, bounds.height),
+        }
+    }
+
+    /// The largest geometry with the aspect ratio of `self` that fits inside `bounds`, scaling up
+    /// or down and rounding to the nearest pixel. A dimension that isn't set in `bounds` is
+    /// unlimited. Without both dimensions there's no aspect ratio to keep, so `self` is
+    /// clamped instead, see [Geometry::clamp_to].
+    pub fn scale_to_fit_within(self, bounds: &Geometry) -> Geometry {
+        let (Some(width), Some(height)) = (self.width, self.height) else {
+            return self.clamp_to(bounds);
+        };
+        if width == 0 || height == 0 {
+            return self.clamp_to(bounds);
+        }
+        // `value * multiplier / divisor`, rounded and never less than a pixel
+        let scale = |value: u32, multiplier: u32, divisor: u32| {
+            let scaled = (u64::from(value) * u64::from(multiplier) + u64::from(divisor) / 2)
+                / u64::from(divisor);
+            u32::try_from(scaled).unwrap_or(u32::MAX).max(1)
+        };
+        let fit_height =
+            |max_height: u32| Geometry::new(scale(width, max_height, height), max_height);
+        match (bounds.width, bounds.height) {
+            (None, None) => self,
+            // proportionally taller than the bounds, so the height is the limit
+            (Some(max_width), Some(max_height))
+                if u64::from(width) * u64::from(max_height)
+                    < u64::from(height) * u64::from(max_width) =>
+            {
+                fit_height(max_height)
+            }
+            (Some(max_width), _) => Geometry::new(max_width, scale(height, max_width, width)),
+            (None, Some(max_height)) => fit_height(max_height),
+        }
+    }
+
+    /// The smallest geometry with the aspect ratio of `self` that covers `bounds`, scaling up or
+    /// down and rounding up so it never falls a pixel short. A dimension that isn't set in
+    /// `bounds` doesn't need covering. Without both dimensions there's no aspect ratio to keep,
+    /// so this is the [Geometry::union] of `self` and `bounds` instead.
+    pub fn scale_to_cover(self, bounds: &Geometry) -> Geometry {
+        let (Some(width), Some(height)) = (self.width, self.height) else {
+            return self.union(bounds);
+        };
+        if width == 0 || height == 0 {
+            return self.union(bounds);
+        }
+        // `value * multiplier / divisor`, rounded up and never less than a pixel
+        let scale = |value: u32, multiplier: u32, divisor: u32| {
+            let scaled = (u64::from(value) * u64::from(multiplier)).div_ceil(u64::from(divisor));
+            u32::try_from(scaled).unwrap_or(u32::MAX).max(1)
+        };
+        let cover_height =
+            |min_height: u32| Geometry::new(scale(width, min_height, height), min_height);
+        match (bounds.width, bounds.height) {
+            (None, None) => self,
+            // proportionally wider than the bounds, so the height is the limit
+            (Some(min_width), Some(min_height))
+                if u64::from(width) * u64::from(min_height)
+                    > u64::from(height) * u64::from(min_width) =>
+            {
+                cover_height(min_height)
+            }
+            (Some(min_width), _) => Geometry::new(min_width, scale(height, min_width, width)),
+            (None, Some(min_height)) => cover_height(min_height),
+        }
+    }
+
+    /// The bounding box of `self` and `other`, the larger of the two in each dimension. A
+    /// dimension that's only set on one side is taken from that side.
+    pub fn union(&self, other: &Geometry) -> Geometry {
+        let larger = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        Geometry {
+            width: larger(self.width, other.width),
+            height: larger(self.height, other.height),
+        }
+    }
+
+    /// The smaller of `self` and `other` in each dimension, as for [Geometry::clamp_to], or
+    /// `None` if that leaves a dimension at zero
+    pub fn intersection(&self, other: &Geometry) -> Option<Geometry> {
+        let intersection = self.clamp_to(other);
+        match (intersection.width, intersection.height) {
+            (Some(0), _) | (_, Some(0)) => None,
+            _ => Some(intersection),
+        }
+    }
+
+    /// Multiply each dimension that's set by `factor`, rounding to the nearest pixel
+    pub fn scale_by(&self, factor: f32) -> Result<Geometry, Error> {
+        if !(factor > 0.0 && factor.is_finite()) {
+            return Err(Error::InvalidGeometry(format!(
+                "Scale factor must be greater than zero, got {factor}"
+            )));
+        }
+        let scale = |dimension: Option<u32>| -> Result<Option<u32>, Error> {
+            let Some(dimension) = dimension else {
+                return Ok(None);
+            };
+            let scaled = (f64::from(dimension) * f64::from(factor)).round();
+            if scaled > f64::from(u32::MAX) {
+                return Err(Error::InvalidGeometry(format!(
+                    "Scaling {dimension} by {factor} is too big"
+                )));
+            }
+            if scaled < 1.0 && dimension > 0 {
+                return Err(Error::InvalidGeometry(format!(
+                    "Scaling {dimension} by {factor} leaves no pixels"
+                )));
+            }
+            Ok(Some(scaled as u32))
+        };
+        Ok(Geometry {
+            width: scale(self.width)?,
+            height: scale(self.height)?,
+        })
+    }
+
+    /// Parse `W:H@WIDTH` or `W:H@xHEIGHT`
+    fn from_aspect_ratio_str(ratio: &str, size: &str) -> Result<Self, Error> {
+        let invalid =
+            || Error::InvalidGeometry(format!("Invalid aspect ratio geometry {ratio}@{size}"));
+        let (numerator, denominator) = ratio.split_once(':').ok_or_else(invalid)?;
+        let numerator = numerator.parse::<u32>().map_err(|_| invalid())?;
+        let denominator = denominator.parse::<u32>().map_err(|_| invalid())?;
+        match size.strip_prefix('x') {
+            Some(height) => Self::from_aspect_ratio_and_height(
+                numerator,
+                denominator,
+                height.parse::<u32>().map_err(|_| invalid())?,
+            ),
+            None => Self::from_aspect_ratio_and_width(
+                numerator,
+                denominator,
+                size.parse::<u32>().map_err(|_| invalid())?,
+            ),
+        }
+    }
+}
+
+/// Greatest common divisor, by Euclid's algorithm
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Returns `value * multiplier / divisor` rounded to the nearest integer
+fn scale_by_ratio(value: u32, multiplier: u32, divisor: u32) -> Result<u32, Error> {
+    if multiplier == 0 || divisor == 0 {
+        return Err(Error::InvalidGeometry(
+            "Aspect ratio values must be greater than zero".to_string(),
+        ));
+    }
+    let (divisor, scaled) = (divisor as u64, value as u64 * multiplier as u64);
+    u32::try_from((scaled + divisor / 2) / divisor).map_err(|_| {
+        Error::InvalidGeometry(format!(
+            "{value} scaled by {multiplier}:{divisor} is too big"
+        ))
+    })
 }
 
 impl Display for Geometry {
@@ -57,10 +1069,15 @@ impl FromStr for Geometry {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.to_lowercase();
+        // some tools write 800,600 rather than 800x600
+        let s = s.to_lowercase().replace(',', "x");
 
         debug!("Parsing geometry from string: {}", s);
 
+        if let Some((ratio, size)) = s.split_once('@') {
+            return Self::from_aspect_ratio_str(ratio, size);
+        }
+
         let (width, height) = if let Some(height_string) = s.strip_prefix('x') {
             (
                 None,
@@ -99,63 +1116,869 @@ impl FromStr for Geometry {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Image {
-    pub original_file_size: u64,
-    pub input_filename: PathBuf,
-    pub original_geometry: Geometry,
-    pub target_geometry: Option<Geometry>,
-    pub output_format: Option<crate::ImageFormat>,
-    pub output_suffix: Option<String>,
-    pub image: image::DynamicImage,
+/// Read the major brand followed by the compatible brands from the leading ftyp box of an
+/// ISO-BMFF (HEIF/AVIF) file. Returns an empty list if there's no ftyp box.
+pub fn ftyp_brands(data: &[u8]) -> Vec<[u8; 4]> {
+    if data.len() < 16 || &data[4..8] != b"ftyp" {
+        return Vec::new();
+    }
+    let box_size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let box_end = box_size.clamp(16, data.len());
+
+    let mut brands = vec![[data[8], data[9], data[10], data[11]]];
+    brands.extend(
+        data[16..box_end]
+            .chunks_exact(4)
+            .map(|brand| [brand[0], brand[1], brand[2], brand[3]]),
+    );
+    brands
 }
 
-impl TryFrom<&PathBuf> for Image {
-    type Error = Error;
+/// Copy interleaved RGBA8 pixel data into separate planes, where the plane at index `n` gets
+/// channel `n` and each plane has its own row stride. Planes and rows are filled in parallel.
+pub fn fill_planes(rgba: &[u8], width: usize, planes: Vec<(&mut [u8], usize)>) {
+    let row_length = width * 4;
+    if row_length == 0 {
+        return;
+    }
+    planes
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(channel, (plane, stride))| {
+            plane
+                .par_chunks_mut(stride)
+                .zip(rgba.par_chunks_exact(row_length))
+                .for_each(|(plane_row, rgba_row)| {
+                    plane_row
+                        .iter_mut()
+                        .zip(rgba_row.chunks_exact(4))
+                        .for_each(|(target, pixel)| *target = pixel[channel]);
+                });
+        });
+}
 
-    fn try_from(path: &PathBuf) -> Result<Self, Self::Error> {
-        let original_size = std::fs::metadata(path)
-            .map_err(|e| Error::FileSystem(e.to_string()))?
-            .len();
+/// Copy interleaved RGBA16 pixel data into separate planes of native-endian 16-bit samples,
+/// scaling each sample down to `bit_depth` bits. Works like [fill_planes].
+pub fn fill_planes_high_bit_depth(
+    rgba: &[u16],
+    width: usize,
+    bit_depth: u8,
+    planes: Vec<(&mut [u8], usize)>,
+) {
+    let row_length = width * 4;
+    if row_length == 0 {
+        return;
+    }
+    let shift = 16u32.saturating_sub(u32::from(bit_depth));
+    planes
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(channel, (plane, stride))| {
+            plane
+                .par_chunks_mut(stride)
+                .zip(rgba.par_chunks_exact(row_length))
+                .for_each(|(plane_row, rgba_row)| {
+                    plane_row
+                        .chunks_exact_mut(2)
+                        .zip(rgba_row.chunks_exact(4))
+                        .for_each(|(target, pixel)| {
+                            target.copy_from_slice(&(pixel[channel] >> shift).to_ne_bytes())
+                        });
+                });
+        });
+}
 
-        let (image, original_geometry) = Image::load_image(path)?;
+/// A box of weighted colors for median-cut quantization
+struct ColorBox {
+    colors: Vec<([u8; 3], u64)>,
+}
 
-        Ok(Self {
-            input_filename: path.clone(),
-            target_geometry: None,
-            output_format: None,
-            output_suffix: None,
-            image,
-            original_file_size: original_size,
-            original_geometry,
-        })
+impl ColorBox {
+    /// The channel with the widest spread of values, and that spread
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3)
+            .map(|channel| {
+                let (min, max) = self
+                    .colors
+                    .iter()
+                    .fold((u8::MAX, u8::MIN), |(min, max), c| {
+                        (min.min(c.0[channel]), max.max(c.0[channel]))
+                    });
+                (channel, max.saturating_sub(min))
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
     }
-}
 
-impl Image {
-    pub fn with_target_geometry(mut self, target_geometry: Geometry) -> Self {
-        self.target_geometry = Some(target_geometry);
-        self
+    fn population(&self) -> u64 {
+        self.colors.iter().map(|(_, count)| count).sum()
     }
 
-    pub fn with_output_format(mut self, output_format: crate::ImageFormat) -> Self {
-        self.output_format = Some(output_format);
-        self
+    /// Split at the weighted median of the widest channel
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.colors
+            .sort_unstable_by_key(|(color, _)| color[channel]);
+        let half = self.population() / 2;
+        let mut seen = 0;
+        // the lower half keeps everything up to and including the median color
+        let median = self
+            .colors
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen > half
+            })
+            .unwrap_or(0);
+        // both halves need at least one color
+        let split_at = (median + 1).clamp(1, self.colors.len() - 1);
+        let upper = self.colors.split_off(split_at);
+        (self, ColorBox { colors: upper })
     }
 
-    pub fn with_output_suffix(mut self, output_suffix: Option<String>) -> Self {
+    /// The weighted average color of the box
+    fn average(&self) -> [u8; 3] {
+        let population = self.population().max(1);
+        let mut totals = [0u64; 3];
+        for (color, count) in &self.colors {
+            for (total, value) in totals.iter_mut().zip(color) {
+                *total += u64::from(*value) * count;
+            }
+        }
+        totals.map(|total| ((total + population / 2) / population) as u8)
+    }
+}
+
+/// Reduce weighted colors to at most `n` representative colors with median cut, most common first
+pub fn median_cut(colors: Vec<([u8; 3], u64)>, n: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![ColorBox { colors }];
+    while boxes.len() < n {
+        // split the box with the widest range, as long as it has something to split
+        let Some((index, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, color_box)| color_box.colors.len() > 1)
+            .max_by_key(|(_, color_box)| (color_box.widest_channel().1, color_box.population()))
+        else {
+            break;
+        };
+        let (lower, upper) = boxes.swap_remove(index).split();
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+    boxes.sort_by_key(|color_box| std::cmp::Reverse(color_box.population()));
+    boxes.iter().map(ColorBox::average).collect()
+}
+
+/// Encodes an image in one format, like [Image::output_with_options], which is what
+/// auto-format normally uses. See [Image::smallest_encoding_with].
+pub type EncodeFn =
+    dyn Fn(&Image, ImageFormat, &EncodeOptions) -> Result<Vec<u8>, Error> + Send + Sync;
+
+/// What happened when auto-format tried a format
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FormatAttempt {
+    pub format: ImageFormat,
+    /// Size of the encoded image, if encoding worked
+    pub encoded_bytes: Option<usize>,
+    /// Why there's no encoded image, if encoding failed or the format was skipped
+    pub error: Option<String>,
+    /// True for the format auto-format picked
+    pub selected: bool,
+}
+
+/// Fail for formats that can be named but that this build has no codec for
+fn check_codec(format: ImageFormat) -> Result<(), Error> {
+    match format {
+        ImageFormat::Jpeg2000 => Err(Error::UnsupportedFormat(
+            "JPEG 2000 format not supported by image crate".to_string(),
+        )),
+        ImageFormat::Jxl => Err(Error::UnsupportedFormat(
+            "shrinky-rs was built without JPEG XL support".to_string(),
+        )),
+        #[cfg(not(feature = "raw"))]
+        ImageFormat::Raw => Err(Error::UnsupportedFormat(
+            "shrinky-rs was built without camera RAW support, export a JPEG or TIFF first"
+                .to_string(),
+        )),
+        #[cfg(not(feature = "svg"))]
+        ImageFormat::Svg => Err(Error::UnsupportedFormat(
+            "shrinky-rs was built without the svg feature, render the SVG to PNG first, eg. with resvg"
+                .to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Pick the smallest successful encoding, preferring the format that usually compresses better on
+/// a tie, and report what happened with each format
+fn pick_smallest(
+    results: Vec<(ImageFormat, Result<Vec<u8>, Error>)>,
+) -> Result<(ImageFormat, Vec<u8>, Vec<FormatAttempt>), Error> {
+    let mut attempts = Vec::with_capacity(results.len());
+    let mut smallest: Option<(ImageFormat, Vec<u8>)> = None;
+    for (format, data) in results {
+        match data {
+            Ok(encoded_data) => {
+                debug!("Format {} produced {} bytes", format, encoded_data.len());
+                attempts.push(FormatAttempt {
+                    format,
+                    encoded_bytes: Some(encoded_data.len()),
+                    error: None,
+                    selected: false,
+                });
+                // on a tie, prefer the format that usually compresses better
+                let is_smaller = smallest.as_ref().is_none_or(|(best_format, best_data)| {
+                    (encoded_data.len(), std::cmp::Reverse(format))
+                        < (best_data.len(), std::cmp::Reverse(*best_format))
+                });
+                if is_smaller {
+                    smallest = Some((format, encoded_data));
+                }
+            }
+            Err(err) => {
+                error!("Failed to encode image as {}: {:?}", format, err);
+                attempts.push(FormatAttempt {
+                    format,
+                    encoded_bytes: None,
+                    error: Some(format!("{err:?}")),
+                    selected: false,
+                });
+            }
+        }
+    }
+
+    if let Some((format, data)) = smallest {
+        debug!("Woo, the smallest is {}", format);
+        attempts
+            .iter_mut()
+            .filter(|attempt| attempt.format == format)
+            .for_each(|attempt| attempt.selected = true);
+        return Ok((format, data, attempts));
+    }
+    Err(Error::AllFormatsFailedEncoding(
+        attempts
+            .into_iter()
+            .map(|attempt| (attempt.format, attempt.error.unwrap_or_default()))
+            .collect(),
+    ))
+}
+
+/// Settings passed through to the encoders when writing an image out
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct EncodeOptions {
+    /// Encoder quality (0-100) used for all lossy formats
+    pub quality: Option<u8>,
+    /// Encoder quality (0-100) used for AVIF output, overrides `quality`
+    pub avif_quality: Option<u8>,
+    /// Encode losslessly, where the format supports it
+    pub lossless: bool,
+    /// Bits per sample for HEIF/AVIF output (8, 10 or 12), defaults to matching the source
+    pub bit_depth: Option<u8>,
+    /// HEIF/AVIF encoder effort (0 is fastest, 9 is slowest), defaults to the encoder's own
+    pub effort: Option<u8>,
+    /// Raw libheif encoder parameters as `(name, value)` pairs, applied after everything else
+    pub encoder_params: Vec<(String, String)>,
+    /// WebP near-lossless preprocessing level (0-100, lower is smaller), implies lossless WebP
+    pub near_lossless: Option<u8>,
+    /// PNG compression level, defaults to the encoder's own (auto-format uses the best)
+    pub png_compression: Option<PngCompression>,
+    /// zlib compression level for PNG output (0 is uncompressed, 9 is smallest), overrides
+    /// `png_compression`
+    pub png_compression_level: Option<u8>,
+    /// PNG row filter, defaults to the encoder's own (adaptive)
+    pub png_filter: Option<PngFilter>,
+    /// Color that transparent areas are flattened onto for JPG output, defaults to
+    /// [DEFAULT_BACKGROUND_COLOR]
+    pub background_color: Option<image::Rgb<u8>>,
+    /// Run PNG output through oxipng at this level (0-6)
+    #[cfg(feature = "oxipng")]
+    pub optimize_png: Option<u8>,
+    /// Quantize PNG output to an indexed palette of at most this many colors (2-256)
+    #[cfg(feature = "imagequant")]
+    pub quantize: Option<u16>,
+    /// Quantize even if the image looks like a photo or misses the quality floor
+    #[cfg(feature = "imagequant")]
+    pub force_quantize: bool,
+}
+
+impl EncodeOptions {
+    /// A copy without the settings that only apply to other formats, since they don't change
+    /// the output for `format`
+    fn relevant_to(&self, format: ImageFormat) -> EncodeOptions {
+        let mut options = self.clone();
+        if format != ImageFormat::Webp {
+            options.near_lossless = None;
+        }
+        if format != ImageFormat::Jpg {
+            options.background_color = None;
+        }
+        if format != ImageFormat::Png {
+            options.png_compression = None;
+            options.png_compression_level = None;
+            options.png_filter = None;
+            #[cfg(feature = "oxipng")]
+            {
+                options.optimize_png = None;
+            }
+            #[cfg(feature = "imagequant")]
+            {
+                options.quantize = None;
+                options.force_quantize = false;
+            }
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub original_file_size: u64,
+    pub input_filename: PathBuf,
+    pub original_geometry: Geometry,
+    pub target_geometry: Option<Geometry>,
+    pub output_format: Option<crate::ImageFormat>,
+    pub output_suffix: Option<String>,
+    pub encode_options: EncodeOptions,
+    /// When set, auto-format fails rather than returning something at least as big as the original file
+    pub respect_original_size: bool,
+    /// When set, encoded output that isn't smaller than the original file shouldn't be written, see
+    /// [Image::should_write]
+    pub overwrite_if_smaller: bool,
+    /// The format the input file's extension claims, if it's one shrinky-rs knows
+    pub claimed_format: Option<ImageFormat>,
+    /// The format of the input going by its contents, see [ImageFormat::detect_from_path]. This
+    /// is what decisions about the input's format should use.
+    pub detected_format: Option<ImageFormat>,
+    /// Set once the pixels have been changed in a way the geometry and color type don't show
+    /// (e.g. a 180 degree rotation), so the input file can't be passed through as-is
+    pub edited: bool,
+    /// How many frames the input has, more than one for an animated GIF or WebP. Animations
+    /// are only encoded as animated WebP, see [Image::flatten_to_first_frame].
+    pub frame_count: usize,
+    /// The encoded animation, kept by [Image::from_bytes] since there's no input file to read
+    /// its frames from, see [Image::animation_frames]
+    pub animation_data: Option<Arc<[u8]>>,
+    /// Resampling filter for resizing to the target geometry
+    pub filter: ResizeFilter,
+    /// Formats auto-format tries instead of its usual picks, see [Image::with_candidate_formats]
+    pub candidate_formats: Option<Vec<ImageFormat>>,
+    pub image: image::DynamicImage,
+}
+
+impl TryFrom<&PathBuf> for Image {
+    type Error = Error;
+
+    fn try_from(path: &PathBuf) -> Result<Self, Self::Error> {
+        ImageBuilder::new(path.clone()).build()
+    }
+}
+
+/// Header-level information about an image file, available without decoding the pixels
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub geometry: Geometry,
+    pub file_size: u64,
+}
+
+/// An image on disk which hasn't been decoded yet
+#[derive(Debug, Clone)]
+pub struct LazyImage {
+    path: PathBuf,
+}
+
+impl LazyImage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Read the format and dimensions from the file header, without decoding the image data.
+    /// Like [ImageBuilder::build], the format comes from the file's contents, not its extension.
+    pub fn metadata(&self) -> Result<ImageInfo, Error> {
+        let format = ImageFormat::detect_from_path(&self.path)?;
+        if matches!(format, ImageFormat::Heif | ImageFormat::Heic) {
+            libheif_rs::integration::image::register_all_decoding_hooks();
+        }
+
+        let file_size = std::fs::metadata(&self.path)
+            .map_err(|e| Error::from_io(&e, &self.path))?
+            .len();
+
+        let mut reader =
+            image::ImageReader::open(&self.path).map_err(|e| Error::from_io(&e, &self.path))?;
+        match format.try_into() {
+            Ok(native_format) => reader.set_format(native_format),
+            Err(_) => {
+                reader = reader
+                    .with_guessed_format()
+                    .map_err(|e| Error::from_io(&e, &self.path))?
+            }
+        }
+        let (width, height) = reader
+            .into_dimensions()
+            .context(format!("reading dimensions of {}", self.path.display()))?;
+
+        Ok(ImageInfo {
+            format,
+            geometry: Geometry::new(width, height),
+            file_size,
+        })
+    }
+
+    /// Fully load and decode the image
+    pub fn decode(self) -> Result<Image, Error> {
+        ImageBuilder::new(self.path).build()
+    }
+}
+
+/// Collects the options for an [Image] before doing any of the expensive I/O.
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    path: PathBuf,
+    target_geometry: Option<Geometry>,
+    output_format: Option<ImageFormat>,
+    output_suffix: Option<String>,
+    encode_options: EncodeOptions,
+    respect_original_size: bool,
+    overwrite_if_smaller: bool,
+    filter: ResizeFilter,
+    tone_map: ToneMap,
+    candidate_formats: Option<Vec<ImageFormat>>,
+}
+
+impl ImageBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            target_geometry: None,
+            output_format: None,
+            output_suffix: None,
+            encode_options: EncodeOptions::default(),
+            respect_original_size: false,
+            overwrite_if_smaller: false,
+            filter: ResizeFilter::default(),
+            tone_map: ToneMap::default(),
+            candidate_formats: None,
+        }
+    }
+
+    pub fn with_target_geometry(mut self, target_geometry: Geometry) -> Self {
+        self.target_geometry = Some(target_geometry);
+        self
+    }
+
+    pub fn with_output_format(mut self, output_format: ImageFormat) -> Self {
+        self.output_format = Some(output_format);
+        self
+    }
+
+    pub fn with_output_suffix(mut self, output_suffix: Option<String>) -> Self {
+        self.output_suffix = output_suffix;
+        self
+    }
+
+    pub fn with_encode_options(mut self, encode_options: EncodeOptions) -> Self {
+        self.encode_options = encode_options;
+        self
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.encode_options.quality = Some(quality);
+        self
+    }
+
+    pub fn with_avif_quality(mut self, avif_quality: u8) -> Self {
+        self.encode_options.avif_quality = Some(avif_quality);
+        self
+    }
+
+    pub fn with_lossless(mut self, lossless: bool) -> Self {
+        self.encode_options.lossless = lossless;
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: u8) -> Self {
+        self.encode_options.bit_depth = Some(bit_depth);
+        self
+    }
+
+    pub fn with_effort(mut self, effort: u8) -> Self {
+        self.encode_options.effort = Some(effort);
+        self
+    }
+
+    pub fn with_encoder_params(mut self, encoder_params: Vec<(String, String)>) -> Self {
+        self.encode_options.encoder_params = encoder_params;
+        self
+    }
+
+    pub fn with_near_lossless(mut self, level: u8) -> Self {
+        self.encode_options.near_lossless = Some(level);
+        self
+    }
+
+    pub fn with_png_compression(mut self, png_compression: PngCompression) -> Self {
+        self.encode_options.png_compression = Some(png_compression);
+        self
+    }
+
+    pub fn with_png_compression_level(mut self, level: u8) -> Self {
+        self.encode_options.png_compression_level = Some(level);
+        self
+    }
+
+    pub fn with_png_filter(mut self, png_filter: PngFilter) -> Self {
+        self.encode_options.png_filter = Some(png_filter);
+        self
+    }
+
+    pub fn with_background_color(mut self, background_color: image::Rgb<u8>) -> Self {
+        self.encode_options.background_color = Some(background_color);
+        self
+    }
+
+    #[cfg(feature = "oxipng")]
+    pub fn with_optimize_png(mut self, level: u8) -> Self {
+        self.encode_options.optimize_png = Some(level);
+        self
+    }
+
+    #[cfg(feature = "imagequant")]
+    pub fn with_quantize(mut self, colors: u16, force: bool) -> Self {
+        self.encode_options.quantize = Some(colors);
+        self.encode_options.force_quantize = force;
+        self
+    }
+
+    pub fn with_respect_original_size(mut self, respect_original_size: bool) -> Self {
+        self.respect_original_size = respect_original_size;
+        self
+    }
+
+    /// Sets [Image::overwrite_if_smaller] on the built image, so output that doesn't save space
+    /// over the input file isn't written
+    pub fn with_overwrite_if_smaller(mut self, overwrite_if_smaller: bool) -> Self {
+        self.overwrite_if_smaller = overwrite_if_smaller;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: ResizeFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Only try these formats when auto-selecting the output format, instead of the usual
+    /// candidates. Formats like [ImageFormat::Qoi] are only tried when asked for here.
+    pub fn with_candidate_formats(mut self, formats: Vec<ImageFormat>) -> Self {
+        self.candidate_formats = Some(formats);
+        self
+    }
+
+    /// How to tone map HDR (OpenEXR or Radiance) input down to 8 bits, defaults to
+    /// [ToneMap::Reinhard]
+    pub fn with_tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.tone_map = tone_map;
+        self
+    }
+
+    /// Load the image from disk and apply the configured options. HDR input is resized to the
+    /// target geometry while it still has float samples, and then tone mapped. SVG input is
+    /// drawn at the target geometry.
+    pub fn build(self) -> Result<Image, Error> {
+        let original_file_size = std::fs::metadata(&self.path)
+            .map_err(|e| Error::from_io(&e, &self.path))?
+            .len();
+
+        let detected_format = ImageFormat::detect_from_path(&self.path)?;
+        let claimed_format = ImageFormat::from_path(&self.path).ok();
+        if let Some(claimed_format) = claimed_format
+            && claimed_format != detected_format
+        {
+            warn!(
+                "{}: The file is named like {claimed_format} but it's {detected_format}, reading it as {detected_format}",
+                self.path.display()
+            );
+        }
+        // SVGs are drawn at the target size, rather than drawn at their own size and resized
+        let (image, original_geometry, target_geometry) = if detected_format == ImageFormat::Svg {
+            check_codec(detected_format)?;
+            let data = std::fs::read(&self.path).map_err(|e| Error::from_io(&e, &self.path))?;
+            let image = render_svg(&data, self.target_geometry.as_ref())
+                .context(format!("drawing {}", self.path.display()))?;
+            let geometry = Geometry::new(image.width(), image.height());
+            (image, geometry.clone(), Some(geometry))
+        } else {
+            let (image, original_geometry) = Image::load_image_as(&self.path, detected_format)?;
+            (image, original_geometry, self.target_geometry)
+        };
+        let frame_count = Image::count_frames_as(&self.path, detected_format)?;
+        let is_hdr = detected_format.is_hdr();
+        let tone_map_operator = self.tone_map;
+
+        let mut image = Image {
+            original_file_size,
+            original_geometry,
+            target_geometry,
+            output_format: self.output_format,
+            output_suffix: self.output_suffix,
+            encode_options: self.encode_options,
+            respect_original_size: self.respect_original_size,
+            overwrite_if_smaller: self.overwrite_if_smaller,
+            claimed_format,
+            detected_format: Some(detected_format),
+            frame_count,
+            filter: self.filter,
+            candidate_formats: self.candidate_formats,
+            ..Image::from_dynamic_image(image, self.path)
+        };
+        if is_hdr {
+            image.resize()?;
+            image.image = tone_map(image.image, tone_map_operator);
+        }
+        Ok(image)
+    }
+}
+
+impl Image {
+    pub fn builder(path: impl Into<PathBuf>) -> ImageBuilder {
+        ImageBuilder::new(path)
+    }
+
+    /// Wrap an image that's already in memory, with default settings and no file size. The
+    /// input filename is only used to work out the output filename, it isn't read.
+    pub fn from_dynamic_image(image: DynamicImage, input_filename: impl Into<PathBuf>) -> Image {
+        Image {
+            original_file_size: 0,
+            input_filename: input_filename.into(),
+            original_geometry: Geometry::new(image.width(), image.height()),
+            target_geometry: None,
+            output_format: None,
+            output_suffix: None,
+            encode_options: EncodeOptions::default(),
+            respect_original_size: false,
+            overwrite_if_smaller: false,
+            claimed_format: None,
+            detected_format: None,
+            edited: false,
+            frame_count: 1,
+            animation_data: None,
+            filter: ResizeFilter::default(),
+            candidate_formats: None,
+            image,
+        }
+    }
+
+    /// Decode an image from memory. If `hint_format` is `None` the format is detected from the
+    /// data's magic bytes. There's no input file, so `input_filename` is empty and should be set
+    /// before working out an output filename. HDR data is tone mapped with [ToneMap::default],
+    /// see [Image::from_bytes_with_tone_map] to pick the operator.
+    pub fn from_bytes(data: &[u8], hint_format: Option<ImageFormat>) -> Result<Image, Error> {
+        Image::from_bytes_with_tone_map(data, hint_format, ToneMap::default())
+    }
+
+    /// Like [Image::from_bytes], but tone maps HDR (OpenEXR or Radiance) data down to 8 bits with
+    /// `tone_map_operator`, like [ImageBuilder::with_tone_map]
+    pub fn from_bytes_with_tone_map(
+        data: &[u8],
+        hint_format: Option<ImageFormat>,
+        tone_map_operator: ToneMap,
+    ) -> Result<Image, Error> {
+        let format = match hint_format {
+            Some(format) => format,
+            None => ImageFormat::detect_from_bytes(data)?,
+        };
+        check_codec(format)?;
+        let image = if format == ImageFormat::Ico {
+            decode_ico(data, None)?
+        } else if format == ImageFormat::Dds {
+            decode_dds(data)?
+        } else if format == ImageFormat::Svg {
+            render_svg(data, None).context("drawing SVG data from memory")?
+        } else if format == ImageFormat::Raw {
+            decode_dng(data).context("loading camera RAW data from memory")?
+        } else if matches!(format, ImageFormat::Heif | ImageFormat::Heic)
+            && let Ok(context) = HeifContext::read_from_bytes(data)
+            && let Some(image) = decode_heif_high_bit_depth(&context)
+                .context(format!("loading {format} data from memory"))?
+        {
+            image
+        } else {
+            match format.try_into() {
+                Ok(native_format) => image::load_from_memory_with_format(data, native_format),
+                Err(_) => {
+                    libheif_rs::integration::image::register_all_decoding_hooks();
+                    image::load_from_memory(data)
+                }
+            }
+            .context(format!("loading {format} data from memory"))?
+        };
+        let image = if format.is_hdr() {
+            tone_map(image, tone_map_operator)
+        } else {
+            image
+        };
+        let frame_count = animation_frame_iter(Cursor::new(data), format)
+            .map(|frames| frames.map_or(1, Iterator::count))
+            .context(format!("reading {format} frames from memory"))?;
+
+        Ok(Image {
+            original_file_size: data.len() as u64,
+            detected_format: Some(format),
+            frame_count,
+            animation_data: (frame_count > 1).then(|| Arc::from(data)),
+            ..Image::from_dynamic_image(image, PathBuf::new())
+        })
+    }
+
+    pub fn with_target_geometry(mut self, target_geometry: Geometry) -> Self {
+        self.target_geometry = Some(target_geometry);
+        self
+    }
+
+    pub fn with_output_format(mut self, output_format: crate::ImageFormat) -> Self {
+        self.output_format = Some(output_format);
+        self
+    }
+
+    pub fn with_output_suffix(mut self, output_suffix: Option<String>) -> Self {
         self.output_suffix = output_suffix;
         self
     }
 
+    pub fn with_encode_options(mut self, encode_options: EncodeOptions) -> Self {
+        self.encode_options = encode_options;
+        self
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.encode_options.quality = Some(quality);
+        self
+    }
+
+    pub fn with_avif_quality(mut self, avif_quality: u8) -> Self {
+        self.encode_options.avif_quality = Some(avif_quality);
+        self
+    }
+
+    pub fn with_lossless(mut self, lossless: bool) -> Self {
+        self.encode_options.lossless = lossless;
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: u8) -> Self {
+        self.encode_options.bit_depth = Some(bit_depth);
+        self
+    }
+
+    pub fn with_effort(mut self, effort: u8) -> Self {
+        self.encode_options.effort = Some(effort);
+        self
+    }
+
+    pub fn with_encoder_params(mut self, encoder_params: Vec<(String, String)>) -> Self {
+        self.encode_options.encoder_params = encoder_params;
+        self
+    }
+
+    pub fn with_near_lossless(mut self, level: u8) -> Self {
+        self.encode_options.near_lossless = Some(level);
+        self
+    }
+
+    pub fn with_png_compression(mut self, png_compression: PngCompression) -> Self {
+        self.encode_options.png_compression = Some(png_compression);
+        self
+    }
+
+    pub fn with_png_compression_level(mut self, level: u8) -> Self {
+        self.encode_options.png_compression_level = Some(level);
+        self
+    }
+
+    pub fn with_png_filter(mut self, png_filter: PngFilter) -> Self {
+        self.encode_options.png_filter = Some(png_filter);
+        self
+    }
+
+    pub fn with_background_color(mut self, background_color: image::Rgb<u8>) -> Self {
+        self.encode_options.background_color = Some(background_color);
+        self
+    }
+
+    #[cfg(feature = "oxipng")]
+    pub fn with_optimize_png(mut self, level: u8) -> Self {
+        self.encode_options.optimize_png = Some(level);
+        self
+    }
+
+    #[cfg(feature = "imagequant")]
+    pub fn with_quantize(mut self, colors: u16, force: bool) -> Self {
+        self.encode_options.quantize = Some(colors);
+        self.encode_options.force_quantize = force;
+        self
+    }
+
+    pub fn with_respect_original_size(mut self, respect_original_size: bool) -> Self {
+        self.respect_original_size = respect_original_size;
+        self
+    }
+
+    /// Don't write encoded output unless it's smaller than the original file, see
+    /// [Image::should_write]
+    pub fn with_overwrite_if_smaller(mut self, overwrite_if_smaller: bool) -> Self {
+        self.overwrite_if_smaller = overwrite_if_smaller;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: ResizeFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Only try these formats when auto-selecting the output format, instead of the usual
+    /// candidates. Formats like [ImageFormat::Qoi] are only tried when asked for here.
+    pub fn with_candidate_formats(mut self, formats: Vec<ImageFormat>) -> Self {
+        self.candidate_formats = Some(formats);
+        self
+    }
+
+    /// The input's format, from its contents when they were checked and otherwise from the
+    /// input file's extension
+    pub fn input_format(&self) -> Result<ImageFormat, Error> {
+        match self.detected_format {
+            Some(format) => Ok(format),
+            None => ImageFormat::from_path(&self.input_filename),
+        }
+    }
+
     /// Check if output file will overwrite existing file
     pub fn will_overwrite(&self) -> bool {
         self.output_filename().exists()
     }
 
+    /// Check if the output file is the input file, ie. re-encoding in place
+    pub fn will_overwrite_input(&self) -> bool {
+        self.output_filename() == self.input_filename
+    }
+
+    /// Decode the file at `input_filename`, going by its contents rather than its extension
     pub fn load_image(input_filename: &PathBuf) -> Result<(DynamicImage, Geometry), Error> {
-        let image_format = ImageFormat::try_from(input_filename)?;
+        Image::load_image_as(
+            input_filename,
+            ImageFormat::detect_from_path(input_filename)?,
+        )
+    }
 
+    /// Decode the file at `input_filename` as `image_format`, which has already been detected
+    fn load_image_as(
+        input_filename: &PathBuf,
+        image_format: ImageFormat,
+    ) -> Result<(DynamicImage, Geometry), Error> {
         match image_format {
             ImageFormat::Heif | ImageFormat::Heic => {
                 // Ensure libheif is initialized
@@ -164,16 +1987,110 @@ impl Image {
             _ => {}
         }
 
-        let img = image::open(input_filename)
-            .map_err(|e| Error::ImageLoadingError(input_filename.display().to_string(), e))?;
+        check_codec(image_format)?;
+        if matches!(
+            image_format,
+            ImageFormat::Ico | ImageFormat::Dds | ImageFormat::Svg | ImageFormat::Raw
+        ) {
+            let data =
+                std::fs::read(input_filename).map_err(|e| Error::from_io(&e, input_filename))?;
+            let img = match image_format {
+                ImageFormat::Ico => decode_ico(&data, None)?,
+                ImageFormat::Svg => render_svg(&data, None)
+                    .context(format!("drawing {}", input_filename.display()))?,
+                ImageFormat::Raw => {
+                    decode_dng(&data).context(format!("loading {}", input_filename.display()))?
+                }
+                _ => decode_dds(&data).context(format!("loading {}", input_filename.display()))?,
+            };
+            let geometry = Geometry::new(img.width(), img.height());
+            return Ok((img, geometry));
+        }
+        // files libheif can't parse are left for image::open() to report
+        if matches!(image_format, ImageFormat::Heif | ImageFormat::Heic)
+            && let Some(path) = input_filename.to_str()
+            && let Ok(context) = HeifContext::read_from_file(path)
+            && let Some(img) = decode_heif_high_bit_depth(&context)
+                .context(format!("loading {}", input_filename.display()))?
+        {
+            let geometry = Geometry::new(img.width(), img.height());
+            return Ok((img, geometry));
+        }
+
+        let mut reader = image::ImageReader::open(input_filename)
+            .map_err(|e| Error::from_io(&e, input_filename))?;
+        match image_format.try_into() {
+            Ok(native_format) => reader.set_format(native_format),
+            // the libheif hooks recognise HEIF-family files by their ftyp brands
+            Err(_) => {
+                reader = reader
+                    .with_guessed_format()
+                    .map_err(|e| Error::from_io(&e, input_filename))?
+            }
+        }
+        let img = reader
+            .decode()
+            .context(format!("loading {}", input_filename.display()))?;
 
         let geometry = Geometry::new(img.width(), img.height());
 
         Ok((img, geometry))
     }
 
+    /// Count the frames in an image file, which is 1 unless it's an animated GIF or WebP
+    pub fn count_frames(input_filename: &PathBuf) -> Result<usize, Error> {
+        Image::count_frames_as(
+            input_filename,
+            ImageFormat::detect_from_path(input_filename)?,
+        )
+    }
+
+    /// Like [Image::count_frames], for a file whose format has already been detected
+    fn count_frames_as(input_filename: &PathBuf, format: ImageFormat) -> Result<usize, Error> {
+        if !matches!(format, ImageFormat::Gif | ImageFormat::Webp) {
+            return Ok(1);
+        }
+        let file =
+            std::fs::File::open(input_filename).map_err(|e| Error::from_io(&e, input_filename))?;
+        animation_frame_iter(std::io::BufReader::new(file), format)
+            .map(|frames| frames.map_or(1, Iterator::count))
+            .context(format!("counting frames in {}", input_filename.display()))
+    }
+
+    /// Keep just the first frame of an animated input, so it can be encoded as a still image
+    pub fn flatten_to_first_frame(&mut self) {
+        if self.frame_count > 1 {
+            debug!(
+                "{}: Flattening {} frame animation to its first frame",
+                self.input_filename.display(),
+                self.frame_count
+            );
+            self.frame_count = 1;
+            self.animation_data = None;
+            // the input file is still the whole animation, so it can't be passed through
+            self.edited = true;
+        }
+    }
+
+    /// Use image `index` (counting from 0) of an ICO input instead of the largest one. Any edits
+    /// made to the in-memory image are lost.
+    pub fn select_ico_entry(&mut self, index: usize) -> Result<(), Error> {
+        if self.input_format()? != ImageFormat::Ico {
+            return Err(Error::InvalidOptions(format!(
+                "Picking an image only works for ICO files, {} isn't one",
+                self.input_filename.display()
+            )));
+        }
+        let data = std::fs::read(&self.input_filename)
+            .map_err(|e| Error::from_io(&e, &self.input_filename))?;
+        self.image = decode_ico(&data, Some(index))?;
+        self.original_geometry = Geometry::new(self.image.width(), self.image.height());
+        Ok(())
+    }
+
     /// Get the final target geometry of the image after resizing (if any)
     pub fn final_geometry(&self) -> Geometry {
+        let aspect_ratio = self.current_geometry().aspect_ratio().unwrap_or(1.0);
         match self.target_geometry {
             Some(ref geom) => match geom {
                 Geometry {
@@ -183,51 +2100,815 @@ impl Image {
                 Geometry {
                     width: Some(w),
                     height: None,
-                } => {
-                    let ratio = *w as f32 / self.image.width() as f32;
-                    Geometry::new(*w, (self.image.height() as f32 * ratio) as u32)
-                }
+                } => Geometry::new(*w, (*w as f64 / aspect_ratio) as u32),
                 Geometry {
                     width: None,
                     height: Some(h),
-                } => {
-                    let ratio = *h as f32 / self.image.height() as f32;
-                    Geometry::new((self.image.width() as f32 * ratio) as u32, *h)
-                }
+                } => Geometry::new((*h as f64 * aspect_ratio) as u32, *h),
                 Geometry {
                     width: None,
                     height: None,
-                } => Geometry::new(self.image.width(), self.image.height()),
+                } => self.current_geometry(),
             },
-            None => Geometry::new(self.image.width(), self.image.height()),
+            None => self.current_geometry(),
+        }
+    }
+
+    /// Get the geometry of the image as it currently is in memory
+    pub fn current_geometry(&self) -> Geometry {
+        Geometry::new(self.image.width(), self.image.height())
+    }
+
+    /// A copy of the image with its target geometry set to roughly `target_mp` megapixels,
+    /// keeping the aspect ratio. Scales up as well as down.
+    pub fn scale_to_megapixels(&self, target_mp: f64) -> Result<Image, Error> {
+        let factor = self.megapixel_scale_factor(target_mp)?;
+        let target_geometry = self.current_geometry().scale_by(factor as f32)?;
+        debug!("Scaling to {target_mp} megapixels: {target_geometry}");
+        Ok(self.clone().with_target_geometry(target_geometry))
+    }
+
+    /// Set the target geometry to at most `max_mp` megapixels, keeping the aspect ratio, if the
+    /// image is currently bigger than that. Returns whether it was. Any target geometry that
+    /// was already set is replaced.
+    pub fn downscale_to_megapixels_if_needed(&mut self, max_mp: f64) -> Result<bool, Error> {
+        let factor = self.megapixel_scale_factor(max_mp)?;
+        if factor >= 1.0 {
+            return Ok(false);
+        }
+        // round down, so the result is never over the limit
+        let scale = |dimension: u32| ((f64::from(dimension) * factor).floor() as u32).max(1);
+        let target_geometry = Geometry::new(scale(self.image.width()), scale(self.image.height()));
+        debug!("Downscaling to at most {max_mp} megapixels: {target_geometry}");
+        self.target_geometry = Some(target_geometry);
+        Ok(true)
+    }
+
+    /// The factor to scale each dimension by to get roughly `target_mp` megapixels
+    fn megapixel_scale_factor(&self, target_mp: f64) -> Result<f64, Error> {
+        if target_mp.is_nan() || target_mp <= 0.0 {
+            return Err(Error::InvalidOptions(format!(
+                "Megapixels must be greater than zero, got {target_mp}"
+            )));
+        }
+        let pixels = f64::from(self.image.width()) * f64::from(self.image.height());
+        if pixels == 0.0 {
+            return Err(Error::InvalidGeometry(
+                "Can't scale an empty image".to_string(),
+            ));
+        }
+        Ok((target_mp * 1_000_000.0 / pixels).sqrt())
+    }
+
+    /// Count the 8-bit RGBA sample values of the in-memory image
+    pub fn histogram(&self) -> ColorHistogram {
+        self.image
+            .to_rgba8()
+            .as_raw()
+            .par_chunks(HISTOGRAM_CHUNK_PIXELS * 4)
+            .fold(ColorHistogram::default, ColorHistogram::add_pixels)
+            .reduce(ColorHistogram::default, |a, b| a.merge(&b))
+    }
+
+    /// Count the 8-bit sample values of a single channel, where luminance comes from converting
+    /// the image to grayscale. Only that channel is counted, use [Image::histogram] and
+    /// [ColorHistogram::channel] when more than one of the RGBA channels is needed.
+    pub fn histogram_channel(&self, channel: HistogramChannel) -> [u64; 256] {
+        let (samples, samples_per_pixel, offset) = match channel {
+            HistogramChannel::Luminance => (self.image.to_luma8().into_raw(), 1, 0),
+            HistogramChannel::R => (self.image.to_rgba8().into_raw(), 4, 0),
+            HistogramChannel::G => (self.image.to_rgba8().into_raw(), 4, 1),
+            HistogramChannel::B => (self.image.to_rgba8().into_raw(), 4, 2),
+            HistogramChannel::A => (self.image.to_rgba8().into_raw(), 4, 3),
+        };
+        samples
+            .par_chunks(HISTOGRAM_CHUNK_PIXELS * samples_per_pixel)
+            .fold(
+                || [0u64; 256],
+                |mut counts, chunk| {
+                    chunk
+                        .iter()
+                        .skip(offset)
+                        .step_by(samples_per_pixel)
+                        .for_each(|value| counts[*value as usize] += 1);
+                    counts
+                },
+            )
+            .reduce(
+                || [0u64; 256],
+                |mut a, b| {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    a
+                },
+            )
+    }
+
+    /// Up to `n` representative RGB colors of the image, found with median-cut quantization and
+    /// sorted by how many pixels they cover
+    pub fn dominant_colors(&self, n: usize) -> Result<Vec<[u8; 3]>, Error> {
+        if n == 0 {
+            return Err(Error::InvalidOptions(
+                "Can't find a palette of zero colors".to_string(),
+            ));
+        }
+        let mut counts: HashMap<[u8; 3], u64> = HashMap::new();
+        for pixel in self.image.to_rgb8().pixels() {
+            *counts.entry(pixel.0).or_default() += 1;
+        }
+        if counts.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(median_cut(counts.into_iter().collect(), n))
+    }
+
+    /// Split the image into 8-bit red, green and blue channel images, plus alpha if the image
+    /// has an alpha channel. Higher bit depths are reduced to 8 bits.
+    pub fn split_channels(
+        &self,
+    ) -> Result<
+        (
+            DynamicImage,
+            DynamicImage,
+            DynamicImage,
+            Option<DynamicImage>,
+        ),
+        Error,
+    > {
+        let rgba = self.image.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let channel = |index: usize| -> Result<DynamicImage, Error> {
+            let samples = rgba.pixels().map(|pixel| pixel.0[index]).collect();
+            image::GrayImage::from_raw(width, height, samples)
+                .map(DynamicImage::ImageLuma8)
+                .ok_or_else(|| {
+                    Error::ImageEncodingError(format!(
+                        "Failed to split channel {index} of a {width}x{height} image"
+                    ))
+                })
+        };
+        let alpha = match self.image.color().has_alpha() {
+            true => Some(channel(3)?),
+            false => None,
+        };
+        Ok((channel(0)?, channel(1)?, channel(2)?, alpha))
+    }
+
+    /// Convert the in-memory image to 8-bit grayscale, dropping any alpha channel
+    pub fn to_grayscale(&mut self) {
+        self.image = self.image.to_luma8().into();
+    }
+
+    /// Convert the in-memory image to 8-bit RGBA
+    pub fn to_rgba(&mut self) {
+        self.image = self.image.to_rgba8().into();
+    }
+
+    /// Brighten (or darken, if negative) and adjust the contrast of the in-memory image. The CLI
+    /// takes brightness from -100 to 100 and contrast from -100.0 to 100.0, zero leaves the
+    /// image alone.
+    pub fn adjust(&mut self, brightness: i32, contrast: f32) -> &mut Self {
+        if brightness != 0 {
+            self.image = self.image.brighten(brightness);
+            self.edited = true;
+        }
+        if contrast != 0.0 {
+            self.image = self.image.adjust_contrast(contrast);
+            self.edited = true;
+        }
+        self
+    }
+
+    /// Composite the in-memory image onto a solid `background` color, replacing the alpha
+    /// channel. Images without alpha are left alone.
+    pub fn flatten_alpha(&mut self, background: image::Rgb<u8>) -> &mut Self {
+        if self.image.color().has_alpha() {
+            self.image = flatten_alpha_onto(&self.image, background);
+            self.edited = true;
+        }
+        self
+    }
+
+    /// Rotate or flip the in-memory image. Any pending resize is applied first, so the target
+    /// geometry keeps meaning the image as it was loaded.
+    pub fn rotate(&mut self, angle: RotationAngle) -> Result<(), Error> {
+        self.resize()?;
+        self.image = match angle {
+            RotationAngle::Cw90 => self.image.rotate90(),
+            RotationAngle::Cw180 => self.image.rotate180(),
+            RotationAngle::Cw270 => self.image.rotate270(),
+            RotationAngle::FlipH => self.image.fliph(),
+            RotationAngle::FlipV => self.image.flipv(),
+        };
+        self.target_geometry = None;
+        self.edited = true;
+        Ok(())
+    }
+
+    /// Expand the canvas by the given number of pixels on each side, filling the new area with
+    /// `color`. Any pending resize is applied first, so the border goes around the final image.
+    pub fn add_border(
+        &mut self,
+        top: u32,
+        right: u32,
+        bottom: u32,
+        left: u32,
+        color: image::Rgba<u8>,
+    ) -> Result<(), Error> {
+        self.resize()?;
+        let too_big = || {
+            Error::InvalidGeometry(format!(
+                "Adding a {top}/{right}/{bottom}/{left} border to a {} image is too big",
+                self.current_geometry()
+            ))
+        };
+        let width = self
+            .image
+            .width()
+            .checked_add(left)
+            .and_then(|w| w.checked_add(right))
+            .ok_or_else(too_big)?;
+        let height = self
+            .image
+            .height()
+            .checked_add(top)
+            .and_then(|h| h.checked_add(bottom))
+            .ok_or_else(too_big)?;
+
+        // only add an alpha channel if the image or the border needs one
+        self.image = if self.image.color().has_alpha() || color.0[3] != u8::MAX {
+            let mut canvas = image::RgbaImage::from_pixel(width, height, color);
+            image::imageops::replace(&mut canvas, &self.image.to_rgba8(), left.into(), top.into());
+            canvas.into()
+        } else {
+            let [r, g, b, _] = color.0;
+            let mut canvas = image::RgbImage::from_pixel(width, height, image::Rgb([r, g, b]));
+            image::imageops::replace(&mut canvas, &self.image.to_rgb8(), left.into(), top.into());
+            canvas.into()
+        };
+        // the border is part of the final image now, don't resize it away
+        self.target_geometry = None;
+        Ok(())
+    }
+
+    /// Blend `overlay` onto the image with its top-left corner at (`x`, `y`), scaling the
+    /// overlay's own alpha by `alpha` (0.0-1.0). The overlay is clipped to the image, and any
+    /// pending resize is applied first.
+    pub fn composite(
+        &mut self,
+        overlay: &DynamicImage,
+        x: u32,
+        y: u32,
+        alpha: f32,
+    ) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&alpha) {
+            return Err(Error::InvalidOptions(format!(
+                "Overlay alpha must be between 0.0 and 1.0, got {alpha}"
+            )));
+        }
+        self.resize()?;
+        if x >= self.image.width() || y >= self.image.height() {
+            return Err(Error::InvalidGeometry(format!(
+                "Overlay position {x},{y} is outside the {} image",
+                self.current_geometry()
+            )));
+        }
+
+        let had_alpha = self.image.color().has_alpha();
+        let mut base = self.image.to_rgba8();
+        let overlay = overlay.to_rgba8();
+        for (overlay_x, overlay_y, overlay_pixel) in overlay.enumerate_pixels() {
+            let (Some(target_x), Some(target_y)) =
+                (x.checked_add(overlay_x), y.checked_add(overlay_y))
+            else {
+                continue;
+            };
+            if target_x >= base.width() || target_y >= base.height() {
+                continue;
+            }
+            let base_pixel = base.get_pixel_mut(target_x, target_y);
+            let overlay_alpha = f32::from(overlay_pixel.0[3]) / 255.0 * alpha;
+            let base_alpha = f32::from(base_pixel.0[3]) / 255.0;
+            let out_alpha = overlay_alpha + base_alpha * (1.0 - overlay_alpha);
+            if out_alpha <= 0.0 {
+                continue;
+            }
+            for channel in 0..3 {
+                let blended = (f32::from(overlay_pixel.0[channel]) * overlay_alpha
+                    + f32::from(base_pixel.0[channel]) * base_alpha * (1.0 - overlay_alpha))
+                    / out_alpha;
+                base_pixel.0[channel] = blended.round().clamp(0.0, 255.0) as u8;
+            }
+            base_pixel.0[3] = (out_alpha * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+
+        self.image = if had_alpha {
+            base.into()
+        } else {
+            DynamicImage::ImageRgba8(base).to_rgb8().into()
+        };
+        self.edited = true;
+        Ok(())
+    }
+
+    /// Resize the in-memory image to the final geometry
+    pub fn resize(&mut self) -> Result<(), Error> {
+        self.image = self.resized_image()?;
+        Ok(())
+    }
+
+    /// Return a copy of the image resized to the final geometry, leaving `self.image` as-is
+    pub fn resized_image(&self) -> Result<DynamicImage, Error> {
+        let final_geometry = self.final_geometry();
+        if final_geometry != self.current_geometry() {
+            debug!(
+                "Resizing image from {}x{} to {}",
+                self.image.width(),
+                self.image.height(),
+                final_geometry,
+            );
+            let resized_img = self.image.resize_exact(
+                final_geometry.width.unwrap_or(0), // safe unwraps, as final_geometry is derived from existing dimensions
+                final_geometry.height.unwrap_or(0), // safe unwraps, as final_geometry is derived from existing dimensions
+                self.filter.into(),
+            );
+            Ok(resized_img)
+        } else {
+            Ok(self.image.clone())
+        }
+    }
+
+    /// Return a copy of the image with every pending transform applied, which is what the
+    /// encoders work from. Only the target geometry is deferred, edits like [Image::rotate] and
+    /// [Image::add_border] change `self.image` straight away.
+    pub fn apply_all_transforms(&self) -> Result<DynamicImage, Error> {
+        self.resized_image()
+    }
+
+    /// The libheif encoder quality to use for a given output format
+    fn heif_quality(format: ImageFormat, options: &EncodeOptions) -> Result<EncoderQuality, Error> {
+        if options.lossless {
+            return Ok(EncoderQuality::LossLess);
+        }
+        let quality = match format {
+            ImageFormat::Avif => options.avif_quality.or(options.quality),
+            _ => options.quality,
+        };
+        Ok(EncoderQuality::Lossy(validate_quality(
+            quality.unwrap_or(DEFAULT_HEIF_QUALITY),
+        )?))
+    }
+
+    /// The HEIF/AVIF bit depth to encode with, 10-bit if the source has more than 8 bits per
+    /// sample unless `options` asks for something specific
+    fn heif_bit_depth(image: &DynamicImage, options: &EncodeOptions) -> Result<u8, Error> {
+        match options.bit_depth {
+            Some(bit_depth) => validate_bit_depth(bit_depth),
+            None if image.color().bytes_per_pixel() / image.color().channel_count() > 1 => Ok(10),
+            None => Ok(8),
+        }
+    }
+
+    /// Set the encoder's speed from an effort level. AV1 encoders take a `speed` (0 is slowest),
+    /// x265 takes a named `preset`.
+    fn set_heif_effort(encoder: &Encoder, effort: u8) -> Result<(), Error> {
+        let effort = validate_effort(effort)?;
+        let parameters = encoder.parameters_names();
+        let (name, value) = if parameters.iter().any(|name| name == "speed") {
+            (
+                "speed",
+                EncoderParameterValue::Int(i32::from(MAX_EFFORT - effort)),
+            )
+        } else if parameters.iter().any(|name| name == "preset") {
+            (
+                "preset",
+                EncoderParameterValue::String(X265_PRESETS[effort as usize].to_string()),
+            )
+        } else {
+            warn!(
+                "The {} encoder has no speed setting, ignoring effort {}",
+                encoder.name(),
+                effort
+            );
+            return Ok(());
+        };
+        debug!("Setting {} encoder {name} to {value:?}", encoder.name());
+        encoder.set_parameter_value(name, value).map_err(|e| {
+            Error::ImageEncodingError(format!(
+                "Failed to set {name} on the {} encoder: {e}",
+                encoder.name()
+            ))
+        })
+    }
+
+    /// Set raw encoder parameters, parsing the value as the type the encoder expects. Unknown
+    /// names are passed through as strings so libheif can reject them.
+    fn set_heif_encoder_params(
+        encoder: &Encoder,
+        params: &[(String, String)],
+    ) -> Result<(), Error> {
+        for (name, value) in params {
+            if name.contains('\0') || value.contains('\0') {
+                return Err(Error::InvalidOptions(format!(
+                    "Encoder parameter {name:?} contains a NUL byte"
+                )));
+            }
+            let invalid_value = |kind: &str| {
+                Error::InvalidOptions(format!(
+                    "Encoder parameter {name} expects {kind}, got {value:?}"
+                ))
+            };
+            let typed_value = match encoder.parameter(name)? {
+                Some(EncoderParameterValue::Int(_)) => EncoderParameterValue::Int(
+                    value.parse().map_err(|_| invalid_value("an integer"))?,
+                ),
+                Some(EncoderParameterValue::Bool(_)) => EncoderParameterValue::Bool(
+                    value.parse().map_err(|_| invalid_value("true or false"))?,
+                ),
+                Some(EncoderParameterValue::String(_)) | None => {
+                    EncoderParameterValue::String(value.clone())
+                }
+            };
+            debug!(
+                "Setting {} encoder {name} to {typed_value:?}",
+                encoder.name()
+            );
+            encoder
+                .set_parameter_value(name, typed_value)
+                .map_err(|e| {
+                    Error::ImageEncodingError(format!(
+                        "Failed to set encoder parameter {name}={value} on the {} encoder: {}",
+                        encoder.name(),
+                        e.message
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// True if ravif can handle these options, otherwise AVIF falls back to libheif. ravif only
+    /// writes 8-bit lossy data and doesn't take libheif encoder parameters.
+    #[cfg(feature = "ravif")]
+    fn ravif_can_encode(&self, options: &EncodeOptions) -> Result<bool, Error> {
+        let reason = if options.lossless {
+            "lossless output"
+        } else if !options.encoder_params.is_empty() {
+            "encoder parameters"
+        } else if Self::heif_bit_depth(&self.image, options)? > 8 {
+            "high bit depth output"
+        } else {
+            return Ok(true);
+        };
+        debug!("ravif doesn't support {reason}, encoding AVIF with libheif");
+        Ok(false)
+    }
+
+    /// build and return AVIF image data using ravif, through the image crate's encoder
+    #[cfg(feature = "ravif")]
+    fn output_ravif(&self, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        let quality = validate_quality(
+            options
+                .avif_quality
+                .or(options.quality)
+                .unwrap_or(DEFAULT_HEIF_QUALITY),
+        )?;
+        // ravif speeds run from 1 (slowest) to 10, and 4 is what cavif uses
+        let speed = match options.effort {
+            Some(effort) => 10 - validate_effort(effort)?,
+            None => 4,
+        };
+        debug!("ravif encoding AVIF at quality {quality}, speed {speed}");
+        let mut buffer: Vec<u8> = Vec::new();
+        self.apply_all_transforms()?
+            .write_with_encoder(image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                &mut buffer,
+                speed,
+                quality.max(1),
+            ))
+            .map_err(|e| Error::ImageEncodingError(e.to_string()))?;
+        Ok(buffer)
+    }
+
+    /// Encode with the image crate's own encoders
+    fn output_native(
+        image: &DynamicImage,
+        format: ImageFormat,
+        write_format: image::ImageFormat,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let flattened;
+        let image = if format == ImageFormat::Jpg && image.color().has_alpha() {
+            let background = options.background_color.unwrap_or(DEFAULT_BACKGROUND_COLOR);
+            debug!("JPG has no alpha channel, flattening onto {background:?}");
+            flattened = flatten_alpha_onto(image, background);
+            &flattened
+        } else {
+            image
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        match (format, options.quality) {
+            (ImageFormat::Jpg, Some(quality)) => {
+                let quality = validate_quality(quality)?;
+                debug!("JPEG encoding at quality {}", quality);
+                image.write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))
+            }
+            (ImageFormat::Webp, _) => return Self::output_webp(image, options),
+            // QOI only stores 8-bit RGB or RGBA
+            (ImageFormat::Qoi, _) => match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => DynamicImage::ImageRgb8(image.to_rgb8()),
+            }
+            .write_to(&mut Cursor::new(&mut buffer), write_format),
+            // farbfeld is always 16-bit RGBA, widen rather than going through 8-bit
+            (ImageFormat::Farbfeld, _) => DynamicImage::ImageRgba16(image.to_rgba16())
+                .write_to(&mut Cursor::new(&mut buffer), write_format),
+            (ImageFormat::Png, _)
+                if options.png_compression.is_some()
+                    || options.png_compression_level.is_some()
+                    || options.png_filter.is_some() =>
+            {
+                let compression = match options.png_compression_level {
+                    Some(level) => CompressionType::Level(validate_png_compression_level(level)?),
+                    None => options.png_compression.unwrap_or_default().into(),
+                };
+                let filter = options.png_filter.unwrap_or_default();
+                debug!("PNG encoding with {compression:?} compression and {filter:?} filter");
+                image.write_with_encoder(PngEncoder::new_with_quality(
+                    &mut buffer,
+                    compression,
+                    filter.into(),
+                ))
+            }
+            _ => image.write_to(&mut Cursor::new(&mut buffer), write_format),
+        }
+        .map_err(|e| Error::ImageEncodingError(e.to_string()))?;
+        Ok(buffer)
+    }
+
+    /// Encode WebP with libwebp, lossy at the requested quality unless `lossless` is set. The
+    /// image crate's own WebP encoder is lossless only, which loses to JPG on photos.
+    fn output_webp(image: &DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        let (width, height) = (image.width(), image.height());
+        let pixels = match image.color().has_alpha() {
+            true => image.to_rgba8().into_raw(),
+            false => image.to_rgb8().into_raw(),
+        };
+        let encoder = match image.color().has_alpha() {
+            true => webp::Encoder::from_rgba(&pixels, width, height),
+            false => webp::Encoder::from_rgb(&pixels, width, height),
+        };
+        encoder
+            .encode_advanced(&Self::webp_config(options)?)
+            .map(|data| data.to_vec())
+            .map_err(|e| Error::ImageEncodingError(format!("Failed to encode WebP: {e:?}")))
+    }
+
+    /// The libwebp settings for `options`, shared by still and animated WebP
+    fn webp_config(options: &EncodeOptions) -> Result<webp::WebPConfig, Error> {
+        let quality = validate_quality(options.quality.unwrap_or(DEFAULT_WEBP_QUALITY))?;
+        let mut config = webp::WebPConfig::new().map_err(|_| {
+            Error::ImageEncodingError("Failed to set up the WebP encoder".to_string())
+        })?;
+        config.quality = f32::from(quality);
+        match options.near_lossless {
+            Some(level) => {
+                let level = validate_near_lossless(level)?;
+                debug!("WebP encoding near-losslessly at level {level}");
+                config.lossless = 1;
+                config.near_lossless = i32::from(level);
+            }
+            None if options.lossless => {
+                debug!("WebP encoding losslessly");
+                config.lossless = 1;
+            }
+            None => {
+                debug!("WebP encoding at quality {quality}");
+                config.lossless = 0;
+                config.alpha_compression = 1;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Decode every frame of the input if it's an animated GIF or WebP, from
+    /// [Image::animation_data] when it came from memory and the input file otherwise. Returns
+    /// `None` for still images (including single-frame GIFs and flattened animations), which go
+    /// through the normal still image pipeline.
+    pub fn animation_frames(&self) -> Result<Option<Vec<image::Frame>>, Error> {
+        if self.frame_count <= 1 {
+            return Ok(None);
         }
+        let format = self.input_format()?;
+        let (frames, context) = if let Some(data) = &self.animation_data {
+            (
+                animation_frame_iter(Cursor::new(data.as_ref()), format),
+                "reading frames from memory".to_string(),
+            )
+        } else {
+            let file = std::fs::File::open(&self.input_filename)
+                .map_err(|e| Error::from_io(&e, &self.input_filename))?;
+            (
+                animation_frame_iter(std::io::BufReader::new(file), format),
+                format!("reading frames from {}", self.input_filename.display()),
+            )
+        };
+        let Some(frames) = frames.context(&context)? else {
+            return Ok(None);
+        };
+        let frames = frames.collect_frames().context(context)?;
+        debug!(
+            "{}: {} has {} frame(s)",
+            self.input_filename.display(),
+            format,
+            frames.len()
+        );
+        Ok(Some(frames))
+    }
+
+    /// Encode animation frames as an animated WebP, keeping each frame's delay and resizing
+    /// every frame to the [Image::final_geometry]. The encoder settings are the same as for a
+    /// still WebP.
+    pub fn output_animated_webp(&self, frames: &[image::Frame]) -> Result<Vec<u8>, Error> {
+        self.animated_webp(frames, &self.encode_options)
     }
 
-    pub fn resize(&self) -> Result<DynamicImage, Error> {
+    fn animated_webp(
+        &self,
+        frames: &[image::Frame],
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>, Error> {
         let final_geometry = self.final_geometry();
-        if final_geometry != Geometry::new(self.image.width(), self.image.height()) {
-            debug!(
-                "Resizing image from {}x{} to {}",
-                self.image.width(),
-                self.image.height(),
-                final_geometry,
-            );
-            let resized_img = self.image.resize_exact(
-                final_geometry.width.unwrap_or(0), // safe unwraps, as final_geometry is derived from existing dimensions
-                final_geometry.height.unwrap_or(0), // safe unwraps, as final_geometry is derived from existing dimensions
-                image::imageops::FilterType::Lanczos3,
+        let (Some(width), Some(height)) = (final_geometry.width, final_geometry.height) else {
+            return Err(Error::InvalidGeometry(format!(
+                "Can't encode an animation at {final_geometry}"
+            )));
+        };
+        let config = Self::webp_config(options)?;
+
+        let mut timestamp_ms: u32 = 0;
+        let mut timed_frames = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let buffer = frame.buffer();
+            let pixels = if buffer.dimensions() == (width, height) {
+                buffer.clone()
+            } else {
+                image::imageops::resize(buffer, width, height, self.filter.into())
+            };
+            timed_frames.push((pixels, timestamp_ms));
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            timestamp_ms = timestamp_ms.saturating_add(numerator / denominator.max(1));
+        }
+
+        // libwebp gives the last frame the average duration of the others, the webp crate
+        // doesn't pass on the end timestamp
+        let mut encoder = webp::AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(0);
+        for (pixels, timestamp_ms) in &timed_frames {
+            let timestamp_ms = i32::try_from(*timestamp_ms).map_err(|_| {
+                Error::ImageEncodingError("Animation is too long to encode as WebP".to_string())
+            })?;
+            encoder.add_frame(webp::AnimFrame::from_rgba(
+                pixels,
+                width,
+                height,
+                timestamp_ms,
+            ));
+        }
+        debug!(
+            "Encoding {} frames as an animated {}x{} WebP",
+            timed_frames.len(),
+            width,
+            height
+        );
+        encoder.try_encode().map(|data| data.to_vec()).map_err(|e| {
+            Error::ImageEncodingError(format!("Failed to encode animated WebP: {e:?}"))
+        })
+    }
+
+    /// Quantize the image to a palette of at most `colors` with imagequant and encode it as an
+    /// indexed PNG. Returns `None` when quantizing would look bad (photos, or missing the quality
+    /// floor), unless `force_quantize` is set, so the caller can fall back to a truecolor PNG.
+    #[cfg(feature = "imagequant")]
+    fn quantized_png(
+        &self,
+        image: &DynamicImage,
+        colors: u16,
+        options: &EncodeOptions,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if !options.force_quantize && self.is_photo() {
+            warn!(
+                "{} looks like a photo, not quantizing it without --force-quantize",
+                self.input_filename.display()
             );
-            Ok(resized_img)
-        } else {
-            Ok(self.image.clone())
+            return Ok(None);
+        }
+        let quant_error = |e: imagequant::Error| {
+            Error::ImageEncodingError(format!("Failed to quantize image: {e}"))
+        };
+        let rgba = image.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let pixels: Vec<imagequant::RGBA> = rgba
+            .pixels()
+            .map(|pixel| imagequant::RGBA::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+            .collect();
+
+        let mut attributes = imagequant::new();
+        attributes.set_max_colors(u32::from(colors)).map_err(|_| {
+            Error::InvalidOptions(format!(
+                "Quantize colors must be between 2 and {MAX_QUANTIZE_COLORS}, got {colors}"
+            ))
+        })?;
+        let minimum_quality = match options.force_quantize {
+            true => 0,
+            false => QUANTIZE_MIN_QUALITY,
+        };
+        attributes
+            .set_quality(minimum_quality, 100)
+            .map_err(quant_error)?;
+        let mut quant_image = attributes
+            .new_image(pixels, width as usize, height as usize, 0.0)
+            .map_err(quant_error)?;
+        let mut result = match attributes.quantize(&mut quant_image) {
+            Ok(result) => result,
+            Err(imagequant::Error::QualityTooLow) => {
+                warn!(
+                    "{} can't be quantized to {colors} colors at quality {minimum_quality} or better, not quantizing it",
+                    self.input_filename.display()
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(quant_error(e)),
+        };
+        result.set_dithering_level(1.0).map_err(quant_error)?;
+        let (palette, indexes) = result.remapped(&mut quant_image).map_err(quant_error)?;
+        debug!(
+            "Quantized to {} colors at quality {:?}",
+            palette.len(),
+            result.quantization_quality()
+        );
+
+        let png_error = |e: png::EncodingError| {
+            Error::ImageEncodingError(format!("Failed to write indexed PNG: {e}"))
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buffer, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(
+            palette
+                .iter()
+                .flat_map(|color| [color.r, color.g, color.b])
+                .collect::<Vec<u8>>(),
+        );
+        if palette.iter().any(|color| color.a < u8::MAX) {
+            encoder.set_trns(palette.iter().map(|color| color.a).collect::<Vec<u8>>());
         }
+        match options.png_compression_level {
+            Some(0) => encoder.set_deflate_compression(png::DeflateCompression::NoCompression),
+            Some(level) => encoder.set_deflate_compression(png::DeflateCompression::Level(
+                validate_png_compression_level(level)?,
+            )),
+            None => encoder.set_compression(match options.png_compression {
+                Some(PngCompression::Best) => png::Compression::High,
+                // png's fast mode hardly compresses indexed data, so don't go below balanced
+                _ => png::Compression::Balanced,
+            }),
+        }
+        // filtering rarely helps palette images
+        encoder.set_filter(match options.png_filter {
+            None | Some(PngFilter::NoFilter) => png::Filter::NoFilter,
+            Some(PngFilter::Sub) => png::Filter::Sub,
+            Some(PngFilter::Up) => png::Filter::Up,
+            Some(PngFilter::Avg) => png::Filter::Avg,
+            Some(PngFilter::Paeth) => png::Filter::Paeth,
+            Some(PngFilter::Adaptive) => png::Filter::Adaptive,
+        });
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        writer.write_image_data(&indexes).map_err(png_error)?;
+        writer.finish().map_err(png_error)?;
+        Ok(Some(buffer))
     }
 
-    /// build and return HEIF/HEIC image data
-    fn output_heif(&self) -> Result<Vec<u8>, Error> {
+    /// build and return HEIF/HEIC/AVIF image data
+    fn output_heif(&self, format: ImageFormat, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        let quality = Self::heif_quality(format, options)?;
         let lib_heif = LibHeif::new();
         let mut context = HeifContext::new()?;
-        let mut encoder = lib_heif.encoder_for_format(CompressionFormat::Hevc)?;
+        let compression_format = match format {
+            ImageFormat::Avif => CompressionFormat::Av1,
+            ImageFormat::Heic | ImageFormat::Heif => CompressionFormat::Hevc,
+            _ => {
+                return Err(Error::UnsupportedFormat(format!(
+                    "{format} can't be encoded through libheif"
+                )));
+            }
+        };
+        let mut encoder = lib_heif
+            .encoder_for_format(compression_format)
+            .map_err(|e| {
+                Error::ImageEncodingError(format!(
+                    "libheif has no {compression_format:?} encoder available for {format}: {e}"
+                ))
+            })?;
         let Geometry { width, height } = self.final_geometry();
 
         let width = width.ok_or_else(|| {
@@ -242,65 +2923,255 @@ impl Image {
             height,
             libheif_rs::ColorSpace::Rgb(libheif_rs::RgbChroma::C444),
         )?;
-        image.create_plane(Channel::R, width, height, 8)?;
-        image.create_plane(Channel::G, width, height, 8)?;
-        image.create_plane(Channel::B, width, height, 8)?;
-        image.create_plane(Channel::Alpha, width, height, 8)?;
+
+        let resized_image = self.apply_all_transforms()?;
+        // only carry an alpha plane when the source actually has transparency
+        let has_alpha = resized_image.color().has_alpha();
+        let bit_depth = Self::heif_bit_depth(&resized_image, options)?;
+
+        image.create_plane(Channel::R, width, height, bit_depth)?;
+        image.create_plane(Channel::G, width, height, bit_depth)?;
+        image.create_plane(Channel::B, width, height, bit_depth)?;
+        if has_alpha {
+            image.create_plane(Channel::Alpha, width, height, bit_depth)?;
+            // the image crate stores straight (non-premultiplied) alpha
+            image.set_premultiplied_alpha(false);
+        }
 
         let planes = image.planes_mut();
 
-        let (Some(plane_r), Some(plane_g), Some(plane_b), Some(plane_a)) =
-            (planes.r, planes.g, planes.b, planes.a)
-        else {
+        let (Some(plane_r), Some(plane_g), Some(plane_b)) = (planes.r, planes.g, planes.b) else {
             return Err(Error::ImageEncodingError(
                 "Failed to get one of the planes for HEIF image, this is definitely a bug in the code!".to_string(),
             ));
         };
-
-        let stride = plane_r.stride as u32;
         debug!(
-            "HEIF encoding image with width {}, height {}, stride {}",
-            width, height, stride
+            "HEIF encoding image with width {}, height {}, stride {}, alpha {}, bit depth {}",
+            width, height, plane_r.stride, has_alpha, bit_depth
         );
 
-        let resized_image = self.resize()?;
-        let rgb8 = resized_image.into_rgba8();
-        rgb8.enumerate_pixels().for_each(|(x, y, pixel)| {
-            let offset = (y * stride + x) as usize;
-            pixel
-                .0
-                .iter()
-                .enumerate()
-                .for_each(|(i, &channel)| match i {
-                    0 => {
-                        plane_r.data[offset] = channel;
-                    }
-                    1 => {
-                        plane_g.data[offset] = channel;
-                    }
-                    2 => {
-                        plane_b.data[offset] = channel;
-                    }
-                    3 => {
-                        plane_a.data[offset] = channel;
-                    }
-                    _ => {}
-                });
-        });
+        let mut target_planes = vec![
+            (plane_r.data, plane_r.stride),
+            (plane_g.data, plane_g.stride),
+            (plane_b.data, plane_b.stride),
+        ];
+        if has_alpha {
+            let Some(plane_a) = planes.a else {
+                return Err(Error::ImageEncodingError(
+                    "Failed to get the alpha plane for HEIF image, this is definitely a bug in the code!"
+                        .to_string(),
+                ));
+            };
+            target_planes.push((plane_a.data, plane_a.stride));
+        }
+
+        if bit_depth > 8 {
+            let rgba16 = resized_image.into_rgba16();
+            fill_planes_high_bit_depth(rgba16.as_raw(), width as usize, bit_depth, target_planes);
+        } else {
+            let rgba8 = resized_image.into_rgba8();
+            fill_planes(rgba8.as_raw(), width as usize, target_planes);
+        }
 
-        encoder.set_quality(EncoderQuality::Lossy(85))?;
+        debug!("HEIF encoding {} at quality {:?}", format, quality);
+        encoder.set_quality(quality)?;
+        if let Some(effort) = options.effort {
+            Self::set_heif_effort(&encoder, effort)?;
+        }
+        Self::set_heif_encoder_params(&encoder, &options.encoder_params)?;
         context.encode_image(&image, &mut encoder, None)?;
-        context.write_to_bytes().map_err(Error::from)
+        let data = context.write_to_bytes()?;
+
+        // make sure the container matches the extension, eg. a .avif never carries HEVC data
+        if let Some(expected_brand) = format.heif_brand()
+            && !ftyp_brands(&data).contains(expected_brand)
+        {
+            return Err(Error::ImageEncodingError(format!(
+                "{} output is missing the '{}' brand",
+                format,
+                String::from_utf8_lossy(expected_brand)
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Read the color type of the input file from its header, decoding it as the format that was
+    /// detected when it was loaded
+    fn input_color_type(&self) -> Option<image::ColorType> {
+        let mut reader = image::ImageReader::open(&self.input_filename).ok()?;
+        reader.set_format(self.input_format().ok()?.try_into().ok()?);
+        let decoder = reader.into_decoder().ok()?;
+        Some(image::ImageDecoder::color_type(&decoder))
+    }
+
+    /// True if encoding to `format` would just reproduce the input file, so it can be copied as-is
+    pub fn is_passthrough_eligible(&self, format: ImageFormat) -> bool {
+        self.passthrough_eligible(format, &self.encode_options)
+    }
+
+    fn passthrough_eligible(&self, format: ImageFormat, options: &EncodeOptions) -> bool {
+        options.relevant_to(format) == EncodeOptions::default()
+            && !self.edited
+            && self.current_geometry() == self.original_geometry
+            && self.final_geometry() == self.original_geometry
+            && self.input_format().ok() == Some(format)
+            && self.input_color_type() == Some(self.image.color())
     }
 
+    /// Encode the image as `format` and return it as a `data:` URL
+    pub fn to_data_url(&self, format: ImageFormat) -> Result<String, Error> {
+        Ok(data_url(format, &self.output_as_format(format)?))
+    }
+
+    /// The same as [Image::to_data_url], for callers looking for it under its encoding
+    pub fn to_base64(&self, format: ImageFormat) -> Result<String, Error> {
+        self.to_data_url(format)
+    }
+
+    /// Encode the image as `format` using the image's own [EncodeOptions]
     pub fn output_as_format(&self, format: ImageFormat) -> Result<Vec<u8>, Error> {
+        self.output_with_options(format, &self.encode_options)
+    }
+
+    /// Encode the image as `format` and write it to `path` with [write_atomically], returning
+    /// the number of bytes written
+    pub fn save_to_path(&self, path: &Path, format: ImageFormat) -> Result<usize, Error> {
+        let data = self.output_as_format(format)?;
+        write_atomically(path, &data)?;
+        debug!("Wrote {} bytes to {}", data.len(), path.display());
+        Ok(data.len())
+    }
+
+    /// Encode the image as `format` using the given [EncodeOptions]
+    pub fn output_with_options(
+        &self,
+        format: ImageFormat,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>, Error> {
+        if format.is_input_only() {
+            return Err(Error::UnsupportedFormat(format!(
+                "{format} is only supported as an input format"
+            )));
+        }
+        check_codec(format)?;
+
+        if self.passthrough_eligible(format, options) {
+            debug!(
+                "{} is already {} with no changes requested, passing through the original bytes",
+                self.input_filename.display(),
+                format
+            );
+            return std::fs::read(&self.input_filename)
+                .map_err(|e| Error::from_io(&e, &self.input_filename));
+        }
+
+        if self.frame_count > 1 {
+            if format != ImageFormat::Webp {
+                return Err(Error::UnsupportedFormat(format!(
+                    "{} is a {} frame animation and {format} output would only keep the first frame, use WebP to keep the animation or --first-frame to flatten it",
+                    self.input_filename.display(),
+                    self.frame_count
+                )));
+            }
+            let frames = self.animation_frames()?.ok_or_else(|| {
+                Error::UnsupportedFormat(format!(
+                    "Couldn't read the animation frames from {}",
+                    self.input_filename.display()
+                ))
+            })?;
+            return self.animated_webp(&frames, options);
+        }
+
+        // lossy WebP is for photos, graphics stay lossless unless a quality was asked for
+        let graphic_options;
+        let options = if format == ImageFormat::Webp
+            && options.quality.is_none()
+            && !options.lossless
+            && options.near_lossless.is_none()
+            && self.is_graphic()
+        {
+            debug!(
+                "{} looks like a graphic, encoding WebP losslessly",
+                self.input_filename.display()
+            );
+            graphic_options = EncodeOptions {
+                lossless: true,
+                ..options.clone()
+            };
+            &graphic_options
+        } else {
+            options
+        };
+
+        if options.lossless {
+            match format {
+                ImageFormat::Jpg => {
+                    return Err(Error::InvalidOptions(
+                        "JPG can't be encoded losslessly".to_string(),
+                    ));
+                }
+                ImageFormat::Png
+                | ImageFormat::Tiff
+                | ImageFormat::Bmp
+                | ImageFormat::Qoi
+                | ImageFormat::Farbfeld => {
+                    debug!("{} output is always lossless, nothing to change", format);
+                }
+                _ => {}
+            }
+        }
+
+        if !options.encoder_params.is_empty() && format.is_native_image_format() {
+            warn!(
+                "Encoder parameters only apply to HEIF/HEIC/AVIF output, ignoring them for {format}"
+            );
+        }
+
+        if format != ImageFormat::Webp && options.near_lossless.is_some() {
+            debug!("Near-lossless only applies to WebP output, ignoring it for {format}");
+        }
+        if format != ImageFormat::Png
+            && (options.png_compression.is_some()
+                || options.png_compression_level.is_some()
+                || options.png_filter.is_some())
+        {
+            debug!("PNG compression options only apply to PNG output, ignoring them for {format}");
+        }
+
+        #[cfg(feature = "ravif")]
+        if format == ImageFormat::Avif && self.ravif_can_encode(options)? {
+            debug!("Encoding AVIF with the ravif backend");
+            return self.output_ravif(options);
+        }
+
         let write_format: Result<image::ImageFormat, Error> = format.try_into();
         if let Ok(write_format) = write_format {
-            let resized_image = self.resize()?;
-            let mut buffer: Vec<u8> = Vec::new();
-            resized_image
-                .write_to(&mut Cursor::new(&mut buffer), write_format)
-                .map_err(|e| Error::ImageEncodingError(e.to_string()))?;
+            let resized_image = self.apply_all_transforms()?;
+            #[cfg(feature = "imagequant")]
+            let quantized = match (format, options.quantize) {
+                (ImageFormat::Png, Some(colors)) => {
+                    self.quantized_png(&resized_image, colors, options)?
+                }
+                _ => None,
+            };
+            #[cfg(not(feature = "imagequant"))]
+            let quantized: Option<Vec<u8>> = None;
+            let buffer = match quantized {
+                Some(buffer) => buffer,
+                None => Self::output_native(&resized_image, format, write_format, options)?,
+            };
+            #[cfg(feature = "oxipng")]
+            if format == ImageFormat::Png
+                && let Some(level) = options.optimize_png
+            {
+                let optimized = optimize_png(&buffer, level)?;
+                debug!(
+                    "oxipng level {level} shrank PNG from {} to {} bytes",
+                    buffer.len(),
+                    optimized.len()
+                );
+                return Ok(optimized);
+            }
             Ok(buffer)
         } else {
             if format.is_native_image_format() {
@@ -308,7 +3179,7 @@ impl Image {
                     "Failed to convert to native image format".to_string(),
                 ));
             }
-            self.output_heif()
+            self.output_heif(format, options)
         }
     }
 
@@ -321,12 +3192,17 @@ impl Image {
             self.input_filename.clone()
         };
 
-        if output_path
+        // always use the short extensions, input-only formats like PGM vs PNM aren't aliases
+        if let Some(extension) = output_path
             .extension()
             .and_then(|ext| ext.to_str())
-            .is_some_and(|ext| ext.eq_ignore_ascii_case("jpeg"))
+            .map(str::to_ascii_lowercase)
+            && let Ok(format) = ImageFormat::from_str(&extension)
+            && !format.is_input_only()
+            && format.all_extensions().contains(&extension.as_str())
+            && extension != format.extension()
         {
-            output_path.set_extension("jpg");
+            output_path.set_extension(format.extension());
         }
 
         if let Some(ref suffix) = self.output_suffix
@@ -345,13 +3221,162 @@ impl Image {
         output_path
     }
 
+    /// A copy of this image's settings wrapped around a different image, with no pending resize
+    fn derive_with_image(&self, image: DynamicImage) -> Image {
+        Image {
+            original_file_size: self.original_file_size,
+            input_filename: self.input_filename.clone(),
+            original_geometry: self.original_geometry.clone(),
+            target_geometry: None,
+            output_format: self.output_format,
+            output_suffix: self.output_suffix.clone(),
+            encode_options: self.encode_options.clone(),
+            respect_original_size: self.respect_original_size,
+            overwrite_if_smaller: self.overwrite_if_smaller,
+            claimed_format: self.claimed_format,
+            detected_format: self.detected_format,
+            edited: self.edited,
+            frame_count: 1,
+            animation_data: None,
+            filter: self.filter,
+            candidate_formats: self.candidate_formats.clone(),
+            image,
+        }
+    }
+
+    /// Split the (resized) image into non-overlapping tiles and encode each one independently.
+    ///
+    /// Tiles are returned in row-major order, tiles on the right and bottom edges may be smaller.
+    pub fn tile_encode(
+        &self,
+        tile_width: u32,
+        tile_height: u32,
+        format: ImageFormat,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        if tile_width == 0 || tile_height == 0 {
+            return Err(Error::InvalidOptions(format!(
+                "Tile size must be non-zero, got {tile_width}x{tile_height}"
+            )));
+        }
+        if self.frame_count > 1 {
+            return Err(Error::UnsupportedFormat(
+                "Can't tile an animation, flatten it to its first frame first".to_string(),
+            ));
+        }
+        let source = self.resized_image()?;
+        let cols = source.width().div_ceil(tile_width);
+        let rows = source.height().div_ceil(tile_height);
+        debug!(
+            "Encoding {}x{} image as {}x{} tiles of {}x{}",
+            source.width(),
+            source.height(),
+            cols,
+            rows,
+            tile_width,
+            tile_height
+        );
+
+        (0..rows * cols)
+            .into_par_iter()
+            .map(|index| {
+                let x = (index % cols) * tile_width;
+                let y = (index / cols) * tile_height;
+                let tile = source.crop_imm(x, y, tile_width, tile_height);
+                self.derive_with_image(tile).output_as_format(format)
+            })
+            .collect()
+    }
+
+    /// Decode a row-major grid of encoded tiles (as produced by [Image::tile_encode]) back into a single image
+    pub fn merge_tiles(tiles: &[&[u8]], cols: u32, rows: u32) -> Result<Image, Error> {
+        let tile_count = cols
+            .checked_mul(rows)
+            .ok_or_else(|| Error::InvalidGeometry(format!("{cols}x{rows} tiles is too many")))?;
+        if tile_count == 0 || tiles.len() != tile_count as usize {
+            return Err(Error::InvalidOptions(format!(
+                "Expected {cols}x{rows} tiles, got {}",
+                tiles.len()
+            )));
+        }
+
+        libheif_rs::integration::image::register_all_decoding_hooks();
+        let decoded = tiles
+            .par_iter()
+            .enumerate()
+            .map(|(index, tile)| {
+                image::load_from_memory(tile).context(format!("decoding tile {index}"))
+            })
+            .collect::<Result<Vec<DynamicImage>, Error>>()?;
+
+        // column widths come from the first row, row heights from the first column
+        let col_widths: Vec<u32> = decoded[..cols as usize].iter().map(|t| t.width()).collect();
+        let row_heights: Vec<u32> = decoded
+            .iter()
+            .step_by(cols as usize)
+            .map(|t| t.height())
+            .collect();
+        let total = |sizes: &[u32]| {
+            sizes
+                .iter()
+                .try_fold(0u32, |total, size| total.checked_add(*size))
+                .ok_or_else(|| Error::InvalidGeometry("the merged image is too big".to_string()))
+        };
+        let (width, height) = (total(&col_widths)?, total(&row_heights)?);
+
+        let has_alpha = decoded.iter().any(|tile| tile.color().has_alpha());
+        let mut canvas = if has_alpha {
+            DynamicImage::new_rgba8(width, height)
+        } else {
+            DynamicImage::new_rgb8(width, height)
+        };
+
+        let mut y = 0;
+        for (row, row_height) in row_heights.iter().enumerate() {
+            let mut x = 0;
+            for (col, col_width) in col_widths.iter().enumerate() {
+                let tile = &decoded[row * cols as usize + col];
+                if tile.width() != *col_width || tile.height() != *row_height {
+                    return Err(Error::InvalidGeometry(format!(
+                        "Tile at column {col}, row {row} is {}x{}, expected {col_width}x{row_height}",
+                        tile.width(),
+                        tile.height()
+                    )));
+                }
+                image::imageops::replace(&mut canvas, tile, x as i64, y as i64);
+                x += col_width;
+            }
+            y += row_height;
+        }
+
+        Ok(Image {
+            original_file_size: tiles.iter().map(|tile| tile.len() as u64).sum(),
+            ..Image::from_dynamic_image(canvas, PathBuf::new())
+        })
+    }
+
+    /// Re-read an output file from disk and check it decodes to an image matching `original`
+    /// with at least `min_ssim` similarity
+    pub fn verify_output(output_path: &Path, original: &Image, min_ssim: f64) -> Result<(), Error> {
+        let written = std::fs::read(output_path).map_err(|e| Error::from_io(&e, output_path))?;
+        let score = original.compare_to_encoded(&written, true, false)?;
+        match score.ssim {
+            Some(ssim) if ssim >= min_ssim => Ok(()),
+            Some(ssim) => Err(Error::ImageComparisonError(format!(
+                "SSIM {ssim:.6} is below the minimum of {min_ssim}"
+            ))),
+            None => Err(Error::ImageComparisonError(
+                "SSIM score was not computed".to_string(),
+            )),
+        }
+    }
+
     pub fn compare_to_encoded(
         &self,
         encoded: &[u8],
         compute_ssim: bool,
         compute_psnr: bool,
     ) -> Result<QualityScore, Error> {
-        let source = self.resize()?;
+        let source = self.resized_image()?;
         let source_rgb = source.to_rgb8();
         libheif_rs::integration::image::register_all_decoding_hooks();
         let candidate = image::load_from_memory(encoded).map_err(|e| {
@@ -375,12 +3400,7 @@ impl Image {
         let mut score = QualityScore::default();
 
         if compute_ssim {
-            let ssim_result =
-                rgb_similarity_structure(&Algorithm::MSSIMSimple, &source_rgb, &candidate_rgb)
-                    .map_err(|e| {
-                        Error::ImageComparisonError(format!("Failed to compare SSIM: {e}"))
-                    })?;
-            score.ssim = Some(ssim_result.score);
+            score.ssim = Some(Self::compute_ssim(&source_rgb, &candidate_rgb)?);
         }
 
         if compute_psnr {
@@ -393,6 +3413,55 @@ impl Image {
         Ok(score)
     }
 
+    /// Compare this image with `other` pixel by pixel, after applying any pending resize to both.
+    /// Only the RGB channels are compared.
+    pub fn diff(&self, other: &Image) -> Result<DifferenceMetrics, Error> {
+        let source_rgb = self.resized_image()?.to_rgb8();
+        let other_rgb = other.resized_image()?.to_rgb8();
+        if source_rgb.dimensions() != other_rgb.dimensions() {
+            return Err(Error::ImageComparisonError(format!(
+                "Can't diff a {}x{} image with a {}x{} image",
+                source_rgb.width(),
+                source_rgb.height(),
+                other_rgb.width(),
+                other_rgb.height()
+            )));
+        }
+
+        let (max_delta, total_delta) = source_rgb
+            .as_raw()
+            .par_iter()
+            .zip(other_rgb.as_raw().par_iter())
+            .map(|(a, b)| a.abs_diff(*b))
+            .fold(
+                || (0u8, 0u64),
+                |(max, total), delta| (max.max(delta), total + u64::from(delta)),
+            )
+            .reduce(
+                || (0u8, 0u64),
+                |(max_a, total_a), (max_b, total_b)| (max_a.max(max_b), total_a + total_b),
+            );
+        let samples = source_rgb.as_raw().len();
+        Ok(DifferenceMetrics {
+            max_delta,
+            mean_delta: match samples {
+                0 => 0.0,
+                _ => total_delta as f64 / samples as f64,
+            },
+            ssim: Self::compute_ssim(&source_rgb, &other_rgb)?,
+            psnr: Self::compute_psnr(source_rgb.as_raw(), other_rgb.as_raw()),
+        })
+    }
+
+    fn compute_ssim(
+        reference: &image::RgbImage,
+        candidate: &image::RgbImage,
+    ) -> Result<f64, Error> {
+        rgb_similarity_structure(&Algorithm::MSSIMSimple, reference, candidate)
+            .map(|result| result.score)
+            .map_err(|e| Error::ImageComparisonError(format!("Failed to compare SSIM: {e}")))
+    }
+
     fn compute_psnr(reference: &[u8], candidate: &[u8]) -> f64 {
         let mut mse = 0f64;
         for (reference_channel, candidate_channel) in reference.iter().zip(candidate.iter()) {
@@ -414,34 +3483,355 @@ impl Image {
         20.0 * max_i.log10() - 10.0 * mse.log10()
     }
 
-    pub fn auto_format(&self) -> Result<(ImageFormat, Vec<u8>), Error> {
-        debug!("Auto-optimizing image format");
-        use rayon::iter::ParallelIterator;
-        let results: Vec<(ImageFormat, Result<Vec<u8>, Error>)> = ImageFormat::all()
+    /// Run `encoder`, turning a panic in it (eg. inside libheif) into an error, so one bad encoder
+    /// doesn't take down the rest of auto-format
+    fn encode_catching_panics(
+        &self,
+        encoder: &EncodeFn,
+        format: ImageFormat,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>, Error> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            encoder(self, format, options)
+        }))
+        .unwrap_or_else(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(Error::ImageEncodingError(format!(
+                "encoder panicked: {message}"
+            )))
+        })
+    }
+
+    /// The image's [EncodeOptions], with the best PNG compression unless a level was chosen,
+    /// since auto-format is after the smallest output
+    fn auto_format_options(&self) -> EncodeOptions {
+        let mut options = self.encode_options.clone();
+        if options.png_compression_level.is_none() {
+            options.png_compression = Some(options.png_compression.unwrap_or(PngCompression::Best));
+        }
+        options
+    }
+
+    /// Encode the image in each of the given formats in parallel with `encoder`, using the
+    /// auto-format options
+    fn encode_formats(
+        &self,
+        formats: Vec<ImageFormat>,
+        encoder: &EncodeFn,
+    ) -> Vec<(ImageFormat, Result<Vec<u8>, Error>)> {
+        let options = self.auto_format_options();
+        formats
             .into_par_iter()
             .map(|fmt| {
-                debug!("Trying format {:?}", fmt);
-                (fmt, self.output_as_format(fmt))
+                debug!("Trying format {fmt}");
+                (fmt, self.encode_catching_panics(encoder, fmt, &options))
             })
-            .collect();
+            .collect()
+    }
 
-        let results = results.into_iter().filter_map(|(format, data)| match data {
-            Ok(encoded_data) => {
-                debug!("Format {} produced {} bytes", format, encoded_data.len());
-                Some((format, encoded_data))
+    /// Encode the image in each of the given formats in parallel and return the smallest result
+    /// and what happened with each format
+    fn smallest_encoding_explained(
+        &self,
+        formats: Vec<ImageFormat>,
+    ) -> Result<(ImageFormat, Vec<u8>, Vec<FormatAttempt>), Error> {
+        self.smallest_encoding_with(formats, &Image::output_with_options)
+    }
+
+    /// Encode the image in each of the given formats in parallel with `encoder` instead of
+    /// [Image::output_with_options], and return the smallest result and what happened with each
+    /// format. A panicking encoder counts as a failed format.
+    pub fn smallest_encoding_with(
+        &self,
+        formats: Vec<ImageFormat>,
+        encoder: &EncodeFn,
+    ) -> Result<(ImageFormat, Vec<u8>, Vec<FormatAttempt>), Error> {
+        pick_smallest(self.encode_formats(formats, encoder))
+    }
+
+    /// Encode the image in each auto-format candidate in parallel, like [Image::auto_format],
+    /// but stop waiting after `timeout` and return the smallest result that was ready by then.
+    /// If no format finished in time the image is re-encoded in the input file's format, see
+    /// [Image::fallback_to_original_format].
+    ///
+    /// Encoders that haven't started by the deadline are cancelled. The ones already running
+    /// can't be interrupted, so they finish in the background and their results are dropped.
+    pub fn auto_format_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<(ImageFormat, Vec<u8>), Error> {
+        let encoder: Arc<EncodeFn> = Arc::new(Image::output_with_options);
+        self.auto_format_with_timeout_using(timeout, &encoder)
+    }
+
+    /// Like [Image::auto_format_with_timeout], but encodes the candidates with `encoder` instead
+    /// of [Image::output_with_options]. The fallback to the input format still uses the built-in
+    /// encoder.
+    pub fn auto_format_with_timeout_using(
+        &self,
+        timeout: Duration,
+        encoder: &Arc<EncodeFn>,
+    ) -> Result<(ImageFormat, Vec<u8>), Error> {
+        let deadline = Instant::now() + timeout;
+        let candidates = self.auto_format_candidates();
+        debug!("Auto-optimizing image format within {timeout:?}: {candidates:?}");
+
+        // the encoders can outlive this call, so they get their own copy of the image
+        let image = Arc::new(self.clone());
+        let options = Arc::new(self.auto_format_options());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        for format in candidates.iter().copied() {
+            let (image, options, encoder, cancelled, sender) = (
+                Arc::clone(&image),
+                Arc::clone(&options),
+                Arc::clone(encoder),
+                Arc::clone(&cancelled),
+                sender.clone(),
+            );
+            rayon::spawn(move || {
+                if cancelled.load(Ordering::Relaxed) {
+                    return;
+                }
+                debug!("Trying format {format}");
+                let result = image.encode_catching_panics(encoder.as_ref(), format, &options);
+                // the receiver is gone if the deadline has passed
+                let _ = sender.send((format, result));
+            });
+        }
+        drop(sender);
+
+        let mut results = Vec::with_capacity(candidates.len());
+        while results.len() < candidates.len() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(remaining) {
+                Ok(result) => results.push(result),
+                Err(_) => break,
             }
-            Err(err) => {
-                error!("Failed to encode image as {}: {:?}", format, err);
-                None
+        }
+        cancelled.store(true, Ordering::Relaxed);
+
+        let unfinished: Vec<ImageFormat> = candidates
+            .into_iter()
+            .filter(|format| !results.iter().any(|(done, _)| done == format))
+            .collect();
+        if !unfinished.is_empty() {
+            warn!(
+                "{}: Auto-format ran out of time, skipping {:?}",
+                self.input_filename.display(),
+                unfinished
+            );
+        }
+
+        if results.is_empty() {
+            return self.fallback_to_original_format(Error::ImageEncodingError(format!(
+                "no format finished encoding within {timeout:?}"
+            )));
+        }
+        let (format, data, _) = pick_smallest(results)?;
+        self.check_original_size((format, data))
+    }
+
+    /// How much smaller `output_bytes` is than the original file, as a percentage. Negative if
+    /// the output is larger, `None` if the original file was empty.
+    pub fn size_reduction(&self, output_bytes: usize) -> Option<f64> {
+        if self.original_file_size == 0 {
+            return None;
+        }
+        Some(100.0 * (1.0 - output_bytes as f64 / self.original_file_size as f64))
+    }
+
+    /// False if `overwrite_if_smaller` is set and `output_bytes` of encoded data wouldn't save any
+    /// space over the original file
+    pub fn should_write(&self, output_bytes: usize) -> bool {
+        !self.overwrite_if_smaller || (output_bytes as u64) < self.original_file_size
+    }
+
+    /// If `respect_original_size` is set, reject a result that isn't smaller than the original file
+    fn check_original_size(
+        &self,
+        result: (ImageFormat, Vec<u8>),
+    ) -> Result<(ImageFormat, Vec<u8>), Error> {
+        if self.respect_original_size && result.1.len() as u64 >= self.original_file_size {
+            debug!(
+                "Best result {} ({} bytes) isn't smaller than the original ({} bytes)",
+                result.0,
+                result.1.len(),
+                self.original_file_size
+            );
+            return Err(Error::ImageEncodingError(
+                "all formats larger than original".to_string(),
+            ));
+        }
+        Ok(result)
+    }
+
+    /// The formats auto-format should try. JPG is skipped in lossless mode and for graphics, and
+    /// PNG is skipped for photos, unless the formats were picked with
+    /// [Image::with_candidate_formats].
+    fn auto_format_candidates(&self) -> Vec<ImageFormat> {
+        if self.frame_count > 1 {
+            debug!("Auto-format only trying WebP, the only format that keeps the animation");
+            return vec![ImageFormat::Webp];
+        }
+        if let Some(formats) = &self.candidate_formats {
+            debug!("Auto-format trying the requested formats: {formats:?}");
+            return formats.clone();
+        }
+        let skip_jpg = self.encode_options.lossless || self.is_graphic();
+        let skip_png = self.is_photo();
+        debug!("Auto-format skipping JPG: {skip_jpg}, PNG: {skip_png}");
+        ImageFormat::all()
+            .into_iter()
+            .filter(|format| match format {
+                ImageFormat::Jpg => !skip_jpg,
+                ImageFormat::Png => !skip_png,
+                ImageFormat::Tiff
+                | ImageFormat::Bmp
+                | ImageFormat::Gif
+                | ImageFormat::Qoi
+                | ImageFormat::Farbfeld
+                | ImageFormat::Jpeg2000
+                | ImageFormat::Jxl => false,
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Encode the image in every format and return the smallest.
+    ///
+    /// If `respect_original_size` is set and nothing is smaller than the original file this returns
+    /// an error, and the caller should keep the source file as-is.
+    pub fn auto_format(&self) -> Result<(ImageFormat, Vec<u8>), Error> {
+        debug!("Auto-optimizing image format");
+        let smallest = self
+            .all_formats_sorted()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                Error::ImageEncodingError("Failed to determine optimal image format".to_string())
+            })?;
+        self.check_original_size(smallest)
+    }
+
+    /// Encode the image in every auto-format candidate and return every format that worked,
+    /// smallest first, eg. to pick a fallback for clients that can't show the smallest.
+    ///
+    /// Sizes are compared like [Image::auto_format], but the original file size isn't checked.
+    pub fn all_formats_sorted(&self) -> Result<Vec<(ImageFormat, Vec<u8>)>, Error> {
+        let mut encoded = Vec::new();
+        let mut errors = Vec::new();
+        for (format, result) in
+            self.encode_formats(self.auto_format_candidates(), &Image::output_with_options)
+        {
+            match result {
+                Ok(data) => encoded.push((format, data)),
+                Err(err) => {
+                    error!("Failed to encode image as {}: {:?}", format, err);
+                    errors.push((format, format!("{err:?}")));
+                }
             }
+        }
+        if encoded.is_empty() {
+            return Err(Error::AllFormatsFailedEncoding(errors));
+        }
+        // on a tie, prefer the format that usually compresses better
+        encoded.sort_by_key(|(format, data)| (data.len(), std::cmp::Reverse(*format)));
+        Ok(encoded)
+    }
+
+    /// Like [Image::auto_format], but also returns a [FormatAttempt] for every format, including
+    /// the ones auto-format skipped, so callers can show why a format was picked
+    pub fn auto_format_explained(
+        &self,
+    ) -> Result<(ImageFormat, Vec<u8>, Vec<FormatAttempt>), Error> {
+        let candidates = self.auto_format_candidates();
+        let (format, data, mut attempts) = self.smallest_encoding_explained(candidates.clone())?;
+        let (format, data) = self.check_original_size((format, data))?;
+        attempts.extend(
+            ImageFormat::all()
+                .into_iter()
+                .filter(|format| !candidates.contains(format))
+                .map(|format| FormatAttempt {
+                    format,
+                    encoded_bytes: None,
+                    error: Some("skipped for this image".to_string()),
+                    selected: false,
+                }),
+        );
+        attempts.sort_by_key(|attempt| {
+            ImageFormat::all()
+                .iter()
+                .position(|format| *format == attempt.format)
         });
+        Ok((format, data, attempts))
+    }
 
-        if let Some((format, data)) = results.into_iter().min_by_key(|r| r.1.iter().len()) {
-            debug!("Woo, the smallest is {}", format);
-            return Ok((format, data));
+    /// After auto-format failed with `err`, re-encode the image in the input file's own format
+    /// instead. Returns `err` if the input format can't be worked out.
+    pub fn fallback_to_original_format(&self, err: Error) -> Result<(ImageFormat, Vec<u8>), Error> {
+        let Ok(format) = self.input_format() else {
+            return Err(err);
+        };
+        warn!(
+            "{}: Auto-format failed ({:?}), falling back to the original format {}",
+            self.input_filename.display(),
+            err,
+            format
+        );
+        Ok((format, self.output_as_format(format)?))
+    }
+
+    /// Like [Image::auto_format], but only tries the slow HEIF-family encoders if the best of the fast
+    /// formats is larger than [DEFAULT_PHASED_THRESHOLD]
+    pub fn auto_format_phased(&self) -> Result<(ImageFormat, Vec<u8>), Error> {
+        self.auto_format_phased_with_threshold(DEFAULT_PHASED_THRESHOLD)
+    }
+
+    /// Like [Image::auto_format], but only tries the slow HEIF-family encoders if the best of the fast
+    /// formats is larger than `threshold` bytes
+    pub fn auto_format_phased_with_threshold(
+        &self,
+        threshold: usize,
+    ) -> Result<(ImageFormat, Vec<u8>), Error> {
+        self.auto_format_phased_using(threshold, &Image::output_with_options)
+    }
+
+    /// Like [Image::auto_format_phased_with_threshold], but encodes with `encoder` instead of
+    /// [Image::output_with_options]
+    pub fn auto_format_phased_using(
+        &self,
+        threshold: usize,
+        encoder: &EncodeFn,
+    ) -> Result<(ImageFormat, Vec<u8>), Error> {
+        let (fast, slow): (Vec<ImageFormat>, Vec<ImageFormat>) = self
+            .auto_format_candidates()
+            .into_iter()
+            .partition(|format| format.is_native_image_format());
+
+        debug!("Auto-optimizing image format, phase 1: {:?}", fast);
+        let mut results = self.encode_formats(fast, encoder);
+        let smallest_fast = results
+            .iter()
+            .filter_map(|(_, data)| data.as_ref().ok().map(Vec::len))
+            .min();
+        if let Some(smallest_fast) = smallest_fast
+            && smallest_fast <= threshold
+        {
+            debug!(
+                "Phase 1 result ({} bytes) is under the threshold of {} bytes, skipping phase 2",
+                smallest_fast, threshold
+            );
+        } else {
+            debug!("Auto-optimizing image format, phase 2: {:?}", slow);
+            results.extend(self.encode_formats(slow, encoder));
         }
-        Err(Error::ImageEncodingError(
-            "Failed to determine optimal image format".to_string(),
-        ))
+        // both phases go through the same tie-breaking as a single-phase run
+        let (format, data, _) = pick_smallest(results)?;
+        self.check_original_size((format, data))
     }
 }
@@ -20,14 +20,18 @@
 
 pub mod cli;
 pub mod imagedata;
+pub mod metrics;
+#[cfg(feature = "raw")]
+pub mod raw;
 
 use clap::ValueEnum;
+use image::codecs::png::{CompressionType, FilterType};
 use libheif_rs::HeifError;
 use log::{debug, error, info, warn};
 use std::{
     cmp::max,
     fmt::Display,
-    io::{self, Write},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -35,9 +39,64 @@ use strum::EnumIter;
 
 use crate::{
     cli::Cli,
-    imagedata::{Geometry, Image},
+    imagedata::{
+        FormatAttempt, Geometry, HistogramChannel, HistogramStats, Image, data_url,
+        write_atomically,
+    },
 };
 
+/// The name of the root element of an XML document, skipping any BOM, XML declaration,
+/// processing instructions, comments and doctype before it. `None` if `text` doesn't start like
+/// XML, or ends before the root element's name does.
+fn xml_root_element(text: &[u8]) -> Option<&[u8]> {
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .map(|position| position + needle.len())
+    };
+    let mut rest = text.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(text);
+    loop {
+        let markup = rest.trim_ascii_start().strip_prefix(b"<")?;
+        let end = match markup.first()? {
+            b'?' => find(markup, b"?>")?,
+            b'!' if markup.starts_with(b"!--") => find(markup, b"-->")?,
+            // a doctype, which might have an internal subset with its own markup
+            b'!' => match (markup.iter().position(|&b| b == b'['), find(markup, b">")) {
+                (Some(subset), Some(end)) if subset < end => find(markup, b"]>")?,
+                (_, end) => end?,
+            },
+            _ => {
+                let length = markup
+                    .iter()
+                    .position(|b| b.is_ascii_whitespace() || matches!(b, b'>' | b'/'))?;
+                return Some(&markup[..length]);
+            }
+        };
+        rest = &markup[end..];
+    }
+}
+
+/// True if `data` starts with a BMP file header: "BM", a file size with room for the headers,
+/// and at offset 14 the size of one of the known DIB headers. "BM" alone is too common a start
+/// for other files to go by.
+fn is_bmp_header(data: &[u8]) -> bool {
+    const DIB_HEADER_SIZES: [u32; 6] = [12, 40, 52, 56, 108, 124];
+    let Some(header) = data.get(..18).filter(|header| header.starts_with(b"BM")) else {
+        return false;
+    };
+    let le_u32 = |offset: usize| {
+        u32::from_le_bytes([
+            header[offset],
+            header[offset + 1],
+            header[offset + 2],
+            header[offset + 3],
+        ])
+    };
+    let dib_header_size = le_u32(14);
+    DIB_HEADER_SIZES.contains(&dib_header_size) && le_u32(2) >= 14 + dib_header_size
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, EnumIter, ValueEnum)]
 pub enum ImageFormat {
     #[value(alias = "jpeg")]
@@ -47,11 +106,218 @@ pub enum ImageFormat {
     Avif,
     Heic,
     Heif,
+    /// Never picked by auto-format, it's rarely the smallest
+    #[value(alias = "tif")]
+    Tiff,
+    /// Never picked by auto-format, it's uncompressed
+    Bmp,
+    /// Never picked by auto-format, and only written for still images since animated GIFs
+    /// are converted to animated WebP
+    Gif,
+    /// 16-bit RGBA, uncompressed, so auto-format never picks it
+    #[value(alias = "ff")]
+    Farbfeld,
+    /// Lossless and fast to decode. Only tried by auto-format when it's listed in `--formats`,
+    /// where it sometimes beats PNG on flat-color graphics.
+    Qoi,
+    /// Only offered with the `jpeg2000` feature, and never picked by auto-format. The image crate
+    /// has no JPEG 2000 codec yet, so reading or writing it fails with [Error::UnsupportedFormat].
+    #[cfg_attr(feature = "jpeg2000", value(name = "jp2", alias = "jpeg2000"))]
+    #[cfg_attr(not(feature = "jpeg2000"), value(skip), strum(disabled))]
+    Jpeg2000,
+    /// JPEG XL. There's no JPEG XL codec in this build, so it's hidden from `--type` and
+    /// auto-format, and reading or writing it fails with [Error::UnsupportedFormat].
+    #[value(skip)]
+    #[strum(disabled)]
+    Jxl,
+    /// Input only, the largest image in the icon is used unless another is picked
+    #[value(skip)]
+    #[strum(disabled)]
+    Ico,
+    /// Input only, with or without an alpha channel
+    #[value(skip)]
+    #[strum(disabled)]
+    Tga,
+    /// Input only, any of PBM, PGM, PPM or PAM. Grayscale stays grayscale.
+    #[value(skip)]
+    #[strum(disabled)]
+    Pnm,
+    /// Input only, OpenEXR HDR images are tone mapped to 8 bits when loaded, see [ToneMap]
+    #[value(skip)]
+    #[strum(disabled)]
+    Exr,
+    /// Input only, Radiance HDR images are tone mapped to 8 bits when loaded, like [ImageFormat::Exr]
+    #[value(skip)]
+    #[strum(disabled)]
+    Hdr,
+    /// Input only, BC1, BC2 or BC3 (DXT1, DXT3 or DXT5) compressed DirectDraw Surface textures.
+    /// Only the top-level image is read, mipmaps are ignored.
+    #[value(skip)]
+    #[strum(disabled)]
+    Dds,
+    /// Input only, and only with the `svg` feature, which rasterizes it with resvg at the target
+    /// geometry. Without the feature loading it fails with [Error::UnsupportedFormat].
+    #[value(skip)]
+    #[strum(disabled)]
+    Svg,
+    /// Input only, camera RAW files (DNG, CR2, NEF and the like), recognised by extension. With
+    /// the `raw` feature DNG is decoded by `raw::decode_dng()`; the others, and everything
+    /// without the feature, fail to load with [Error::UnsupportedFormat].
+    #[value(skip)]
+    #[strum(disabled)]
+    Raw,
+}
+
+/// How to tone map HDR (OpenEXR or Radiance) input down to 8 bits. Both curves approach white without
+/// reaching it, so values over 1.0 aren't clipped.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ToneMap {
+    /// `x / (1 + x)`, which keeps more detail in the highlights
+    #[default]
+    Reinhard,
+    /// `1 - e^-x`, like film exposure, brighter midtones than Reinhard
+    Exposure,
+}
+
+/// How to print a color histogram
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum HistogramFormat {
+    Json,
+    Csv,
+}
+
+/// PNG compression level, see [image::codecs::png::CompressionType]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum PngCompression {
+    /// The image crate's default
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+/// PNG row filter, see [image::codecs::png::FilterType]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum PngFilter {
+    #[value(name = "none")]
+    NoFilter,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Pick the best filter for each row
+    #[default]
+    Adaptive,
 }
 
+impl From<PngFilter> for FilterType {
+    fn from(filter: PngFilter) -> Self {
+        match filter {
+            PngFilter::NoFilter => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+            PngFilter::Adaptive => FilterType::Adaptive,
+        }
+    }
+}
+
+/// Resampling filter used when resizing, see [image::imageops::FilterType]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ResizeFilter {
+    /// Nearest neighbour, keeps pixel art sharp
+    Nearest,
+    /// Linear, the fastest smooth filter
+    Triangle,
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    /// Soft, for smooth thumbnails
+    Gaussian,
+    /// The sharpest and slowest
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => image::imageops::FilterType::CatmullRom,
+            ResizeFilter::Gaussian => image::imageops::FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How to rotate or flip the image, rotations are clockwise
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum RotationAngle {
+    #[value(name = "90")]
+    Cw90,
+    #[value(name = "180")]
+    Cw180,
+    #[value(name = "270")]
+    Cw270,
+    /// Mirror left-to-right
+    #[value(name = "fliph")]
+    FlipH,
+    /// Mirror top-to-bottom
+    #[value(name = "flipv")]
+    FlipV,
+}
+
+/// Where to place a watermark on the image
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    /// The top-left corner for an overlay of `overlay_size` on an image of `image_size`, both
+    /// as (width, height). Overlays bigger than the image are pinned to the top-left.
+    pub fn offset(&self, image_size: (u32, u32), overlay_size: (u32, u32)) -> (u32, u32) {
+        let max_x = image_size.0.saturating_sub(overlay_size.0);
+        let max_y = image_size.1.saturating_sub(overlay_size.1);
+        match self {
+            WatermarkPosition::TopLeft => (0, 0),
+            WatermarkPosition::TopRight => (max_x, 0),
+            WatermarkPosition::BottomLeft => (0, max_y),
+            WatermarkPosition::BottomRight => (max_x, max_y),
+            WatermarkPosition::Center => (max_x / 2, max_y / 2),
+        }
+    }
+}
+
+/// Formats as the (lowercase) extension, see [ImageFormat::display_upper] for headings
 impl Display for ImageFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", format!("{:?}", self).to_uppercase())
+        f.pad(self.extension())
+    }
+}
+
+/// An [ImageFormat] that formats in uppercase, from [ImageFormat::display_upper]
+struct UpperImageFormat(ImageFormat);
+
+impl Display for UpperImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(&self.0.extension().to_uppercase())
     }
 }
 
@@ -64,18 +330,189 @@ impl ImageFormat {
             ImageFormat::Avif => "avif",
             ImageFormat::Heic => "heic",
             ImageFormat::Heif => "heif",
+            ImageFormat::Tiff => "tif",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Farbfeld => "ff",
+            ImageFormat::Qoi => "qoi",
+            ImageFormat::Jpeg2000 => "jp2",
+            ImageFormat::Jxl => "jxl",
+            ImageFormat::Ico => "ico",
+            ImageFormat::Tga => "tga",
+            ImageFormat::Pnm => "pnm",
+            ImageFormat::Exr => "exr",
+            ImageFormat::Hdr => "hdr",
+            ImageFormat::Dds => "dds",
+            ImageFormat::Svg => "svg",
+            ImageFormat::Raw => "dng",
         }
     }
 
-    pub fn try_from_filename(filename: &str) -> Result<Self, Error> {
-        let ext = filename.to_ascii_lowercase();
-        let ext = ext
+    /// The format name in uppercase, eg. `JPG`, for tables and summaries
+    pub fn display_upper(&self) -> impl Display {
+        UpperImageFormat(*self)
+    }
+
+    /// Every extension accepted for this format, the one [ImageFormat::extension] writes first
+    pub fn all_extensions(&self) -> &'static [&'static str] {
+        match self {
+            ImageFormat::Jpg => &["jpg", "jpeg"],
+            ImageFormat::Tiff => &["tif", "tiff"],
+            ImageFormat::Png => &["png"],
+            ImageFormat::Webp => &["webp"],
+            ImageFormat::Avif => &["avif"],
+            ImageFormat::Heic => &["heic"],
+            ImageFormat::Heif => &["heif"],
+            ImageFormat::Bmp => &["bmp"],
+            ImageFormat::Gif => &["gif"],
+            ImageFormat::Farbfeld => &["ff"],
+            ImageFormat::Qoi => &["qoi"],
+            ImageFormat::Jpeg2000 => &["jp2", "j2k"],
+            ImageFormat::Jxl => &["jxl"],
+            ImageFormat::Ico => &["ico"],
+            ImageFormat::Tga => &["tga"],
+            ImageFormat::Pnm => &["pnm", "ppm", "pgm", "pbm", "pam"],
+            ImageFormat::Exr => &["exr"],
+            ImageFormat::Hdr => &["hdr"],
+            ImageFormat::Dds => &["dds"],
+            ImageFormat::Svg => &["svg", "svgz"],
+            ImageFormat::Raw => &[
+                "dng", "cr2", "cr3", "nef", "nrw", "arw", "orf", "rw2", "raf", "pef", "srw",
+            ],
+        }
+    }
+
+    /// Work out the format from the extension of a path's file name
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let filename = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_str()
+            .ok_or_else(|| Error::UnsupportedFormat("Invalid path".to_string()))?;
+        let ext = filename
             .rsplit('.')
             .next()
             .ok_or_else(|| Error::UnsupportedFormat(filename.to_string()))?;
         <ImageFormat as std::str::FromStr>::from_str(ext)
     }
 
+    #[deprecated(note = "use ImageFormat::from_path")]
+    pub fn try_from_filename(filename: &str) -> Result<Self, Error> {
+        ImageFormat::from_path(Path::new(filename))
+    }
+
+    /// Work out the format of a file from its contents with [ImageFormat::detect_from_bytes],
+    /// falling back to its extension when the magic bytes don't say. Where the extension is a
+    /// more specific name for what the contents show, ie. camera RAW in a TIFF container or HEIC
+    /// and HEIF, the extension wins.
+    pub fn detect_from_path(path: &Path) -> Result<Self, Error> {
+        // enough for an SVG's opening tag and any ftyp box seen in the wild
+        const MAGIC_BYTES_LEN: usize = 4096;
+        let mut head = Vec::with_capacity(MAGIC_BYTES_LEN);
+        std::fs::File::open(path)
+            .and_then(|file| file.take(MAGIC_BYTES_LEN as u64).read_to_end(&mut head))
+            .map_err(|e| Error::from_io(&e, path))?;
+        match (
+            ImageFormat::detect_from_bytes(&head),
+            ImageFormat::from_path(path),
+        ) {
+            (Ok(ImageFormat::Tiff), claimed @ Ok(ImageFormat::Raw))
+            | (
+                Ok(ImageFormat::Heic | ImageFormat::Heif),
+                claimed @ Ok(ImageFormat::Heic | ImageFormat::Heif),
+            )
+            | (Err(_), claimed) => claimed,
+            (Ok(detected), _) => Ok(detected),
+        }
+    }
+
+    #[deprecated(note = "use ImageFormat::detect_from_bytes")]
+    pub fn try_from_bytes_magic(data: &[u8]) -> Result<Self, Error> {
+        ImageFormat::detect_from_bytes(data)
+    }
+
+    /// Work out the format of image data from its leading magic bytes, rather than trusting a
+    /// file extension. HEIF-family files are told apart by their ftyp brands. TGA has no magic
+    /// bytes, so it is never detected.
+    pub fn detect_from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            return Ok(ImageFormat::Jpg);
+        }
+        if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+            return Ok(ImageFormat::Png);
+        }
+        if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            return Ok(ImageFormat::Webp);
+        }
+        if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            return Ok(ImageFormat::Tiff);
+        }
+        if is_bmp_header(data) {
+            return Ok(ImageFormat::Bmp);
+        }
+        // P1-P7 then whitespace, P7 being PAM
+        if let [b'P', b'1'..=b'7', whitespace, ..] = data
+            && whitespace.is_ascii_whitespace()
+        {
+            return Ok(ImageFormat::Pnm);
+        }
+        if data.starts_with(&[0, 0, 1, 0]) {
+            return Ok(ImageFormat::Ico);
+        }
+        if data.starts_with(&[0x76, 0x2F, 0x31, 0x01]) {
+            return Ok(ImageFormat::Exr);
+        }
+        if data.starts_with(b"#?RADIANCE") || data.starts_with(b"#?RGBE") {
+            return Ok(ImageFormat::Hdr);
+        }
+        if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            return Ok(ImageFormat::Gif);
+        }
+        if data.starts_with(b"DDS ") {
+            return Ok(ImageFormat::Dds);
+        }
+        if data.starts_with(b"farbfeld") {
+            return Ok(ImageFormat::Farbfeld);
+        }
+        if data.starts_with(b"qoif") {
+            return Ok(ImageFormat::Qoi);
+        }
+        // the JP2 signature box, or a bare J2K codestream
+        if data.starts_with(&[
+            0, 0, 0, 0x0C, b'j', b'P', b' ', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ]) || data.starts_with(&[0xFF, 0x4F, 0xFF, 0x51])
+        {
+            return Ok(ImageFormat::Jpeg2000);
+        }
+        // the JPEG XL container's signature box, or a bare codestream
+        if data.starts_with(&[
+            0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ]) || data.starts_with(&[0xFF, 0x0A])
+        {
+            return Ok(ImageFormat::Jxl);
+        }
+        // XML with an <svg> root element, maybe after a BOM, declaration, comment or doctype
+        if let Some(root) = xml_root_element(&data[..data.len().min(1024)])
+            && (root == b"svg" || root.ends_with(b":svg"))
+        {
+            return Ok(ImageFormat::Svg);
+        }
+
+        let brands = imagedata::ftyp_brands(data);
+        let has_brand = |wanted: &[&[u8; 4]]| brands.iter().any(|brand| wanted.contains(&brand));
+        if has_brand(&[b"avif", b"avis"]) {
+            Ok(ImageFormat::Avif)
+        } else if has_brand(&[b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis"]) {
+            Ok(ImageFormat::Heic)
+        } else if has_brand(&[b"mif1", b"mif2", b"msf1"]) {
+            Ok(ImageFormat::Heif)
+        } else {
+            Err(Error::UnsupportedFormat(
+                "Couldn't detect the image format from its magic bytes".to_string(),
+            ))
+        }
+    }
+
     pub fn is_native_image_format(&self) -> bool {
         !matches!(
             self,
@@ -83,10 +520,108 @@ impl ImageFormat {
         )
     }
 
+    /// Formats that can be read but never written
+    pub fn is_input_only(&self) -> bool {
+        matches!(
+            self,
+            ImageFormat::Ico
+                | ImageFormat::Tga
+                | ImageFormat::Pnm
+                | ImageFormat::Exr
+                | ImageFormat::Hdr
+                | ImageFormat::Dds
+                | ImageFormat::Svg
+                | ImageFormat::Raw
+        )
+    }
+
+    /// Formats with linear float samples, which are tone mapped to 8 bits when loaded
+    pub fn is_hdr(&self) -> bool {
+        matches!(self, ImageFormat::Exr | ImageFormat::Hdr)
+    }
+
+    /// The MIME type for data in this format
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Heic => "image/heic",
+            ImageFormat::Heif => "image/heif",
+            ImageFormat::Tiff => "image/tiff",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Farbfeld => "image/x-farbfeld",
+            ImageFormat::Qoi => "image/qoi",
+            ImageFormat::Jpeg2000 => "image/jp2",
+            ImageFormat::Jxl => "image/jxl",
+            ImageFormat::Ico => "image/vnd.microsoft.icon",
+            ImageFormat::Tga => "image/x-tga",
+            ImageFormat::Pnm => "image/x-portable-anymap",
+            ImageFormat::Exr => "image/x-exr",
+            ImageFormat::Hdr => "image/vnd.radiance",
+            ImageFormat::Dds => "image/vnd-ms.dds",
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Raw => "image/x-adobe-dng",
+        }
+    }
+
+    /// The ftyp brand that a HEIF-family container for this format must carry
+    pub fn heif_brand(&self) -> Option<&'static [u8; 4]> {
+        match self {
+            ImageFormat::Avif => Some(b"avif"),
+            ImageFormat::Heic | ImageFormat::Heif => Some(b"heic"),
+            _ => None,
+        }
+    }
+
     pub fn all() -> Vec<ImageFormat> {
         use strum::IntoEnumIterator;
         Self::iter().collect()
     }
+
+    /// Rough ranking of how well the format typically compresses, higher is better
+    pub fn compression_efficiency(&self) -> u8 {
+        match self {
+            ImageFormat::Hdr => 0,
+            ImageFormat::Exr => 1,
+            ImageFormat::Pnm => 2,
+            ImageFormat::Tga => 3,
+            ImageFormat::Ico => 4,
+            ImageFormat::Dds => 5,
+            ImageFormat::Svg => 6,
+            ImageFormat::Raw => 7,
+            ImageFormat::Farbfeld => 8,
+            ImageFormat::Bmp => 9,
+            ImageFormat::Qoi => 10,
+            ImageFormat::Gif => 11,
+            ImageFormat::Tiff => 12,
+            ImageFormat::Png => 13,
+            ImageFormat::Jpg => 14,
+            ImageFormat::Jpeg2000 => 15,
+            ImageFormat::Webp => 16,
+            ImageFormat::Avif => 17,
+            ImageFormat::Jxl => 18,
+            ImageFormat::Heic => 19,
+            ImageFormat::Heif => 20,
+        }
+    }
+}
+
+/// Formats are ordered by [ImageFormat::compression_efficiency], so the "best" format is the
+/// greatest
+impl Ord for ImageFormat {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.compression_efficiency()
+            .cmp(&other.compression_efficiency())
+    }
+}
+
+impl PartialOrd for ImageFormat {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -170,7 +705,7 @@ impl FromStr for ImageFormat {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.contains('.') {
-            return ImageFormat::try_from_filename(s);
+            return ImageFormat::from_path(Path::new(s));
         }
         match s.to_lowercase().as_str() {
             "jpg" | "jpeg" => Ok(ImageFormat::Jpg),
@@ -179,19 +714,40 @@ impl FromStr for ImageFormat {
             "avif" => Ok(ImageFormat::Avif),
             "heic" => Ok(ImageFormat::Heic),
             "heif" => Ok(ImageFormat::Heif),
+            "tif" | "tiff" => Ok(ImageFormat::Tiff),
+            "bmp" => Ok(ImageFormat::Bmp),
+            "gif" => Ok(ImageFormat::Gif),
+            "ff" => Ok(ImageFormat::Farbfeld),
+            "qoi" => Ok(ImageFormat::Qoi),
+            "jp2" | "j2k" => Ok(ImageFormat::Jpeg2000),
+            "jxl" => Ok(ImageFormat::Jxl),
+            "ico" => Ok(ImageFormat::Ico),
+            "tga" => Ok(ImageFormat::Tga),
+            "pnm" | "ppm" | "pgm" | "pbm" | "pam" => Ok(ImageFormat::Pnm),
+            "exr" => Ok(ImageFormat::Exr),
+            "hdr" => Ok(ImageFormat::Hdr),
+            "dds" => Ok(ImageFormat::Dds),
+            "svg" | "svgz" => Ok(ImageFormat::Svg),
+            "dng" | "cr2" | "cr3" | "nef" | "nrw" | "arw" | "orf" | "rw2" | "raf" | "pef"
+            | "srw" => Ok(ImageFormat::Raw),
             _ => Err(Error::UnsupportedFormat(s.to_string())),
         }
     }
 }
 
+impl TryFrom<&Path> for ImageFormat {
+    type Error = Error;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        ImageFormat::from_path(path)
+    }
+}
+
 impl TryFrom<&PathBuf> for ImageFormat {
     type Error = Error;
 
     fn try_from(path: &PathBuf) -> Result<Self, Self::Error> {
-        let filename = path
-            .to_str()
-            .ok_or_else(|| Error::UnsupportedFormat("Invalid path".to_string()))?;
-        ImageFormat::try_from_filename(filename)
+        ImageFormat::try_from(path.as_path())
     }
 }
 
@@ -202,24 +758,162 @@ impl TryInto<image::ImageFormat> for ImageFormat {
             ImageFormat::Jpg => Ok(image::ImageFormat::Jpeg),
             ImageFormat::Png => Ok(image::ImageFormat::Png),
             ImageFormat::Webp => Ok(image::ImageFormat::WebP),
+            ImageFormat::Tiff => Ok(image::ImageFormat::Tiff),
+            ImageFormat::Bmp => Ok(image::ImageFormat::Bmp),
+            ImageFormat::Gif => Ok(image::ImageFormat::Gif),
+            ImageFormat::Farbfeld => Ok(image::ImageFormat::Farbfeld),
+            ImageFormat::Qoi => Ok(image::ImageFormat::Qoi),
+            ImageFormat::Ico => Ok(image::ImageFormat::Ico),
+            ImageFormat::Tga => Ok(image::ImageFormat::Tga),
+            ImageFormat::Pnm => Ok(image::ImageFormat::Pnm),
+            ImageFormat::Exr => Ok(image::ImageFormat::OpenExr),
+            ImageFormat::Hdr => Ok(image::ImageFormat::Hdr),
+            ImageFormat::Dds => Ok(image::ImageFormat::Dds),
             ImageFormat::Avif | ImageFormat::Heic | ImageFormat::Heif => {
                 Err(Error::UnsupportedFormat(
                     "AVIF/HEIC/HEIF format not supported by image crate".to_string(),
                 ))
             }
+            ImageFormat::Jpeg2000 => Err(Error::UnsupportedFormat(
+                "JPEG 2000 format not supported by image crate".to_string(),
+            )),
+            ImageFormat::Jxl => Err(Error::UnsupportedFormat(
+                "JPEG XL format not supported by image crate".to_string(),
+            )),
+            ImageFormat::Svg => Err(Error::UnsupportedFormat(
+                "SVG format not supported by image crate".to_string(),
+            )),
+            ImageFormat::Raw => Err(Error::UnsupportedFormat(
+                "camera RAW formats not supported by image crate".to_string(),
+            )),
         }
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    /// A file doesn't exist. The path is empty when the error came from an [io::Error] that
+    /// didn't say which file it was about, see [Error::from_io].
+    FileNotFound(PathBuf),
     InvalidOptions(String),
     UnsupportedFormat(String),
     InvalidGeometry(String),
-    ImageLoadingError(String, image::ImageError),
+    /// The image crate couldn't decode an image
+    Image(image::ImageError),
     ImageComparisonError(String),
+    /// Any other I/O error
     FileSystem(String),
+    /// Reading or writing a file wasn't allowed, the path is empty if it's unknown
+    PermissionDenied(PathBuf),
+    /// The disk or quota is full
+    DiskFull,
     ImageEncodingError(String),
+    /// Auto-format couldn't encode the image in any format, with the error for each format tried
+    AllFormatsFailedEncoding(Vec<(ImageFormat, String)>),
+    /// What was being done when the wrapped error happened, added by [`ErrorContext::context`]
+    Context(String, Box<Error>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::FileNotFound(path) if path.as_os_str().is_empty() => write!(f, "file not found"),
+            Error::FileNotFound(path) => write!(f, "file not found: {}", path.display()),
+            Error::InvalidOptions(msg) => write!(f, "invalid options: {msg}"),
+            Error::UnsupportedFormat(msg) => write!(f, "unsupported format: {msg}"),
+            Error::InvalidGeometry(msg) => write!(f, "invalid geometry: {msg}"),
+            Error::Image(err) => write!(f, "{err}"),
+            Error::ImageComparisonError(msg) => write!(f, "image comparison failed: {msg}"),
+            Error::FileSystem(msg) => write!(f, "{msg}"),
+            Error::PermissionDenied(path) if path.as_os_str().is_empty() => {
+                write!(f, "permission denied")
+            }
+            Error::PermissionDenied(path) => write!(f, "permission denied: {}", path.display()),
+            Error::DiskFull => write!(f, "no space left on the disk"),
+            Error::ImageEncodingError(msg) => write!(f, "encoding failed: {msg}"),
+            Error::AllFormatsFailedEncoding(failures) => {
+                write!(f, "couldn't encode the image in any format")?;
+                for (format, err) in failures {
+                    write!(f, ", {}: {err}", format.display_upper())?;
+                }
+                Ok(())
+            }
+            Error::Context(context, err) => write!(f, "{context}: {err}"),
+        }
+    }
+}
+
+impl Error {
+    /// Convert an I/O error that happened while working on `path`, so the not found and
+    /// permission denied variants can say which file it was
+    pub fn from_io(err: &io::Error, path: &Path) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::FileNotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                Error::PermissionDenied(path.to_path_buf())
+            }
+            io::ErrorKind::StorageFull
+            | io::ErrorKind::QuotaExceeded
+            | io::ErrorKind::WriteZero => Error::DiskFull,
+            _ if path.as_os_str().is_empty() => Error::FileSystem(err.to_string()),
+            _ => Error::FileSystem(format!("{}: {err}", path.display())),
+        }
+    }
+
+    /// The process exit code for this error, so scripts can tell failures apart. Errors without
+    /// a specific code exit with 1. Codes 9 and 10 are left for `process_image()`'s "output was
+    /// larger" and "quality gate failed" results.
+    pub fn to_exit_code(&self) -> i32 {
+        match self {
+            Error::UnsupportedFormat(_) => 4,
+            Error::InvalidOptions(_) => 5,
+            Error::ImageEncodingError(_) | Error::AllFormatsFailedEncoding(_) => 6,
+            Error::FileSystem(_) | Error::DiskFull => 7,
+            Error::InvalidGeometry(_) => 8,
+            Error::FileNotFound(_) => 2,
+            Error::PermissionDenied(_) => 3,
+            Error::Context(_, err) => err.to_exit_code(),
+            Error::Image(_) | Error::ImageComparisonError(_) => 1,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Image(err) => Some(err),
+            Error::Context(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Add a description of what was being done to an error, eg.
+/// `image::open(path).context(format!("loading {}", path.display()))?`
+pub trait ErrorContext<T, E> {
+    fn context(self, msg: impl Into<String>) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ErrorContext<T, E> for Result<T, E> {
+    fn context(self, msg: impl Into<String>) -> Result<T, Error> {
+        self.map_err(|err| Error::Context(msg.into(), Box::new(err.into())))
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::IoError(io_error) => Error::from(io_error),
+            err => Error::Image(err),
+        }
+    }
+}
+
+/// Converts without a path, use [Error::from_io] when the path is known
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::from_io(&err, Path::new(""))
+    }
 }
 
 impl From<HeifError> for Error {
@@ -243,6 +937,119 @@ pub fn format_bytes(bytes: u64) -> String {
     result
 }
 
+/// Format auto-format attempts as a table of format, size and which was selected
+pub fn format_attempts_table(attempts: &[FormatAttempt]) -> String {
+    let mut table = format!("{:<8} {:<40} {}\n", "FORMAT", "SIZE", "SELECTED");
+    for attempt in attempts {
+        let size = match (&attempt.encoded_bytes, &attempt.error) {
+            (Some(bytes), _) => format!("{} bytes", format_bytes(*bytes as u64)),
+            (None, Some(error)) => error.clone(),
+            (None, None) => "-".to_string(),
+        };
+        table.push_str(&format!(
+            "{:<8} {:<40} {}\n",
+            attempt.format.display_upper(),
+            size,
+            if attempt.selected { "*" } else { "" }
+        ));
+    }
+    table
+}
+
+/// What happened to one input file, from [process_image_outcome]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outcome {
+    /// The output was written, or printed for `--data-url`
+    Processed,
+    /// Nothing was written because the output wouldn't have been smaller. Auto-format's larger
+    /// output still exits with 9, other skips with 0.
+    Skipped(i32),
+    /// Something went wrong, with the exit code for it
+    Failed(i32),
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Processed => 0,
+            Outcome::Skipped(exit_code) | Outcome::Failed(exit_code) => *exit_code,
+        }
+    }
+}
+
+/// How a run over several inputs went, logged at the end of a `--recursive` run
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl BatchSummary {
+    /// Count a file from what [process_image_outcome] said happened to it
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Processed => self.processed += 1,
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Failed(_) => self.failed += 1,
+        }
+    }
+}
+
+impl Display for BatchSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} processed, {} skipped, {} failed",
+            self.processed, self.skipped, self.failed
+        )
+    }
+}
+
+/// Walk `dir` for images, down to `max_depth` levels (1 is only the files directly inside it),
+/// or all the way when it's `None`. Returns the images and the files that were skipped because
+/// they aren't images, each in sorted order. Subdirectories that can't be read are skipped with
+/// a warning, and symlinked directories aren't followed so a loop can't trap the walk.
+pub fn collect_image_files(
+    dir: &Path,
+    max_depth: Option<usize>,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), Error> {
+    let read_sorted = |dir: &Path| -> Result<Vec<std::fs::DirEntry>, Error> {
+        let mut entries = std::fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| Error::from_io(&e, dir))?;
+        entries.sort_by_key(std::fs::DirEntry::path);
+        Ok(entries)
+    };
+
+    let (mut images, mut skipped) = (Vec::new(), Vec::new());
+    let mut pending = vec![(read_sorted(dir)?, 1)];
+    while let Some((entries, depth)) = pending.pop() {
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                if max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                    continue;
+                }
+                match read_sorted(&path) {
+                    Ok(entries) => pending.push((entries, depth + 1)),
+                    Err(e) => warn!("{}: Skipping directory: {:?}", path.display(), e),
+                }
+            } else if !path.is_file() {
+                debug!("{}: Skipping, not a file", path.display());
+            } else if ImageFormat::detect_from_path(&path).is_ok() {
+                images.push(path);
+            } else {
+                debug!("{}: Skipping, not an image", path.display());
+                skipped.push(path);
+            }
+        }
+    }
+    images.sort();
+    skipped.sort();
+    Ok((images, skipped))
+}
+
 pub fn should_prompt_delete_source(
     output_existed_before_write: bool,
     format_changed: bool,
@@ -251,47 +1058,48 @@ pub fn should_prompt_delete_source(
     !output_existed_before_write && (format_changed || size_reduced)
 }
 
-/// Prompt user to delete source file, showing comparison information
-pub fn prompt_delete_source(
+/// Print the original and new file, their sizes and the savings (or increase) to stdout.
+/// `reduction` is the percentage from [Image::size_reduction].
+pub fn print_conversion_stats(
     input_path: &Path,
     original_size: u64,
     original_format: ImageFormat,
     output_path: &Path,
     output_size: usize,
     output_format: ImageFormat,
-) -> Result<bool, io::Error> {
+    reduction: Option<f64>,
+) {
     println!();
     println!(
         "Original: {} ({}, {} bytes)",
         input_path.display(),
-        original_format.extension().to_uppercase(),
+        original_format.display_upper(),
         format_bytes(original_size)
     );
     println!(
         "New:      {} ({}, {} bytes)",
         output_path.display(),
-        output_format.extension().to_uppercase(),
+        output_format.display_upper(),
         format_bytes(output_size as u64)
     );
 
-    if output_size < original_size as usize {
-        let savings = original_size - output_size as u64;
-        let percent = (savings as f64 / original_size as f64) * 100.0;
-        println!(
+    match reduction {
+        Some(percent) if percent > 0.0 => println!(
             "Savings:  {} bytes ({:.0}% smaller)",
-            format_bytes(savings),
+            format_bytes(original_size - output_size as u64),
             percent
-        );
-    } else if output_size > original_size as usize {
-        let increase = output_size as u64 - original_size;
-        let percent = (increase as f64 / original_size as f64) * 100.0;
-        println!(
+        ),
+        Some(percent) if percent < 0.0 => println!(
             "Increase: {} bytes ({:.0}% larger)",
-            format_bytes(increase),
-            percent
-        );
+            format_bytes(output_size as u64 - original_size),
+            -percent
+        ),
+        _ => {}
     }
+}
 
+/// Prompt user to delete source file, after [print_conversion_stats] has shown the comparison
+pub fn prompt_delete_source() -> Result<bool, io::Error> {
     println!();
     print!("Delete original file? [y/N]: ");
     io::stdout().flush()?;
@@ -303,26 +1111,174 @@ pub fn prompt_delete_source(
     Ok(matches!(response.as_str(), "y" | "yes"))
 }
 
-pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path: &Path) -> u8 {
+/// Re-read and check a written output file, removing it if it fails verification (and
+/// `remove_on_failure` is set). Returns the exit code, 1 if verification failed.
+pub fn verify_written_output(
+    image: &Image,
+    output_path: &Path,
+    min_ssim: f64,
+    remove_on_failure: bool,
+) -> i32 {
+    match Image::verify_output(output_path, image, min_ssim) {
+        Ok(()) => {
+            debug!("{}: Output verified", output_path.display());
+            0
+        }
+        Err(e) => {
+            error!(
+                "{}: Output failed verification: {:?}",
+                output_path.display(),
+                e
+            );
+            if remove_on_failure {
+                match std::fs::remove_file(output_path) {
+                    Ok(_) => info!("Removed failed output file: {}", output_path.display()),
+                    Err(e) => error!(
+                        "Failed to remove failed output file {}: {}",
+                        output_path.display(),
+                        e
+                    ),
+                }
+            } else {
+                error!(
+                    "{}: Not removing failed output, as it replaced the input file",
+                    output_path.display()
+                );
+            }
+            1
+        }
+    }
+}
+
+pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path: &Path) -> i32 {
+    process_image_outcome(cli, target_geometry, input_path).exit_code()
+}
+
+/// Like [process_image], but says whether the file was processed, skipped or failed
+pub fn process_image_outcome(
+    cli: &Cli,
+    target_geometry: Option<&Geometry>,
+    input_path: &Path,
+) -> Outcome {
     if !input_path.exists() {
-        error!("File not found: {}", input_path.display());
-        return 1;
+        let e = Error::FileNotFound(input_path.to_path_buf());
+        error!("{e}");
+        return Outcome::Failed(e.to_exit_code());
     }
     if !input_path.is_file() {
         error!("Not a file: {}", input_path.display());
-        return 1;
+        return Outcome::Failed(1);
     }
 
     debug!("Processing image: {}", input_path.display());
     let input_filename = input_path.to_path_buf();
-    let mut image = match Image::try_from(&input_filename) {
+    let mut builder = Image::builder(&input_filename)
+        .with_tone_map(cli.tonemap)
+        .with_filter(cli.filter)
+        .with_overwrite_if_smaller(cli.overwrite_if_smaller);
+    // HDR input is resized before it's tone mapped, and SVG input is drawn at the target size,
+    // so the builder needs the geometry up front
+    if let Some(target_geometry) = target_geometry {
+        builder = builder.with_target_geometry(target_geometry.clone());
+    }
+    let mut image = match builder.build() {
         Ok(img) => img,
         Err(e) => {
             error!("Error loading image {}: {:?}", input_path.display(), e);
-            return 1;
+            return Outcome::Failed(e.to_exit_code());
         }
     };
     image = image.with_output_suffix(cli.output_suffix.clone());
+    if let Some(quality) = cli.quality {
+        image = image.with_quality(quality);
+    }
+    if let Some(avif_quality) = cli.avif_quality {
+        image = image.with_avif_quality(avif_quality);
+    }
+    image = image.with_lossless(cli.lossless);
+    if let Some(bit_depth) = cli.bit_depth {
+        image = image.with_bit_depth(bit_depth);
+    }
+    if let Some(effort) = cli.effort {
+        image = image.with_effort(effort);
+    }
+    image = image.with_encoder_params(cli.encoder_params.clone());
+    if let Some(formats) = &cli.formats {
+        image = image.with_candidate_formats(formats.clone());
+    }
+    if let Some(level) = cli.near_lossless {
+        image = image.with_near_lossless(level);
+    }
+    if let Some(png_compression) = cli.png_compression {
+        image = image.with_png_compression(png_compression);
+    }
+    if let Some(level) = cli.png_compression_level {
+        image = image.with_png_compression_level(level);
+    }
+    if let Some(png_filter) = cli.png_filter {
+        image = image.with_png_filter(png_filter);
+    }
+    if let Some(background_color) = cli.background_color {
+        image = image.with_background_color(background_color);
+    }
+    #[cfg(feature = "oxipng")]
+    if let Some(level) = cli.optimize_png {
+        image = image.with_optimize_png(level);
+    }
+    #[cfg(feature = "imagequant")]
+    if let Some(colors) = cli.quantize {
+        image = image.with_quantize(colors, cli.force_quantize);
+    }
+    if let Some(index) = cli.frame
+        && let Err(e) = image.select_ico_entry(index)
+    {
+        error!("{}: {:?}", input_path.display(), e);
+        return Outcome::Failed(e.to_exit_code());
+    }
+    // animations are kept as animated WebP, other formats are refused unless --first-frame
+    if cli.first_frame {
+        image.flatten_to_first_frame();
+    }
+    let animated = image.frame_count > 1;
+    if animated {
+        debug!(
+            "{}: Keeping the {} frame animation",
+            input_path.display(),
+            image.frame_count
+        );
+        if cli.grayscale
+            || cli.to_rgba
+            || cli.brightness.is_some()
+            || cli.contrast.is_some()
+            || cli.rotate.is_some()
+            || cli.border.is_some()
+            || cli.watermark.is_some()
+        {
+            warn!(
+                "{}: --grayscale, --to-rgba, --brightness, --contrast, --rotate, --border and --watermark only apply to still images, ignoring them for this animation",
+                input_path.display()
+            );
+        }
+    }
+
+    if cli.grayscale && !animated {
+        debug!("{}: Converting to grayscale", input_path.display());
+        image.to_grayscale();
+    } else if cli.to_rgba && !animated {
+        debug!("{}: Converting to RGBA", input_path.display());
+        image.to_rgba();
+    }
+    if (cli.brightness.is_some() || cli.contrast.is_some()) && !animated {
+        let brightness = cli.brightness.unwrap_or(0);
+        let contrast = cli.contrast.unwrap_or(0.0);
+        debug!(
+            "{}: Adjusting brightness by {} and contrast by {}",
+            input_path.display(),
+            brightness,
+            contrast
+        );
+        image.adjust(brightness, contrast);
+    }
     if cli.info {
         info!(
             "{}: Dimensions: {}x{} Size: {} bytes",
@@ -331,36 +1287,193 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
             image.image.height(),
             format_bytes(image.original_file_size)
         );
+        if cli.verbose {
+            use strum::IntoEnumIterator;
+            // one pass for all the RGBA channels, luminance needs its own
+            let histogram = image.histogram();
+            for channel in HistogramChannel::iter() {
+                let counts = histogram
+                    .channel(channel)
+                    .copied()
+                    .unwrap_or_else(|| image.histogram_channel(channel));
+                if let Some(stats) = HistogramStats::from_histogram(&counts) {
+                    info!(
+                        "{}: {} min {} max {} mean {:.2} std-dev {:.2}",
+                        input_path.display(),
+                        channel,
+                        stats.min,
+                        stats.max,
+                        stats.mean,
+                        stats.std_dev
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(histogram_format) = cli.histogram {
+        let histogram = image.histogram();
+        match histogram_format {
+            HistogramFormat::Json => println!("{}", histogram.to_json()),
+            HistogramFormat::Csv => print!("{}", histogram.to_csv()),
+        }
+    }
+
+    if let Some(palette_size) = cli.palette {
+        match image.dominant_colors(palette_size) {
+            Ok(colors) => {
+                for [r, g, b] in colors {
+                    println!("#{r:02x}{g:02x}{b:02x}");
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Error finding the palette of {}: {:?}",
+                    input_path.display(),
+                    e
+                );
+                return Outcome::Failed(e.to_exit_code());
+            }
+        }
+    }
+
+    if cli.classify {
+        let classification = if image.is_photo() {
+            "photo"
+        } else if image.is_graphic() {
+            "graphic"
+        } else {
+            "unclassified"
+        };
+        println!("{}", classification);
     }
 
     if let Some(target_geometry) = target_geometry {
         image = image.with_target_geometry(target_geometry.clone());
 
         match image.resize() {
-            Ok(new_image) => {
+            Ok(()) => {
                 debug!(
-                    "{}: Resized image to {}x{}",
+                    "{}: Resized image to {}",
                     input_path.display(),
-                    new_image.width(),
-                    new_image.height()
+                    image.current_geometry()
                 );
             }
             Err(e) => {
                 error!("Error resizing image {}: {:?}", input_path.display(), e);
-                return 1;
+                return Outcome::Failed(e.to_exit_code());
             }
         }
     }
 
+    if let Some(angle) = cli.rotate
+        && !animated
+    {
+        if let Err(e) = image.rotate(angle) {
+            error!("Error rotating image {}: {:?}", input_path.display(), e);
+            return Outcome::Failed(e.to_exit_code());
+        }
+        debug!(
+            "{}: Rotated {:?}, now {}",
+            input_path.display(),
+            angle,
+            image.current_geometry()
+        );
+    }
+
+    if let Some(border) = cli.border
+        && !animated
+    {
+        if let Err(e) = image.add_border(border, border, border, border, cli.border_color) {
+            error!("Error adding border to {}: {:?}", input_path.display(), e);
+            return Outcome::Failed(e.to_exit_code());
+        }
+        debug!(
+            "{}: Added a {} pixel border, now {}",
+            input_path.display(),
+            border,
+            image.current_geometry()
+        );
+    }
+
+    if let Some(watermark_path) = cli.watermark.as_ref()
+        && !animated
+    {
+        let watermark = match Image::load_image(watermark_path) {
+            Ok((watermark, _)) => watermark,
+            Err(e) => {
+                error!(
+                    "Error loading watermark {}: {:?}",
+                    watermark_path.display(),
+                    e
+                );
+                return Outcome::Failed(e.to_exit_code());
+            }
+        };
+        let Geometry {
+            width: Some(width),
+            height: Some(height),
+        } = image.final_geometry()
+        else {
+            error!(
+                "{}: Couldn't work out the image size for the watermark",
+                input_path.display()
+            );
+            return Outcome::Failed(1);
+        };
+        let (x, y) = cli
+            .watermark_position
+            .offset((width, height), (watermark.width(), watermark.height()));
+        if let Err(e) = image.composite(&watermark, x, y, cli.watermark_opacity) {
+            error!(
+                "Error adding watermark to {}: {:?}",
+                input_path.display(),
+                e
+            );
+            return Outcome::Failed(e.to_exit_code());
+        }
+        debug!(
+            "{}: Added watermark {} at {},{}",
+            input_path.display(),
+            watermark_path.display(),
+            x,
+            y
+        );
+    }
+
+    let auto_format = |image: &Image| {
+        let result = if cli.explain {
+            image
+                .auto_format_explained()
+                .map(|(format, data, attempts)| {
+                    print!("{}", format_attempts_table(&attempts));
+                    (format, data)
+                })
+        } else if cli.phased_auto_format {
+            image.auto_format_phased_with_threshold(cli.phased_threshold)
+        } else if let Some(timeout) = cli.auto_format_timeout {
+            image.auto_format_with_timeout(timeout)
+        } else {
+            image.auto_format()
+        };
+        match result {
+            Err(err @ Error::AllFormatsFailedEncoding(_)) if cli.fallback_to_original_format => {
+                image.fallback_to_original_format(err)
+            }
+            result => result,
+        }
+    };
+
     let bytes_to_write = match cli.output_type {
-        None => match image.auto_format() {
+        None => match auto_format(&image) {
             Ok((format, data)) => {
                 debug!(
                     "{}: Auto-optimized image to format {}",
                     input_path.display(),
                     format,
                 );
-                if data.len() > image.original_file_size as usize {
+                // --overwrite-if-smaller skips the write with a warning below instead
+                if data.len() > image.original_file_size as usize && !image.overwrite_if_smaller {
                     let original_size = image.original_file_size as usize;
                     let increase = data.len() - original_size;
                     let pct_change = (data.len() as f64 / max(original_size, 1) as f64) * 100.0;
@@ -373,7 +1486,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                         format_bytes(increase as u64),
                         pct_change
                     );
-                    return 2;
+                    return Outcome::Skipped(9);
                 }
                 image.output_format = Some(format);
                 data
@@ -384,7 +1497,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                     input_path.display(),
                     e
                 );
-                return 1;
+                return Outcome::Failed(e.to_exit_code());
             }
         },
         Some(format) => match image.output_as_format(format) {
@@ -400,12 +1513,12 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
             }
             Err(e) => {
                 error!(
-                    "Error encoding image {} as {:?}: {:?}",
+                    "Error encoding image {} as {}: {:?}",
                     input_path.display(),
                     format,
                     e
                 );
-                return 1;
+                return Outcome::Failed(e.to_exit_code());
             }
         },
     };
@@ -443,7 +1556,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                             "{}: SSIM score was not computed, cannot enforce --min-ssim",
                             input_path.display()
                         );
-                        return 3;
+                        return Outcome::Failed(10);
                     }
 
                     if let Some(actual_ssim) = score.ssim
@@ -455,7 +1568,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                             actual_ssim,
                             min_ssim
                         );
-                        return 3;
+                        return Outcome::Failed(10);
                     }
                 }
 
@@ -465,7 +1578,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                             "{}: PSNR score was not computed, cannot enforce --min-psnr",
                             input_path.display()
                         );
-                        return 3;
+                        return Outcome::Failed(10);
                     }
 
                     if let Some(actual_psnr) = score.psnr
@@ -477,7 +1590,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                             actual_psnr,
                             min_psnr
                         );
-                        return 3;
+                        return Outcome::Failed(10);
                     }
                 }
             }
@@ -488,7 +1601,7 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
                         input_path.display(),
                         e
                     );
-                    return 3;
+                    return Outcome::Failed(10);
                 }
                 warn!(
                     "{}: Perceptual comparison failed, continuing: {:?}",
@@ -504,21 +1617,55 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
             "{}: No image data to write. This is probably a bug!",
             input_path.display()
         );
-        return 1;
+        return Outcome::Failed(1);
+    }
+
+    if cli.data_url {
+        let Some(format) = image.output_format else {
+            error!(
+                "{}: No output format was selected. This is probably a bug!",
+                input_path.display()
+            );
+            return Outcome::Failed(1);
+        };
+        println!("{}", data_url(format, &bytes_to_write));
+        return Outcome::Processed;
+    }
+
+    if !image.should_write(bytes_to_write.len()) {
+        warn!(
+            "{}: Not writing {}, the {} output isn't smaller than the {} original",
+            input_path.display(),
+            image.output_filename().display(),
+            format_bytes(bytes_to_write.len() as u64),
+            format_bytes(image.original_file_size)
+        );
+        return Outcome::Skipped(0);
     }
 
     let output_existed_before_write = image.will_overwrite();
+    let overwrites_input = image.will_overwrite_input();
 
-    if output_existed_before_write && !cli.force {
+    if overwrites_input && !cli.in_place {
+        error!(
+            "{}: Output file {} is the input file. Use --in-place to replace it.",
+            input_path.display(),
+            image.output_filename().display()
+        );
+        return Outcome::Failed(1);
+    }
+
+    if output_existed_before_write && !overwrites_input && !cli.force {
         error!(
             "{}: Output file {} already exists. Use --force to overwrite.",
             input_path.display(),
             image.output_filename().display()
         );
-        return 1;
+        return Outcome::Failed(1);
     }
 
-    match std::fs::write(image.output_filename(), &bytes_to_write) {
+    // via a temporary file, so a failed write doesn't leave a partial output behind
+    match write_atomically(&image.output_filename(), &bytes_to_write) {
         Ok(_) => {
             let original_size = max(image.original_file_size, 1) as f64;
             let output_size = max(bytes_to_write.len(), 1) as f64;
@@ -534,89 +1681,118 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
         }
         Err(e) => {
             error!(
-                "{}: Error writing optimized image to {}: {}",
+                "{}: Error writing optimized image to {}: {:?}",
                 input_path.display(),
                 image.output_filename().display(),
                 e
             );
-            return 1;
+            // the specific codes are for problems with the input, eg. 3 is an unreadable input
+            return Outcome::Failed(1);
         }
     }
 
+    if cli.verify {
+        let verify_result = verify_written_output(
+            &image,
+            &image.output_filename(),
+            cli.verify_min_ssim,
+            !overwrites_input,
+        );
+        if verify_result != 0 {
+            return Outcome::Failed(verify_result);
+        }
+    }
+
+    let original_format = image.input_format();
+    let Some(output_format) = image.output_format else {
+        warn!(
+            "{}: Output format not set after conversion",
+            input_path.display()
+        );
+        return Outcome::Processed;
+    };
+    let print_stats = |original_format: ImageFormat| {
+        print_conversion_stats(
+            &image.input_filename,
+            image.original_file_size,
+            original_format,
+            &image.output_filename(),
+            bytes_to_write.len(),
+            output_format,
+            image.size_reduction(bytes_to_write.len()),
+        )
+    };
+    if !cli.quiet
+        && let Ok(original_format) = original_format
+    {
+        print_stats(original_format);
+    }
+
     // Handle --delete flag: prompt user to delete source file if beneficial
     if cli.delete {
         // Don't delete if output overwrote input (file already replaced)
         if !output_existed_before_write {
             // Get original format to compare
-            match ImageFormat::try_from(&image.input_filename) {
+            match original_format {
                 Ok(original_format) => {
-                    // Output format should always be set at this point
-                    if let Some(output_format) = &image.output_format {
-                        let format_changed = &original_format != output_format;
-                        let size_reduced = bytes_to_write.len() < image.original_file_size as usize;
+                    let format_changed = original_format != output_format;
+                    let size_reduced = image
+                        .size_reduction(bytes_to_write.len())
+                        .is_some_and(|reduction| reduction > 0.0);
 
-                        debug!(
-                            "{}: Delete check: format_changed={}, size_reduced={}",
-                            input_path.display(),
-                            format_changed,
-                            size_reduced
-                        );
+                    debug!(
+                        "{}: Delete check: format_changed={}, size_reduced={}",
+                        input_path.display(),
+                        format_changed,
+                        size_reduced
+                    );
 
-                        // Only prompt if there's a benefit (smaller or different format)
-                        if should_prompt_delete_source(
-                            output_existed_before_write,
-                            format_changed,
-                            size_reduced,
-                        ) {
-                            match prompt_delete_source(
-                                &image.input_filename,
-                                image.original_file_size,
-                                original_format,
-                                &image.output_filename(),
-                                bytes_to_write.len(),
-                                *output_format,
-                            ) {
-                                Ok(should_delete) => {
-                                    if should_delete {
-                                        match std::fs::remove_file(&image.input_filename) {
-                                            Ok(_) => {
-                                                info!(
-                                                    "Deleted original file: {}",
-                                                    image.input_filename.display()
-                                                );
-                                            }
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to delete original file {}: {}",
-                                                    image.input_filename.display(),
-                                                    e
-                                                );
-                                            }
+                    // Only prompt if there's a benefit (smaller or different format)
+                    if should_prompt_delete_source(
+                        output_existed_before_write,
+                        format_changed,
+                        size_reduced,
+                    ) {
+                        // the prompt needs the comparison for context, even with --quiet
+                        if cli.quiet {
+                            print_stats(original_format);
+                        }
+                        match prompt_delete_source() {
+                            Ok(should_delete) => {
+                                if should_delete {
+                                    match std::fs::remove_file(&image.input_filename) {
+                                        Ok(_) => {
+                                            info!(
+                                                "Deleted original file: {}",
+                                                image.input_filename.display()
+                                            );
+                                        }
+                                        Err(e) => {
+                                            error!(
+                                                "Failed to delete original file {}: {}",
+                                                image.input_filename.display(),
+                                                e
+                                            );
                                         }
-                                    } else {
-                                        info!(
-                                            "Keeping original file: {}",
-                                            image.input_filename.display()
-                                        );
                                     }
-                                }
-                                Err(e) => {
-                                    warn!(
-                                        "{}: Error prompting for deletion: {}",
-                                        input_path.display(),
-                                        e
+                                } else {
+                                    info!(
+                                        "Keeping original file: {}",
+                                        image.input_filename.display()
                                     );
                                 }
                             }
-                        } else {
-                            debug!(
-                                "{}: No benefit to deleting original file (same format and not smaller)",
-                                input_path.display()
-                            );
+                            Err(e) => {
+                                warn!(
+                                    "{}: Error prompting for deletion: {}",
+                                    input_path.display(),
+                                    e
+                                );
+                            }
                         }
                     } else {
-                        warn!(
-                            "{}: Output format not set after conversion",
+                        debug!(
+                            "{}: No benefit to deleting original file (same format and not smaller)",
                             input_path.display()
                         );
                     }
@@ -638,12 +1814,28 @@ pub fn process_image(cli: &Cli, target_geometry: Option<&Geometry>, input_path:
         }
     }
 
-    0
+    Outcome::Processed
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn test_batch_summary_counts_skips_separately() {
+        let mut summary = BatchSummary::default();
+        for outcome in [
+            Outcome::Processed,
+            Outcome::Skipped(9),
+            Outcome::Skipped(0),
+            Outcome::Failed(2),
+        ] {
+            summary.record(outcome);
+        }
+        assert_eq!(summary.to_string(), "1 processed, 2 skipped, 1 failed");
+        assert_eq!(Outcome::Skipped(9).exit_code(), 9);
+        assert_eq!(Outcome::Processed.exit_code(), 0);
+    }
+
     #[test]
     fn test_should_prompt_delete_source_for_new_output_with_benefit() {
         assert!(should_prompt_delete_source(false, true, false));
@@ -659,4 +1851,46 @@ mod tests {
     fn test_should_not_prompt_delete_source_without_benefit() {
         assert!(!should_prompt_delete_source(false, false, false));
     }
+
+    #[test]
+    fn test_error_context() {
+        use std::error::Error as _;
+
+        let image_error = image::load_from_memory(b"not an image").err();
+        let image_message = image_error.as_ref().map(ToString::to_string);
+        assert!(image_message.is_some());
+
+        let err = image::load_from_memory(b"not an image")
+            .context("loading photo.png")
+            .err();
+        let message = err.as_ref().map(ToString::to_string);
+        assert_eq!(
+            message,
+            image_message.map(|msg| format!("loading photo.png: {msg}"))
+        );
+
+        // the context's source is the wrapped error, and its source is the image crate's error
+        let source = err.as_ref().and_then(|err| err.source());
+        assert!(
+            source.is_some_and(|source| matches!(
+                source.downcast_ref::<Error>(),
+                Some(Error::Image(_))
+            ))
+        );
+        let root = source.and_then(|source| source.source());
+        assert!(root.is_some_and(|root| root.is::<image::ImageError>()));
+
+        // io errors keep their own variants
+        let err = Err::<(), _>(image::ImageError::IoError(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "nope",
+        )))
+        .context("loading locked.png")
+        .err();
+        assert!(matches!(
+            err,
+            Some(Error::Context(context, inner))
+                if context == "loading locked.png" && matches!(*inner, Error::PermissionDenied(_))
+        ));
+    }
 }
@@ -1,11 +1,15 @@
 use clap::Parser;
-use log::error;
-use shrinky_rs::{cli::Cli, imagedata::Geometry, process_image};
-use std::{cmp::max, process::ExitCode, str::FromStr};
+use log::{error, info};
+use shrinky_rs::{
+    BatchSummary, Error, cli::Cli, collect_image_files, imagedata::Geometry, process_image_outcome,
+};
+use std::{process::ExitCode, str::FromStr};
 
-pub fn setup_logging(debug: bool) {
+pub fn setup_logging(debug: bool, quiet: bool) {
     let log_level = if debug {
         log::Level::Debug
+    } else if quiet {
+        log::Level::Error
     } else {
         log::Level::Info
     };
@@ -19,13 +23,23 @@ pub fn setup_logging(debug: bool) {
     }
 }
 
-fn aggregate_exit_code(current: u8, next: u8) -> u8 {
-    max(current, next)
+/// Keep the first failure's code, the codes aren't ordered so a later one isn't "worse"
+fn aggregate_exit_code(current: i32, next: i32) -> i32 {
+    if current == 0 { next } else { current }
+}
+
+fn to_exit_code(code: i32) -> ExitCode {
+    u8::try_from(code).map_or(ExitCode::FAILURE, ExitCode::from)
 }
 
 fn main() -> ExitCode {
     let cli = Cli::parse();
-    setup_logging(cli.debug);
+    setup_logging(cli.debug, cli.quiet);
+
+    if let Err(e) = cli.validate() {
+        error!("Invalid options: {:?}", e);
+        return to_exit_code(e.to_exit_code());
+    }
 
     let target_geometry = match cli.geometry.as_deref() {
         Some(target_geometry) => match Geometry::from_str(target_geometry) {
@@ -33,19 +47,50 @@ fn main() -> ExitCode {
             Ok(geometry) => Some(geometry),
             Err(e) => {
                 error!("Error parsing geometry: {:?}", e);
-                return ExitCode::FAILURE;
+                return to_exit_code(e.to_exit_code());
             }
         },
         None => None,
     };
 
     let mut exit_code = 0;
+    let mut summary = BatchSummary::default();
     for filename in &cli.filenames {
-        let current_exit_code = process_image(&cli, target_geometry.as_ref(), filename.as_path());
-        exit_code = aggregate_exit_code(exit_code, current_exit_code);
+        let filenames = if !filename.is_dir() {
+            vec![filename.clone()]
+        } else if cli.recursive {
+            match collect_image_files(filename, cli.max_depth) {
+                Ok((images, skipped)) => {
+                    summary.skipped += skipped.len();
+                    images
+                }
+                Err(e) => {
+                    error!("{}: Error reading directory: {:?}", filename.display(), e);
+                    summary.failed += 1;
+                    exit_code = aggregate_exit_code(exit_code, e.to_exit_code());
+                    continue;
+                }
+            }
+        } else {
+            let e = Error::InvalidOptions(format!(
+                "{} is a directory, use --recursive to process the images in it",
+                filename.display()
+            ));
+            error!("{:?}", e);
+            exit_code = aggregate_exit_code(exit_code, e.to_exit_code());
+            continue;
+        };
+        for filename in filenames {
+            let outcome = process_image_outcome(&cli, target_geometry.as_ref(), filename.as_path());
+            summary.record(outcome);
+            exit_code = aggregate_exit_code(exit_code, outcome.exit_code());
+        }
+    }
+    if cli.recursive {
+        info!("Summary: {summary}");
     }
 
-    ExitCode::from(exit_code)
+    to_exit_code(exit_code)
 }
 
 #[cfg(test)]
@@ -63,12 +108,12 @@ mod tests {
     }
 
     #[test]
-    fn test_aggregate_exit_code_uses_highest_failure() {
+    fn test_aggregate_exit_code_uses_first_failure() {
         let mut exit_code = 0;
-        for current_code in [1, 3, 2, 1] {
+        for current_code in [0, 2, 9, 0, 3] {
             exit_code = aggregate_exit_code(exit_code, current_code);
         }
 
-        assert_eq!(exit_code, 3);
+        assert_eq!(exit_code, 2);
     }
 }
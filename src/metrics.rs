@@ -0,0 +1,115 @@
+//! Content metrics used to guess what kind of image we're dealing with
+
+use std::{collections::HashSet, f64::consts::PI};
+
+use image::imageops::FilterType;
+
+use crate::imagedata::{HistogramChannel, Image};
+
+/// Luma entropy (in bits) above which an image looks like a photo
+pub const PHOTO_MIN_ENTROPY: f64 = 7.0;
+/// Images with fewer unique colors than this look like graphics
+pub const GRAPHIC_MAX_COLORS: usize = 256;
+/// Side of the grayscale thumbnail a perceptual hash is computed from
+const PHASH_THUMBNAIL_SIZE: usize = 32;
+/// The hash is built from the lowest PHASH_DCT_SIZE x PHASH_DCT_SIZE DCT frequencies
+const PHASH_DCT_SIZE: usize = 8;
+
+impl Image {
+    /// Shannon entropy of the luma channel in bits, from 0.0 (flat) to 8.0
+    pub fn luma_entropy(&self) -> f64 {
+        let histogram = self.histogram_channel(HistogramChannel::Luminance);
+        let total = histogram.iter().sum::<u64>() as f64;
+        if total == 0.0 {
+            return 0.0;
+        }
+        histogram
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let probability = *count as f64 / total;
+                -probability * probability.log2()
+            })
+            .sum()
+    }
+
+    /// True if the image has fewer than `limit` unique RGB colors. This gives the same answer as
+    /// checking `dominant_colors(limit)` returns fewer than `limit` colors, but stops counting early.
+    fn has_fewer_colors_than(&self, limit: usize) -> bool {
+        let mut colors = HashSet::new();
+        for pixel in self.image.to_rgb8().pixels() {
+            colors.insert(pixel.0);
+            if colors.len() >= limit {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Heuristic for photographic content: high luma entropy and lots of colors
+    pub fn is_photo(&self) -> bool {
+        self.luma_entropy() > PHOTO_MIN_ENTROPY && !self.has_fewer_colors_than(GRAPHIC_MAX_COLORS)
+    }
+
+    /// Heuristic for graphics (logos, diagrams, screenshots): low luma entropy and few colors
+    pub fn is_graphic(&self) -> bool {
+        self.luma_entropy() <= PHOTO_MIN_ENTROPY && self.has_fewer_colors_than(GRAPHIC_MAX_COLORS)
+    }
+
+    /// DCT-based perceptual hash. The image is shrunk to a 32x32 grayscale thumbnail, and each
+    /// bit says whether one of its 8x8 lowest frequency DCT coefficients is above their median.
+    /// Re-encoding or resizing an image barely changes the hash, compare them with
+    /// [Image::phash_distance].
+    pub fn phash(&self) -> u64 {
+        let size = PHASH_THUMBNAIL_SIZE as u32;
+        let thumbnail =
+            image::imageops::resize(&self.image.to_luma8(), size, size, FilterType::Triangle);
+        let pixels: Vec<f64> = thumbnail.pixels().map(|p| f64::from(p.0[0])).collect();
+
+        // cosines[k][x] is the DCT-II basis for frequency k at sample x
+        let cosines: Vec<Vec<f64>> = (0..PHASH_DCT_SIZE)
+            .map(|k| {
+                (0..PHASH_THUMBNAIL_SIZE)
+                    .map(|x| {
+                        ((2 * x + 1) as f64 * k as f64 * PI / (2 * PHASH_THUMBNAIL_SIZE) as f64)
+                            .cos()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // the DCT is separable, so transform the rows and then the columns of the result
+        let rows: Vec<Vec<f64>> = pixels
+            .chunks(PHASH_THUMBNAIL_SIZE)
+            .map(|row| {
+                cosines
+                    .iter()
+                    .map(|basis| row.iter().zip(basis).map(|(p, c)| p * c).sum())
+                    .collect()
+            })
+            .collect();
+        let mut coefficients = Vec::with_capacity(PHASH_DCT_SIZE * PHASH_DCT_SIZE);
+        for basis in &cosines {
+            for u in 0..PHASH_DCT_SIZE {
+                coefficients.push(rows.iter().zip(basis).map(|(row, c)| row[u] * c).sum());
+            }
+        }
+
+        let mut sorted = coefficients.clone();
+        sorted.sort_by(f64::total_cmp);
+        let middle = sorted.len() / 2;
+        let median = (sorted[middle - 1] + sorted[middle]) / 2.0;
+
+        coefficients
+            .iter()
+            .enumerate()
+            .filter(|(_, coefficient)| **coefficient > median)
+            .fold(0, |hash, (bit, _)| hash | (1 << bit))
+    }
+
+    /// Number of bits that differ between two [Image::phash] values, from 0 (the same image) to
+    /// 64. Re-encodes of the same image are usually within 10.
+    pub fn phash_distance(a: u64, b: u64) -> u32 {
+        (a ^ b).count_ones()
+    }
+}
@@ -0,0 +1,889 @@
+//! Camera RAW decoding for the `raw` feature. Only DNG is read, since it's a documented TIFF
+//! layout: the sensor data is a CFA (color filter array) image, uncompressed or lossless JPEG
+//! compressed. It's demosaiced bilinearly and white balanced as shot, which is plenty for proofs
+//! but isn't a RAW developer.
+
+use std::collections::{HashMap, HashSet};
+
+use image::{
+    DynamicImage, ImageBuffer, ImageError, Rgb,
+    error::{DecodingError, ImageFormatHint},
+    metadata::Orientation,
+};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::Error;
+
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const ORIENTATION: u16 = 274;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const TILE_WIDTH: u16 = 322;
+const TILE_LENGTH: u16 = 323;
+const TILE_OFFSETS: u16 = 324;
+const TILE_BYTE_COUNTS: u16 = 325;
+const SUB_IFDS: u16 = 330;
+const CFA_REPEAT_PATTERN_DIM: u16 = 33421;
+const CFA_PATTERN: u16 = 33422;
+const DNG_VERSION: u16 = 50706;
+const LINEARIZATION_TABLE: u16 = 50712;
+const BLACK_LEVEL_REPEAT_DIM: u16 = 50713;
+const BLACK_LEVEL: u16 = 50714;
+const WHITE_LEVEL: u16 = 50717;
+const DEFAULT_CROP_ORIGIN: u16 = 50719;
+const DEFAULT_CROP_SIZE: u16 = 50720;
+const AS_SHOT_NEUTRAL: u16 = 50728;
+
+/// PhotometricInterpretation of a DNG's sensor data
+const PHOTOMETRIC_CFA: u64 = 32803;
+const COMPRESSION_NONE: u64 = 1;
+const COMPRESSION_LOSSLESS_JPEG: u64 = 7;
+
+/// Directories to read at most, so a loop of IFD pointers can't keep us busy
+const MAX_DIRECTORIES: usize = 64;
+/// Largest sensor, in pixels, that will be decoded
+const MAX_PIXELS: u64 = 1 << 28;
+
+fn raw_error(message: impl Into<String>) -> Error {
+    Error::Image(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("dng".to_string()),
+        message.into(),
+    )))
+}
+
+fn not_dng() -> Error {
+    Error::UnsupportedFormat(
+        "only DNG camera RAW files can be decoded, convert other RAW files to DNG first, eg. with Adobe DNG Converter"
+            .to_string(),
+    )
+}
+
+/// A TIFF directory entry, with the offset its values start at
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    kind: u16,
+    count: usize,
+    offset: usize,
+}
+
+type Directory = HashMap<u16, Entry>;
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let big_endian = match data.get(..4) {
+            Some(b"II*\0") => false,
+            Some(b"MM\0*") => true,
+            _ => return Err(not_dng()),
+        };
+        Ok(Tiff { data, big_endian })
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], Error> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| raw_error("the DNG is truncated"))
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, Error> {
+        let bytes = self.bytes(offset, 2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, Error> {
+        let bytes = self.bytes(offset, 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Read the directory at `offset`, and the offset of the next one (0 at the end)
+    fn directory(&self, offset: usize) -> Result<(Directory, usize), Error> {
+        let count = usize::from(self.u16_at(offset)?);
+        let mut directory = Directory::new();
+        for index in 0..count {
+            let start = offset + 2 + index * 12;
+            let tag = self.u16_at(start)?;
+            let kind = self.u16_at(start + 2)?;
+            let count = self.u32_at(start + 4)? as usize;
+            let size = match kind {
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => 1,
+            };
+            // values that fit in four bytes are stored in the entry itself
+            let offset = if count.saturating_mul(size) <= 4 {
+                start + 8
+            } else {
+                self.u32_at(start + 8)? as usize
+            };
+            directory.insert(
+                tag,
+                Entry {
+                    kind,
+                    count,
+                    offset,
+                },
+            );
+        }
+        let next = self.u32_at(offset + 2 + count * 12)? as usize;
+        Ok((directory, next))
+    }
+
+    /// Every directory in the main chain and their SubIFDs, main chain first
+    fn directories(&self) -> Result<Vec<Directory>, Error> {
+        let mut pending = vec![self.u32_at(4)? as usize];
+        let mut seen = HashSet::new();
+        let mut directories = Vec::new();
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || !seen.insert(offset) || directories.len() == MAX_DIRECTORIES {
+                continue;
+            }
+            let (directory, next) = self.directory(offset)?;
+            pending.push(next);
+            if let Some(entry) = directory.get(&SUB_IFDS) {
+                pending.extend(self.values(entry)?.iter().rev().map(|&sub| sub as usize));
+            }
+            directories.push(directory);
+        }
+        if directories.is_empty() {
+            return Err(raw_error("the DNG has no image directories"));
+        }
+        Ok(directories)
+    }
+
+    /// An entry's values, whatever their TIFF type, as floats. Rationals are divided out.
+    fn values(&self, entry: &Entry) -> Result<Vec<f64>, Error> {
+        (0..entry.count)
+            .map(|index| {
+                Ok(match entry.kind {
+                    3 => f64::from(self.u16_at(entry.offset + index * 2)?),
+                    8 => f64::from(self.u16_at(entry.offset + index * 2)? as i16),
+                    4 | 13 => f64::from(self.u32_at(entry.offset + index * 4)?),
+                    9 => f64::from(self.u32_at(entry.offset + index * 4)? as i32),
+                    11 => f64::from(f32::from_bits(self.u32_at(entry.offset + index * 4)?)),
+                    5 | 10 => {
+                        let start = entry.offset + index * 8;
+                        let (numerator, denominator) =
+                            (self.u32_at(start)?, self.u32_at(start + 4)?);
+                        let (numerator, denominator) = if entry.kind == 10 {
+                            (f64::from(numerator as i32), f64::from(denominator as i32))
+                        } else {
+                            (f64::from(numerator), f64::from(denominator))
+                        };
+                        if denominator == 0.0 {
+                            0.0
+                        } else {
+                            numerator / denominator
+                        }
+                    }
+                    12 => {
+                        let bytes = self.bytes(entry.offset + index * 8, 8)?;
+                        let mut raw = [0; 8];
+                        raw.copy_from_slice(bytes);
+                        if self.big_endian {
+                            f64::from_be_bytes(raw)
+                        } else {
+                            f64::from_le_bytes(raw)
+                        }
+                    }
+                    _ => f64::from(self.bytes(entry.offset + index, 1)?[0]),
+                })
+            })
+            .collect()
+    }
+
+    fn tag(&self, directory: &Directory, tag: u16) -> Result<Option<Vec<f64>>, Error> {
+        directory
+            .get(&tag)
+            .map(|entry| self.values(entry))
+            .transpose()
+    }
+
+    fn first(&self, directory: &Directory, tag: u16) -> Result<Option<u64>, Error> {
+        Ok(self
+            .tag(directory, tag)?
+            .and_then(|values| values.first().copied())
+            .map(|value| value as u64))
+    }
+}
+
+/// A strip or tile of sensor data, and where it goes
+struct Chunk {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    offset: usize,
+    len: usize,
+}
+
+fn chunks(
+    tiff: &Tiff,
+    directory: &Directory,
+    width: usize,
+    height: usize,
+) -> Result<Vec<Chunk>, Error> {
+    let list = |tag| -> Result<Vec<usize>, Error> {
+        Ok(tiff
+            .tag(directory, tag)?
+            .ok_or_else(|| raw_error("the DNG's sensor data has no offsets"))?
+            .into_iter()
+            .map(|value| value as usize)
+            .collect())
+    };
+    let (chunk_width, chunk_height, offsets, lengths) = if directory.contains_key(&TILE_OFFSETS) {
+        let tile_width = tiff.first(directory, TILE_WIDTH)?.unwrap_or(0) as usize;
+        let tile_height = tiff.first(directory, TILE_LENGTH)?.unwrap_or(0) as usize;
+        (
+            tile_width,
+            tile_height,
+            list(TILE_OFFSETS)?,
+            list(TILE_BYTE_COUNTS)?,
+        )
+    } else {
+        let rows = tiff
+            .first(directory, ROWS_PER_STRIP)?
+            .map_or(height, |rows| (rows as usize).min(height));
+        (width, rows, list(STRIP_OFFSETS)?, list(STRIP_BYTE_COUNTS)?)
+    };
+    if chunk_width == 0 || chunk_height == 0 {
+        return Err(raw_error("the DNG's tiles have no size"));
+    }
+    let across = width.div_ceil(chunk_width);
+    let down = height.div_ceil(chunk_height);
+    if offsets.len() < across * down || lengths.len() < offsets.len() {
+        return Err(raw_error("the DNG is missing some of its sensor data"));
+    }
+    Ok(offsets
+        .into_iter()
+        .zip(lengths)
+        .take(across * down)
+        .enumerate()
+        .map(|(index, (offset, len))| Chunk {
+            x: index % across * chunk_width,
+            y: index / across * chunk_height,
+            width: chunk_width,
+            height: chunk_height,
+            offset,
+            len,
+        })
+        .collect())
+}
+
+/// Unpack uncompressed samples. 8 and 16-bit samples are whole bytes (16-bit in the file's byte
+/// order), other depths are packed most significant bit first with each row padded to a byte.
+fn unpack(tiff: &Tiff, data: &[u8], bits: u32, width: usize, height: usize) -> Vec<u16> {
+    match bits {
+        8 => data.iter().map(|byte| u16::from(*byte)).collect(),
+        16 => data
+            .chunks_exact(2)
+            .map(|pair| {
+                if tiff.big_endian {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                }
+            })
+            .collect(),
+        _ => {
+            let row_bytes = (width * bits as usize).div_ceil(8);
+            let mut samples = Vec::with_capacity(width * height);
+            for row in data.chunks(row_bytes).take(height) {
+                let mut reader = BitReader::new(row);
+                samples.extend((0..width).map(|_| reader.bits(bits) as u16));
+            }
+            samples
+        }
+    }
+}
+
+/// Reads bits most significant first. In JPEG entropy coded data a 0xFF byte is followed by a
+/// stuffed 0x00, and anything else is a marker, after which zeros are read.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    available: u32,
+    jpeg: bool,
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            available: 0,
+            jpeg: false,
+            at_marker: false,
+        }
+    }
+
+    fn jpeg(data: &'a [u8]) -> Self {
+        BitReader {
+            jpeg: true,
+            ..BitReader::new(data)
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.available <= 56 {
+            let mut byte = 0;
+            if !self.at_marker
+                && let Some(&next) = self.data.get(self.position)
+            {
+                byte = next;
+                self.position += 1;
+                if self.jpeg && byte == 0xFF {
+                    match self.data.get(self.position) {
+                        Some(0) => self.position += 1,
+                        _ => {
+                            // leave the marker to be read by restart()
+                            self.position -= 1;
+                            self.at_marker = true;
+                            byte = 0;
+                        }
+                    }
+                }
+            }
+            self.buffer |= u64::from(byte) << (56 - self.available);
+            self.available += 8;
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        self.fill();
+        let value = (self.buffer >> (64 - count)) as u32;
+        self.buffer <<= count;
+        self.available -= count;
+        value
+    }
+
+    fn bit(&mut self) -> u32 {
+        self.bits(1)
+    }
+
+    /// Drop the rest of the byte and skip a restart marker
+    fn restart(&mut self) {
+        self.buffer = 0;
+        self.available = 0;
+        if self.at_marker
+            && let Some(0xD0..=0xD7) = self.data.get(self.position + 1)
+        {
+            self.position += 2;
+            self.at_marker = false;
+        }
+    }
+}
+
+/// A JPEG Huffman table, decoded a bit at a time as in Annex F.2.2.3 of the JPEG spec
+#[derive(Clone, Default)]
+struct Huffman {
+    max_code: [i32; 17],
+    value_offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8], values: Vec<u8>) -> Self {
+        let mut table = Huffman {
+            max_code: [-1; 17],
+            value_offset: [0; 17],
+            values,
+        };
+        let (mut code, mut index) = (0_i32, 0_i32);
+        for length in 1..=16 {
+            let count = i32::from(counts[length - 1]);
+            table.value_offset[length] = index - code;
+            code += count;
+            index += count;
+            table.max_code[length] = if count > 0 { code - 1 } else { -1 };
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, Error> {
+        let mut code = reader.bit() as i32;
+        for length in 1..=16 {
+            if code <= self.max_code[length] {
+                return usize::try_from(code + self.value_offset[length])
+                    .ok()
+                    .and_then(|index| self.values.get(index).copied())
+                    .ok_or_else(|| raw_error("bad Huffman code in lossless JPEG data"));
+            }
+            code = (code << 1) | reader.bit() as i32;
+        }
+        Err(raw_error("bad Huffman code in lossless JPEG data"))
+    }
+}
+
+/// Decode a lossless (process 14, SOF3) JPEG to its samples, with components interleaved
+fn decode_lossless_jpeg(data: &[u8]) -> Result<Vec<u16>, Error> {
+    let read_u16 = |offset: usize| -> Result<usize, Error> {
+        data.get(offset..offset + 2)
+            .map(|bytes| usize::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+            .ok_or_else(|| raw_error("the lossless JPEG data is truncated"))
+    };
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(raw_error("the DNG's sensor data isn't a JPEG"));
+    }
+    let mut tables: [Huffman; 4] = Default::default();
+    let (mut precision, mut width, mut height, mut components) = (0, 0, 0, 0);
+    let mut restart_interval = 0;
+    let mut position = 2;
+    loop {
+        let (Some(0xFF), Some(&marker)) = (data.get(position), data.get(position + 1)) else {
+            return Err(raw_error("the lossless JPEG data has no scan"));
+        };
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        let length = read_u16(position + 2)?;
+        let segment = data
+            .get(position + 4..position + 2 + length)
+            .ok_or_else(|| raw_error("the lossless JPEG data is truncated"))?;
+        position += 2 + length;
+        match marker {
+            0xC3 => {
+                let header = segment
+                    .get(..6)
+                    .ok_or_else(|| raw_error("short lossless JPEG frame header"))?;
+                precision = u32::from(header[0]);
+                height = usize::from(u16::from_be_bytes([header[1], header[2]]));
+                width = usize::from(u16::from_be_bytes([header[3], header[4]]));
+                components = usize::from(header[5]);
+            }
+            0xC0..=0xC2 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err(Error::UnsupportedFormat(
+                    "the DNG's sensor data is lossy JPEG, which isn't supported".to_string(),
+                ));
+            }
+            0xC4 => {
+                let mut rest = segment;
+                while let Some((&class_id, tail)) = rest.split_first() {
+                    let counts = tail
+                        .get(..16)
+                        .ok_or_else(|| raw_error("short Huffman table"))?;
+                    let total = counts
+                        .iter()
+                        .map(|count| usize::from(*count))
+                        .sum::<usize>();
+                    let values = tail
+                        .get(16..16 + total)
+                        .ok_or_else(|| raw_error("short Huffman table"))?;
+                    tables[usize::from(class_id & 3)] = Huffman::new(counts, values.to_vec());
+                    rest = &tail[16 + total..];
+                }
+            }
+            0xDD => {
+                let interval = segment
+                    .get(..2)
+                    .ok_or_else(|| raw_error("short lossless JPEG restart interval"))?;
+                restart_interval = usize::from(u16::from_be_bytes([interval[0], interval[1]]));
+            }
+            0xDA => {
+                let count = usize::from(*segment.first().unwrap_or(&0));
+                if count == 0 || count != components || precision == 0 || precision > 16 {
+                    return Err(raw_error("unsupported lossless JPEG scan"));
+                }
+                let selectors: Vec<usize> = (0..count)
+                    .map(|index| {
+                        segment
+                            .get(2 + index * 2)
+                            .map(|table| usize::from(table >> 4) & 3)
+                            .ok_or_else(|| raw_error("short lossless JPEG scan header"))
+                    })
+                    .collect::<Result<_, _>>()?;
+                let tail = segment
+                    .get(1 + count * 2..1 + count * 2 + 3)
+                    .ok_or_else(|| raw_error("short lossless JPEG scan header"))?;
+                let predictor = tail[0];
+                let point_transform = u32::from(tail[2] & 0xF);
+                let scan = Scan {
+                    width,
+                    height,
+                    components,
+                    precision,
+                    predictor,
+                    point_transform,
+                    restart_interval,
+                };
+                return scan.decode(&data[position..], &tables, &selectors);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Scan {
+    width: usize,
+    height: usize,
+    components: usize,
+    precision: u32,
+    predictor: u8,
+    point_transform: u32,
+    restart_interval: usize,
+}
+
+impl Scan {
+    fn decode(
+        &self,
+        data: &[u8],
+        tables: &[Huffman; 4],
+        selectors: &[usize],
+    ) -> Result<Vec<u16>, Error> {
+        let row_len = self.width * self.components;
+        let total = row_len
+            .checked_mul(self.height)
+            .filter(|total| *total as u64 <= MAX_PIXELS)
+            .ok_or_else(|| raw_error("the lossless JPEG data is too large"))?;
+        let mut samples = vec![0_i32; total];
+        let mut reader = BitReader::jpeg(data);
+        let initial = 1_i32 << (self.precision - self.point_transform - 1);
+        let mask = (1_i32 << self.precision) - 1;
+        for y in 0..self.height {
+            // restart intervals in lossless JPEG are whole rows, and each one is predicted as
+            // though it were the first row of the image
+            let first_row = y == 0
+                || (self.restart_interval > 0
+                    && (y * self.width).is_multiple_of(self.restart_interval));
+            if first_row && y > 0 {
+                reader.restart();
+            }
+            for x in 0..self.width {
+                for (component, selector) in selectors.iter().enumerate() {
+                    let index = y * row_len + x * self.components + component;
+                    let left = || samples[index - self.components];
+                    let up = || samples[index - row_len];
+                    let prediction = match (x, first_row) {
+                        (0, true) => initial,
+                        (_, true) => left(),
+                        (0, false) => up(),
+                        _ => {
+                            let (a, b, c) =
+                                (left(), up(), samples[index - row_len - self.components]);
+                            match self.predictor {
+                                1 => a,
+                                2 => b,
+                                3 => c,
+                                4 => a + b - c,
+                                5 => a + ((b - c) >> 1),
+                                6 => b + ((a - c) >> 1),
+                                _ => (a + b) >> 1,
+                            }
+                        }
+                    };
+                    let length = u32::from(tables[*selector].decode(&mut reader)?);
+                    let difference = match length {
+                        0 => 0,
+                        16 => 32768,
+                        _ => {
+                            let bits = reader.bits(length) as i32;
+                            if bits < 1 << (length - 1) {
+                                bits - (1 << length) + 1
+                            } else {
+                                bits
+                            }
+                        }
+                    };
+                    samples[index] = (prediction + difference) & mask;
+                }
+            }
+        }
+        Ok(samples
+            .into_iter()
+            .map(|sample| (sample << self.point_transform) as u16)
+            .collect())
+    }
+}
+
+/// Decode a DNG to a 16-bit RGB image, cropped and oriented the way the camera meant it to be
+/// shown. Other camera RAW formats are rejected with a hint to convert them to DNG.
+pub fn decode_dng(data: &[u8]) -> Result<DynamicImage, Error> {
+    let tiff = Tiff::new(data)?;
+    let directories = tiff.directories()?;
+    let main = &directories[0];
+    if !main.contains_key(&DNG_VERSION) {
+        return Err(not_dng());
+    }
+    let mut sensor_directory = None;
+    for directory in &directories {
+        if tiff.first(directory, NEW_SUBFILE_TYPE)?.unwrap_or(0) == 0
+            && tiff.first(directory, PHOTOMETRIC_INTERPRETATION)? == Some(PHOTOMETRIC_CFA)
+        {
+            sensor_directory = Some(directory);
+            break;
+        }
+    }
+    let Some(directory) = sensor_directory else {
+        return Err(Error::UnsupportedFormat(
+            "the DNG has no color filter array data, only mosaiced DNGs can be decoded".to_string(),
+        ));
+    };
+
+    let width = tiff.first(directory, IMAGE_WIDTH)?.unwrap_or(0);
+    let height = tiff.first(directory, IMAGE_LENGTH)?.unwrap_or(0);
+    if width == 0 || height == 0 || width * height > MAX_PIXELS {
+        return Err(raw_error(format!(
+            "the DNG's sensor size {width}x{height} isn't supported"
+        )));
+    }
+    let (width, height) = (width as usize, height as usize);
+    let bits = tiff.first(directory, BITS_PER_SAMPLE)?.unwrap_or(16);
+    if !(1..=16).contains(&bits) || tiff.first(directory, SAMPLES_PER_PIXEL)?.unwrap_or(1) != 1 {
+        return Err(raw_error(
+            "the DNG's sensor data isn't one sample of up to 16 bits",
+        ));
+    }
+    let sensor = read_sensor(&tiff, directory, width, height, bits as u32)?;
+    let levels = Levels::new(&tiff, directory, bits as u32)?;
+    let pattern = CfaPattern::new(&tiff, directory)?;
+    let gains = match tiff.tag(main, AS_SHOT_NEUTRAL)? {
+        Some(neutral) if neutral.len() == 3 && neutral.iter().all(|value| *value > 0.0) => [
+            (neutral[1] / neutral[0]) as f32,
+            1.0,
+            (neutral[1] / neutral[2]) as f32,
+        ],
+        _ => [1.0; 3],
+    };
+
+    let linear: Vec<f32> = sensor
+        .iter()
+        .enumerate()
+        .map(|(index, sample)| levels.normalise(*sample, index % width, index / width))
+        .collect();
+    let mut pixels = vec![0_u16; width * height * 3];
+    pixels
+        .par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                for (channel, value) in pixel.iter_mut().enumerate() {
+                    let sample = pattern.interpolate(&linear, width, height, x, y, channel);
+                    *value = encode_srgb(sample * gains[channel]);
+                }
+            }
+        });
+    let image = ImageBuffer::<Rgb<u16>, Vec<u16>>::from_raw(width as u32, height as u32, pixels)
+        .ok_or_else(|| raw_error("the demosaiced image is the wrong size"))?;
+    let mut image = DynamicImage::ImageRgb16(image);
+
+    if let (Some(origin), Some(size)) = (
+        tiff.tag(directory, DEFAULT_CROP_ORIGIN)?,
+        tiff.tag(directory, DEFAULT_CROP_SIZE)?,
+    ) && let ([x, y], [crop_width, crop_height]) = (origin.as_slice(), size.as_slice())
+    {
+        let (x, y) = (
+            (*x as u32).min(image.width()),
+            (*y as u32).min(image.height()),
+        );
+        let crop_width = (*crop_width as u32).min(image.width() - x);
+        let crop_height = (*crop_height as u32).min(image.height() - y);
+        if crop_width > 0 && crop_height > 0 {
+            image = image.crop_imm(x, y, crop_width, crop_height);
+        }
+    }
+    if let Some(orientation) = tiff
+        .first(main, ORIENTATION)?
+        .and_then(|value| u8::try_from(value).ok())
+        .and_then(Orientation::from_exif)
+    {
+        image.apply_orientation(orientation);
+    }
+    Ok(image)
+}
+
+/// Read the sensor's samples, row by row
+fn read_sensor(
+    tiff: &Tiff,
+    directory: &Directory,
+    width: usize,
+    height: usize,
+    bits: u32,
+) -> Result<Vec<u16>, Error> {
+    let compression = tiff
+        .first(directory, COMPRESSION)?
+        .unwrap_or(COMPRESSION_NONE);
+    let mut sensor = vec![0_u16; width * height];
+    for chunk in chunks(tiff, directory, width, height)? {
+        let data = tiff.bytes(chunk.offset, chunk.len)?;
+        let samples = match compression {
+            COMPRESSION_NONE => unpack(tiff, data, bits, chunk.width, chunk.height),
+            COMPRESSION_LOSSLESS_JPEG => decode_lossless_jpeg(data)?,
+            other => {
+                return Err(Error::UnsupportedFormat(format!(
+                    "DNG compression {other} isn't supported, only uncompressed and lossless JPEG are"
+                )));
+            }
+        };
+        // samples fill the strip or tile row by row, whatever shape the JPEG said it was, and
+        // anything past the edge of the image is padding
+        for (row, samples) in samples.chunks(chunk.width).take(chunk.height).enumerate() {
+            let y = chunk.y + row;
+            if y >= height || chunk.x >= width {
+                break;
+            }
+            let count = samples.len().min(width - chunk.x);
+            let start = y * width + chunk.x;
+            sensor[start..start + count].copy_from_slice(&samples[..count]);
+        }
+    }
+    Ok(sensor)
+}
+
+/// Turns the sensor's samples into linear values from 0 to 1
+struct Levels {
+    linearization: Vec<f64>,
+    black: Vec<f64>,
+    black_rows: usize,
+    black_columns: usize,
+    white: f64,
+}
+
+impl Levels {
+    fn new(tiff: &Tiff, directory: &Directory, bits: u32) -> Result<Self, Error> {
+        let (black_rows, black_columns) =
+            match tiff.tag(directory, BLACK_LEVEL_REPEAT_DIM)?.as_deref() {
+                Some([rows, columns]) if *rows >= 1.0 && *columns >= 1.0 => {
+                    (*rows as usize, *columns as usize)
+                }
+                _ => (1, 1),
+            };
+        Ok(Levels {
+            linearization: tiff
+                .tag(directory, LINEARIZATION_TABLE)?
+                .unwrap_or_default(),
+            black: tiff.tag(directory, BLACK_LEVEL)?.unwrap_or_default(),
+            black_rows,
+            black_columns,
+            white: tiff
+                .tag(directory, WHITE_LEVEL)?
+                .and_then(|values| values.first().copied())
+                .unwrap_or_else(|| f64::from((1_u32 << bits) - 1)),
+        })
+    }
+
+    fn normalise(&self, sample: u16, x: usize, y: usize) -> f32 {
+        let sample = match self.linearization.last() {
+            Some(last) => *self.linearization.get(usize::from(sample)).unwrap_or(last),
+            None => f64::from(sample),
+        };
+        let black = self
+            .black
+            .get((y % self.black_rows) * self.black_columns + x % self.black_columns)
+            .or(self.black.first())
+            .copied()
+            .unwrap_or(0.0);
+        if self.white <= black {
+            return 0.0;
+        }
+        ((sample - black) / (self.white - black)).clamp(0.0, 1.0) as f32
+    }
+}
+
+/// Which of red, green and blue each photosite sees
+struct CfaPattern {
+    rows: usize,
+    columns: usize,
+    colors: Vec<usize>,
+}
+
+impl CfaPattern {
+    fn new(tiff: &Tiff, directory: &Directory) -> Result<Self, Error> {
+        let (rows, columns) = match tiff.tag(directory, CFA_REPEAT_PATTERN_DIM)?.as_deref() {
+            Some([rows, columns]) => (*rows as usize, *columns as usize),
+            _ => (2, 2),
+        };
+        let colors: Vec<usize> = tiff
+            .tag(directory, CFA_PATTERN)?
+            .ok_or_else(|| raw_error("the DNG has no CFA pattern"))?
+            .into_iter()
+            .map(|color| color as usize)
+            .collect();
+        if rows == 0 || columns == 0 || colors.len() != rows * columns {
+            return Err(raw_error("the DNG's CFA pattern doesn't match its size"));
+        }
+        if colors.iter().any(|color| *color > 2) {
+            return Err(Error::UnsupportedFormat(
+                "the DNG's sensor has colors other than red, green and blue".to_string(),
+            ));
+        }
+        Ok(CfaPattern {
+            rows,
+            columns,
+            colors,
+        })
+    }
+
+    fn color(&self, x: usize, y: usize) -> usize {
+        self.colors[(y % self.rows) * self.columns + x % self.columns]
+    }
+
+    /// A channel's value at a photosite: its own reading if it's that color, otherwise the
+    /// average of the neighbours that are (bilinear demosaicing)
+    fn interpolate(
+        &self,
+        linear: &[f32],
+        width: usize,
+        height: usize,
+        x: usize,
+        y: usize,
+        channel: usize,
+    ) -> f32 {
+        if self.color(x, y) == channel {
+            return linear[y * width + x];
+        }
+        let (mut total, mut count) = (0.0, 0.0);
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                if self.color(nx, ny) == channel {
+                    total += linear[ny * width + nx];
+                    count += 1.0;
+                }
+            }
+        }
+        if count > 0.0 { total / count } else { 0.0 }
+    }
+}
+
+/// Gamma encode a linear value for sRGB
+fn encode_srgb(value: f32) -> u16 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * f32::from(u16::MAX)).round() as u16
+}
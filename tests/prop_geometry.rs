@@ -0,0 +1,80 @@
+//! Property tests for Geometry arithmetic. proptest shrinks a failing case down to the smallest
+//! sizes that still fail, so landscape/portrait boundary bugs show up as simple examples.
+
+use proptest::prelude::*;
+use shrinky_rs::imagedata::Geometry;
+
+const MAX_DIMENSION: u32 = 10_000;
+
+fn geometry() -> impl Strategy<Value = Geometry> {
+    (1..=MAX_DIMENSION, 1..=MAX_DIMENSION).prop_map(|(width, height)| Geometry::new(width, height))
+}
+
+/// Pairs of geometries, where the second is sometimes the first or its transpose
+fn pair() -> impl Strategy<Value = (Geometry, Geometry)> {
+    prop_oneof![
+        (geometry(), geometry()),
+        geometry().prop_map(|geometry| (geometry.clone(), geometry)),
+        geometry().prop_map(|geometry| {
+            let transposed = Geometry {
+                width: geometry.height,
+                height: geometry.width,
+            };
+            (geometry, transposed)
+        }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn prop_scale_by_one_is_identity(geometry in geometry()) {
+        prop_assert_eq!(
+            geometry.scale_by(1.0).expect("scaling by 1 should work"),
+            geometry
+        );
+    }
+
+    #[test]
+    fn prop_scale_to_fit_within_stays_inside_bounds((geometry, bounds) in pair()) {
+        let scaled = geometry.clone().scale_to_fit_within(&bounds);
+        prop_assert!(
+            scaled.fits_within(&bounds),
+            "{} scaled to {}, outside {}", geometry, scaled, bounds
+        );
+        // and it fills one of them
+        prop_assert!(
+            scaled.width == bounds.width || scaled.height == bounds.height,
+            "{} scaled to {}, which doesn't touch {}", geometry, scaled, bounds
+        );
+    }
+
+    #[test]
+    fn prop_scale_to_cover_covers_bounds((geometry, bounds) in pair()) {
+        let scaled = geometry.clone().scale_to_cover(&bounds);
+        prop_assert!(
+            bounds.fits_within(&scaled),
+            "{} scaled to {}, which doesn't cover {}", geometry, scaled, bounds
+        );
+        // and it only overflows in one of them
+        prop_assert!(
+            scaled.width == bounds.width || scaled.height == bounds.height,
+            "{} scaled to {}, which doesn't touch {}", geometry, scaled, bounds
+        );
+        prop_assert!(geometry.scale_to_fit_within(&bounds).fits_within(&scaled));
+    }
+
+    #[test]
+    fn prop_union_is_commutative_and_idempotent((a, b) in pair()) {
+        prop_assert_eq!(a.union(&b), b.union(&a), "{} and {}", a, b);
+        prop_assert_eq!(a.union(&a), a.clone());
+        prop_assert!(a.fits_within(&a.union(&b)));
+    }
+
+    #[test]
+    fn prop_intersection_is_commutative_and_idempotent((a, b) in pair()) {
+        prop_assert_eq!(a.intersection(&b), b.intersection(&a), "{} and {}", a, b);
+        prop_assert_eq!(a.intersection(&a), Some(a.clone()));
+        let intersection = a.intersection(&b).expect("non-zero sizes should intersect");
+        prop_assert!(intersection.fits_within(&a) && intersection.fits_within(&b));
+    }
+}
@@ -0,0 +1,62 @@
+use clap::Parser;
+use shrinky_rs::{Error, ImageFormat, cli::Cli};
+
+fn parse(args: &[&str]) -> Cli {
+    Cli::try_parse_from(["shrinky-rs"].iter().chain(args).chain(&["input.png"]))
+        .expect("failed to parse arguments")
+}
+
+#[test]
+fn test_validate_accepts_consistent_options() {
+    for args in [
+        &[][..],
+        &["--output-type", "webp", "--lossless"],
+        &["--explain", "--fallback-to-original-format"],
+        &["--phased-auto-format", "--delete"],
+        &["--data-url", "--output-type", "png"],
+        &["--in-place", "--output-type", "jpg"],
+        &["--quiet", "--delete"],
+        &["--formats", "png,qoi", "--explain"],
+        &["--recursive", "--max-depth", "2"],
+    ] {
+        assert!(
+            parse(args).validate().is_ok(),
+            "{args:?} should be accepted"
+        );
+    }
+}
+
+#[test]
+fn test_validate_rejects_conflicting_options() {
+    for args in [
+        &["--output-type", "jpg", "--lossless"][..],
+        &["--output-type", "png", "--explain"],
+        &["--output-type", "webp", "--phased-auto-format"],
+        &["--output-type", "avif", "--fallback-to-original-format"],
+        &["--data-url", "--delete"],
+        &["--debug", "--quiet"],
+        &["--output-type", "png", "--formats", "png,qoi"],
+        &["--max-depth", "2"],
+        &["--recursive", "--max-depth", "0"],
+    ] {
+        match parse(args).validate() {
+            Err(Error::InvalidOptions(message)) => {
+                let flag = args.iter().rfind(|arg| arg.starts_with("--")).copied();
+                assert!(
+                    flag.is_some_and(|flag| message.contains(flag)),
+                    "error for {args:?} should name the flag: {message}"
+                );
+            }
+            other => panic!("{args:?} should be rejected, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_formats_list() {
+    assert_eq!(
+        parse(&["--formats", "png,qoi,jpeg"]).formats,
+        Some(vec![ImageFormat::Png, ImageFormat::Qoi, ImageFormat::Jpg])
+    );
+    assert_eq!(parse(&[]).formats, None);
+}
@@ -0,0 +1,110 @@
+use std::{path::PathBuf, process::Command, str::FromStr};
+
+use base64::Engine;
+use shrinky_rs::{
+    ImageFormat,
+    cli::test_setup_logging,
+    imagedata::{Geometry, Image},
+};
+
+/// Upper bound for a data URL of a thumbnail, anything bigger shouldn't be inlined
+const THUMBNAIL_DATA_URL_LIMIT: usize = 32 * 1024;
+
+fn thumbnail() -> Image {
+    Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load fixture")
+        .with_target_geometry(Geometry::from_str("64x").expect("valid geometry"))
+}
+
+#[test]
+fn test_to_data_url() {
+    test_setup_logging();
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    let image = thumbnail();
+
+    // there's no JPEG 2000 encoder to build one with
+    for format in ImageFormat::all()
+        .into_iter()
+        .filter(|format| *format != ImageFormat::Jpeg2000)
+    {
+        let url = image
+            .to_data_url(format)
+            .unwrap_or_else(|e| panic!("failed to build {format} data URL: {e:?}"));
+        let prefix = format!("data:{};base64,", format.mime_type());
+        assert!(
+            url.starts_with(&prefix),
+            "{format} data URL should start with {prefix}"
+        );
+        assert!(
+            url.len() < THUMBNAIL_DATA_URL_LIMIT,
+            "{format} thumbnail data URL is {} bytes",
+            url.len()
+        );
+
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&url[prefix.len()..])
+            .expect("data URL payload should be valid base64");
+        let decoded = image::load_from_memory(&bytes)
+            .unwrap_or_else(|e| panic!("{format} data URL doesn't hold a valid image: {e:?}"));
+        assert_eq!(decoded.width(), 64);
+    }
+}
+
+#[test]
+fn test_cli_data_url_prints_instead_of_writing() {
+    let tempdir = tempfile::TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("data-url.png");
+    std::fs::copy("tests/test_images/bruny-oysters.png", &input).expect("failed to copy fixture");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_shrinky-rs"))
+        .args([
+            "--data-url",
+            "--geometry",
+            "32x",
+            "--output-type",
+            "jpg",
+            input.to_str().expect("utf-8 path"),
+        ])
+        .output()
+        .expect("failed to run shrinky-rs");
+
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.trim().starts_with("data:image/jpeg;base64,"));
+    assert!(
+        !input.with_extension("jpg").exists(),
+        "--data-url shouldn't write an output file"
+    );
+}
+
+#[test]
+fn test_to_base64_holds_the_encoded_bytes() {
+    test_setup_logging();
+    let image = thumbnail();
+
+    for format in [ImageFormat::Png, ImageFormat::Jpg, ImageFormat::Webp] {
+        let url = image
+            .to_base64(format)
+            .unwrap_or_else(|e| panic!("failed to base64 encode {format}: {e:?}"));
+        let prefix = format!("data:{};base64,", format.mime_type());
+        assert!(
+            url.starts_with(&prefix),
+            "{format} should start with {prefix}"
+        );
+
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(&url[prefix.len()..])
+            .expect("payload should be valid base64");
+        assert_eq!(
+            bytes,
+            image
+                .output_as_format(format)
+                .expect("failed to encode image"),
+            "{format} payload should be the encoded image"
+        );
+    }
+}
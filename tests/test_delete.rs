@@ -159,3 +159,72 @@ fn test_delete_never_removes_source_when_write_fails() {
         "source file should remain after write failure"
     );
 }
+
+#[test]
+fn test_conversion_stats_printed_without_delete() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = copy_fixture_to_tempdir(&tempdir, "stats.png");
+
+    let result = run_shrinky(
+        &["--output-type", "jpg", input.to_str().expect("utf-8 path")],
+        None,
+    );
+
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.contains("Original: "), "stdout: {stdout}");
+    assert!(stdout.contains("New: "), "stdout: {stdout}");
+    assert!(stdout.contains("% smaller)"), "stdout: {stdout}");
+    assert!(!stdout.contains("Delete original file?"));
+    assert!(input.exists());
+}
+
+#[test]
+fn test_quiet_hides_conversion_stats() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = copy_fixture_to_tempdir(&tempdir, "quiet.png");
+
+    let result = run_shrinky(
+        &[
+            "--quiet",
+            "--output-type",
+            "jpg",
+            input.to_str().expect("utf-8 path"),
+        ],
+        None,
+    );
+
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(result.stdout.is_empty(), "--quiet should print nothing");
+    assert!(
+        result.stderr.is_empty(),
+        "--quiet should only log errors: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(output_path_for(&input).exists());
+
+    // --delete still shows the comparison it's asking about
+    let input = copy_fixture_to_tempdir(&tempdir, "quiet-delete.png");
+    let result = run_shrinky(
+        &[
+            "--quiet",
+            "--delete",
+            "--output-type",
+            "jpg",
+            input.to_str().expect("utf-8 path"),
+        ],
+        Some("n\n"),
+    );
+    assert!(result.status.success());
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.contains("Original: "), "stdout: {stdout}");
+    assert!(stdout.contains("Delete original file?"), "stdout: {stdout}");
+}
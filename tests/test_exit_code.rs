@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    process::{Command, Output},
+};
+
+use shrinky_rs::Error;
+use tempfile::TempDir;
+
+fn run_shrinky(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shrinky-rs"))
+        .args(args)
+        .output()
+        .expect("failed to run shrinky-rs")
+}
+
+#[test]
+fn test_missing_input_exits_with_2() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("missing.png");
+
+    let result = run_shrinky(&[input.to_str().expect("utf-8 path")]);
+
+    assert_eq!(result.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("file not found"));
+}
+
+#[test]
+fn test_unsupported_extension_exits_with_4() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("notes.xyz");
+    fs::write(&input, b"not an image").expect("failed to write input");
+
+    let result = run_shrinky(&[input.to_str().expect("utf-8 path")]);
+
+    assert_eq!(result.status.code(), Some(4));
+}
+
+#[test]
+fn test_invalid_options_exit_with_5() {
+    let result = run_shrinky(&["--data-url", "--delete", "input.png"]);
+
+    assert_eq!(result.status.code(), Some(5));
+}
+
+#[test]
+fn test_several_inputs_exit_with_the_first_failure() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let unsupported = tempdir.path().join("notes.xyz");
+    fs::write(&unsupported, b"not an image").expect("failed to write input");
+    let missing = tempdir.path().join("missing.png");
+
+    let result = run_shrinky(&[
+        unsupported.to_str().expect("utf-8 path"),
+        missing.to_str().expect("utf-8 path"),
+    ]);
+
+    assert_eq!(result.status.code(), Some(4));
+}
+
+#[test]
+fn test_error_exit_codes() {
+    for (error, code) in [
+        (Error::FileNotFound("a.png".into()), 2),
+        (Error::PermissionDenied("a.png".into()), 3),
+        (Error::UnsupportedFormat("xyz".to_string()), 4),
+        (Error::InvalidOptions("--nope".to_string()), 5),
+        (Error::ImageEncodingError("boom".to_string()), 6),
+        (Error::FileSystem("a.png".to_string()), 7),
+        (Error::DiskFull, 7),
+        (Error::InvalidGeometry("0x0".to_string()), 8),
+        (Error::ImageComparisonError("size".to_string()), 1),
+        (
+            Error::Context(
+                "loading a.png".to_string(),
+                Box::new(Error::PermissionDenied("a.png".into())),
+            ),
+            3,
+        ),
+    ] {
+        assert_eq!(error.to_exit_code(), code, "{error:?}");
+    }
+}
@@ -81,3 +81,452 @@ fn test_geometry() {
         }
     }
 }
+
+#[test]
+fn test_geometry_aspect_ratio() {
+    test_setup_logging();
+    assert_eq!(Geometry::new(100, 100).aspect_ratio(), Some(1.0));
+    assert_eq!(
+        Geometry::new(1920, 1080).aspect_ratio(),
+        Some(1920.0 / 1080.0)
+    );
+    assert_eq!(Geometry::new(450, 800).aspect_ratio(), Some(0.5625));
+    assert_eq!(Geometry::new(100, 0).aspect_ratio(), None);
+
+    let wide = Geometry::new(800, 600)
+        .aspect_ratio()
+        .expect("should have an aspect ratio");
+    assert!(wide > 1.0, "landscape geometry should be wider than tall");
+
+    for partial in [
+        Geometry {
+            width: Some(800),
+            height: None,
+        },
+        Geometry {
+            width: None,
+            height: Some(600),
+        },
+        Geometry::empty(),
+    ] {
+        assert_eq!(
+            partial.aspect_ratio(),
+            None,
+            "Expected no aspect ratio for {}",
+            partial
+        );
+    }
+}
+
+#[test]
+fn test_geometry_reduced_aspect_ratio() {
+    test_setup_logging();
+    assert_eq!(
+        Geometry::new(1920, 1080).reduced_aspect_ratio(),
+        Some((16, 9))
+    );
+    assert_eq!(Geometry::new(800, 600).reduced_aspect_ratio(), Some((4, 3)));
+    assert_eq!(
+        Geometry::new(450, 800).reduced_aspect_ratio(),
+        Some((9, 16))
+    );
+    assert_eq!(Geometry::new(500, 500).reduced_aspect_ratio(), Some((1, 1)));
+    assert_eq!(
+        Geometry::new(997, 991).reduced_aspect_ratio(),
+        Some((997, 991))
+    );
+    assert_eq!(Geometry::new(800, 0).reduced_aspect_ratio(), None);
+    assert_eq!(
+        Geometry {
+            width: Some(800),
+            height: None
+        }
+        .reduced_aspect_ratio(),
+        None
+    );
+    assert_eq!(Geometry::empty().reduced_aspect_ratio(), None);
+
+    // and it goes back to the same geometry
+    let (numerator, denominator) = Geometry::new(1280, 720)
+        .reduced_aspect_ratio()
+        .expect("should have an aspect ratio");
+    assert_eq!(
+        Geometry::from_aspect_ratio_and_width(numerator, denominator, 1280)
+            .expect("valid aspect ratio"),
+        Geometry::new(1280, 720)
+    );
+}
+
+#[test]
+fn test_geometry_comma_separator() {
+    test_setup_logging();
+    for (input, expected) in [
+        ("800,600", Some(Geometry::new(800, 600))),
+        (
+            "800,",
+            Some("800x".parse::<Geometry>().expect("valid geometry")),
+        ),
+        (
+            ",600",
+            Some("x600".parse::<Geometry>().expect("valid geometry")),
+        ),
+        ("800x600", Some(Geometry::new(800, 600))),
+        ("800,600,", None),
+        ("800,x600", None),
+        ("800x600,1", None),
+        (",", None),
+        ("800,abc", None),
+    ] {
+        match expected {
+            Some(geometry) => assert_eq!(
+                Geometry::from_str(input).expect("expected a valid geometry"),
+                geometry,
+                "wrong geometry for '{}'",
+                input
+            ),
+            None => assert!(
+                Geometry::from_str(input).is_err(),
+                "expected an error for '{}'",
+                input
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_geometry_from_aspect_ratio() {
+    test_setup_logging();
+    assert_eq!(
+        Geometry::from_aspect_ratio_and_width(16, 9, 1920).expect("valid aspect ratio"),
+        Geometry::new(1920, 1080)
+    );
+    assert_eq!(
+        Geometry::from_aspect_ratio_and_height(16, 9, 1080).expect("valid aspect ratio"),
+        Geometry::new(1920, 1080)
+    );
+    // 100 * 2 / 3 = 66.67, rounds up
+    assert_eq!(
+        Geometry::from_aspect_ratio_and_width(3, 2, 100).expect("valid aspect ratio"),
+        Geometry::new(100, 67)
+    );
+    assert!(Geometry::from_aspect_ratio_and_width(0, 9, 1920).is_err());
+    assert!(Geometry::from_aspect_ratio_and_width(16, 0, 1920).is_err());
+    assert!(Geometry::from_aspect_ratio_and_height(0, 9, 1080).is_err());
+    assert!(Geometry::from_aspect_ratio_and_width(1, u32::MAX, u32::MAX).is_err());
+
+    for (input, expected) in [
+        ("16:9@1920", Some(Geometry::new(1920, 1080))),
+        ("16:9@x1080", Some(Geometry::new(1920, 1080))),
+        ("4:3@800", Some(Geometry::new(800, 600))),
+        ("0:9@1920", None),
+        ("16:9@", None),
+        ("16@1920", None),
+        ("16:9@1920x", None),
+        ("a:9@1920", None),
+    ] {
+        match expected {
+            Some(geometry) => assert_eq!(
+                Geometry::from_str(input).expect("expected a valid geometry"),
+                geometry,
+                "wrong geometry for '{}'",
+                input
+            ),
+            None => assert!(
+                Geometry::from_str(input).is_err(),
+                "expected an error for '{}'",
+                input
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_geometry_scale_by() {
+    test_setup_logging();
+    assert_eq!(
+        Geometry::new(1330, 2364)
+            .scale_by(0.5)
+            .expect("valid scale factor"),
+        Geometry::new(665, 1182)
+    );
+    assert_eq!(
+        Geometry::new(1330, 2364)
+            .scale_by(1.0)
+            .expect("valid scale factor"),
+        Geometry::new(1330, 2364)
+    );
+    // 333 * 1.5 = 499.5, rounds up
+    assert_eq!(
+        Geometry::new(333, 100)
+            .scale_by(1.5)
+            .expect("valid scale factor"),
+        Geometry::new(500, 150)
+    );
+    let width_only = Geometry {
+        width: Some(800),
+        height: None,
+    };
+    assert_eq!(
+        width_only.scale_by(0.25).expect("valid scale factor"),
+        Geometry {
+            width: Some(200),
+            height: None,
+        }
+    );
+    assert_eq!(
+        Geometry::empty().scale_by(2.0).expect("valid scale factor"),
+        Geometry::empty()
+    );
+
+    for factor in [-1.0, 0.0, f32::NAN, f32::INFINITY] {
+        assert!(
+            Geometry::new(100, 100).scale_by(factor).is_err(),
+            "expected an error for {factor}"
+        );
+    }
+    assert!(Geometry::new(u32::MAX, 1).scale_by(2.0).is_err());
+    assert!(Geometry::new(10, 10).scale_by(0.01).is_err());
+}
+
+#[test]
+fn test_geometry_fits_within_and_clamp_to() {
+    test_setup_logging();
+    let dimensions = [None, Some(100), Some(300)];
+    let all_geometries = || {
+        dimensions.iter().flat_map(|width| {
+            dimensions.iter().map(|height| Geometry {
+                width: *width,
+                height: *height,
+            })
+        })
+    };
+    // None is unlimited
+    let fits = |dimension: Option<u32>, bound: Option<u32>| match (dimension, bound) {
+        (Some(dimension), Some(bound)) => dimension <= bound,
+        _ => true,
+    };
+    let min = |dimension: Option<u32>, bound: Option<u32>| match (dimension, bound) {
+        (Some(dimension), Some(bound)) => Some(dimension.min(bound)),
+        (dimension, bound) => dimension.or(bound),
+    };
+
+    for geometry in all_geometries() {
+        for bounds in all_geometries() {
+            assert_eq!(
+                geometry.fits_within(&bounds),
+                fits(geometry.width, bounds.width) && fits(geometry.height, bounds.height),
+                "fits_within({geometry}, {bounds})"
+            );
+            let clamped = geometry.clamp_to(&bounds);
+            assert_eq!(
+                clamped,
+                Geometry {
+                    width: min(geometry.width, bounds.width),
+                    height: min(geometry.height, bounds.height),
+                },
+                "clamp_to({geometry}, {bounds})"
+            );
+            assert!(
+                clamped.fits_within(&bounds),
+                "clamp_to({geometry}, {bounds}) = {clamped} should fit"
+            );
+        }
+    }
+
+    // and some spelled out
+    let bounds = Geometry::new(800, 600);
+    assert!(Geometry::new(800, 600).fits_within(&bounds));
+    assert!(!Geometry::new(801, 600).fits_within(&bounds));
+    assert!(!Geometry::new(800, 601).fits_within(&bounds));
+    assert!(
+        Geometry {
+            width: Some(5000),
+            height: None,
+        }
+        .fits_within(&Geometry {
+            width: None,
+            height: Some(10),
+        })
+    );
+    assert_eq!(
+        Geometry::new(1920, 400).clamp_to(&bounds),
+        Geometry::new(800, 400)
+    );
+    assert_eq!(
+        Geometry::empty().clamp_to(&bounds),
+        bounds,
+        "unset dimensions take the bound"
+    );
+    assert_eq!(
+        Geometry::new(1920, 1080).clamp_to(&Geometry::empty()),
+        Geometry::new(1920, 1080)
+    );
+}
+
+#[test]
+fn test_geometry_scale_to_fit_within() {
+    test_setup_logging();
+    let portrait = Geometry::new(450, 800);
+    let landscape = Geometry::new(800, 450);
+    let square = Geometry::new(500, 500);
+    for (geometry, bounds, expected) in [
+        (&portrait, Geometry::new(100, 100), Geometry::new(56, 100)),
+        (&portrait, Geometry::new(200, 100), Geometry::new(56, 100)),
+        (&portrait, Geometry::new(100, 300), Geometry::new(100, 178)),
+        (&landscape, Geometry::new(100, 100), Geometry::new(100, 56)),
+        (&landscape, Geometry::new(200, 100), Geometry::new(178, 100)),
+        (&landscape, Geometry::new(100, 300), Geometry::new(100, 56)),
+        (&square, Geometry::new(100, 100), Geometry::new(100, 100)),
+        (&square, Geometry::new(200, 100), Geometry::new(100, 100)),
+        (&square, Geometry::new(100, 300), Geometry::new(100, 100)),
+        // already smaller than the bounds, so it's scaled up
+        (&portrait, Geometry::new(900, 900), Geometry::new(506, 900)),
+        (
+            &landscape,
+            Geometry::new(1600, 1000),
+            Geometry::new(1600, 900),
+        ),
+        (&square, Geometry::new(1000, 600), Geometry::new(600, 600)),
+        // an exact fit stays put
+        (&portrait, Geometry::new(450, 800), Geometry::new(450, 800)),
+    ] {
+        let scaled = geometry.clone().scale_to_fit_within(&bounds);
+        assert_eq!(scaled, expected, "{geometry} in {bounds}");
+        assert!(scaled.fits_within(&bounds), "{scaled} should fit {bounds}");
+    }
+
+    // unset bounds are unlimited
+    let width_only = Geometry {
+        width: Some(90),
+        height: None,
+    };
+    assert_eq!(
+        portrait.clone().scale_to_fit_within(&width_only),
+        Geometry::new(90, 160)
+    );
+    let height_only = Geometry {
+        width: None,
+        height: Some(90),
+    };
+    assert_eq!(
+        landscape.clone().scale_to_fit_within(&height_only),
+        Geometry::new(160, 90)
+    );
+    assert_eq!(
+        square.clone().scale_to_fit_within(&Geometry::empty()),
+        square
+    );
+
+    // no aspect ratio to keep
+    assert_eq!(
+        Geometry::empty().scale_to_fit_within(&Geometry::new(100, 100)),
+        Geometry::new(100, 100)
+    );
+}
+
+#[test]
+fn test_geometry_scale_to_cover() {
+    test_setup_logging();
+    let portrait = Geometry::new(450, 800);
+    let landscape = Geometry::new(800, 450);
+    let square = Geometry::new(500, 500);
+    for (geometry, bounds, expected) in [
+        (&portrait, Geometry::new(100, 100), Geometry::new(100, 178)),
+        (&portrait, Geometry::new(200, 100), Geometry::new(200, 356)),
+        (&landscape, Geometry::new(100, 100), Geometry::new(178, 100)),
+        (&landscape, Geometry::new(100, 300), Geometry::new(534, 300)),
+        (&square, Geometry::new(200, 100), Geometry::new(200, 200)),
+        // an exact fit stays put
+        (&portrait, Geometry::new(450, 800), Geometry::new(450, 800)),
+    ] {
+        let scaled = geometry.clone().scale_to_cover(&bounds);
+        assert_eq!(scaled, expected, "{geometry} over {bounds}");
+        assert!(
+            bounds.fits_within(&scaled),
+            "{scaled} should cover {bounds}"
+        );
+    }
+
+    // unset bounds don't need covering
+    let width_only = Geometry {
+        width: Some(90),
+        height: None,
+    };
+    assert_eq!(
+        portrait.clone().scale_to_cover(&width_only),
+        Geometry::new(90, 160)
+    );
+    assert_eq!(square.clone().scale_to_cover(&Geometry::empty()), square);
+
+    // no aspect ratio to keep
+    assert_eq!(
+        Geometry::empty().scale_to_cover(&Geometry::new(100, 100)),
+        Geometry::new(100, 100)
+    );
+}
+
+#[test]
+fn test_geometry_union_and_intersection() {
+    test_setup_logging();
+    let dimensions = [None, Some(0), Some(100), Some(300)];
+    let all_geometries = || {
+        dimensions.iter().flat_map(|width| {
+            dimensions.iter().map(|height| Geometry {
+                width: *width,
+                height: *height,
+            })
+        })
+    };
+
+    for a in all_geometries() {
+        for b in all_geometries() {
+            assert_eq!(a.union(&b), b.union(&a), "union({a}, {b})");
+            assert_eq!(
+                a.intersection(&b),
+                b.intersection(&a),
+                "intersection({a}, {b})"
+            );
+            assert!(
+                a.fits_within(&a.union(&b)),
+                "{a} should fit in union with {b}"
+            );
+            if let Some(intersection) = a.intersection(&b) {
+                assert!(
+                    intersection.fits_within(&a) && intersection.fits_within(&b),
+                    "intersection({a}, {b}) = {intersection} should fit in both"
+                );
+            }
+        }
+    }
+
+    // and some spelled out
+    let width_only = Geometry {
+        width: Some(800),
+        height: None,
+    };
+    let height_only = Geometry {
+        width: None,
+        height: Some(600),
+    };
+    assert_eq!(width_only.union(&height_only), Geometry::new(800, 600));
+    assert_eq!(
+        width_only.intersection(&height_only),
+        Some(Geometry::new(800, 600))
+    );
+    assert_eq!(
+        Geometry::new(800, 400).union(&Geometry::new(640, 600)),
+        Geometry::new(800, 600)
+    );
+    assert_eq!(
+        Geometry::new(800, 400).intersection(&Geometry::new(640, 600)),
+        Some(Geometry::new(640, 400))
+    );
+    assert_eq!(
+        Geometry::new(0, 600).intersection(&Geometry::new(800, 600)),
+        None
+    );
+    assert_eq!(
+        Geometry::empty().union(&Geometry::empty()),
+        Geometry::empty()
+    );
+}
@@ -1,8 +1,11 @@
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use shrinky_rs::{
-    ImageFormat,
+    Error, ImageFormat, PngCompression, PngFilter, ResizeFilter, ToneMap,
     cli::test_setup_logging,
-    imagedata::{Geometry, Image},
+    imagedata::{
+        EncodeFn, EncodeOptions, Geometry, Image, fill_planes, fill_planes_high_bit_depth,
+        ftyp_brands, median_cut, scale_to_16_bits,
+    },
 };
 use std::io::Cursor;
 use std::path::PathBuf;
@@ -160,7 +163,7 @@ fn test_output_format() {
     );
 
     assert_eq!(
-        image.resize().expect("Failed to resize image"),
+        image.resized_image().expect("Failed to resize image"),
         image.image,
         "Resizing without changing geometry should be a no-op"
     );
@@ -170,7 +173,7 @@ fn test_output_format() {
         height: None,
     });
 
-    let resized_image = image.resize().expect("Failed to resize image");
+    let resized_image = image.resized_image().expect("Failed to resize image");
     assert!(
         resized_image != image.image,
         "Resizing with changed geometry should produce a different image"
@@ -192,15 +195,10 @@ fn test_output_format() {
 #[test]
 fn test_output_filename_never_jpeg() {
     test_setup_logging();
-    let base_image = Image {
-        original_file_size: 0,
-        input_filename: std::path::PathBuf::from("tests/test_images/sample.jpeg"),
-        original_geometry: Geometry::new(1, 1),
-        target_geometry: None,
-        output_format: None,
-        output_suffix: None,
-        image: image::DynamicImage::new_rgba8(1, 1),
-    };
+    let base_image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgba8(1, 1),
+        std::path::PathBuf::from("tests/test_images/sample.jpeg"),
+    );
 
     assert_eq!(
         base_image.output_filename(),
@@ -220,6 +218,69 @@ fn test_output_filename_never_jpeg() {
     );
 }
 
+#[test]
+fn test_will_overwrite_heif_family() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let input = tempdir.path().join("photo.heif");
+    std::fs::write(&input, b"heif").expect("failed to create photo.heif");
+    let image = Image {
+        output_format: Some(ImageFormat::Heic),
+        ..Image::from_dynamic_image(image::DynamicImage::new_rgba8(1, 1), input.clone())
+    };
+    // HEIC output gets its own extension, so a .heif input is left alone
+    let output = tempdir.path().join("photo.heic");
+    assert_eq!(image.output_filename(), output);
+    assert!(!image.will_overwrite_input());
+    assert!(!image.will_overwrite());
+    std::fs::write(&output, b"heic").expect("failed to create photo.heic");
+    assert!(image.will_overwrite());
+
+    let same = Image {
+        output_format: Some(ImageFormat::Heif),
+        ..image.clone()
+    };
+    assert_eq!(same.output_filename(), input);
+    assert!(same.will_overwrite_input());
+
+    // aliases are normalized through ImageFormat::all_extensions
+    let no_format = Image {
+        output_format: None,
+        input_filename: tempdir.path().join("scan.TIFF"),
+        ..image
+    };
+    assert_eq!(no_format.output_filename(), tempdir.path().join("scan.tif"));
+}
+
+#[test]
+fn test_will_overwrite_jpeg_input() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    for input_name in ["photo.jpeg", "photo.JPEG"] {
+        let image = Image {
+            output_format: Some(ImageFormat::Jpg),
+            ..Image::from_dynamic_image(
+                image::DynamicImage::new_rgba8(1, 1),
+                tempdir.path().join(input_name),
+            )
+        };
+        let output = tempdir.path().join("photo.jpg");
+        assert_eq!(image.output_filename(), output);
+        assert!(
+            !image.will_overwrite_input(),
+            "{input_name} is written to photo.jpg, not over itself"
+        );
+        assert!(!image.will_overwrite(), "photo.jpg doesn't exist yet");
+
+        std::fs::write(&output, b"existing").expect("failed to create photo.jpg");
+        assert!(
+            image.will_overwrite(),
+            "{input_name} with --output-type jpg should report overwriting photo.jpg"
+        );
+        std::fs::remove_file(&output).expect("failed to remove photo.jpg");
+    }
+}
+
 #[test]
 fn test_compare_identical_images() {
     test_setup_logging();
@@ -228,13 +289,11 @@ fn test_compare_identical_images() {
         image::Rgb([base, 255 - base, base / 2])
     }));
     let image = Image {
-        original_file_size: 0,
-        input_filename: PathBuf::from("tests/test_images/source-compare.jpg"),
-        original_geometry: Geometry::new(16, 16),
-        target_geometry: None,
         output_format: Some(ImageFormat::Jpg),
-        output_suffix: None,
-        image: source.clone(),
+        ..Image::from_dynamic_image(
+            source.clone(),
+            PathBuf::from("tests/test_images/source-compare.jpg"),
+        )
     };
 
     let mut encoded = Vec::new();
@@ -264,13 +323,11 @@ fn test_compare_degraded_image() {
         image::Rgb([base, 255 - base, base / 2])
     }));
     let image = Image {
-        original_file_size: 0,
-        input_filename: PathBuf::from("tests/test_images/source-compare.jpg"),
-        original_geometry: Geometry::new(16, 16),
-        target_geometry: None,
         output_format: Some(ImageFormat::Jpg),
-        output_suffix: None,
-        image: source.clone(),
+        ..Image::from_dynamic_image(
+            source.clone(),
+            PathBuf::from("tests/test_images/source-compare.jpg"),
+        )
     };
 
     let mut degraded = source.clone().to_rgb8();
@@ -298,13 +355,11 @@ fn test_compare_dimension_mismatch() {
         image::Rgb([base, 255 - base, base / 2])
     }));
     let image = Image {
-        original_file_size: 0,
-        input_filename: PathBuf::from("tests/test_images/source-compare.jpg"),
-        original_geometry: Geometry::new(16, 16),
-        target_geometry: None,
         output_format: Some(ImageFormat::Png),
-        output_suffix: None,
-        image: source,
+        ..Image::from_dynamic_image(
+            source,
+            PathBuf::from("tests/test_images/source-compare.jpg"),
+        )
     };
 
     let mut encoded = Vec::new();
@@ -365,13 +420,12 @@ fn test_cli_multiple_filenames() {
 fn test_output_filename_with_suffix() {
     test_setup_logging();
     let image = Image {
-        original_file_size: 0,
-        input_filename: std::path::PathBuf::from("tests/test_images/example.gif"),
-        original_geometry: Geometry::new(1, 1),
-        target_geometry: None,
         output_format: Some(ImageFormat::Jpg),
         output_suffix: Some("-foo".to_string()),
-        image: image::DynamicImage::new_rgba8(1, 1),
+        ..Image::from_dynamic_image(
+            image::DynamicImage::new_rgba8(1, 1),
+            std::path::PathBuf::from("tests/test_images/example.gif"),
+        )
     };
 
     assert_eq!(
@@ -380,3 +434,3103 @@ fn test_output_filename_with_suffix() {
         "Output filename should include suffix before extension"
     );
 }
+
+#[test]
+fn test_image_builder() {
+    use shrinky_rs::imagedata::ImageBuilder;
+
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+
+    let image = ImageBuilder::new(&img_path)
+        .with_target_geometry(Geometry::new(100, 200))
+        .with_output_format(ImageFormat::Webp)
+        .with_output_suffix(Some("-small".to_string()))
+        .build()
+        .expect("failed to build Image");
+
+    assert_eq!(image.input_filename, img_path);
+    assert_eq!(image.target_geometry, Some(Geometry::new(100, 200)));
+    assert_eq!(image.output_format, Some(ImageFormat::Webp));
+    assert_eq!(image.output_suffix, Some("-small".to_string()));
+    assert_eq!(
+        image.original_geometry,
+        Geometry::new(PNG_EXPECTED_WIDTH, PNG_EXPECTED_HEIGHT)
+    );
+    assert_eq!(image.final_geometry(), Geometry::new(100, 200));
+    assert_eq!(
+        image.output_filename(),
+        PathBuf::from(format!("tests/test_images/{}-small.webp", IMAGE_NAME))
+    );
+
+    assert!(
+        ImageBuilder::new("tests/test_images/does-not-exist.png")
+            .build()
+            .is_err(),
+        "Building from a missing file should fail"
+    );
+}
+
+#[test]
+fn test_lazy_image_metadata() {
+    use shrinky_rs::imagedata::LazyImage;
+
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Jpg.extension()
+    ));
+
+    let lazy = LazyImage::new(&img_path);
+    let info = lazy.metadata().expect("failed to read image metadata");
+    assert_eq!(info.format, ImageFormat::Jpg);
+    assert_eq!(
+        info.geometry,
+        Geometry::new(JPG_EXPECTED_WIDTH, JPG_EXPECTED_HEIGHT)
+    );
+    assert!(info.file_size > 0, "file size should be read from disk");
+
+    let image = lazy.decode().expect("failed to decode lazy image");
+    assert_eq!(image.original_geometry, info.geometry);
+    assert_eq!(image.original_file_size, info.file_size);
+
+    // metadata only reads the header, so a file cut off part way through its pixels still has
+    // metadata but can't be decoded (benches/lazy_image.rs compares how long each takes)
+    let png = std::fs::read(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ))
+    .expect("failed to read fixture");
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let truncated = tempdir.path().join("truncated.png");
+    std::fs::write(&truncated, &png[..png.len() / 2]).expect("failed to write truncated PNG");
+    let lazy = LazyImage::new(&truncated);
+    let info = lazy.metadata().expect("the header is all metadata needs");
+    assert_eq!(info.format, ImageFormat::Png);
+    assert_eq!(info.file_size, (png.len() / 2) as u64);
+    assert!(
+        lazy.decode().is_err(),
+        "decoding a truncated file should fail"
+    );
+
+    assert!(
+        LazyImage::new("tests/test_images/does-not-exist.jpg")
+            .metadata()
+            .is_err(),
+        "metadata for a missing file should fail"
+    );
+}
+
+#[test]
+fn test_heic_preserves_alpha() {
+    test_setup_logging();
+    let source = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(32, 32, |x, _y| {
+        // left half opaque, right half half-transparent
+        let alpha = if x < 16 { 255 } else { 128 };
+        image::Rgba([200, 100, 50, alpha])
+    }));
+    let image = Image {
+        output_format: Some(ImageFormat::Heic),
+        ..Image::from_dynamic_image(source, PathBuf::from("tests/test_images/transparent.png"))
+    };
+
+    let encoded = image
+        .output_as_format(ImageFormat::Heic)
+        .expect("failed to encode HEIC");
+
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    let decoded = image::load_from_memory(&encoded).expect("failed to decode HEIC output");
+    assert!(
+        decoded.color().has_alpha(),
+        "decoded HEIC should still have an alpha channel"
+    );
+    let decoded = decoded.to_rgba8();
+    assert_eq!(decoded.get_pixel(0, 0).0[3], 255);
+    assert!(
+        decoded.get_pixel(31, 31).0[3] < 255,
+        "half-transparent pixels should survive the round trip"
+    );
+}
+
+#[test]
+fn test_resize_updates_current_geometry() {
+    test_setup_logging();
+    let mut image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(200, 100),
+        PathBuf::from("tests/test_images/resize.png"),
+    );
+    assert_eq!(image.current_geometry(), Geometry::new(200, 100));
+
+    image = image.with_target_geometry(Geometry {
+        width: Some(100),
+        height: None,
+    });
+    assert_eq!(
+        image.current_geometry(),
+        Geometry::new(200, 100),
+        "setting a target geometry shouldn't change the current geometry"
+    );
+    assert_eq!(image.final_geometry(), Geometry::new(100, 50));
+    image.resize().expect("failed to resize image");
+    assert_eq!(image.current_geometry(), Geometry::new(100, 50));
+
+    image = image.with_target_geometry(Geometry::new(40, 40));
+    image.resize().expect("failed to resize image");
+    assert_eq!(image.current_geometry(), Geometry::new(40, 40));
+    assert_eq!(
+        image.original_geometry,
+        Geometry::new(200, 100),
+        "original geometry should be untouched by resizing"
+    );
+}
+
+#[test]
+fn test_resize_filter() {
+    test_setup_logging();
+    // 4x4 black and white checkerboard, upscaled 4 times
+    let checkerboard = image::DynamicImage::ImageLuma8(image::GrayImage::from_fn(4, 4, |x, y| {
+        image::Luma([if (x + y) % 2 == 0 { 0 } else { 255 }])
+    }));
+    let mut image = Image {
+        target_geometry: Some(Geometry::new(16, 16)),
+        ..Image::from_dynamic_image(
+            checkerboard,
+            PathBuf::from("tests/test_images/pixel-art.png"),
+        )
+    };
+    assert_eq!(image.filter, ResizeFilter::Lanczos3);
+    let count_shades = |image: &image::DynamicImage| {
+        image
+            .to_luma8()
+            .pixels()
+            .map(|pixel| pixel.0[0])
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    };
+    let smooth = image.resized_image().expect("failed to resize");
+    assert!(count_shades(&smooth) > 2, "Lanczos3 should blend the edges");
+
+    image = image.with_filter(ResizeFilter::Nearest);
+    let sharp = image.resized_image().expect("failed to resize");
+    assert_eq!(sharp.width(), 16);
+    assert_eq!(count_shades(&sharp), 2, "nearest should keep hard edges");
+    assert_eq!(sharp.to_luma8().get_pixel(3, 3).0[0], 0);
+    assert_eq!(sharp.to_luma8().get_pixel(4, 3).0[0], 255);
+
+    let image = Image::builder(format!("tests/test_images/{IMAGE_NAME}.png"))
+        .with_filter(ResizeFilter::Gaussian)
+        .build()
+        .expect("failed to load PNG fixture");
+    assert_eq!(image.filter, ResizeFilter::Gaussian);
+
+    for (arg, filter) in [
+        ("nearest", ResizeFilter::Nearest),
+        ("triangle", ResizeFilter::Triangle),
+        ("catmullrom", ResizeFilter::CatmullRom),
+        ("gaussian", ResizeFilter::Gaussian),
+        ("lanczos3", ResizeFilter::Lanczos3),
+    ] {
+        let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--filter", arg, "a.png"])
+            .expect("failed to parse --filter");
+        assert_eq!(cli.filter, filter);
+    }
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "a.png"])
+        .expect("failed to parse arguments");
+    assert_eq!(cli.filter, ResizeFilter::Lanczos3);
+}
+
+#[test]
+fn test_builder_overwrite_if_smaller() {
+    test_setup_logging();
+    let path = format!("tests/test_images/{IMAGE_NAME}.png");
+    let image = Image::builder(&path)
+        .build()
+        .expect("failed to load PNG fixture");
+    assert!(!image.overwrite_if_smaller);
+    assert!(image.should_write(image.original_file_size as usize));
+
+    let image = Image::builder(&path)
+        .with_overwrite_if_smaller(true)
+        .build()
+        .expect("failed to load PNG fixture");
+    assert!(image.overwrite_if_smaller);
+    assert!(!image.should_write(image.original_file_size as usize));
+    assert!(image.should_write(image.original_file_size as usize - 1));
+}
+
+#[test]
+fn test_resize_then_encode_uses_resized_image() {
+    test_setup_logging();
+    let mut image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(200, 100),
+        PathBuf::from("tests/test_images/resize.png"),
+    )
+    .with_target_geometry(Geometry::new(60, 30));
+    image.resize().expect("failed to resize image");
+
+    for format in [ImageFormat::Jpg, ImageFormat::Png, ImageFormat::Webp] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode resized {format}: {e:?}"));
+        let decoded = image::load_from_memory(&encoded)
+            .unwrap_or_else(|e| panic!("failed to decode resized {format}: {e:?}"));
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (60, 30),
+            "{format} output should have the resized dimensions"
+        );
+    }
+}
+
+#[test]
+fn test_avif_quality_changes_output_size() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let low = image
+        .clone()
+        .with_avif_quality(20)
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode low quality AVIF");
+    let high = image
+        .with_avif_quality(95)
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode high quality AVIF");
+
+    assert!(
+        low.len() < high.len(),
+        "AVIF at quality 20 ({} bytes) should be smaller than at quality 95 ({} bytes)",
+        low.len(),
+        high.len()
+    );
+}
+
+#[test]
+fn test_cli_avif_quality() {
+    use shrinky_rs::cli::Cli;
+
+    let cli = Cli::parse_from([
+        "shrinky-rs",
+        "--avif-quality",
+        "40",
+        "tests/test_images/bruny-oysters.jpg",
+    ]);
+    assert_eq!(cli.avif_quality, Some(40));
+
+    let cli = Cli::parse_from([
+        "shrinky-rs",
+        "--quality",
+        "60",
+        "tests/test_images/bruny-oysters.jpg",
+    ]);
+    assert_eq!(cli.quality, Some(60));
+
+    for arg in ["--quality", "--avif-quality"] {
+        let err = Cli::try_parse_from([
+            "shrinky-rs",
+            arg,
+            "101",
+            "tests/test_images/bruny-oysters.jpg",
+        ])
+        .expect_err("quality above 100 should be rejected by the argument parser");
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+}
+
+#[test]
+fn test_cli_effort_and_bit_depth() {
+    use shrinky_rs::cli::Cli;
+
+    let cli = Cli::parse_from([
+        "shrinky-rs",
+        "--effort",
+        "9",
+        "--bit-depth",
+        "12",
+        "tests/test_images/bruny-oysters.jpg",
+    ]);
+    assert_eq!((cli.effort, cli.bit_depth), (Some(9), Some(12)));
+
+    // rejected once while parsing, rather than for every input file
+    for (arg, value) in [
+        ("--effort", "10"),
+        ("--bit-depth", "9"),
+        ("--bit-depth", "16"),
+    ] {
+        let err = Cli::try_parse_from([
+            "shrinky-rs",
+            arg,
+            value,
+            "tests/test_images/bruny-oysters.jpg",
+        ])
+        .expect_err("out of range values should be rejected by the argument parser");
+        assert_eq!(
+            err.kind(),
+            clap::error::ErrorKind::ValueValidation,
+            "{arg} {value}"
+        );
+    }
+}
+
+#[test]
+fn test_quality_changes_output_size() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    for fmt in [ImageFormat::Jpg, ImageFormat::Heic] {
+        let low = image
+            .clone()
+            .with_quality(30)
+            .output_as_format(fmt)
+            .expect("failed to encode at quality 30");
+        let high = image
+            .clone()
+            .with_quality(95)
+            .output_as_format(fmt)
+            .expect("failed to encode at quality 95");
+        assert!(
+            low.len() < high.len(),
+            "{} at quality 30 ({} bytes) should be smaller than at quality 95 ({} bytes)",
+            fmt,
+            low.len(),
+            high.len()
+        );
+    }
+}
+
+#[test]
+fn test_quality_out_of_range() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(8, 8),
+        PathBuf::from("tests/test_images/quality.png"),
+    )
+    .with_quality(101);
+
+    for fmt in [ImageFormat::Jpg, ImageFormat::Heic, ImageFormat::Avif] {
+        match image.output_as_format(fmt) {
+            Err(shrinky_rs::Error::InvalidOptions(_)) => {}
+            other => panic!("Expected InvalidOptions for {}, got {:?}", fmt, other),
+        }
+    }
+}
+
+#[test]
+fn test_passthrough_same_format() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Jpg.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    assert!(image.is_passthrough_eligible(ImageFormat::Jpg));
+    assert!(!image.is_passthrough_eligible(ImageFormat::Png));
+
+    let output = image
+        .output_as_format(ImageFormat::Jpg)
+        .expect("failed to output JPG");
+    let original = std::fs::read(&img_path).expect("failed to read original file");
+    assert!(
+        output == original,
+        "JPG to JPG with no changes should return the original bytes"
+    );
+
+    assert!(
+        !image
+            .clone()
+            .with_quality(50)
+            .is_passthrough_eligible(ImageFormat::Jpg),
+        "a quality override should force a re-encode"
+    );
+    assert!(
+        !image
+            .with_target_geometry(Geometry::new(10, 10))
+            .is_passthrough_eligible(ImageFormat::Jpg),
+        "a geometry change should force a re-encode"
+    );
+}
+
+#[test]
+fn test_heif_codec_matches_extension() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let heic = image
+        .output_as_format(ImageFormat::Heic)
+        .expect("failed to encode HEIC");
+    let brands = ftyp_brands(&heic);
+    assert_eq!(
+        brands.first(),
+        Some(b"heic"),
+        "HEIC output should carry HEVC data"
+    );
+    assert!(!brands.contains(b"avif"));
+
+    let avif = image
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode AVIF");
+    let brands = ftyp_brands(&avif);
+    assert!(
+        brands.contains(b"avif"),
+        "AVIF output should have the avif brand"
+    );
+    assert!(
+        !brands.contains(b"heic"),
+        "AVIF output should not carry HEVC data"
+    );
+
+    assert_ne!(heic, avif, "HEIC and AVIF output should differ");
+}
+
+#[test]
+fn test_tile_encode_and_merge() {
+    test_setup_logging();
+    let source = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(50, 30, |x, y| {
+        image::Rgb([(x * 5) as u8, (y * 8) as u8, (x + y) as u8])
+    }));
+    let image =
+        Image::from_dynamic_image(source.clone(), PathBuf::from("tests/test_images/tiles.png"));
+
+    let tiles = image
+        .tile_encode(20, 16, ImageFormat::Png)
+        .expect("failed to tile encode image");
+    assert_eq!(tiles.len(), 6, "50x30 in 20x16 tiles should be a 3x2 grid");
+
+    let tile_refs: Vec<&[u8]> = tiles.iter().map(|tile| tile.as_slice()).collect();
+    let merged = Image::merge_tiles(&tile_refs, 3, 2).expect("failed to merge tiles");
+    assert_eq!(merged.current_geometry(), Geometry::new(50, 30));
+    assert_eq!(merged.image.to_rgb8(), source.to_rgb8());
+
+    assert!(image.tile_encode(0, 16, ImageFormat::Png).is_err());
+    assert!(Image::merge_tiles(&tile_refs, 2, 2).is_err());
+    // 65536 * 65536 wraps to 0 in u32, which would let no tiles through
+    assert!(matches!(
+        Image::merge_tiles(&[], 65536, 65536),
+        Err(shrinky_rs::Error::InvalidGeometry(_))
+    ));
+}
+
+#[test]
+fn test_fill_planes_matches_sequential_copy() {
+    let (width, height, stride) = (7usize, 5usize, 9usize);
+    let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i % 251) as u8).collect();
+
+    // the straightforward per-pixel version this replaced
+    let mut expected = vec![vec![0u8; stride * height]; 4];
+    for y in 0..height {
+        for x in 0..width {
+            for (channel, plane) in expected.iter_mut().enumerate() {
+                plane[y * stride + x] = rgba[(y * width + x) * 4 + channel];
+            }
+        }
+    }
+
+    let mut actual = vec![vec![0u8; stride * height]; 4];
+    fill_planes(
+        &rgba,
+        width,
+        actual
+            .iter_mut()
+            .map(|plane| (plane.as_mut_slice(), stride))
+            .collect(),
+    );
+    assert_eq!(actual, expected);
+
+    // without an alpha plane only the first three channels are copied
+    let mut rgb_only = vec![vec![0u8; stride * height]; 3];
+    fill_planes(
+        &rgba,
+        width,
+        rgb_only
+            .iter_mut()
+            .map(|plane| (plane.as_mut_slice(), stride))
+            .collect(),
+    );
+    assert_eq!(rgb_only, expected[..3]);
+}
+
+#[test]
+fn test_fill_planes_high_bit_depth_scales_samples() {
+    let (width, height, stride) = (3usize, 2usize, 8usize);
+    let rgba: Vec<u16> = (0..width * height * 4).map(|i| (i as u16) * 2749).collect();
+
+    for bit_depth in [10u8, 12] {
+        let mut planes = vec![vec![0u8; stride * height]; 4];
+        fill_planes_high_bit_depth(
+            &rgba,
+            width,
+            bit_depth,
+            planes
+                .iter_mut()
+                .map(|plane| (plane.as_mut_slice(), stride))
+                .collect(),
+        );
+        for y in 0..height {
+            for x in 0..width {
+                for (channel, plane) in planes.iter().enumerate() {
+                    let offset = y * stride + x * 2;
+                    let sample = u16::from_ne_bytes([plane[offset], plane[offset + 1]]);
+                    assert_eq!(
+                        sample,
+                        rgba[(y * width + x) * 4 + channel] >> (16 - bit_depth)
+                    );
+                    assert!(sample < 1 << bit_depth);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_scale_to_16_bits() {
+    for bit_depth in [10u8, 12] {
+        let max = (1u16 << bit_depth) - 1;
+        assert_eq!(scale_to_16_bits(0, bit_depth), 0);
+        assert_eq!(scale_to_16_bits(max, bit_depth), u16::MAX);
+        // scaling back down gives the original sample
+        for sample in 0..=max {
+            assert_eq!(
+                scale_to_16_bits(sample, bit_depth) >> (16 - bit_depth),
+                sample
+            );
+        }
+    }
+    assert_eq!(scale_to_16_bits(512, 10), 0x8020);
+    assert_eq!(scale_to_16_bits(0x1234, 16), 0x1234);
+}
+
+#[test]
+fn test_median_cut_splits_clusters() {
+    let colors = vec![
+        ([250, 0, 0], 10),
+        ([255, 5, 0], 30),
+        ([0, 0, 250], 5),
+        ([0, 10, 255], 5),
+    ];
+    assert_eq!(median_cut(colors.clone(), 1), vec![[203, 4, 51]]);
+    assert_eq!(
+        median_cut(colors.clone(), 2),
+        vec![[254, 4, 0], [0, 5, 253]]
+    );
+    // can't make more colors than there are
+    assert_eq!(median_cut(colors, 10).len(), 4);
+}
+
+#[test]
+fn test_ftyp_brands() {
+    test_setup_logging();
+    let mut data = Vec::new();
+    data.extend_from_slice(&24u32.to_be_bytes());
+    data.extend_from_slice(b"ftypavif");
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(b"mif1miaf");
+    data.extend_from_slice(b"trailing data that isn't a brand");
+
+    assert_eq!(ftyp_brands(&data), vec![*b"avif", *b"mif1", *b"miaf"]);
+    assert!(ftyp_brands(b"\x89PNG\r\n\x1a\n").is_empty());
+    assert!(ftyp_brands(&[]).is_empty());
+
+    assert_eq!(ImageFormat::Avif.heif_brand(), Some(b"avif"));
+    assert_eq!(ImageFormat::Heic.heif_brand(), Some(b"heic"));
+    assert_eq!(ImageFormat::Png.heif_brand(), None);
+}
+
+#[test]
+fn test_auto_format_phased() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(image::DynamicImage::new_rgb8(8, 8), "phased.png");
+    // every format encodes to the same size, recording which formats were tried
+    let tried = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let same_size = {
+        let tried = std::sync::Arc::clone(&tried);
+        move |_: &Image, format: ImageFormat, _: &EncodeOptions| {
+            tried.lock().expect("poisoned lock").push(format);
+            Ok(vec![0; 100])
+        }
+    };
+
+    // under the threshold phase 2 is never started
+    let (format, data) = image
+        .auto_format_phased_using(100, &same_size)
+        .expect("failed to run phased auto format");
+    assert!(format.is_native_image_format(), "got {format}");
+    assert_eq!(data.len(), 100);
+    let phase_one = std::mem::take(&mut *tried.lock().expect("poisoned lock"));
+    assert!(!phase_one.is_empty());
+    assert!(
+        phase_one.iter().all(ImageFormat::is_native_image_format),
+        "phase 2 shouldn't run under the threshold: {phase_one:?}"
+    );
+
+    // over it phase 2 runs, and a tie goes to the format that usually compresses better, like
+    // it does in a single-phase run
+    let (format, _) = image
+        .auto_format_phased_using(99, &same_size)
+        .expect("failed to run phased auto format");
+    let both_phases = tried.lock().expect("poisoned lock").clone();
+    assert!(
+        both_phases
+            .iter()
+            .any(|format| !format.is_native_image_format()),
+        "phase 2 should run over the threshold: {both_phases:?}"
+    );
+    assert_eq!(Some(&format), both_phases.iter().max());
+}
+
+#[test]
+fn test_encoder_panics_are_caught() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(image::DynamicImage::new_rgb8(8, 8), "panic.png");
+    let png_panics = |image: &Image, format: ImageFormat, options: &EncodeOptions| {
+        if format == ImageFormat::Png {
+            panic!("mock {format} encoder exploded");
+        }
+        image.output_with_options(format, options)
+    };
+    let (format, _, attempts) = image
+        .smallest_encoding_with(vec![ImageFormat::Png, ImageFormat::Webp], &png_panics)
+        .expect("WebP should still work when the PNG encoder panics");
+    assert_eq!(format, ImageFormat::Webp);
+    let png_error = attempts
+        .into_iter()
+        .find(|attempt| attempt.format == ImageFormat::Png)
+        .and_then(|attempt| attempt.error)
+        .unwrap_or_default();
+    assert!(png_error.contains("encoder panicked"), "{png_error}");
+    assert!(
+        png_error.contains("mock png encoder exploded"),
+        "{png_error}"
+    );
+
+    let all_panic = |_: &Image, format: ImageFormat, _: &EncodeOptions| -> Result<Vec<u8>, Error> {
+        panic!("mock {format} encoder exploded")
+    };
+    assert!(matches!(
+        image.smallest_encoding_with(vec![ImageFormat::Png, ImageFormat::Webp], &all_panic),
+        Err(Error::AllFormatsFailedEncoding(errors)) if errors.len() == 2
+    ));
+}
+
+#[test]
+fn test_fallback_to_original_format() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(8, 8),
+        "tests/test_images/bruny-oysters.png",
+    );
+    let all_panic = |_: &Image, format: ImageFormat, _: &EncodeOptions| -> Result<Vec<u8>, Error> {
+        panic!("mock {format} encoder exploded")
+    };
+    let result = image
+        .smallest_encoding_with(ImageFormat::all(), &all_panic)
+        .map(|(format, data, _)| (format, data));
+
+    assert!(
+        matches!(result, Err(Error::AllFormatsFailedEncoding(_))),
+        "expected every format to fail, got {result:?}"
+    );
+    let fallback = result.or_else(|err| image.fallback_to_original_format(err));
+    assert!(
+        matches!(&fallback, Ok((ImageFormat::Png, data)) if !data.is_empty()),
+        "expected a PNG fallback, got {fallback:?}"
+    );
+
+    let unknown = Image {
+        input_filename: "does-not-exist.bin".into(),
+        ..image
+    };
+    assert!(matches!(
+        unknown.fallback_to_original_format(Error::AllFormatsFailedEncoding(Vec::new())),
+        Err(Error::AllFormatsFailedEncoding(_))
+    ));
+}
+
+#[test]
+fn test_auto_format_with_timeout() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let mut image = Image::try_from(&img_path)
+        .expect("failed to load Image from path")
+        .with_target_geometry(Geometry {
+            width: Some(200),
+            height: None,
+        });
+    image.resize().expect("failed to resize image");
+
+    // the encoders are held until the call has returned, so none of them can finish in time and
+    // it falls back to the input format
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let released = std::sync::Mutex::new(released);
+    let blocking_encoder: std::sync::Arc<EncodeFn> =
+        std::sync::Arc::new(move |image: &Image, format, options: &EncodeOptions| {
+            if let Ok(released) = released.lock() {
+                let _ = released.recv_timeout(std::time::Duration::from_secs(60));
+            }
+            image.output_with_options(format, options)
+        });
+    let (format, data) = image
+        .auto_format_with_timeout_using(std::time::Duration::from_millis(10), &blocking_encoder)
+        .expect("failed to run auto format with a timeout");
+    drop(release);
+    assert_eq!(format, ImageFormat::Png);
+    assert!(!data.is_empty());
+
+    // with plenty of time it should match the full search
+    let (format, data) = image
+        .auto_format_with_timeout(std::time::Duration::from_secs(600))
+        .expect("failed to run auto format with a timeout");
+    let (full_format, full_data) = image.auto_format().expect("failed to run auto format");
+    assert_eq!(format, full_format);
+    assert_eq!(data.len(), full_data.len());
+}
+
+#[test]
+fn test_scale_to_megapixels() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load Image from path");
+    let (width, height) = (image.image.width(), image.image.height());
+
+    // the fixture is 450x800, about 0.36 MP, so try both directions
+    for target_mp in [0.1, 1.0] {
+        let mut scaled = image
+            .scale_to_megapixels(target_mp)
+            .expect("failed to scale to megapixels");
+        scaled.resize().expect("failed to resize image");
+        let (new_width, new_height) = (scaled.image.width(), scaled.image.height());
+        let pixels = f64::from(new_width) * f64::from(new_height);
+        let target = target_mp * 1_000_000.0;
+        assert!(
+            (pixels - target).abs() / target < 0.05,
+            "{new_width}x{new_height} isn't within 5% of {target_mp} MP"
+        );
+        let aspect = f64::from(width) / f64::from(height);
+        let new_aspect = f64::from(new_width) / f64::from(new_height);
+        assert!((aspect - new_aspect).abs() < 0.01, "aspect ratio changed");
+    }
+    assert_eq!(image.current_geometry(), Geometry::new(width, height));
+
+    for target_mp in [0.0, -1.0, f64::NAN] {
+        assert!(matches!(
+            image.scale_to_megapixels(target_mp),
+            Err(shrinky_rs::Error::InvalidOptions(_))
+        ));
+    }
+}
+
+#[test]
+fn test_downscale_to_megapixels_if_needed() {
+    test_setup_logging();
+    let mut image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load Image from path");
+    let (width, height) = (image.image.width(), image.image.height());
+
+    // the fixture is 450x800, about 0.36 MP
+    assert!(
+        !image
+            .downscale_to_megapixels_if_needed(5.0)
+            .expect("failed to check megapixels")
+    );
+    assert_eq!(image.target_geometry, None);
+    assert!(
+        !image
+            .downscale_to_megapixels_if_needed(0.36)
+            .expect("failed to check megapixels")
+    );
+
+    assert!(
+        image
+            .downscale_to_megapixels_if_needed(0.2)
+            .expect("failed to downscale")
+    );
+    image.resize().expect("failed to resize image");
+    let (new_width, new_height) = (image.image.width(), image.image.height());
+    let pixels = u64::from(new_width) * u64::from(new_height);
+    assert!(pixels <= 200_000, "{new_width}x{new_height} is over 0.2 MP");
+    assert!(pixels > 190_000, "{new_width}x{new_height} shrank too far");
+    let aspect = f64::from(width) / f64::from(height);
+    let new_aspect = f64::from(new_width) / f64::from(new_height);
+    assert!((aspect - new_aspect).abs() < 0.01, "aspect ratio changed");
+
+    // already small enough now
+    assert!(
+        !image
+            .downscale_to_megapixels_if_needed(0.2)
+            .expect("failed to check megapixels")
+    );
+    assert!(matches!(
+        image.downscale_to_megapixels_if_needed(0.0),
+        Err(shrinky_rs::Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn test_save_to_path() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load Image from path");
+    let leftovers = || -> Vec<String> {
+        std::fs::read_dir(tempdir.path())
+            .expect("failed to list tempdir")
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect()
+    };
+
+    let path = tempdir.path().join("saved.jpg");
+    let written = image
+        .save_to_path(&path, ImageFormat::Jpg)
+        .expect("failed to save image");
+    let data = std::fs::read(&path).expect("failed to read saved image");
+    assert_eq!(written, data.len());
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Jpg
+    );
+    assert_eq!(leftovers(), ["saved.jpg"]);
+
+    // renaming over a directory fails, and the temporary file goes with it
+    let blocked = tempdir.path().join("blocked.jpg");
+    std::fs::create_dir(&blocked).expect("failed to create directory");
+    assert!(image.save_to_path(&blocked, ImageFormat::Jpg).is_err());
+    let mut names = leftovers();
+    names.sort();
+    assert_eq!(names, ["blocked.jpg", "saved.jpg"]);
+
+    // nothing is written if encoding fails
+    let path = tempdir.path().join("icon.ico");
+    assert!(matches!(
+        image.save_to_path(&path, ImageFormat::Ico),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert!(!path.exists());
+    assert_eq!(leftovers().len(), 2);
+}
+
+#[test]
+fn test_size_reduction() {
+    let mut image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load Image from path");
+    image.original_file_size = 1000;
+    assert_eq!(image.size_reduction(500), Some(50.0));
+    assert_eq!(image.size_reduction(1000), Some(0.0));
+    assert_eq!(image.size_reduction(1500), Some(-50.0));
+
+    image.original_file_size = 0;
+    assert_eq!(image.size_reduction(500), None);
+}
+
+#[test]
+fn test_auto_format_respect_original_size() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    // pretend the original was tiny, so nothing can beat it
+    let tiny_original = Image {
+        original_file_size: 10,
+        ..image.clone()
+    };
+    assert!(
+        tiny_original.auto_format_phased().is_ok(),
+        "without respect_original_size a larger result is still returned"
+    );
+    match tiny_original
+        .with_respect_original_size(true)
+        .auto_format_phased()
+    {
+        Err(shrinky_rs::Error::ImageEncodingError(msg)) => {
+            assert_eq!(msg, "all formats larger than original")
+        }
+        other => panic!("Expected ImageEncodingError, got {:?}", other),
+    }
+
+    // the real PNG is easily beaten by a lossy format
+    let (_, data) = image
+        .with_respect_original_size(true)
+        .auto_format_phased()
+        .expect("should find something smaller than the original PNG");
+    assert!(data.len() < 547_762);
+}
+
+#[test]
+fn test_lossless_avif_round_trip() {
+    test_setup_logging();
+    let source = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 24, |x, y| {
+        image::Rgb([(x * 7) as u8, (y * 9) as u8, ((x * y) % 256) as u8])
+    }));
+    let image = Image::from_dynamic_image(
+        source.clone(),
+        PathBuf::from("tests/test_images/lossless.png"),
+    )
+    .with_lossless(true);
+
+    let encoded = image
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode lossless AVIF");
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    let decoded = image::load_from_memory(&encoded).expect("failed to decode lossless AVIF");
+    assert_eq!(
+        decoded.to_rgb8(),
+        source.to_rgb8(),
+        "lossless AVIF should round trip exactly"
+    );
+}
+
+#[test]
+fn test_lossless_excludes_jpg() {
+    test_setup_logging();
+    let image = Image {
+        encode_options: shrinky_rs::imagedata::EncodeOptions {
+            lossless: true,
+            ..Default::default()
+        },
+        ..Image::from_dynamic_image(
+            image::DynamicImage::new_rgb8(8, 8),
+            PathBuf::from("tests/test_images/lossless.png"),
+        )
+    };
+
+    match image.output_as_format(ImageFormat::Jpg) {
+        Err(shrinky_rs::Error::InvalidOptions(_)) => {}
+        other => panic!("Expected InvalidOptions for lossless JPG, got {:?}", other),
+    }
+    assert!(
+        image.output_as_format(ImageFormat::Png).is_ok(),
+        "PNG is always lossless so the flag is ignored"
+    );
+
+    let (format, _) = image
+        .auto_format_phased()
+        .expect("failed to auto format losslessly");
+    assert_ne!(format, ImageFormat::Jpg);
+}
+
+#[test]
+fn test_heif_high_bit_depth_gradient() {
+    test_setup_logging();
+    let (width, height) = (1024u32, 4u32);
+    let source =
+        image::DynamicImage::ImageRgb16(image::ImageBuffer::from_fn(width, height, |x, _y| {
+            let level = (x * 64) as u16;
+            image::Rgb([level, level, level])
+        }));
+    let image = Image {
+        output_format: Some(ImageFormat::Avif),
+        ..Image::from_dynamic_image(source, PathBuf::from("tests/test_images/gradient16.png"))
+    };
+
+    let encoded = image
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode 16-bit gradient");
+
+    let lib_heif = libheif_rs::LibHeif::new();
+    let context =
+        libheif_rs::HeifContext::read_from_bytes(&encoded).expect("failed to read AVIF output");
+    let handle = context
+        .primary_image_handle()
+        .expect("failed to get primary image");
+    assert_eq!(
+        handle.luma_bits_per_pixel(),
+        10,
+        "16-bit source should encode as 10-bit"
+    );
+    let decoded = lib_heif
+        .decode(
+            &handle,
+            libheif_rs::ColorSpace::Rgb(libheif_rs::RgbChroma::HdrRgbLe),
+            None,
+        )
+        .expect("failed to decode AVIF output");
+    let planes = decoded.planes();
+    let interleaved = planes.interleaved.expect("expected interleaved RGB data");
+    let scanline = &interleaved.data[..width as usize * 6];
+    let levels: std::collections::HashSet<u16> = scanline
+        .chunks_exact(6)
+        .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+        .collect();
+    assert!(
+        levels.len() > 256,
+        "expected more than 256 distinct levels, got {}",
+        levels.len()
+    );
+
+    assert!(
+        image
+            .clone()
+            .with_bit_depth(9)
+            .output_as_format(ImageFormat::Avif)
+            .is_err(),
+        "unsupported bit depths should be rejected"
+    );
+}
+
+#[test]
+fn test_heic_10_bit_input_keeps_its_depth() {
+    test_setup_logging();
+    // 1024x8 gray ramp, each column one 10-bit level brighter than the last
+    let path = PathBuf::from("tests/test_images/ramp-10bit.heic");
+    let image = Image::try_from(&path).expect("failed to load 10-bit HEIC");
+    assert_eq!(image.original_geometry, Geometry::new(1024, 8));
+    assert_eq!(image.image.color(), image::ColorType::Rgb16);
+
+    let row: Vec<u16> = (0..1024)
+        .map(|x| {
+            image
+                .image
+                .as_rgb16()
+                .expect("expected RGB16")
+                .get_pixel(x, 4)
+                .0[1]
+        })
+        .collect();
+    let levels: std::collections::HashSet<u16> = row.iter().copied().collect();
+    assert!(
+        levels.len() > 256,
+        "expected more than 256 distinct levels, got {}",
+        levels.len()
+    );
+    // scaled to the full 16-bit range, not left in the low 10 bits
+    assert!(row[1020] > 64_000, "the bright end is {}", row[1020]);
+    assert!(row[3] < 1_000, "the dark end is {}", row[3]);
+
+    let from_bytes =
+        Image::from_bytes(&std::fs::read(&path).expect("failed to read fixture"), None)
+            .expect("failed to load 10-bit HEIC from memory");
+    assert_eq!(from_bytes.image, image.image);
+}
+
+#[test]
+fn test_heif_effort() {
+    test_setup_logging();
+    let source = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+        image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+    }));
+    let image = Image::from_dynamic_image(source, PathBuf::from("tests/test_images/effort.png"));
+
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    for effort in [0, shrinky_rs::imagedata::MAX_EFFORT] {
+        for format in [ImageFormat::Avif, ImageFormat::Heic] {
+            let encoded = image
+                .clone()
+                .with_effort(effort)
+                .output_as_format(format)
+                .unwrap_or_else(|e| panic!("failed to encode {format} at effort {effort}: {e:?}"));
+            let decoded = image::load_from_memory(&encoded)
+                .unwrap_or_else(|e| panic!("failed to decode {format} at effort {effort}: {e:?}"));
+            assert_eq!((decoded.width(), decoded.height()), (64, 48));
+        }
+    }
+
+    match image.with_effort(10).output_as_format(ImageFormat::Avif) {
+        Err(shrinky_rs::Error::InvalidOptions(_)) => {}
+        other => panic!("Expected InvalidOptions for effort 10, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_heif_encoder_params() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(16, 16),
+        PathBuf::from("tests/test_images/encoder-params.png"),
+    );
+
+    // every libheif encoder has an integer quality parameter
+    image
+        .clone()
+        .with_encoder_params(vec![("quality".to_string(), "50".to_string())])
+        .output_as_format(ImageFormat::Avif)
+        .expect("a known encoder parameter should be accepted");
+
+    let bad_params = image.with_encoder_params(vec![(
+        "definitely-not-a-parameter".to_string(),
+        "1".to_string(),
+    )]);
+    match bad_params.output_as_format(ImageFormat::Heic) {
+        Err(shrinky_rs::Error::ImageEncodingError(message)) => {
+            assert!(
+                message.contains("definitely-not-a-parameter"),
+                "{}",
+                message
+            );
+        }
+        other => panic!("Expected ImageEncodingError, got {:?}", other),
+    }
+    assert!(
+        bad_params.output_as_format(ImageFormat::Png).is_ok(),
+        "encoder parameters are ignored for native formats"
+    );
+}
+
+#[test]
+fn test_cli_encoder_params() {
+    use clap::Parser;
+    test_setup_logging();
+    let cli = shrinky_rs::cli::Cli::try_parse_from([
+        "shrinky-rs",
+        "--encoder-param",
+        "chroma=444",
+        "--encoder-param",
+        "tune=ssim",
+        "input.png",
+    ])
+    .expect("failed to parse encoder params");
+    assert_eq!(
+        cli.encoder_params,
+        vec![
+            ("chroma".to_string(), "444".to_string()),
+            ("tune".to_string(), "ssim".to_string()),
+        ]
+    );
+    assert!(
+        shrinky_rs::cli::Cli::try_parse_from([
+            "shrinky-rs",
+            "--encoder-param",
+            "chroma",
+            "input.png"
+        ])
+        .is_err()
+    );
+}
+
+#[test]
+fn test_color_space_conversion() {
+    test_setup_logging();
+    let mut image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 200])
+        })),
+        PathBuf::from("tests/test_images/colorful.png"),
+    );
+
+    image.to_grayscale();
+    assert_eq!(image.image.color(), image::ColorType::L8);
+    for pixel in image.image.to_rgb8().pixels() {
+        let [r, g, b] = pixel.0;
+        assert!(
+            r == g && g == b,
+            "grayscale pixel has distinct channel values: {:?}",
+            pixel
+        );
+    }
+    for format in [ImageFormat::Jpg, ImageFormat::Png, ImageFormat::Webp] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode grayscale {format}: {e:?}"));
+        let decoded = image::load_from_memory(&encoded)
+            .unwrap_or_else(|e| panic!("failed to decode grayscale {format}: {e:?}"));
+        assert_eq!((decoded.width(), decoded.height()), (32, 32));
+    }
+
+    image.to_rgba();
+    assert_eq!(image.image.color(), image::ColorType::Rgba8);
+    assert!(
+        image
+            .image
+            .to_rgba8()
+            .pixels()
+            .all(|pixel| pixel.0[3] == 255)
+    );
+    image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode RGBA PNG");
+}
+
+#[test]
+fn test_grayscale_skips_passthrough() {
+    test_setup_logging();
+    let mut image = Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load PNG fixture");
+    assert!(image.is_passthrough_eligible(ImageFormat::Png));
+    image.to_grayscale();
+    assert!(
+        !image.is_passthrough_eligible(ImageFormat::Png),
+        "a converted image can't reuse the original bytes"
+    );
+}
+
+#[test]
+fn test_add_border() {
+    test_setup_logging();
+    let mut image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            20,
+            10,
+            image::Rgb([10, 20, 30]),
+        )),
+        PathBuf::from("tests/test_images/border.png"),
+    );
+
+    image
+        .add_border(1, 2, 3, 4, image::Rgba([255, 0, 0, 255]))
+        .expect("failed to add border");
+    assert_eq!(
+        image.current_geometry(),
+        Geometry::new(20 + 2 + 4, 10 + 1 + 3)
+    );
+    assert_eq!(image.final_geometry(), image.current_geometry());
+    assert!(!image.image.color().has_alpha());
+    let rgb = image.image.to_rgb8();
+    assert_eq!(rgb.get_pixel(0, 0).0, [255, 0, 0]);
+    assert_eq!(rgb.get_pixel(4, 1).0, [10, 20, 30]);
+    assert_eq!(rgb.get_pixel(25, 13).0, [255, 0, 0]);
+
+    // a transparent border adds an alpha channel
+    image
+        .add_border(5, 5, 5, 5, image::Rgba([0, 0, 0, 0]))
+        .expect("failed to add transparent border");
+    assert_eq!(image.current_geometry(), Geometry::new(36, 24));
+    assert!(image.image.color().has_alpha());
+    assert_eq!(image.image.to_rgba8().get_pixel(0, 0).0[3], 0);
+
+    // a pending resize happens before the border is added
+    let mut image = image.with_target_geometry(Geometry::new(10, 10));
+    image
+        .add_border(2, 2, 2, 2, image::Rgba([0, 0, 0, 255]))
+        .expect("failed to add border after resize");
+    assert_eq!(image.current_geometry(), Geometry::new(14, 14));
+}
+
+#[test]
+fn test_output_applies_pending_resize() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path)
+        .expect("failed to load Image from path")
+        .with_target_geometry(Geometry::new(100, 80));
+    let original = image.original_geometry.clone();
+
+    let transformed = image
+        .apply_all_transforms()
+        .expect("failed to apply transforms");
+    assert_eq!((transformed.width(), transformed.height()), (100, 80));
+    assert_eq!(
+        Geometry::new(image.image.width(), image.image.height()),
+        original,
+        "applying transforms shouldn't change the in-memory image"
+    );
+
+    // no explicit resize() before encoding
+    for format in [
+        ImageFormat::Png,
+        ImageFormat::Jpg,
+        ImageFormat::Webp,
+        ImageFormat::Tiff,
+        ImageFormat::Bmp,
+        ImageFormat::Gif,
+    ] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode {format}: {e:?}"));
+        let decoded = image::load_from_memory(&encoded)
+            .unwrap_or_else(|e| panic!("failed to decode {format} output: {e:?}"));
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (100, 80),
+            "{format} output should be resized"
+        );
+    }
+}
+
+#[test]
+fn test_rotate() {
+    use shrinky_rs::RotationAngle;
+    test_setup_logging();
+    let original = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(20, 10, |x, y| {
+        image::Rgb([x as u8, y as u8, 0])
+    }));
+    let rotated = |angle| {
+        let mut image = Image::from_dynamic_image(
+            original.clone(),
+            PathBuf::from("tests/test_images/rotate.png"),
+        );
+        image.rotate(angle).expect("failed to rotate");
+        image
+    };
+
+    for angle in [RotationAngle::Cw90, RotationAngle::Cw270] {
+        assert_eq!(rotated(angle).current_geometry(), Geometry::new(10, 20));
+    }
+    for angle in [
+        RotationAngle::Cw180,
+        RotationAngle::FlipH,
+        RotationAngle::FlipV,
+    ] {
+        assert_eq!(rotated(angle).current_geometry(), Geometry::new(20, 10));
+    }
+
+    // the top-left pixel ends up in the top-right corner after a clockwise turn
+    let image = rotated(RotationAngle::Cw90).image.to_rgb8();
+    assert_eq!(image.get_pixel(9, 0).0, [0, 0, 0]);
+    assert_eq!(image.get_pixel(0, 19).0, [19, 9, 0]);
+    let image = rotated(RotationAngle::Cw180).image.to_rgb8();
+    assert_eq!(image.get_pixel(0, 0).0, [19, 9, 0]);
+    let image = rotated(RotationAngle::FlipH).image.to_rgb8();
+    assert_eq!(image.get_pixel(0, 0).0, [19, 0, 0]);
+    let image = rotated(RotationAngle::FlipV).image.to_rgb8();
+    assert_eq!(image.get_pixel(0, 0).0, [0, 9, 0]);
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--rotate", "270", "input.png"])
+        .expect("failed to parse rotate");
+    assert_eq!(cli.rotate, Some(RotationAngle::Cw270));
+    assert!(
+        shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--rotate", "45", "input.png"])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_rotate_skips_passthrough() {
+    test_setup_logging();
+    let mut image = Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load PNG fixture");
+    assert!(image.is_passthrough_eligible(ImageFormat::Png));
+    image
+        .rotate(shrinky_rs::RotationAngle::Cw180)
+        .expect("failed to rotate");
+    assert!(
+        !image.is_passthrough_eligible(ImageFormat::Png),
+        "a rotated image can't reuse the original bytes"
+    );
+}
+
+#[test]
+fn test_cli_border_color() {
+    use clap::Parser;
+    use shrinky_rs::cli::{Cli, parse_rgba_color};
+    test_setup_logging();
+    assert_eq!(
+        parse_rgba_color("11223344").expect("valid color"),
+        image::Rgba([0x11, 0x22, 0x33, 0x44])
+    );
+    assert_eq!(
+        parse_rgba_color("#aabbcc").expect("valid color"),
+        image::Rgba([0xaa, 0xbb, 0xcc, 0xff])
+    );
+    assert!(parse_rgba_color("fff").is_err());
+    assert!(parse_rgba_color("gg000000").is_err());
+
+    let cli = Cli::try_parse_from(["shrinky-rs", "--border", "8", "input.png"])
+        .expect("failed to parse border");
+    assert_eq!(cli.border, Some(8));
+    assert_eq!(cli.border_color, image::Rgba([255, 255, 255, 255]));
+}
+
+#[test]
+fn test_jpg_flattens_alpha_onto_background() {
+    use clap::Parser;
+    use shrinky_rs::cli::{Cli, parse_rgb_color};
+    test_setup_logging();
+    // left half fully transparent black, right half opaque red
+    let (width, height) = (32u32, 16u32);
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, _| {
+            match x < width / 2 {
+                true => image::Rgba([0, 0, 0, 0]),
+                false => image::Rgba([255, 0, 0, 255]),
+            }
+        })),
+        PathBuf::from("tests/test_images/transparent.png"),
+    );
+    let left_and_right = |image: &Image| {
+        let jpg = image
+            .output_as_format(ImageFormat::Jpg)
+            .expect("failed to encode JPG");
+        let decoded = image::load_from_memory(&jpg)
+            .expect("failed to decode JPG")
+            .to_rgb8();
+        (decoded.get_pixel(4, 8).0, decoded.get_pixel(width - 4, 8).0)
+    };
+    let close_to = |actual: [u8; 3], expected: [u8; 3]| {
+        actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 8)
+    };
+
+    let cli = Cli::try_parse_from(["shrinky-rs", "--background-color", "ffffff", "input.png"])
+        .expect("failed to parse --background-color");
+    let background = cli
+        .background_color
+        .expect("background color should be set");
+    let (left, right) = left_and_right(&image.clone().with_background_color(background));
+    assert!(
+        close_to(left, [255, 255, 255]),
+        "expected white, got {left:?}"
+    );
+    assert!(close_to(right, [255, 0, 0]), "expected red, got {right:?}");
+
+    // white is the default too
+    let (left, _) = left_and_right(&image);
+    assert!(
+        close_to(left, [255, 255, 255]),
+        "expected white, got {left:?}"
+    );
+
+    let (left, _) = left_and_right(&image.clone().with_background_color(image::Rgb([0, 0, 255])));
+    assert!(close_to(left, [0, 0, 255]), "expected blue, got {left:?}");
+
+    let mut flattened = image.clone();
+    flattened.flatten_alpha(image::Rgb([0, 255, 0]));
+    assert!(!flattened.image.color().has_alpha());
+    assert_eq!(flattened.image.to_rgb8().get_pixel(0, 0).0, [0, 255, 0]);
+    assert!(flattened.edited);
+
+    assert_eq!(
+        parse_rgb_color("#102030").expect("valid color"),
+        image::Rgb([0x10, 0x20, 0x30])
+    );
+    assert!(parse_rgb_color("ffffffff").is_err());
+    assert!(parse_rgb_color("fff").is_err());
+}
+
+#[test]
+fn test_histogram_solid_red() {
+    test_setup_logging();
+    let (width, height) = (300u32, 200u32);
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            width,
+            height,
+            image::Rgb([255, 0, 0]),
+        )),
+        PathBuf::from("tests/test_images/red.png"),
+    );
+    let total_pixels = u64::from(width * height);
+
+    let histogram = image.histogram();
+    for (counts, full_bucket) in [
+        (&histogram.red, 255),
+        (&histogram.green, 0),
+        (&histogram.blue, 0),
+        (&histogram.alpha, 255),
+    ] {
+        for (value, count) in counts.iter().enumerate() {
+            let expected = if value == full_bucket {
+                total_pixels
+            } else {
+                0
+            };
+            assert_eq!(*count, expected, "bucket {} is wrong", value);
+        }
+    }
+
+    let csv = histogram.to_csv();
+    assert_eq!(csv.lines().count(), 257);
+    assert_eq!(
+        csv.lines().nth(1),
+        Some(format!("0,0,{0},{0},0", total_pixels).as_str())
+    );
+    let json = histogram.to_json();
+    assert!(json.starts_with("{\"red\":[0,0,"));
+    assert!(json.contains("\"alpha\":[0,"));
+}
+
+#[test]
+fn test_composite_is_never_passed_through() {
+    test_setup_logging();
+    let overlay = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        16,
+        16,
+        image::Rgba([255, 0, 255, 255]),
+    ));
+    for format in [ImageFormat::Png, ImageFormat::Jpg] {
+        let path = PathBuf::from(format!("tests/test_images/{IMAGE_NAME}.{format}"));
+        let mut image = Image::try_from(&path).expect("failed to load fixture");
+        assert!(image.is_passthrough_eligible(format));
+
+        image
+            .composite(&overlay, 0, 0, 1.0)
+            .expect("failed to composite watermark");
+        assert!(!image.is_passthrough_eligible(format));
+        assert_ne!(
+            image
+                .output_as_format(format)
+                .expect("failed to encode watermarked image"),
+            std::fs::read(&path).expect("failed to read fixture"),
+            "{format} output should have the watermark, not be the original file"
+        );
+    }
+}
+
+#[test]
+fn test_composite_watermark() {
+    test_setup_logging();
+    let mut image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(40, 30, image::Rgb([0, 0, 0]))),
+        PathBuf::from("tests/test_images/watermark-base.png"),
+    );
+    let overlay = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        10,
+        10,
+        image::Rgba([255, 255, 255, 255]),
+    ));
+
+    let (x, y) = shrinky_rs::WatermarkPosition::BottomRight.offset((40, 30), (10, 10));
+    assert_eq!((x, y), (30, 20));
+    image
+        .composite(&overlay, x, y, 0.5)
+        .expect("failed to composite watermark");
+    assert_eq!(image.current_geometry(), Geometry::new(40, 30));
+    assert!(!image.image.color().has_alpha());
+    let rgb = image.image.to_rgb8();
+    assert_eq!(rgb.get_pixel(0, 0).0, [0, 0, 0], "outside the overlay");
+    let blended = rgb.get_pixel(35, 25).0[0];
+    assert!(
+        (126..=129).contains(&blended),
+        "half-opacity white on black should be mid grey, got {}",
+        blended
+    );
+
+    // overlays hanging off the edge are clipped
+    image
+        .composite(&overlay, 35, 25, 1.0)
+        .expect("failed to composite clipped watermark");
+    assert_eq!(image.image.to_rgb8().get_pixel(39, 29).0, [255, 255, 255]);
+
+    assert!(image.composite(&overlay, 40, 0, 1.0).is_err());
+    assert!(image.composite(&overlay, 0, 0, 1.5).is_err());
+    assert_eq!(
+        shrinky_rs::WatermarkPosition::Center.offset((40, 30), (10, 10)),
+        (15, 10)
+    );
+    assert_eq!(
+        shrinky_rs::WatermarkPosition::TopRight.offset((5, 5), (10, 10)),
+        (0, 0)
+    );
+}
+
+#[test]
+fn test_dominant_colors_checkerboard() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            if (x / 4 + y / 4) % 2 == 0 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        })),
+        PathBuf::from("tests/test_images/checkerboard.png"),
+    );
+
+    let mut colors = image.dominant_colors(2).expect("failed to find palette");
+    colors.sort();
+    assert_eq!(colors.len(), 2);
+    let close = |actual: [u8; 3], expected: [u8; 3]| {
+        actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 2)
+    };
+    assert!(close(colors[0], [0, 0, 0]), "got {:?}", colors);
+    assert!(close(colors[1], [255, 255, 255]), "got {:?}", colors);
+
+    match image.dominant_colors(0) {
+        Err(shrinky_rs::Error::InvalidOptions(_)) => {}
+        other => panic!("Expected InvalidOptions for n=0, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_histogram_channel_pure_red() {
+    use shrinky_rs::imagedata::{HistogramChannel, HistogramStats};
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            10,
+            10,
+            image::Rgb([255, 0, 0]),
+        )),
+        PathBuf::from("tests/test_images/red.png"),
+    );
+
+    let red = image.histogram_channel(HistogramChannel::R);
+    assert_eq!(red[255], 100);
+    assert!(red[..255].iter().all(|count| *count == 0));
+    assert_eq!(image.histogram_channel(HistogramChannel::G)[0], 100);
+    assert_eq!(image.histogram_channel(HistogramChannel::A)[255], 100);
+    let luminance = image.histogram_channel(HistogramChannel::Luminance);
+    assert_eq!(luminance.iter().sum::<u64>(), 100);
+    assert_eq!(luminance.iter().filter(|count| **count > 0).count(), 1);
+    let histogram = image.histogram();
+    assert_eq!(histogram.channel(HistogramChannel::R), Some(&red));
+    assert_eq!(histogram.channel(HistogramChannel::Luminance), None);
+
+    let stats = HistogramStats::from_histogram(&red).expect("red channel has pixels");
+    assert_eq!((stats.min, stats.max), (255, 255));
+    assert_eq!(stats.mean, 255.0);
+    assert_eq!(stats.std_dev, 0.0);
+
+    let mut split = [0u64; 256];
+    split[0] = 50;
+    split[200] = 50;
+    let stats = HistogramStats::from_histogram(&split).expect("histogram has values");
+    assert_eq!((stats.min, stats.max), (0, 200));
+    assert_eq!(stats.mean, 100.0);
+    assert_eq!(stats.std_dev, 100.0);
+    assert!(HistogramStats::from_histogram(&[0; 256]).is_none());
+}
+
+#[test]
+fn test_classify_content() {
+    test_setup_logging();
+    let photo = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Jpg.extension()
+    )))
+    .expect("failed to load JPG fixture");
+    assert!(
+        photo.luma_entropy() > shrinky_rs::metrics::PHOTO_MIN_ENTROPY,
+        "entropy was {}",
+        photo.luma_entropy()
+    );
+    assert!(photo.is_photo());
+    assert!(!photo.is_graphic());
+
+    let graphic = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, _y| {
+            if x < 16 {
+                image::Rgb([20, 40, 200])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        })),
+        PathBuf::from("tests/test_images/graphic.png"),
+    );
+    assert!((graphic.luma_entropy() - 1.0).abs() < f64::EPSILON);
+    assert!(graphic.is_graphic());
+    assert!(!graphic.is_photo());
+    let (format, _) = graphic
+        .auto_format_phased()
+        .expect("failed to auto format graphic");
+    assert_ne!(format, ImageFormat::Jpg, "JPG is skipped for graphics");
+}
+
+#[test]
+fn test_image_from_bytes() {
+    test_setup_logging();
+    let data = std::fs::read(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ))
+    .expect("failed to read fixture");
+
+    let image = Image::from_bytes(&data, None).expect("failed to decode PNG bytes");
+    assert_eq!(image.original_geometry, Geometry::new(450, 800));
+    assert_eq!(image.original_file_size, data.len() as u64);
+
+    let hinted = Image::from_bytes(&data, Some(ImageFormat::Png)).expect("failed to decode PNG");
+    assert_eq!(hinted.current_geometry(), image.current_geometry());
+    assert!(Image::from_bytes(&data, Some(ImageFormat::Jpg)).is_err());
+    assert!(Image::from_bytes(b"not an image", None).is_err());
+}
+
+#[test]
+fn test_auto_format_explained() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let (format, data, attempts) = image
+        .auto_format_explained()
+        .expect("failed to run auto format");
+    assert_eq!(attempts.len(), ImageFormat::all().len());
+    for format in ImageFormat::all() {
+        assert_eq!(
+            attempts.iter().filter(|a| a.format == format).count(),
+            1,
+            "expected one attempt for {format}"
+        );
+    }
+    let selected: Vec<_> = attempts.iter().filter(|a| a.selected).collect();
+    assert_eq!(selected.len(), 1);
+    assert_eq!(selected[0].format, format);
+    assert_eq!(selected[0].encoded_bytes, Some(data.len()));
+    assert!(
+        attempts
+            .iter()
+            .all(|a| a.encoded_bytes.is_some() != a.error.is_some()),
+        "every attempt has either a size or a reason"
+    );
+
+    let table = shrinky_rs::format_attempts_table(&attempts);
+    assert_eq!(table.lines().count(), ImageFormat::all().len() + 1);
+}
+
+#[test]
+fn test_split_channels_recombines_to_original() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.jpg"))
+        .expect("failed to load JPEG fixture");
+    let (red, green, blue, alpha) = image
+        .split_channels()
+        .expect("failed to split JPEG channels");
+    assert!(alpha.is_none(), "JPEG shouldn't have an alpha channel");
+    for channel in [&red, &green, &blue] {
+        assert_eq!(channel.color(), image::ColorType::L8);
+        assert_eq!(
+            (channel.width(), channel.height()),
+            (image.image.width(), image.image.height())
+        );
+    }
+
+    let (red, green, blue) = (red.to_luma8(), green.to_luma8(), blue.to_luma8());
+    let recombined = image::RgbImage::from_fn(red.width(), red.height(), |x, y| {
+        image::Rgb([
+            red.get_pixel(x, y).0[0],
+            green.get_pixel(x, y).0[0],
+            blue.get_pixel(x, y).0[0],
+        ])
+    });
+    assert_eq!(recombined, image.image.to_rgb8());
+
+    let with_alpha = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            4,
+            4,
+            image::Rgba([1, 2, 3, 4]),
+        )),
+        PathBuf::from("tests/test_images/split.png"),
+    );
+    let (_, _, _, alpha) = with_alpha
+        .split_channels()
+        .expect("failed to split RGBA channels");
+    let alpha = alpha.expect("RGBA image should have an alpha channel");
+    assert!(alpha.to_luma8().pixels().all(|pixel| pixel.0[0] == 4));
+}
+
+#[test]
+fn test_auto_format_reports_every_failed_format() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::new_rgb8(0, 0),
+        PathBuf::from("tests/test_images/empty.png"),
+    );
+
+    let errors = match image.auto_format() {
+        Err(shrinky_rs::Error::AllFormatsFailedEncoding(errors)) => errors,
+        other => panic!("expected AllFormatsFailedEncoding, got {other:?}"),
+    };
+    assert!(!errors.is_empty());
+    for format in ImageFormat::all() {
+        assert!(
+            errors.iter().filter(|(f, _)| *f == format).count() <= 1,
+            "expected at most one entry for {format}"
+        );
+    }
+    for format in [ImageFormat::Png, ImageFormat::Webp, ImageFormat::Heic] {
+        assert!(
+            errors.iter().any(|(f, _)| *f == format),
+            "expected an entry for {format}: {errors:?}"
+        );
+    }
+    for (format, error) in &errors {
+        assert!(!error.is_empty(), "{format} has an empty error");
+    }
+}
+
+#[test]
+fn test_png_compression_levels() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let fast = image
+        .clone()
+        .with_png_compression(PngCompression::Fast)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG with fast compression");
+    let best = image
+        .clone()
+        .with_png_compression(PngCompression::Best)
+        .with_png_filter(PngFilter::Adaptive)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG with best compression");
+    assert!(
+        best.len() <= fast.len(),
+        "best ({} bytes) should be no bigger than fast ({} bytes)",
+        best.len(),
+        fast.len()
+    );
+
+    let fast = image::load_from_memory(&fast).expect("failed to decode fast PNG");
+    let best = image::load_from_memory(&best).expect("failed to decode best PNG");
+    assert_eq!(fast.to_rgba8(), best.to_rgba8());
+
+    let cli = shrinky_rs::cli::Cli::parse_from([
+        "shrinky-rs",
+        "--png-compression",
+        "best",
+        "--png-filter",
+        "none",
+        "image.png",
+    ]);
+    assert_eq!(cli.png_compression, Some(PngCompression::Best));
+    assert_eq!(cli.png_filter, Some(PngFilter::NoFilter));
+}
+
+#[test]
+fn test_png_compression_level() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let uncompressed = image
+        .clone()
+        .with_png_compression_level(0)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG at level 0");
+    let smallest = image
+        .clone()
+        .with_png_compression_level(9)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG at level 9");
+    assert!(
+        smallest.len() <= uncompressed.len(),
+        "level 9 ({} bytes) should be no bigger than level 0 ({} bytes)",
+        smallest.len(),
+        uncompressed.len()
+    );
+
+    let uncompressed = image::load_from_memory(&uncompressed).expect("failed to decode level 0");
+    let smallest = image::load_from_memory(&smallest).expect("failed to decode level 9");
+    assert_eq!(uncompressed.to_rgba8(), smallest.to_rgba8());
+
+    assert!(matches!(
+        image
+            .clone()
+            .with_png_compression_level(10)
+            .output_as_format(ImageFormat::Png),
+        Err(shrinky_rs::Error::InvalidOptions(_))
+    ));
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from([
+        "shrinky-rs",
+        "--png-compression-level",
+        "9",
+        "image.png",
+    ])
+    .expect("failed to parse --png-compression-level");
+    assert_eq!(cli.png_compression_level, Some(9));
+    assert!(
+        shrinky_rs::cli::Cli::try_parse_from([
+            "shrinky-rs",
+            "--png-compression-level",
+            "10",
+            "image.png",
+        ])
+        .is_err()
+    );
+}
+
+#[test]
+fn test_all_formats_sorted() {
+    test_setup_logging();
+    for source_format in [ImageFormat::Png, ImageFormat::Jpg, ImageFormat::Webp] {
+        let img_path = PathBuf::from(format!(
+            "tests/test_images/{}.{}",
+            IMAGE_NAME,
+            source_format.extension()
+        ));
+        let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+        let sorted = image
+            .all_formats_sorted()
+            .unwrap_or_else(|e| panic!("failed to encode {source_format} fixture: {e:?}"));
+        assert!(!sorted.is_empty());
+        assert!(
+            sorted
+                .windows(2)
+                .all(|pair| pair[0].1.len() <= pair[1].1.len()),
+            "{source_format} fixture results aren't sorted by size: {:?}",
+            sorted
+                .iter()
+                .map(|(format, data)| (*format, data.len()))
+                .collect::<Vec<_>>()
+        );
+
+        let (best_format, best_data) = image.auto_format().expect("failed to run auto format");
+        assert_eq!(best_format, sorted[0].0);
+        assert_eq!(best_data.len(), sorted[0].1.len());
+    }
+}
+
+#[test]
+fn test_image_diff() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let identical = image.diff(&image.clone()).expect("failed to diff image");
+    assert_eq!(identical.max_delta, 0);
+    assert_eq!(identical.mean_delta, 0.0);
+    assert_eq!(identical.ssim, 1.0);
+    assert_eq!(identical.psnr, f64::INFINITY);
+
+    let jpeg = image
+        .clone()
+        .with_quality(95)
+        .output_as_format(ImageFormat::Jpg)
+        .expect("failed to encode JPG");
+    let jpeg = Image::from_bytes(&jpeg, Some(ImageFormat::Jpg)).expect("failed to load JPG");
+    let compressed = image.diff(&jpeg).expect("failed to diff JPG");
+    assert!(compressed.max_delta > 0);
+    assert!(compressed.mean_delta > 0.0);
+    assert!(compressed.ssim > 0.95, "SSIM was {}", compressed.ssim);
+    assert!(compressed.psnr.is_finite());
+
+    let mut smaller = image.clone().with_target_geometry(Geometry {
+        width: Some(100),
+        height: None,
+    });
+    smaller.resize().expect("failed to resize image");
+    assert!(image.diff(&smaller).is_err());
+}
+
+#[test]
+fn test_lossy_webp() {
+    test_setup_logging();
+    let img_path = PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Jpg.extension()
+    ));
+    let image = Image::try_from(&img_path).expect("failed to load Image from path");
+
+    let lossy = image
+        .clone()
+        .with_quality(80)
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode lossy WebP");
+    let lossless = image
+        .clone()
+        .with_lossless(true)
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode lossless WebP");
+    assert!(
+        lossy.len() * 3 < lossless.len(),
+        "lossy WebP ({} bytes) should be much smaller than lossless ({} bytes)",
+        lossy.len(),
+        lossless.len()
+    );
+
+    for (name, data) in [("lossy", &lossy), ("lossless", &lossless)] {
+        let decoded = image::load_from_memory_with_format(data, image::ImageFormat::WebP)
+            .unwrap_or_else(|e| panic!("failed to decode {name} WebP: {e:?}"));
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (image.image.width(), image.image.height())
+        );
+    }
+    let decoded = image::load_from_memory(&lossless).expect("failed to decode lossless WebP");
+    assert_eq!(decoded.to_rgb8(), image.image.to_rgb8());
+
+    // a sanity check that it's still the same picture, this fixture is hard on SSIM
+    let score = image
+        .compare_to_encoded(&lossy, true, false)
+        .expect("failed to compare lossy WebP");
+    assert!(score.ssim.unwrap_or_default() > 0.8, "{score:?}");
+}
+
+#[test]
+fn test_graphics_are_lossless_webp_by_default() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            match (x / 16 + y / 16) % 2 {
+                0 => image::Rgb([230, 40, 40]),
+                _ => image::Rgb([250, 250, 250]),
+            }
+        })),
+        "tests/test_images/logo.png",
+    );
+    assert!(image.is_graphic());
+    // the first chunk after the RIFF header is VP8L for lossless WebP, VP8 for lossy
+    let chunk = |data: Vec<u8>| data[12..16].to_vec();
+
+    let default = image
+        .clone()
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode WebP");
+    assert_eq!(chunk(default.clone()), b"VP8L");
+    let decoded = image::load_from_memory(&default).expect("failed to decode WebP");
+    assert_eq!(decoded.to_rgb8(), image.image.to_rgb8());
+
+    let with_quality = image
+        .with_quality(80)
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode WebP");
+    assert_eq!(chunk(with_quality), b"VP8 ");
+}
+
+/// Something like a screenshot: flat UI panels, "text", and a noisy photo-ish panel
+fn screenshot_like_image() -> image::DynamicImage {
+    let mut state = 0x2545_f491_u32;
+    let mut noise = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 192, |x, y| {
+        let n = noise();
+        if y < 16 {
+            image::Rgb([40, 60, 120])
+        } else if x < 80 {
+            // rows of dark "glyphs" on a light background
+            match (y % 12 < 8) && (n % 5 == 0) {
+                true => image::Rgb([20, 20, 20]),
+                false => image::Rgb([245, 245, 245]),
+            }
+        } else {
+            let jitter = (n % 12) as u8;
+            image::Rgb([
+                (x as u8).wrapping_add(jitter),
+                (y as u8).wrapping_add(jitter),
+                140,
+            ])
+        }
+    }))
+}
+
+#[test]
+fn test_near_lossless_webp() {
+    test_setup_logging();
+    let image = Image::from_dynamic_image(
+        screenshot_like_image(),
+        PathBuf::from("tests/test_images/screenshot.png"),
+    );
+    let encode = |image: Image| {
+        image
+            .output_as_format(ImageFormat::Webp)
+            .expect("failed to encode WebP")
+    };
+    let lossless = encode(image.clone().with_lossless(true));
+    let near_lossless = encode(image.clone().with_near_lossless(60));
+    let lossy = encode(image.clone().with_quality(80));
+    assert!(
+        lossy.len() < near_lossless.len() && near_lossless.len() < lossless.len(),
+        "near-lossless ({} bytes) should sit between lossy ({} bytes) and lossless ({} bytes)",
+        near_lossless.len(),
+        lossy.len(),
+        lossless.len()
+    );
+    let decoded = image::load_from_memory(&near_lossless).expect("failed to decode WebP");
+    assert_eq!((decoded.width(), decoded.height()), (256, 192));
+
+    assert!(
+        image
+            .clone()
+            .with_near_lossless(101)
+            .output_as_format(ImageFormat::Webp)
+            .is_err()
+    );
+    assert!(
+        shrinky_rs::cli::Cli::try_parse_from([
+            "shrinky-rs",
+            "--near-lossless",
+            "60",
+            "--lossless",
+            "image.png",
+        ])
+        .is_err(),
+        "--near-lossless should conflict with --lossless"
+    );
+    let cli =
+        shrinky_rs::cli::Cli::parse_from(["shrinky-rs", "--near-lossless", "60", "image.png"]);
+    assert_eq!(cli.near_lossless, Some(60));
+}
+
+#[test]
+fn test_animated_gif_to_webp() {
+    test_setup_logging();
+    // four 64x48 frames, 100ms each
+    let image = Image::try_from(&PathBuf::from("tests/test_images/animated.gif"))
+        .expect("failed to load animated GIF")
+        .with_target_geometry(Geometry::new(32, 24));
+    let frames = image
+        .animation_frames()
+        .expect("failed to decode GIF frames")
+        .expect("GIF should be animated");
+    assert_eq!(frames.len(), 4);
+
+    let encoded = image
+        .output_animated_webp(&frames)
+        .expect("failed to encode animated WebP");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&encoded).expect("unknown format"),
+        ImageFormat::Webp
+    );
+    let decoded = webp::AnimDecoder::new(&encoded)
+        .decode()
+        .expect("failed to decode animated WebP");
+    assert!(decoded.has_animation(), "expected more than one frame");
+    let last_frame = decoded
+        .get_frame(decoded.len() - 1)
+        .expect("missing last frame");
+    assert_eq!((last_frame.width(), last_frame.height()), (32, 24));
+    // the timestamp of the last frame is when the animation ends
+    let total_ms = last_frame.get_time_ms();
+    assert!(
+        (360..=440).contains(&total_ms),
+        "expected about 400ms of animation, got {total_ms}ms"
+    );
+}
+
+#[test]
+fn test_animated_gif_from_bytes_to_webp() {
+    test_setup_logging();
+    // there's no input file to read the frames from, so they come from the bytes
+    let data = std::fs::read("tests/test_images/animated.gif").expect("failed to read fixture");
+    let image = Image::from_bytes(&data, None).expect("failed to decode animated GIF");
+    assert_eq!(image.frame_count, 4);
+
+    let encoded = image
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode animated WebP");
+    let decoded = webp::AnimDecoder::new(&encoded)
+        .decode()
+        .expect("failed to decode animated WebP");
+    assert_eq!(decoded.len(), 4);
+}
+
+#[test]
+fn test_single_frame_gif_is_still() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("still.gif");
+    image::RgbImage::from_pixel(8, 8, image::Rgb([200, 100, 50]))
+        .save(&path)
+        .expect("failed to write GIF");
+
+    let image = Image::try_from(&path).expect("failed to load GIF");
+    assert!(
+        image
+            .animation_frames()
+            .expect("failed to decode GIF frames")
+            .is_none()
+    );
+    image
+        .output_as_format(ImageFormat::Webp)
+        .expect("a still GIF should encode like any other image");
+}
+
+#[test]
+fn test_still_gif_fixture() {
+    test_setup_logging();
+    let path = PathBuf::from("tests/test_images/still.gif");
+    let image = Image::try_from(&path).expect("failed to load GIF");
+    assert_eq!(image.frame_count, 1);
+    assert_eq!(image.original_geometry, Geometry::new(40, 30));
+
+    let png = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode GIF as PNG");
+    let decoded = image::load_from_memory(&png).expect("failed to decode PNG output");
+    assert_eq!((decoded.width(), decoded.height()), (40, 30));
+
+    // GIF output has to be asked for, auto-format never picks it
+    let gif = image
+        .clone()
+        .with_target_geometry(Geometry::new(20, 15))
+        .output_as_format(ImageFormat::Gif)
+        .expect("failed to encode GIF");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&gif).expect("unknown output format"),
+        ImageFormat::Gif
+    );
+    let decoded = image::load_from_memory(&gif).expect("failed to decode GIF output");
+    assert_eq!((decoded.width(), decoded.height()), (20, 15));
+    let (_, attempts) = image
+        .auto_format_explained()
+        .map(|(format, _, attempts)| (format, attempts))
+        .expect("failed to run auto format");
+    let gif_attempt = attempts
+        .iter()
+        .find(|attempt| attempt.format == ImageFormat::Gif)
+        .expect("GIF should be listed");
+    assert!(gif_attempt.encoded_bytes.is_none() && !gif_attempt.selected);
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "gif", "input.png"])
+        .expect("failed to parse -t gif");
+    assert_eq!(cli.output_type, Some(ImageFormat::Gif));
+}
+
+#[test]
+fn test_adjust_brightness_and_contrast() {
+    test_setup_logging();
+    let gradient = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+        image::Rgb([(x * 4 + 40) as u8, (y * 4 + 40) as u8, 128])
+    }));
+    let adjusted = |brightness, contrast| {
+        let mut image = Image::from_dynamic_image(
+            gradient.clone(),
+            PathBuf::from("tests/test_images/adjust.png"),
+        );
+        image.adjust(brightness, contrast);
+        image
+    };
+    let mean = |image: &image::DynamicImage| {
+        let pixels = image.to_rgb8().into_raw();
+        pixels.iter().map(|&sample| f64::from(sample)).sum::<f64>() / pixels.len() as f64
+    };
+
+    let unchanged = adjusted(0, 0.0);
+    assert_eq!(unchanged.image, gradient);
+    assert!(
+        !unchanged.edited,
+        "a no-op adjustment shouldn't block passthrough"
+    );
+
+    let brighter = adjusted(50, 0.0);
+    assert!(brighter.edited);
+    assert!(
+        mean(&brighter.image) > mean(&gradient) + 40.0,
+        "brightening should raise the mean"
+    );
+    let darker = adjusted(-50, 0.0);
+    assert!(mean(&darker.image) < mean(&gradient) - 40.0);
+
+    // more contrast pushes the samples away from the middle
+    let spread = |image: &image::DynamicImage| {
+        let pixels = image.to_rgb8().into_raw();
+        let (min, max) = (pixels.iter().min(), pixels.iter().max());
+        max.zip(min).map(|(max, min)| max - min).unwrap_or_default()
+    };
+    assert!(spread(&adjusted(0, 50.0).image) > spread(&gradient));
+    assert!(spread(&adjusted(0, -50.0).image) < spread(&gradient));
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from([
+        "shrinky-rs",
+        "--brightness",
+        "-20",
+        "--contrast",
+        "12.5",
+        "input.png",
+    ])
+    .expect("failed to parse adjustments");
+    assert_eq!(cli.brightness, Some(-20));
+    assert_eq!(cli.contrast, Some(12.5));
+    for (flag, value) in [("--brightness", "101"), ("--contrast", "-100.5")] {
+        assert!(
+            shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", flag, value, "input.png"]).is_err(),
+            "{flag} {value} should be rejected"
+        );
+    }
+}
+
+#[test]
+fn test_animations_are_not_silently_flattened() {
+    test_setup_logging();
+    let mut image = Image::try_from(&PathBuf::from("tests/test_images/animated.gif"))
+        .expect("failed to load animated GIF");
+    assert_eq!(image.frame_count, 4);
+
+    // still formats are refused
+    for format in [ImageFormat::Jpg, ImageFormat::Png, ImageFormat::Avif] {
+        assert!(
+            matches!(
+                image.output_as_format(format),
+                Err(shrinky_rs::Error::UnsupportedFormat(_))
+            ),
+            "{format} output should be refused for an animation"
+        );
+    }
+
+    // WebP keeps the animation, and it's all auto-format tries
+    let (format, encoded) = image.auto_format().expect("failed to auto-format");
+    assert_eq!(format, ImageFormat::Webp);
+    let webp = Image::from_bytes(&encoded, None).expect("failed to load animated WebP");
+    assert_eq!(webp.frame_count, 4);
+    assert!(
+        webp.output_as_format(ImageFormat::Png).is_err(),
+        "animated WebP input should be refused too"
+    );
+
+    // unless the animation is explicitly flattened
+    image.flatten_to_first_frame();
+    assert_eq!(image.frame_count, 1);
+    let encoded = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode the first frame");
+    let decoded = image::load_from_memory(&encoded).expect("failed to decode PNG");
+    assert_eq!((decoded.width(), decoded.height()), (64, 48));
+    let encoded = image
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode the first frame as WebP");
+    let decoded = webp::AnimDecoder::new(&encoded)
+        .decode()
+        .expect("failed to decode WebP");
+    assert!(!decoded.has_animation());
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--first-frame", "input.gif"])
+        .expect("failed to parse --first-frame");
+    assert!(cli.first_frame);
+}
+
+#[test]
+fn test_first_frame_in_the_same_format_is_a_still() {
+    use image::AnimationDecoder;
+    test_setup_logging();
+    let mut gif = Image::try_from(&PathBuf::from("tests/test_images/animated.gif"))
+        .expect("failed to load animated GIF");
+    let animated_webp = gif
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode animated WebP");
+
+    gif.flatten_to_first_frame();
+    let encoded = gif
+        .output_as_format(ImageFormat::Gif)
+        .expect("failed to encode the first frame as GIF");
+    let frames = image::codecs::gif::GifDecoder::new(Cursor::new(&encoded))
+        .expect("failed to read GIF")
+        .into_frames()
+        .collect_frames()
+        .expect("failed to decode GIF frames");
+    assert_eq!(frames.len(), 1, "GIF output should be a single frame");
+
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("animated.webp");
+    std::fs::write(&path, &animated_webp).expect("failed to write animated WebP");
+    let mut webp = Image::try_from(&path).expect("failed to load animated WebP");
+    assert_eq!(webp.frame_count, 4);
+    webp.flatten_to_first_frame();
+    let encoded = webp
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode the first frame as WebP");
+    let decoded = webp::AnimDecoder::new(&encoded)
+        .decode()
+        .expect("failed to decode WebP");
+    assert!(!decoded.has_animation(), "WebP output should be a still");
+}
+
+#[test]
+fn test_animated_webp_round_trip() {
+    test_setup_logging();
+    let gif = Image::try_from(&PathBuf::from("tests/test_images/animated.gif"))
+        .expect("failed to load animated GIF");
+    let encoded = gif
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode animated WebP");
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("animated.webp");
+    std::fs::write(&path, &encoded).expect("failed to write animated WebP");
+
+    let image = Image::try_from(&path)
+        .expect("failed to load animated WebP")
+        .with_target_geometry(Geometry::new(32, 24));
+    assert_eq!(image.frame_count, 4);
+    let resized = image
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to re-encode animated WebP");
+    let decoded = webp::AnimDecoder::new(&resized)
+        .decode()
+        .expect("failed to decode animated WebP");
+    assert_eq!(decoded.len(), 4);
+    let first_frame = decoded.get_frame(0).expect("missing first frame");
+    assert_eq!((first_frame.width(), first_frame.height()), (32, 24));
+}
+
+#[test]
+fn test_tiff_round_trip() {
+    test_setup_logging();
+    let path = PathBuf::from("tests/test_images/scan.tif");
+    let data = std::fs::read(&path).expect("failed to read TIFF fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Tiff
+    );
+
+    let image = Image::try_from(&path)
+        .expect("failed to load TIFF")
+        .with_target_geometry(Geometry::new(100, 178));
+    assert_eq!(image.original_geometry, Geometry::new(200, 356));
+    assert!(
+        !image.is_passthrough_eligible(ImageFormat::Tiff),
+        "resized TIFF can't be passed through"
+    );
+
+    for format in [ImageFormat::Jpg, ImageFormat::Webp, ImageFormat::Tiff] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode TIFF as {format}: {e:?}"));
+        let decoded = Image::from_bytes(&encoded, None)
+            .unwrap_or_else(|e| panic!("failed to decode {format} output: {e:?}"));
+        assert_eq!(decoded.original_geometry, Geometry::new(100, 178));
+    }
+
+    // TIFF output uses the short extension, like JPG
+    let image = Image::try_from(&path)
+        .expect("failed to load TIFF")
+        .with_output_format(ImageFormat::Tiff);
+    assert!(image.is_passthrough_eligible(ImageFormat::Tiff));
+    assert_eq!(image.output_filename(), path);
+    let mut renamed = image.clone();
+    renamed.input_filename = PathBuf::from("tests/test_images/scan.tiff");
+    assert_eq!(renamed.output_filename(), path);
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "tif", "scan.png"])
+        .expect("failed to parse tif");
+    assert_eq!(cli.output_type, Some(ImageFormat::Tiff));
+}
+
+#[test]
+fn test_bmp_input() {
+    test_setup_logging();
+    let path = PathBuf::from("tests/test_images/screenshot.bmp");
+    let data = std::fs::read(&path).expect("failed to read BMP fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Bmp
+    );
+
+    let image = Image::try_from(&path).expect("failed to load BMP");
+    assert_eq!(image.original_geometry, Geometry::new(120, 90));
+    for format in [ImageFormat::Png, ImageFormat::Webp, ImageFormat::Bmp] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode BMP as {format}: {e:?}"));
+        assert_eq!(
+            ImageFormat::detect_from_bytes(&encoded).expect("unknown output format"),
+            format
+        );
+        let decoded = Image::from_bytes(&encoded, None)
+            .unwrap_or_else(|e| panic!("failed to decode {format} output: {e:?}"));
+        assert_eq!(decoded.original_geometry, Geometry::new(120, 90));
+    }
+
+    let image = image.with_output_format(ImageFormat::Png);
+    assert_eq!(
+        image.output_filename(),
+        PathBuf::from("tests/test_images/screenshot.png")
+    );
+    assert!(!image.will_overwrite());
+}
+
+#[test]
+fn test_ico_input() {
+    test_setup_logging();
+    // a 16x16 BMP entry, then 48x48 RGB and 32x32 RGBA PNG entries. The image crate's own
+    // decoder would pick the 32x32 one.
+    let path = PathBuf::from("tests/test_images/favicon.ico");
+    let data = std::fs::read(&path).expect("failed to read ICO fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Ico
+    );
+
+    let image = Image::try_from(&path).expect("failed to load ICO");
+    assert_eq!(image.original_geometry, Geometry::new(48, 48));
+    assert_eq!(
+        Image::from_bytes(&data, None)
+            .expect("failed to load ICO from memory")
+            .original_geometry,
+        Geometry::new(48, 48)
+    );
+    for format in [ImageFormat::Png, ImageFormat::Webp] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode ICO as {format}: {e:?}"));
+        let decoded = image::load_from_memory(&encoded)
+            .unwrap_or_else(|e| panic!("failed to decode {format} output: {e:?}"));
+        assert_eq!((decoded.width(), decoded.height()), (48, 48));
+    }
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Ico),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert!(!ImageFormat::all().contains(&ImageFormat::Ico));
+
+    for (index, size) in [(0, 16), (1, 48), (2, 32)] {
+        let mut picked = image.clone();
+        picked
+            .select_ico_entry(index)
+            .unwrap_or_else(|e| panic!("failed to pick ICO image {index}: {e:?}"));
+        assert_eq!(picked.original_geometry, Geometry::new(size, size));
+        assert_eq!(picked.image.width(), size);
+    }
+    assert!(matches!(
+        image.clone().select_ico_entry(3),
+        Err(shrinky_rs::Error::InvalidOptions(_))
+    ));
+    let mut gif =
+        Image::try_from(&PathBuf::from("tests/test_images/still.gif")).expect("failed to load GIF");
+    assert!(matches!(
+        gif.select_ico_entry(0),
+        Err(shrinky_rs::Error::InvalidOptions(_))
+    ));
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--frame", "2", "favicon.ico"])
+        .expect("failed to parse --frame");
+    assert_eq!(cli.frame, Some(2));
+}
+
+#[test]
+fn test_tga_input_keeps_alpha() {
+    test_setup_logging();
+    // 64x32, alpha ramps up across the left half and the right half is opaque
+    let path = PathBuf::from("tests/test_images/sprite.tga");
+    let image = Image::try_from(&path).expect("failed to load TGA");
+    assert_eq!(image.original_geometry, Geometry::new(64, 32));
+    assert!(image.image.color().has_alpha());
+
+    let png = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode TGA as PNG");
+    let decoded = image::load_from_memory(&png).expect("failed to decode PNG output");
+    assert!(decoded.color().has_alpha(), "PNG output should keep alpha");
+    assert_eq!(decoded.to_rgba8(), image.image.to_rgba8());
+    let decoded = decoded.to_rgba8();
+    assert_eq!(decoded.get_pixel(0, 0).0[3], 0);
+    assert_eq!(decoded.get_pixel(63, 31).0[3], 255);
+
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Tga),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert!(ImageFormat::Tga.is_input_only());
+    assert!(!ImageFormat::all().contains(&ImageFormat::Tga));
+    assert!(shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "tga", "in.png"]).is_err());
+}
+
+#[test]
+fn test_dds_input_uses_top_level_image() {
+    test_setup_logging();
+    // 16x8 BC1, red on the left and blue on the right, with green 8x4 to 1x1 mipmaps
+    let path = PathBuf::from("tests/test_images/texture-bc1.dds");
+    let image = Image::try_from(&path).expect("failed to load DDS");
+    assert_eq!(image.original_geometry, Geometry::new(16, 8));
+
+    let png = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode DDS as PNG");
+    let decoded = image::load_from_memory(&png)
+        .expect("failed to decode PNG output")
+        .to_rgb8();
+    assert_eq!(decoded.dimensions(), (16, 8));
+    assert_eq!(decoded.get_pixel(0, 0).0, [255, 0, 0]);
+    assert_eq!(decoded.get_pixel(15, 7).0, [0, 0, 255]);
+    assert!(ImageFormat::Dds.is_input_only());
+    assert!(!ImageFormat::all().contains(&ImageFormat::Dds));
+
+    // the same header, but BC7 compressed
+    let data = std::fs::read(&path).expect("failed to read DDS fixture");
+    let mut bc7 = data[..128].to_vec();
+    bc7[84..88].copy_from_slice(b"DX10");
+    for value in [98u32, 3, 0, 1, 0] {
+        bc7.extend_from_slice(&value.to_le_bytes());
+    }
+    bc7.extend_from_slice(&[0; 16 * 8]);
+    match Image::from_bytes(&bc7, None) {
+        Err(shrinky_rs::Error::UnsupportedFormat(message)) => {
+            assert!(message.contains("BC7_UNORM (98)"), "{message}")
+        }
+        other => panic!("BC7 should be unsupported, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_jpeg2000_has_no_codec() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load image");
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Jpeg2000),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert_eq!(
+        shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "jp2", "in.png"]).is_ok(),
+        cfg!(feature = "jpeg2000")
+    );
+}
+
+#[test]
+fn test_jxl_not_built_in() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.jpg"
+    )))
+    .expect("failed to load image");
+    match image.output_as_format(ImageFormat::Jxl) {
+        Err(shrinky_rs::Error::UnsupportedFormat(message)) => {
+            assert!(message.contains("JPEG XL"), "{message}")
+        }
+        other => panic!("expected UnsupportedFormat, got {other:?}"),
+    }
+    assert!(matches!(
+        Image::from_bytes(b"\xFF\x0A\xFA\x7F", None),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert!(!ImageFormat::all().contains(&ImageFormat::Jxl));
+    assert!(shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "jxl", "in.png"]).is_err());
+    assert_eq!(
+        image.with_output_format(ImageFormat::Jxl).output_filename(),
+        PathBuf::from(format!("tests/test_images/{IMAGE_NAME}.jxl"))
+    );
+}
+
+#[test]
+#[cfg(not(feature = "svg"))]
+fn test_svg_not_built_in() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("icon.svg");
+    let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"16\" height=\"16\"><rect width=\"16\" height=\"16\"/></svg>";
+    std::fs::write(&path, svg).expect("failed to write SVG");
+
+    match Image::try_from(&path) {
+        Err(shrinky_rs::Error::UnsupportedFormat(message)) => {
+            assert!(message.contains("SVG"), "{message}")
+        }
+        other => panic!("expected UnsupportedFormat, got {other:?}"),
+    }
+    assert!(matches!(
+        Image::from_bytes(svg, None),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert!(ImageFormat::Svg.is_input_only());
+    assert!(!ImageFormat::all().contains(&ImageFormat::Svg));
+    assert!(shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "-t", "svg", "in.png"]).is_err());
+}
+
+#[test]
+#[cfg(not(feature = "raw"))]
+fn test_raw_not_built_in() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    // RAW files are TIFF containers, so this would decode as TIFF if the extension was ignored
+    let path = tempdir.path().join("IMG_0001.NEF");
+    std::fs::copy("tests/test_images/scan.tif", &path).expect("failed to copy fixture");
+
+    assert_eq!(
+        ImageFormat::from_path(&path).expect("failed to detect RAW"),
+        ImageFormat::Raw
+    );
+    assert_eq!(
+        ImageFormat::detect_from_path(&path).expect("failed to detect RAW"),
+        ImageFormat::Raw
+    );
+    match Image::try_from(&path) {
+        Err(shrinky_rs::Error::UnsupportedFormat(message)) => {
+            assert!(message.contains("RAW"), "{message}")
+        }
+        other => panic!("expected UnsupportedFormat, got {other:?}"),
+    }
+    assert!(ImageFormat::Raw.is_input_only());
+    assert!(!ImageFormat::all().contains(&ImageFormat::Raw));
+}
+
+#[test]
+fn test_png_named_jpg_is_read_as_png() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("photo.jpg");
+    std::fs::copy(format!("tests/test_images/{IMAGE_NAME}.png"), &path)
+        .expect("failed to copy fixture");
+
+    assert_eq!(
+        ImageFormat::detect_from_path(&path).expect("failed to detect format"),
+        ImageFormat::Png
+    );
+    let info = shrinky_rs::imagedata::LazyImage::new(&path)
+        .metadata()
+        .expect("failed to read metadata");
+    assert_eq!(info.format, ImageFormat::Png);
+    assert_eq!(
+        info.geometry,
+        Geometry::new(PNG_EXPECTED_WIDTH, PNG_EXPECTED_HEIGHT)
+    );
+    let image = Image::try_from(&path).expect("failed to load PNG named .jpg");
+    assert_eq!(image.claimed_format, Some(ImageFormat::Jpg));
+    assert_eq!(image.detected_format, Some(ImageFormat::Png));
+    assert_eq!(
+        image.input_format().expect("no input format"),
+        ImageFormat::Png
+    );
+    assert_eq!(
+        image.original_geometry,
+        Geometry::new(PNG_EXPECTED_WIDTH, PNG_EXPECTED_HEIGHT)
+    );
+
+    // it's already a PNG, so PNG output is the original file
+    assert!(image.is_passthrough_eligible(ImageFormat::Png));
+    assert_eq!(
+        image
+            .output_as_format(ImageFormat::Png)
+            .expect("failed to encode PNG"),
+        std::fs::read(&path).expect("failed to read input")
+    );
+    let fallback = image
+        .fallback_to_original_format(shrinky_rs::Error::ImageEncodingError("test".to_string()))
+        .expect("failed to fall back");
+    assert_eq!(fallback.0, ImageFormat::Png);
+}
+
+#[test]
+fn test_pgm_stays_grayscale() {
+    test_setup_logging();
+    let path = PathBuf::from("tests/test_images/scan.pgm");
+    let data = std::fs::read(&path).expect("failed to read PGM fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Pnm
+    );
+
+    let image = Image::try_from(&path).expect("failed to load PGM");
+    assert_eq!(image.original_geometry, Geometry::new(64, 48));
+    assert_eq!(image.image.color(), image::ColorType::L8);
+
+    let png = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PGM as PNG");
+    let decoded = image::load_from_memory(&png).expect("failed to decode PNG output");
+    assert_eq!(
+        decoded.color(),
+        image::ColorType::L8,
+        "PNG should be grayscale"
+    );
+    assert_eq!(decoded.to_luma8(), image.image.to_luma8());
+
+    let image = image.with_output_format(ImageFormat::Png);
+    assert_eq!(
+        image.output_filename(),
+        PathBuf::from("tests/test_images/scan.png")
+    );
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Pnm),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+}
+
+#[test]
+fn test_qoi_round_trip() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{IMAGE_NAME}.png"
+    )))
+    .expect("failed to load PNG");
+
+    let qoi = image
+        .output_as_format(ImageFormat::Qoi)
+        .expect("failed to encode QOI");
+    // 14-byte header: magic, big-endian width and height, channels, colorspace
+    assert!(qoi.len() > 14);
+    assert_eq!(&qoi[0..4], b"qoif");
+    let width = u32::from_be_bytes([qoi[4], qoi[5], qoi[6], qoi[7]]);
+    let height = u32::from_be_bytes([qoi[8], qoi[9], qoi[10], qoi[11]]);
+    assert_eq!(Geometry::new(width, height), image.original_geometry);
+    assert!(matches!(qoi[12], 3 | 4), "channels: {}", qoi[12]);
+    assert!(qoi[13] <= 1, "colorspace: {}", qoi[13]);
+
+    let from_qoi = Image::from_bytes(&qoi, None).expect("failed to decode QOI");
+    let png = from_qoi
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    let decoded = image::load_from_memory(&png).expect("failed to decode PNG output");
+    assert_eq!(decoded.to_rgba8(), image.image.to_rgba8());
+
+    // only tried by auto-format when asked for
+    let (format, data) = image
+        .clone()
+        .with_candidate_formats(vec![ImageFormat::Qoi])
+        .auto_format()
+        .expect("failed to auto-format");
+    assert_eq!(format, ImageFormat::Qoi);
+    assert_eq!(data, qoi);
+}
+
+#[test]
+fn test_farbfeld_round_trip_keeps_16_bits() {
+    test_setup_logging();
+    // every sample is different in its low byte, which an 8-bit trip would lose
+    let gradient = image::ImageBuffer::from_fn(64, 32, |x, y| {
+        let value = (x * 1021 + y * 17) as u16;
+        image::Rgba([
+            value,
+            u16::MAX - value,
+            value.wrapping_mul(3),
+            40_000 + y as u16,
+        ])
+    });
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgba16(gradient.clone())
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .expect("failed to encode 16-bit PNG");
+    let image = Image::from_bytes(&png, None).expect("failed to load 16-bit PNG");
+
+    let farbfeld = image
+        .output_as_format(ImageFormat::Farbfeld)
+        .expect("failed to encode farbfeld");
+    assert_eq!(&farbfeld[0..8], b"farbfeld");
+    assert_eq!(farbfeld.len(), 16 + 64 * 32 * 8);
+
+    let decoded = Image::from_bytes(&farbfeld, None).expect("failed to decode farbfeld");
+    assert_eq!(decoded.image.color(), image::ColorType::Rgba16);
+    assert_eq!(decoded.image.to_rgba16(), gradient);
+
+    let png = decoded
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    assert_eq!(
+        image::load_from_memory(&png)
+            .expect("failed to decode PNG")
+            .to_rgba16(),
+        gradient
+    );
+    assert!(!ImageFormat::Farbfeld.is_input_only());
+}
+
+#[test]
+fn test_load_error_names_the_file() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("broken.png");
+    std::fs::write(&path, b"\x89PNG\r\n\x1a\nnot really").expect("failed to write file");
+
+    let err = Image::try_from(&path).expect_err("a broken PNG shouldn't load");
+    let message = err.to_string();
+    assert!(
+        message.starts_with(&format!("loading {}: ", path.display())),
+        "{message}"
+    );
+    assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn test_phash() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    )))
+    .expect("failed to load PNG fixture");
+    let hash = image.phash();
+    assert_eq!(hash, image.phash(), "the hash should be deterministic");
+
+    for format in [ImageFormat::Jpg, ImageFormat::Webp] {
+        let encoded = image
+            .output_as_format(format)
+            .unwrap_or_else(|e| panic!("failed to encode {format}: {e}"));
+        let reencoded = Image::from_bytes(&encoded, Some(format))
+            .unwrap_or_else(|e| panic!("failed to decode {format}: {e}"));
+        let distance = Image::phash_distance(hash, reencoded.phash());
+        assert!(distance <= 10, "{format} distance was {distance}");
+    }
+
+    // the same photo upside down
+    let mut flipped = Image::try_from(&PathBuf::from(format!(
+        "tests/test_images/{}.{}",
+        IMAGE_NAME,
+        ImageFormat::Png.extension()
+    )))
+    .expect("failed to load PNG fixture");
+    flipped
+        .rotate(shrinky_rs::RotationAngle::FlipV)
+        .expect("failed to flip image");
+    let distance = Image::phash_distance(hash, flipped.phash());
+    assert!(distance >= 20, "flipped image was only {distance} apart");
+
+    // diagonal stripes
+    let mut stripes = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 150, |x, y| {
+        if (x + y) / 25 % 2 == 0 {
+            image::Rgb([200, 30, 30])
+        } else {
+            image::Rgb([240, 240, 240])
+        }
+    }))
+    .write_to(&mut Cursor::new(&mut stripes), image::ImageFormat::Png)
+    .expect("failed to encode stripes");
+    let stripes =
+        Image::from_bytes(&stripes, Some(ImageFormat::Png)).expect("failed to load stripes");
+    let distance = Image::phash_distance(hash, stripes.phash());
+    assert!(
+        distance >= 20,
+        "different images were only {distance} apart"
+    );
+
+    assert_eq!(Image::phash_distance(0, u64::MAX), 64);
+    assert_eq!(Image::phash_distance(0b1010, 0b0110), 2);
+}
+
+#[test]
+fn test_exr_tone_mapping() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("gradient.exr");
+    // linear light from 0.0 to 8.0, left to right, which would clip from a quarter of the way
+    let hdr = image::Rgb32FImage::from_fn(64, 8, |x, _y| {
+        let value = x as f32 / 63.0 * 8.0;
+        image::Rgb([value, value, value * 0.5])
+    });
+    image::DynamicImage::ImageRgb32F(hdr)
+        .save(&path)
+        .expect("failed to write EXR");
+    let data = std::fs::read(&path).expect("failed to read EXR");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Exr
+    );
+
+    let image = Image::try_from(&path).expect("failed to load EXR");
+    assert_eq!(image.original_geometry, Geometry::new(64, 8));
+    assert_eq!(image.image.color(), image::ColorType::Rgb8);
+
+    let jpg = image
+        .output_as_format(ImageFormat::Jpg)
+        .expect("failed to encode EXR as JPG");
+    let decoded = image::load_from_memory(&jpg)
+        .expect("failed to decode JPG output")
+        .to_luma8();
+    let row: Vec<u8> = (0..64).map(|x| decoded.get_pixel(x, 4).0[0]).collect();
+    assert!(row[0] < 10, "black should stay black: {row:?}");
+    assert!(
+        row.iter().all(|luma| *luma < 250),
+        "highlights blew out to white: {row:?}"
+    );
+    // values over 1.0 are still told apart
+    assert!(row[16] + 10 < row[63], "{row:?}");
+
+    let exposure = Image::builder(&path)
+        .with_tone_map(ToneMap::Exposure)
+        .build()
+        .expect("failed to load EXR with exposure tone mapping");
+    assert_ne!(exposure.image, image.image);
+    assert!(
+        exposure.image.to_luma8().get_pixel(8, 0).0[0]
+            > image.image.to_luma8().get_pixel(8, 0).0[0],
+        "exposure should have brighter midtones than Reinhard"
+    );
+    // decoding from memory uses the same operators
+    assert_eq!(
+        Image::from_bytes(&data, None)
+            .expect("failed to decode EXR data")
+            .image,
+        image.image
+    );
+    assert_eq!(
+        Image::from_bytes_with_tone_map(&data, None, ToneMap::Exposure)
+            .expect("failed to decode EXR data with exposure tone mapping")
+            .image,
+        exposure.image
+    );
+
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Exr),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+
+    let cli = shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "render.exr"])
+        .expect("failed to parse arguments");
+    assert_eq!(cli.tonemap, ToneMap::Reinhard);
+    let cli =
+        shrinky_rs::cli::Cli::try_parse_from(["shrinky-rs", "--tonemap", "exposure", "render.exr"])
+            .expect("failed to parse --tonemap");
+    assert_eq!(cli.tonemap, ToneMap::Exposure);
+}
+
+#[test]
+fn test_hdr_input() {
+    test_setup_logging();
+    let path = PathBuf::from("tests/test_images/environment.hdr");
+    let data = std::fs::read(&path).expect("failed to read HDR fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&data).expect("unknown format"),
+        ImageFormat::Hdr
+    );
+
+    let image = Image::try_from(&path).expect("failed to load HDR");
+    assert_eq!(image.original_geometry, Geometry::new(64, 48));
+    assert_eq!(image.image.color(), image::ColorType::Rgb8);
+
+    let webp = image
+        .output_as_format(ImageFormat::Webp)
+        .expect("failed to encode HDR as WebP");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&webp).expect("unknown output format"),
+        ImageFormat::Webp
+    );
+    let decoded = image::load_from_memory(&webp).expect("failed to decode WebP output");
+    assert_eq!((decoded.width(), decoded.height()), (64, 48));
+
+    // resized while it's still float, then tone mapped
+    let resized = Image::builder(&path)
+        .with_target_geometry(Geometry::new(32, 24))
+        .build()
+        .expect("failed to load and resize HDR");
+    assert_eq!(resized.current_geometry(), Geometry::new(32, 24));
+    assert_eq!(resized.original_geometry, Geometry::new(64, 48));
+    assert_eq!(resized.image.color(), image::ColorType::Rgb8);
+
+    assert!(matches!(
+        image.output_as_format(ImageFormat::Hdr),
+        Err(shrinky_rs::Error::UnsupportedFormat(_))
+    ));
+    assert_eq!(
+        image
+            .with_output_format(ImageFormat::Webp)
+            .output_filename(),
+        PathBuf::from("tests/test_images/environment.webp")
+    );
+}
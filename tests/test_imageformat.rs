@@ -1,6 +1,9 @@
 use libheif_rs::HeifError;
 use shrinky_rs::{ImageFormat, cli::test_setup_logging};
-use std::str::FromStr;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 #[test]
 fn test_imageformat() {
@@ -11,8 +14,24 @@ fn test_imageformat() {
         ("png", Some(ImageFormat::Png)),
         ("webp", Some(ImageFormat::Webp)),
         ("avif", Some(ImageFormat::Avif)),
-        ("bmp", None),
-        ("tiff", None),
+        ("tiff", Some(ImageFormat::Tiff)),
+        ("tif", Some(ImageFormat::Tiff)),
+        ("gif", Some(ImageFormat::Gif)),
+        ("ico", Some(ImageFormat::Ico)),
+        ("tga", Some(ImageFormat::Tga)),
+        ("pgm", Some(ImageFormat::Pnm)),
+        ("ppm", Some(ImageFormat::Pnm)),
+        ("pbm", Some(ImageFormat::Pnm)),
+        ("pnm", Some(ImageFormat::Pnm)),
+        ("exr", Some(ImageFormat::Exr)),
+        ("hdr", Some(ImageFormat::Hdr)),
+        ("dds", Some(ImageFormat::Dds)),
+        ("svg", Some(ImageFormat::Svg)),
+        ("dng", Some(ImageFormat::Raw)),
+        ("CR2", Some(ImageFormat::Raw)),
+        ("nef", Some(ImageFormat::Raw)),
+        ("bmp", Some(ImageFormat::Bmp)),
+        ("pcx", None),
     ];
 
     for (input, expected) in expected.iter() {
@@ -29,7 +48,16 @@ fn test_imageformat() {
         }
     }
 
-    assert_eq!(format!("{}", ImageFormat::Jpg), "JPG");
+    assert_eq!(format!("{}", ImageFormat::Jpg), "jpg");
+    assert_eq!(
+        format!("{}", ImageFormat::Tiff),
+        ImageFormat::Tiff.extension()
+    );
+    assert_eq!(format!("{}", ImageFormat::Jpg.display_upper()), "JPG");
+    assert_eq!(
+        format!("{:<5}|", ImageFormat::Webp.display_upper()),
+        "WEBP |"
+    );
 
     assert_eq!(
         <ImageFormat as FromStr>::from_str("testfile.jpg").expect("Failed to parse from filename"),
@@ -47,7 +75,14 @@ fn test_imageformat() {
 
     assert!(<ImageFormat as FromStr>::from_str("cheese").is_err());
 
-    assert!(ImageFormat::all().len() == 6);
+    assert_eq!(
+        ImageFormat::all().len(),
+        if cfg!(feature = "jpeg2000") { 12 } else { 11 }
+    );
+    assert_eq!(
+        ImageFormat::all().contains(&ImageFormat::Jpeg2000),
+        cfg!(feature = "jpeg2000")
+    );
 
     assert!(ImageFormat::Jpg.is_native_image_format());
     assert!(!ImageFormat::Avif.is_native_image_format());
@@ -60,6 +95,21 @@ fn test_imageformat() {
         (ImageFormat::Avif, false),
         (ImageFormat::Heic, false),
         (ImageFormat::Heif, false),
+        (ImageFormat::Tiff, true),
+        (ImageFormat::Bmp, true),
+        (ImageFormat::Gif, true),
+        (ImageFormat::Qoi, true),
+        (ImageFormat::Farbfeld, true),
+        (ImageFormat::Ico, true),
+        (ImageFormat::Tga, true),
+        (ImageFormat::Pnm, true),
+        (ImageFormat::Exr, true),
+        (ImageFormat::Hdr, true),
+        (ImageFormat::Dds, true),
+        (ImageFormat::Jpeg2000, false),
+        (ImageFormat::Jxl, false),
+        (ImageFormat::Svg, false),
+        (ImageFormat::Raw, false),
     ] {
         let test_format: Result<image::ImageFormat, shrinky_rs::Error> = fmt.try_into();
         if expected_result {
@@ -96,3 +146,292 @@ fn test_error() {
     let shrinky_error: shrinky_rs::Error = error.into();
     assert!(format!("{:?}", shrinky_error).contains("Test error message"));
 }
+
+#[test]
+fn test_imageformat_ordering() {
+    test_setup_logging();
+    let mut formats = ImageFormat::all();
+    formats.sort();
+    let mut expected = vec![
+        ImageFormat::Farbfeld,
+        ImageFormat::Bmp,
+        ImageFormat::Qoi,
+        ImageFormat::Gif,
+        ImageFormat::Tiff,
+        ImageFormat::Png,
+        ImageFormat::Jpg,
+        ImageFormat::Webp,
+        ImageFormat::Avif,
+        ImageFormat::Heic,
+        ImageFormat::Heif,
+    ];
+    if cfg!(feature = "jpeg2000") {
+        expected.insert(7, ImageFormat::Jpeg2000);
+    }
+    assert_eq!(formats, expected);
+    assert!(ImageFormat::Heif > ImageFormat::Avif);
+    assert!(ImageFormat::Avif > ImageFormat::Webp);
+    assert!(ImageFormat::Jpg > ImageFormat::Png);
+    assert_eq!(
+        ImageFormat::all().into_iter().max(),
+        Some(ImageFormat::Heif)
+    );
+}
+
+#[test]
+fn test_io_error_kinds() {
+    test_setup_logging();
+    let cases = [
+        (std::io::ErrorKind::NotFound, "FileNotFound"),
+        (std::io::ErrorKind::PermissionDenied, "PermissionDenied"),
+        (std::io::ErrorKind::StorageFull, "DiskFull"),
+        (std::io::ErrorKind::WriteZero, "DiskFull"),
+        (std::io::ErrorKind::InvalidData, "FileSystem"),
+    ];
+    for (kind, variant) in cases {
+        let error: shrinky_rs::Error = std::io::Error::new(kind, "test io error").into();
+        let debug = format!("{:?}", error);
+        assert!(
+            debug.starts_with(variant),
+            "{:?} should map to {}, got {}",
+            kind,
+            variant,
+            debug
+        );
+    }
+    let error: shrinky_rs::Error =
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "test io error").into();
+    assert!(format!("{error:?}").contains("test io error"));
+
+    let path = std::path::Path::new("tests/test_images/missing.png");
+    let error = shrinky_rs::Error::from_io(&std::io::ErrorKind::NotFound.into(), path);
+    assert!(matches!(&error, shrinky_rs::Error::FileNotFound(p) if p == path));
+    assert_eq!(
+        error.to_string(),
+        "file not found: tests/test_images/missing.png"
+    );
+    let error = shrinky_rs::Error::from_io(&std::io::ErrorKind::PermissionDenied.into(), path);
+    assert!(matches!(&error, shrinky_rs::Error::PermissionDenied(p) if p == path));
+
+    let missing = shrinky_rs::imagedata::Image::try_from(&path.to_path_buf())
+        .expect_err("a missing file shouldn't load");
+    assert!(
+        matches!(&missing, shrinky_rs::Error::FileNotFound(p) if p == path),
+        "{missing:?}"
+    );
+}
+
+#[test]
+fn test_detect_from_bytes() {
+    test_setup_logging();
+    for (fixture, expected) in [
+        ("jpg", ImageFormat::Jpg),
+        ("png", ImageFormat::Png),
+        ("webp", ImageFormat::Webp),
+        ("avif", ImageFormat::Avif),
+        ("heic", ImageFormat::Heic),
+    ] {
+        let data = std::fs::read(format!("tests/test_images/bruny-oysters.{fixture}"))
+            .expect("failed to read fixture");
+        assert_eq!(
+            ImageFormat::detect_from_bytes(&data).expect("failed to detect format"),
+            expected,
+            "wrong format for the {fixture} fixture"
+        );
+    }
+
+    // a plain HEIF file only has the structural brands
+    let mut heif_header = vec![0x00, 0x00, 0x00, 0x18];
+    heif_header.extend_from_slice(b"ftypmif1\0\0\0\0mif1miaf");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&heif_header).expect("failed to detect HEIF"),
+        ImageFormat::Heif
+    );
+
+    let webp_header = b"RIFF\x24\x00\x00\x00WEBPVP8 ";
+    assert_eq!(
+        ImageFormat::detect_from_bytes(webp_header).expect("failed to detect WebP"),
+        ImageFormat::Webp
+    );
+    assert!(ImageFormat::detect_from_bytes(b"RIFF\x24\x00\x00\x00WAVEfmt ").is_err());
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"GIF89a").expect("failed to detect GIF"),
+        ImageFormat::Gif
+    );
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"qoif\0\0\0\x40\0\0\0\x30\x03\0")
+            .expect("failed to detect QOI"),
+        ImageFormat::Qoi
+    );
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"farbfeld\0\0\0\x40\0\0\0\x30")
+            .expect("failed to detect farbfeld"),
+        ImageFormat::Farbfeld
+    );
+    for tiff_header in [&b"II*\0\x08\0\0\0"[..], b"MM\0*\0\0\0\x08"] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(tiff_header).expect("failed to detect TIFF"),
+            ImageFormat::Tiff
+        );
+    }
+    let bmp = std::fs::read("tests/test_images/screenshot.bmp").expect("failed to read fixture");
+    assert_eq!(
+        ImageFormat::detect_from_bytes(&bmp).expect("failed to detect BMP"),
+        ImageFormat::Bmp
+    );
+    // the file size, reserved bytes and pixel offset, then a BITMAPCOREHEADER's size
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"BM\x26\0\0\0\0\0\0\0\x1a\0\0\0\x0c\0\0\0")
+            .expect("failed to detect BMP"),
+        ImageFormat::Bmp
+    );
+    // "BM" then something that isn't a BMP header
+    assert!(ImageFormat::detect_from_bytes(b"BMW 320i service history").is_err());
+    assert!(
+        ImageFormat::detect_from_bytes(b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0\x29\0\0\0").is_err(),
+        "41 isn't a DIB header size"
+    );
+    assert!(
+        ImageFormat::detect_from_bytes(b"BM\x10\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0").is_err(),
+        "a 16 byte file can't hold a 40 byte DIB header"
+    );
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"\0\0\x01\0\x02\0").expect("failed to detect ICO"),
+        ImageFormat::Ico
+    );
+    for pnm_header in [&b"P5\n64 48\n255\n"[..], b"P3 1 1 255", b"P7\nWIDTH 1"] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(pnm_header).expect("failed to detect PNM"),
+            ImageFormat::Pnm
+        );
+    }
+    assert!(ImageFormat::detect_from_bytes(b"P8\n").is_err());
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"\x76\x2f\x31\x01\x02\0\0\0")
+            .expect("failed to detect EXR"),
+        ImageFormat::Exr
+    );
+    for hdr_header in [&b"#?RADIANCE\n"[..], b"#?RGBE\n"] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(hdr_header).expect("failed to detect HDR"),
+            ImageFormat::Hdr
+        );
+    }
+    assert_eq!(
+        ImageFormat::detect_from_bytes(b"DDS \x7c\0\0\0").expect("failed to detect DDS"),
+        ImageFormat::Dds
+    );
+    for jpeg2000_header in [
+        &b"\0\0\0\x0CjP  \r\n\x87\n\0\0\0\x14ftypjp2 "[..],
+        b"\xFF\x4F\xFF\x51\0\x2F",
+    ] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(jpeg2000_header).expect("failed to detect JPEG 2000"),
+            ImageFormat::Jpeg2000
+        );
+    }
+    for jxl_header in [&b"\0\0\0\x0CJXL \r\n\x87\n"[..], b"\xFF\x0A\xFA\x7F"] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(jxl_header).expect("failed to detect JPEG XL"),
+            ImageFormat::Jxl
+        );
+    }
+    for svg in [
+        &b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"[..],
+        b"\xEF\xBB\xBF<?xml version=\"1.0\"?>\n<!-- icon -->\n<svg width=\"16\"/>",
+        b"<!DOCTYPE svg [<!ENTITY red \"#f00\">]>\n<svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"/>",
+    ] {
+        assert_eq!(
+            ImageFormat::detect_from_bytes(svg).expect("failed to detect SVG"),
+            ImageFormat::Svg
+        );
+    }
+    assert!(ImageFormat::detect_from_bytes(b"<?xml version=\"1.0\"?><html/>").is_err());
+    // XHTML with an inline SVG is still an HTML page
+    assert!(
+        ImageFormat::detect_from_bytes(
+            b"<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\"><body><svg/></body></html>"
+        )
+        .is_err()
+    );
+    assert!(ImageFormat::detect_from_bytes(&[]).is_err());
+}
+
+#[test]
+fn test_imageformat_from_path() {
+    test_setup_logging();
+    let cases = [
+        ("photo.jpg", Some(ImageFormat::Jpg)),
+        ("photo.JPEG", Some(ImageFormat::Jpg)),
+        ("some/dir/photo.png", Some(ImageFormat::Png)),
+        ("/tmp/archive.tar.webp", Some(ImageFormat::Webp)),
+        ("scan.tiff", Some(ImageFormat::Tiff)),
+        ("image.heic", Some(ImageFormat::Heic)),
+        ("dir.png/photo.avif", Some(ImageFormat::Avif)),
+        ("photo", None),
+        ("photo.", None),
+        ("photo.bmp", Some(ImageFormat::Bmp)),
+        ("photo.pcx", None),
+        ("dir.png/photo", None),
+    ];
+    for (path, expected) in cases {
+        let from_path = ImageFormat::from_path(Path::new(path)).ok();
+        assert_eq!(from_path, expected, "from_path({path})");
+        assert_eq!(
+            ImageFormat::try_from(Path::new(path)).ok(),
+            expected,
+            "TryFrom<&Path>({path})"
+        );
+        assert_eq!(
+            ImageFormat::try_from(&PathBuf::from(path)).ok(),
+            expected,
+            "TryFrom<&PathBuf>({path})"
+        );
+    }
+
+    // works straight from a directory listing
+    let fixture = std::fs::read_dir("tests/test_images")
+        .expect("failed to list fixtures")
+        .filter_map(Result::ok)
+        .find(|entry| entry.file_name() == "bruny-oysters.png")
+        .expect("missing PNG fixture");
+    assert_eq!(
+        ImageFormat::from_path(&fixture.path()).expect("failed to detect PNG"),
+        ImageFormat::Png
+    );
+}
+
+#[test]
+fn test_imageformat_all_extensions() {
+    test_setup_logging();
+    for format in ImageFormat::all().into_iter().chain([
+        ImageFormat::Ico,
+        ImageFormat::Tga,
+        ImageFormat::Pnm,
+        ImageFormat::Exr,
+        ImageFormat::Hdr,
+        ImageFormat::Dds,
+        ImageFormat::Jpeg2000,
+        ImageFormat::Jxl,
+        ImageFormat::Svg,
+        ImageFormat::Raw,
+    ]) {
+        let extensions = format.all_extensions();
+        assert_eq!(extensions.first(), Some(&format.extension()));
+        for extension in extensions {
+            assert_eq!(
+                extension.parse::<ImageFormat>().ok(),
+                Some(format),
+                "{extension} should parse as {format}"
+            );
+            assert_eq!(
+                extension.to_uppercase().parse::<ImageFormat>().ok(),
+                Some(format)
+            );
+        }
+    }
+    assert_eq!(ImageFormat::Jpg.all_extensions(), ["jpg", "jpeg"]);
+    assert_eq!(ImageFormat::Tiff.all_extensions(), ["tif", "tiff"]);
+    assert_eq!(ImageFormat::Jpeg2000.all_extensions(), ["jp2", "j2k"]);
+    assert_eq!(ImageFormat::Jpeg2000.mime_type(), "image/jp2");
+}
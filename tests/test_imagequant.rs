@@ -0,0 +1,113 @@
+#![cfg(feature = "imagequant")]
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use shrinky_rs::{
+    ImageFormat,
+    cli::{Cli, test_setup_logging},
+    imagedata::Image,
+};
+
+/// The IHDR color type byte, 3 is indexed
+fn png_color_type(data: &[u8]) -> u8 {
+    assert_eq!(
+        &data[12..16],
+        b"IHDR",
+        "expected IHDR to be the first chunk"
+    );
+    data[25]
+}
+
+fn flat_color_image() -> Image {
+    let source = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(128, 96, |x, y| {
+        // UI-like blocks of flat color, with a transparent border
+        if x < 4 || y < 4 {
+            return image::Rgba([0, 0, 0, 0]);
+        }
+        let block = (x / 16 + y / 16 * 8) as u8;
+        image::Rgba([block * 5, 255 - block * 3, block * 2, 255])
+    }));
+    Image {
+        output_format: Some(ImageFormat::Png),
+        ..Image::from_dynamic_image(source, PathBuf::from("tests/test_images/quantize.png"))
+    }
+}
+
+#[test]
+fn test_quantize_flat_colors() {
+    test_setup_logging();
+    let image = flat_color_image();
+    let truecolor = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode truecolor PNG");
+    let quantized = image
+        .clone()
+        .with_quantize(256, false)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode quantized PNG");
+
+    assert_eq!(png_color_type(&truecolor), 6);
+    assert_eq!(png_color_type(&quantized), 3, "expected a palette PNG");
+    assert!(
+        quantized.len() < truecolor.len(),
+        "quantized ({} bytes) should be smaller than truecolor ({} bytes)",
+        quantized.len(),
+        truecolor.len()
+    );
+
+    // few enough colors that they should all survive, give or take rounding
+    let decoded = image::load_from_memory(&quantized).expect("failed to decode quantized PNG");
+    for (decoded, original) in decoded
+        .to_rgba8()
+        .pixels()
+        .zip(image.image.to_rgba8().pixels())
+    {
+        assert_eq!(decoded[3], original[3], "alpha should be kept");
+        if original[3] > 0 {
+            let max_diff = (0..3)
+                .map(|channel| decoded[channel].abs_diff(original[channel]))
+                .max()
+                .unwrap_or_default();
+            assert!(max_diff <= 2, "{decoded:?} is too far from {original:?}");
+        }
+    }
+}
+
+#[test]
+fn test_quantize_skips_photos_unless_forced() {
+    test_setup_logging();
+    let photo = Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load PNG fixture");
+    assert!(photo.is_photo());
+
+    let skipped = photo
+        .clone()
+        .with_quantize(64, false)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    assert_ne!(png_color_type(&skipped), 3, "photos shouldn't be quantized");
+
+    let forced = photo
+        .with_quantize(64, true)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode forced quantized PNG");
+    assert_eq!(png_color_type(&forced), 3);
+}
+
+#[test]
+fn test_quantize_cli() {
+    let cli = Cli::parse_from(["shrinky-rs", "--quantize", "image.png"]);
+    assert_eq!(cli.quantize, Some(256));
+    assert!(!cli.force_quantize);
+    let cli = Cli::parse_from([
+        "shrinky-rs",
+        "--quantize=16",
+        "--force-quantize",
+        "image.png",
+    ]);
+    assert_eq!(cli.quantize, Some(16));
+    assert!(cli.force_quantize);
+    assert!(Cli::try_parse_from(["shrinky-rs", "--quantize=1", "image.png"]).is_err());
+    assert!(Cli::try_parse_from(["shrinky-rs", "--quantize=257", "image.png"]).is_err());
+}
@@ -0,0 +1,116 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use tempfile::TempDir;
+
+fn copy_fixture_to_tempdir(tempdir: &TempDir, filename: &str) -> PathBuf {
+    let destination = tempdir.path().join(filename);
+    fs::copy("tests/test_images/bruny-oysters.jpg", &destination)
+        .expect("failed to copy fixture image");
+    destination
+}
+
+fn run_shrinky(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shrinky-rs"))
+        .args(args)
+        .output()
+        .expect("failed to run shrinky-rs")
+}
+
+#[test]
+fn test_force_refuses_to_replace_input() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = copy_fixture_to_tempdir(&tempdir, "force.jpg");
+    let original = fs::read(&input).expect("failed to read input");
+
+    let result = run_shrinky(&[
+        "--force",
+        "--output-type",
+        "jpg",
+        "--quality",
+        "50",
+        input.to_str().expect("utf-8 path"),
+    ]);
+
+    assert!(
+        !result.status.success(),
+        "--force alone should not replace the input file"
+    );
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--in-place"));
+    assert_eq!(
+        fs::read(&input).expect("failed to read input"),
+        original,
+        "input file should be untouched"
+    );
+}
+
+#[test]
+fn test_in_place_replaces_input() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = copy_fixture_to_tempdir(&tempdir, "in-place.jpg");
+    let original = fs::read(&input).expect("failed to read input");
+
+    let result = run_shrinky(&[
+        "--in-place",
+        "--output-type",
+        "jpg",
+        "--quality",
+        "50",
+        input.to_str().expect("utf-8 path"),
+    ]);
+
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let replaced = fs::read(&input).expect("failed to read input");
+    assert!(
+        replaced.len() < original.len(),
+        "input should have been re-encoded at a lower quality"
+    );
+}
+
+#[test]
+fn test_overwrite_if_smaller_keeps_larger_input() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = copy_fixture_to_tempdir(&tempdir, "if-smaller.jpg");
+    let original = fs::read(&input).expect("failed to read input");
+
+    let run = |quality: &str| {
+        run_shrinky(&[
+            "--in-place",
+            "--overwrite-if-smaller",
+            "--output-type",
+            "jpg",
+            "--quality",
+            quality,
+            input.to_str().expect("utf-8 path"),
+        ])
+    };
+
+    // re-encoding at the highest quality makes the file bigger
+    let result = run("100");
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(String::from_utf8_lossy(&result.stderr).contains("isn't smaller than"));
+    assert_eq!(
+        fs::read(&input).expect("failed to read input"),
+        original,
+        "input file should be untouched"
+    );
+
+    let result = run("50");
+    assert!(
+        result.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(fs::read(&input).expect("failed to read input").len() < original.len());
+}
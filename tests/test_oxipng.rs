@@ -0,0 +1,60 @@
+#![cfg(feature = "oxipng")]
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use shrinky_rs::{
+    ImageFormat,
+    cli::{Cli, test_setup_logging},
+    imagedata::{DEFAULT_OXIPNG_LEVEL, Image, MAX_OXIPNG_LEVEL, optimize_png},
+};
+
+fn fixture() -> Image {
+    Image::try_from(&PathBuf::from("tests/test_images/bruny-oysters.png"))
+        .expect("failed to load PNG fixture")
+}
+
+#[test]
+fn test_optimize_png_is_smaller_and_lossless() {
+    test_setup_logging();
+    let image = fixture();
+    let unoptimized = image
+        .clone()
+        .with_png_compression(shrinky_rs::PngCompression::Best)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    let optimized = image
+        .clone()
+        .with_png_compression(shrinky_rs::PngCompression::Best)
+        .with_optimize_png(DEFAULT_OXIPNG_LEVEL)
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode optimized PNG");
+    assert!(
+        optimized.len() <= unoptimized.len(),
+        "optimized ({} bytes) should be no bigger than unoptimized ({} bytes)",
+        optimized.len(),
+        unoptimized.len()
+    );
+
+    let unoptimized = image::load_from_memory(&unoptimized).expect("failed to decode PNG");
+    let optimized = image::load_from_memory(&optimized).expect("failed to decode optimized PNG");
+    assert_eq!(unoptimized.to_rgba8(), optimized.to_rgba8());
+}
+
+#[test]
+fn test_optimize_png_options() {
+    test_setup_logging();
+    assert!(optimize_png(b"not a png", DEFAULT_OXIPNG_LEVEL).is_err());
+    assert!(
+        fixture()
+            .with_optimize_png(MAX_OXIPNG_LEVEL + 1)
+            .output_as_format(ImageFormat::Png)
+            .is_err()
+    );
+
+    let cli = Cli::parse_from(["shrinky-rs", "--optimize-png", "image.png"]);
+    assert_eq!(cli.optimize_png, Some(DEFAULT_OXIPNG_LEVEL));
+    let cli = Cli::parse_from(["shrinky-rs", "--optimize-png=4", "image.png"]);
+    assert_eq!(cli.optimize_png, Some(4));
+    assert!(Cli::try_parse_from(["shrinky-rs", "--optimize-png=7", "image.png"]).is_err());
+}
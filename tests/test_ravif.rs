@@ -0,0 +1,64 @@
+#![cfg(feature = "ravif")]
+
+use std::path::PathBuf;
+
+use shrinky_rs::{ImageFormat, cli::test_setup_logging, imagedata::Image};
+
+fn test_image(source: image::DynamicImage) -> Image {
+    Image {
+        output_format: Some(ImageFormat::Avif),
+        ..Image::from_dynamic_image(source, PathBuf::from("tests/test_images/ravif.png"))
+    }
+}
+
+#[test]
+fn test_ravif_output_decodes() {
+    test_setup_logging();
+    let image = test_image(image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(
+        48,
+        32,
+        |x, y| image::Rgb([(x * 5) as u8, (y * 7) as u8, 90]),
+    )));
+
+    for effort in [0, shrinky_rs::imagedata::MAX_EFFORT] {
+        let encoded = image
+            .clone()
+            .with_effort(effort)
+            .with_avif_quality(70)
+            .output_as_format(ImageFormat::Avif)
+            .expect("failed to encode AVIF with ravif");
+        assert_eq!(
+            image::guess_format(&encoded).expect("failed to guess format"),
+            image::ImageFormat::Avif
+        );
+        let decoded = image::load_from_memory_with_format(&encoded, image::ImageFormat::Avif)
+            .expect("the image crate should decode ravif output");
+        assert_eq!((decoded.width(), decoded.height()), (48, 32));
+    }
+}
+
+#[test]
+fn test_ravif_preserves_alpha() {
+    test_setup_logging();
+    let image = test_image(image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(
+        32,
+        32,
+        |x, _y| {
+            let alpha = if x < 16 { 255 } else { 0 };
+            image::Rgba([30, 160, 220, alpha])
+        },
+    )));
+
+    let encoded = image
+        .output_as_format(ImageFormat::Avif)
+        .expect("failed to encode AVIF with ravif");
+    let decoded = image::load_from_memory_with_format(&encoded, image::ImageFormat::Avif)
+        .expect("the image crate should decode ravif output");
+    assert!(
+        decoded.color().has_alpha(),
+        "decoded AVIF should have alpha"
+    );
+    let decoded = decoded.to_rgba8();
+    assert_eq!(decoded.get_pixel(0, 16).0[3], 255);
+    assert!(decoded.get_pixel(31, 16).0[3] < 16);
+}
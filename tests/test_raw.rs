@@ -0,0 +1,78 @@
+#![cfg(feature = "raw")]
+
+use std::path::PathBuf;
+
+use shrinky_rs::{Error, ImageFormat, cli::test_setup_logging, imagedata::Image};
+
+/// 64x48 RGGB sensor, 16-bit uncompressed strips, little endian, cropped to 60x40
+const SENSOR: &str = "tests/test_images/sensor.dng";
+/// The same sensor data in lossless JPEG tiles with restart markers, big endian
+const SENSOR_LJPEG: &str = "tests/test_images/sensor-ljpeg.dng";
+
+fn decode_png(image: &Image) -> image::DynamicImage {
+    let encoded = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    image::load_from_memory_with_format(&encoded, image::ImageFormat::Png)
+        .expect("failed to decode PNG")
+}
+
+#[test]
+fn test_dng_to_jpeg() {
+    test_setup_logging();
+    let image = Image::try_from(&PathBuf::from(SENSOR)).expect("failed to decode DNG");
+    assert_eq!(image.input_format().ok(), Some(ImageFormat::Raw));
+
+    let encoded = image
+        .output_as_format(ImageFormat::Jpg)
+        .expect("failed to encode JPEG");
+    let decoded = image::load_from_memory_with_format(&encoded, image::ImageFormat::Jpeg)
+        .expect("failed to decode JPEG");
+    // DefaultCropSize, not the whole sensor
+    assert_eq!((decoded.width(), decoded.height()), (60, 40));
+
+    // the scene gets redder to the right, once it's white balanced
+    let rgb = decoded.to_rgb8();
+    assert!(
+        rgb.get_pixel(55, 20).0[0] > rgb.get_pixel(4, 20).0[0] + 40,
+        "{:?} {:?}",
+        rgb.get_pixel(4, 20),
+        rgb.get_pixel(55, 20)
+    );
+}
+
+#[test]
+fn test_lossless_jpeg_dng_matches_uncompressed() {
+    test_setup_logging();
+    let uncompressed = Image::try_from(&PathBuf::from(SENSOR)).expect("failed to decode DNG");
+    let compressed =
+        Image::try_from(&PathBuf::from(SENSOR_LJPEG)).expect("failed to decode lossless DNG");
+    assert_eq!(
+        decode_png(&uncompressed).to_rgb16(),
+        decode_png(&compressed).to_rgb16()
+    );
+
+    let data = std::fs::read(SENSOR_LJPEG).expect("failed to read DNG");
+    let from_bytes =
+        Image::from_bytes(&data, Some(ImageFormat::Raw)).expect("failed to decode DNG bytes");
+    assert_eq!(
+        decode_png(&from_bytes).to_rgb16(),
+        decode_png(&compressed).to_rgb16()
+    );
+}
+
+#[test]
+fn test_other_raw_formats_need_converting() {
+    test_setup_logging();
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    // a TIFF without a DNGVersion tag, like a CR2 or NEF
+    let path = tempdir.path().join("IMG_0001.CR2");
+    std::fs::copy("tests/test_images/scan.tif", &path).expect("failed to copy fixture");
+
+    let err = Image::try_from(&path).expect_err("only DNG can be decoded");
+    assert_eq!(
+        err.to_exit_code(),
+        Error::UnsupportedFormat(String::new()).to_exit_code()
+    );
+    assert!(err.to_string().contains("convert"), "{err}");
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+use tempfile::TempDir;
+
+fn run_shrinky(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shrinky-rs"))
+        .args(args)
+        .output()
+        .expect("failed to run shrinky-rs")
+}
+
+/// A tree with an image at each of three depths, plus a text file that should be skipped
+fn image_tree() -> TempDir {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let nested = tempdir.path().join("a").join("b");
+    fs::create_dir_all(&nested).expect("failed to create directories");
+    for (fixture, destination) in [
+        ("still.gif", tempdir.path().join("still.gif")),
+        ("scan.pgm", tempdir.path().join("a").join("scan.pgm")),
+        ("screenshot.bmp", nested.join("screenshot.bmp")),
+    ] {
+        fs::copy(Path::new("tests/test_images").join(fixture), destination)
+            .expect("failed to copy fixture");
+    }
+    fs::write(tempdir.path().join("notes.txt"), "not an image").expect("failed to write notes");
+    tempdir
+}
+
+#[test]
+fn test_recursive_processes_every_level() {
+    let tempdir = image_tree();
+
+    let result = run_shrinky(&[
+        "--recursive",
+        "--output-type",
+        "png",
+        tempdir.path().to_str().expect("utf-8 path"),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "command failed: {stderr}");
+    for output in ["still.png", "a/scan.png", "a/b/screenshot.png"] {
+        assert!(tempdir.path().join(output).exists(), "{output} missing");
+    }
+    assert!(
+        stderr.contains("3 processed, 1 skipped, 0 failed"),
+        "unexpected summary: {stderr}"
+    );
+}
+
+#[test]
+fn test_max_depth_limits_the_walk_and_failures_are_counted() {
+    let tempdir = image_tree();
+    // named like an image, so it's attempted, but it won't decode
+    fs::write(tempdir.path().join("a").join("broken.png"), "not a PNG")
+        .expect("failed to write broken image");
+
+    let result = run_shrinky(&[
+        "--recursive",
+        "--max-depth",
+        "2",
+        "--output-type",
+        "png",
+        tempdir.path().to_str().expect("utf-8 path"),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(!result.status.success(), "the broken image should fail");
+    assert!(tempdir.path().join("a/scan.png").exists());
+    assert!(
+        !tempdir.path().join("a/b/screenshot.png").exists(),
+        "--max-depth 2 shouldn't reach a/b"
+    );
+    assert!(
+        stderr.contains("2 processed, 1 skipped, 1 failed"),
+        "unexpected summary: {stderr}"
+    );
+}
+
+#[test]
+fn test_directory_without_recursive_exits_with_5() {
+    let tempdir = image_tree();
+
+    let result = run_shrinky(&[tempdir.path().to_str().expect("utf-8 path")]);
+
+    assert_eq!(result.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--recursive"));
+    assert!(!tempdir.path().join("still.png").exists());
+}
+
+#[test]
+fn test_outputs_that_arent_smaller_are_counted_as_skipped() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    // a photo gets much bigger as a PNG, a flat screenshot gets smaller
+    for fixture in ["bruny-oysters.jpg", "screenshot.bmp"] {
+        fs::copy(
+            Path::new("tests/test_images").join(fixture),
+            tempdir.path().join(fixture),
+        )
+        .expect("failed to copy fixture");
+    }
+
+    let result = run_shrinky(&[
+        "--recursive",
+        "--overwrite-if-smaller",
+        "--output-type",
+        "png",
+        tempdir.path().to_str().expect("utf-8 path"),
+    ]);
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "command failed: {stderr}");
+    assert!(!tempdir.path().join("bruny-oysters.png").exists());
+    assert!(tempdir.path().join("screenshot.png").exists());
+    assert!(
+        stderr.contains("1 processed, 1 skipped, 0 failed"),
+        "unexpected summary: {stderr}"
+    );
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "svg")]
+
+use std::path::PathBuf;
+
+use shrinky_rs::{
+    Error, ImageFormat,
+    cli::test_setup_logging,
+    imagedata::{Geometry, Image},
+};
+
+const BADGE: &str = "tests/test_images/badge.svg";
+
+fn decode_png(image: Image) -> image::RgbaImage {
+    let encoded = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    image::load_from_memory_with_format(&encoded, image::ImageFormat::Png)
+        .expect("failed to decode PNG")
+        .to_rgba8()
+}
+
+#[test]
+fn test_svg_renders_at_target_geometry() {
+    test_setup_logging();
+    let image = Image::builder(BADGE)
+        .with_target_geometry(Geometry::new(256, 256))
+        .build()
+        .expect("failed to draw SVG");
+    assert_eq!(image.input_format().ok(), Some(ImageFormat::Svg));
+
+    let decoded = decode_png(image);
+    assert_eq!(decoded.dimensions(), (256, 256));
+    assert_eq!(decoded.get_pixel(64, 128).0, [255, 0, 0, 255]);
+    assert_eq!(decoded.get_pixel(192, 128).0[3], 0);
+}
+
+#[test]
+fn test_svg_keeps_its_aspect_ratio() {
+    test_setup_logging();
+    let image = Image::builder(BADGE)
+        .with_target_geometry(Geometry {
+            width: Some(100),
+            height: None,
+        })
+        .build()
+        .expect("failed to draw SVG");
+    assert_eq!(decode_png(image).dimensions(), (100, 100));
+
+    // without a geometry it's drawn at its own width and height
+    let image = Image::try_from(&PathBuf::from(BADGE)).expect("failed to draw SVG");
+    assert_eq!(decode_png(image).dimensions(), (32, 32));
+}
+
+#[test]
+fn test_svg_without_a_size_needs_a_geometry() {
+    test_setup_logging();
+    let svg = br#"<svg xmlns="http://www.w3.org/2000/svg"><rect width="8" height="8"/></svg>"#;
+    let tempdir = tempfile::tempdir().expect("failed to create tempdir");
+    let path = tempdir.path().join("unsized.svg");
+    std::fs::write(&path, svg).expect("failed to write SVG");
+
+    let err = Image::from_bytes(svg, None).expect_err("an unsized SVG needs a geometry");
+    assert_eq!(
+        err.to_exit_code(),
+        Error::InvalidGeometry(String::new()).to_exit_code()
+    );
+
+    let image = Image::builder(&path)
+        .with_target_geometry(Geometry::new(64, 48))
+        .build()
+        .expect("failed to draw SVG");
+    assert_eq!(decode_png(image).dimensions(), (64, 48));
+}
@@ -0,0 +1,70 @@
+use std::{fs, path::PathBuf};
+
+use shrinky_rs::{ImageFormat, cli::test_setup_logging, imagedata::Image, verify_written_output};
+use tempfile::TempDir;
+
+fn test_image() -> Image {
+    let source = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+        image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+    }));
+    Image {
+        output_format: Some(ImageFormat::Png),
+        ..Image::from_dynamic_image(source, PathBuf::from("tests/test_images/verify.png"))
+    }
+}
+
+#[test]
+fn test_verify_accepts_good_output() {
+    test_setup_logging();
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let output = tempdir.path().join("good.png");
+    let image = test_image();
+    let encoded = image
+        .output_as_format(ImageFormat::Png)
+        .expect("failed to encode PNG");
+    fs::write(&output, encoded).expect("failed to write output");
+
+    Image::verify_output(&output, &image, 0.9).expect("good output should verify");
+    assert_eq!(verify_written_output(&image, &output, 0.9, true), 0);
+    assert!(output.exists(), "good output should be kept");
+}
+
+#[test]
+fn test_verify_removes_corrupt_output() {
+    test_setup_logging();
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let output = tempdir.path().join("corrupt.png");
+    fs::write(&output, b"this is definitely not a PNG file").expect("failed to write garbage");
+    let image = test_image();
+
+    assert!(Image::verify_output(&output, &image, 0.9).is_err());
+    assert_eq!(
+        verify_written_output(&image, &output, 0.9, true),
+        1,
+        "corrupt output should fail verification"
+    );
+    assert!(!output.exists(), "corrupt output should be removed");
+}
+
+#[test]
+fn test_verify_keeps_corrupt_output_when_it_replaced_the_input() {
+    test_setup_logging();
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let output = tempdir.path().join("in-place.png");
+    fs::write(&output, b"garbage").expect("failed to write garbage");
+
+    assert_eq!(verify_written_output(&test_image(), &output, 0.9, false), 1);
+    assert!(output.exists(), "output replacing the input isn't removed");
+}
+
+#[test]
+fn test_verify_rejects_dimension_mismatch() {
+    test_setup_logging();
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let output = tempdir.path().join("small.png");
+    image::DynamicImage::new_rgb8(8, 8)
+        .save(&output)
+        .expect("failed to write small image");
+
+    assert!(Image::verify_output(&output, &test_image(), 0.0).is_err());
+}
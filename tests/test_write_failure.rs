@@ -0,0 +1,131 @@
+//! A failed write mustn't leave a partial output file, or the temporary file it was written to,
+//! and a successful one replaces an existing output without changing its permissions
+#![cfg(unix)]
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{Command, Output},
+};
+
+use tempfile::TempDir;
+
+fn run_shrinky(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shrinky-rs"))
+        .args(args)
+        .output()
+        .expect("failed to run shrinky-rs")
+}
+
+fn file_names(dir: &Path) -> BTreeSet<String> {
+    fs::read_dir(dir)
+        .expect("failed to list tempdir")
+        .map(|entry| {
+            entry
+                .expect("failed to read tempdir entry")
+                .file_name()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+#[test]
+fn test_read_only_directory_leaves_no_output() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("read-only.jpg");
+    fs::copy("tests/test_images/bruny-oysters.jpg", &input).expect("failed to copy fixture");
+    // the directory starts out writable...
+    fs::write(tempdir.path().join("marker"), [0]).expect("failed to write marker");
+    let before = file_names(tempdir.path());
+
+    // ...then loses write permission. It keeps execute, or the input couldn't be opened.
+    fs::set_permissions(tempdir.path(), fs::Permissions::from_mode(0o555))
+        .expect("failed to make tempdir read-only");
+    if fs::write(tempdir.path().join("probe"), [0]).is_ok() {
+        // root ignores directory permissions, so there's nothing to test
+        fs::set_permissions(tempdir.path(), fs::Permissions::from_mode(0o755))
+            .expect("failed to restore tempdir permissions");
+        eprintln!("skipping, permissions aren't enforced for this user");
+        return;
+    }
+
+    let result = run_shrinky(&["--output-type", "png", input.to_str().expect("utf-8 path")]);
+    fs::set_permissions(tempdir.path(), fs::Permissions::from_mode(0o755))
+        .expect("failed to restore tempdir permissions");
+
+    assert_eq!(
+        result.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert_eq!(
+        file_names(tempdir.path()),
+        before,
+        "no output or temporary file should be left behind"
+    );
+}
+
+#[test]
+fn test_failed_rename_removes_temporary_file() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("blocked.jpg");
+    fs::copy("tests/test_images/bruny-oysters.jpg", &input).expect("failed to copy fixture");
+    // the temporary file can be written, but not renamed over a directory
+    fs::create_dir(tempdir.path().join("blocked.png")).expect("failed to create directory");
+    let before = file_names(tempdir.path());
+
+    let result = run_shrinky(&[
+        "--force",
+        "--output-type",
+        "png",
+        input.to_str().expect("utf-8 path"),
+    ]);
+
+    assert!(
+        !result.status.success(),
+        "writing over a directory should fail"
+    );
+    assert_eq!(
+        file_names(tempdir.path()),
+        before,
+        "the temporary file should be cleaned up"
+    );
+    assert!(tempdir.path().join("blocked.png").is_dir());
+}
+
+#[test]
+fn test_overwriting_keeps_permissions() {
+    let tempdir = TempDir::new().expect("failed to create tempdir");
+    let input = tempdir.path().join("shared.jpg");
+    fs::copy("tests/test_images/bruny-oysters.jpg", &input).expect("failed to copy fixture");
+    let output = tempdir.path().join("shared.png");
+    fs::write(&output, b"old output").expect("failed to write existing output");
+    fs::set_permissions(&output, fs::Permissions::from_mode(0o640))
+        .expect("failed to set output permissions");
+
+    let result = run_shrinky(&[
+        "--force",
+        "--output-type",
+        "png",
+        input.to_str().expect("utf-8 path"),
+    ]);
+
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert_ne!(
+        fs::read(&output).expect("failed to read output"),
+        b"old output"
+    );
+    let mode = fs::metadata(&output)
+        .expect("failed to read output metadata")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o640);
+}